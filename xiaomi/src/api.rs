@@ -0,0 +1,331 @@
+// REST API of `serve --api`, for other software on this computer or the LAN. Answers are JSON.
+//  GET  /devices                  devices in toml and devices heard, with their latest readings
+//  GET  /devices/{name}/readings  latest reading of each metric of a device
+//  GET  /devices/{name}/history   readings of the last day, for charts of `serve --web`
+//  POST /devices/{name}/sync      starts syncing the clock of a device in the background, answered with 202 at once
+//  GET  /syncs/{id}               a sync started over the API, with the summary of the run once it's done
+// {name} is a name in toml or an address, like `sync` takes.
+
+use crate::state::State;
+use crate::store::Metric;
+use crate::webhook::SyncReport;
+use crate::{format_bluetooth_address, http, Config};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+// history is kept in memory for a day, a reading a minute of each metric. ~4300 readings of a device.
+pub const HISTORY_SECONDS: u64 = 24 * 3600;
+const HISTORY_STEP_SECONDS: u64 = 60;
+// syncs looked up with GET /syncs/{id}. older ones are forgotten.
+const SYNC_JOBS_KEPT: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Reading {
+    // unix epoch when it was heard.
+    pub time: u64,
+    pub metric: &'static str,
+    pub value: f32,
+}
+
+// latest value of each metric a device sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatestReadings {
+    readings: Vec<Reading>,
+}
+
+impl LatestReadings {
+    pub fn record(&mut self, metric: Metric, value: f32, time: u64) {
+        let reading = Reading { time: time, metric: metric.name(), value: value };
+        match self.readings.iter_mut().find(|r| r.metric == reading.metric) {
+            Some(latest) => *latest = reading,
+            None => self.readings.push(reading),
+        }
+    }
+
+    pub fn get(&self, metric: Metric) -> Option<f32> {
+        return self.readings.iter().find(|r| r.metric == metric.name()).map(|r| r.value);
+    }
+
+    // time of the latest reading of any metric.
+    pub fn last_seen(&self) -> Option<u64> {
+        return self.readings.iter().map(|r| r.time).max();
+    }
+
+    // temperature, humidity, then battery.
+    pub fn readings(&self) -> Vec<Reading> {
+        let mut readings = self.readings.clone();
+        readings.sort_by_key(|r| [Metric::Temperature, Metric::Humidity, Metric::Battery].iter().position(|m| m.name() == r.metric));
+        return readings;
+    }
+}
+
+// readings of the last HISTORY_SECONDS, oldest first. nothing is kept after `serve` ends.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct History {
+    readings: VecDeque<Reading>,
+}
+
+impl History {
+    // a reading less than a step after the last one of the same metric is left out.
+    pub fn record(&mut self, metric: Metric, value: f32, time: u64) {
+        let last = self.readings.iter().rev().find(|r| r.metric == metric.name());
+        if last.is_some_and(|r| time < r.time + HISTORY_STEP_SECONDS) {
+            return;
+        }
+        self.readings.push_back(Reading { time: time, metric: metric.name(), value: value });
+        while self.readings.front().is_some_and(|r| r.time + HISTORY_SECONDS < time) {
+            self.readings.pop_front();
+        }
+    }
+
+    pub fn readings(&self) -> Vec<Reading> {
+        return self.readings.iter().copied().collect();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    pub address: String,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<f32>,
+    // battery is below low_battery_percent.
+    pub low_battery: bool,
+    // unix epochs. last_seen is of this run of `serve`, or the last command which heard the device.
+    pub last_seen: Option<u64>,
+    pub last_synced: Option<u64>,
+    // positive when the clock was ahead, before it was synced.
+    pub last_drift_seconds: Option<i64>,
+}
+
+// devices in toml first, in toml order, then devices heard which aren't in toml, by address.
+pub fn devices(config: &Config, heard: &HashMap<u64, LatestReadings>, state: &State) -> Vec<Device> {
+    let mut addresses: Vec<u64> = config.devices.iter().flatten().map(|d| d.address).collect();
+    let mut others: Vec<u64> = heard.keys().copied().filter(|a| !addresses.contains(a)).collect();
+    others.sort();
+    addresses.extend(others);
+
+    let low_battery_percent = config.general.low_battery_percent.unwrap_or(20) as f32;
+    let configured: HashMap<u64, &crate::DeviceConfig> = config.devices.iter().flatten().map(|d| (d.address, d)).collect();
+    let mut devices = Vec::new();
+    for address in addresses {
+        let readings = heard.get(&address);
+        let device_state = state.get(address);
+        let battery = readings.and_then(|r| r.get(Metric::Battery));
+        devices.push(Device {
+            address: format_bluetooth_address(address),
+            name: configured.get(&address).and_then(|d| d.name.clone()),
+            group: configured.get(&address).and_then(|d| d.group.clone()),
+            temperature: readings.and_then(|r| r.get(Metric::Temperature)),
+            humidity: readings.and_then(|r| r.get(Metric::Humidity)),
+            battery: battery,
+            low_battery: battery.is_some_and(|b| b < low_battery_percent),
+            last_seen: readings.and_then(|r| r.last_seen()).or(device_state.and_then(|s| s.last_seen)),
+            last_synced: device_state.and_then(|s| s.last_synced),
+            last_drift_seconds: device_state.and_then(|s| s.last_drift_seconds),
+        });
+    }
+    return devices;
+}
+
+// a sync started over the API. it runs in the background, with the advertisements `serve` listens to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncJob {
+    pub id: u64,
+    // {name} it was started with.
+    pub device: String,
+    // unix epoch.
+    pub started: u64,
+    // "running", then "done".
+    pub status: &'static str,
+    // summary of the run, the same as `sync --format json`. null while running.
+    pub report: Option<SyncReport>,
+}
+
+// syncs started over the API, the latest SYNC_JOBS_KEPT of them. ids start at 1.
+#[derive(Debug, Default)]
+pub struct SyncJobs {
+    last_id: u64,
+    jobs: VecDeque<SyncJob>,
+}
+
+impl SyncJobs {
+    // None while another one is running. two runs would connect to the same devices.
+    pub fn start(&mut self, device: &str, time: u64) -> Option<SyncJob> {
+        if self.is_running() {
+            return None;
+        }
+        self.last_id += 1;
+        let job = SyncJob { id: self.last_id, device: device.to_string(), started: time, status: "running", report: None };
+        self.jobs.push_back(job.clone());
+        while self.jobs.len() > SYNC_JOBS_KEPT {
+            self.jobs.pop_front();
+        }
+        return Some(job);
+    }
+
+    pub fn finish(&mut self, id: u64, report: SyncReport) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.status = "done";
+            job.report = Some(report);
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&SyncJob> {
+        return self.jobs.iter().find(|job| job.id == id);
+    }
+
+    pub fn is_running(&self) -> bool {
+        return self.jobs.iter().any(|job| job.report.is_none());
+    }
+}
+
+// answer of POST /devices/{name}/sync. `status` is the path to poll.
+//  {"id":3,"status":"/syncs/3"}
+#[derive(Serialize)]
+struct SyncStarted {
+    id: u64,
+    status: String,
+}
+
+pub fn sync_started(job: &SyncJob) -> http::Response {
+    let status = format!("/syncs/{}", job.id);
+    let mut response = json(202, &SyncStarted { id: job.id, status: status.clone() });
+    response.headers.push(("Location", status));
+    return response;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Devices,
+    Readings(String),
+    History(String),
+    Sync(String),
+    SyncJob(u64),
+}
+
+// None for paths which aren't of the API. names are percent-decoded, so "Living%20room" is "Living room".
+pub fn route(path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+    return match segments.as_slice() {
+        ["devices"] => Some(Route::Devices),
+        ["devices", name, "readings"] => http::decode_component(name).map(Route::Readings),
+        ["devices", name, "history"] => http::decode_component(name).map(Route::History),
+        ["devices", name, "sync"] => http::decode_component(name).map(Route::Sync),
+        ["syncs", id] => id.parse().ok().map(Route::SyncJob),
+        _ => None,
+    };
+}
+
+pub fn json<T: Serialize>(status: u16, value: &T) -> http::Response {
+    return match serde_json::to_string(value) {
+        Ok(body) => http::Response::new(status, "application/json", body),
+        Err(e) => error(500, &e.to_string()),
+    };
+}
+
+//  {"error":"Unknown device \"attic\". Use a name in toml, or a bluetooth address."}
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+pub fn error(status: u16, message: &str) -> http::Response {
+    return json(status, &ErrorBody { error: message });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_readings() {
+        let mut latest = LatestReadings::default();
+        latest.record(Metric::Battery, 87.0, 1696891900);
+        latest.record(Metric::Temperature, 23.5, 1696891910);
+        latest.record(Metric::Temperature, 23.4, 1696891938);
+        assert_eq!(latest.get(Metric::Temperature), Some(23.4));
+        assert_eq!(latest.get(Metric::Humidity), None);
+        assert_eq!(latest.last_seen(), Some(1696891938));
+        assert_eq!(serde_json::to_string(&latest.readings()).unwrap(),
+            r#"[{"time":1696891938,"metric":"temperature","value":23.4},{"time":1696891900,"metric":"battery","value":87.0}]"#);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        history.record(Metric::Temperature, 23.5, 1696800000);
+        history.record(Metric::Humidity, 68.0, 1696800010);
+        history.record(Metric::Temperature, 23.6, 1696800030);
+        history.record(Metric::Temperature, 23.7, 1696800060);
+        let values: Vec<(u64, f32)> = history.readings().iter().map(|r| (r.time, r.value)).collect();
+        assert_eq!(values, vec![(1696800000, 23.5), (1696800010, 68.0), (1696800060, 23.7)]);
+
+        // a day later, the oldest are dropped.
+        history.record(Metric::Temperature, 24.0, 1696800000 + HISTORY_SECONDS + 30);
+        let values: Vec<(u64, f32)> = history.readings().iter().map(|r| (r.time, r.value)).collect();
+        assert_eq!(values, vec![(1696800060, 23.7), (1696886430, 24.0)]);
+    }
+
+    #[test]
+    fn test_devices() {
+        let config: Config = toml::from_str("[[device]]\naddress = \"11:22:33:44:55:66\"\nname = \"Tokyo\"\ngroup = \"bedroom\"").unwrap();
+        let mut heard = HashMap::new();
+        heard.entry(0xAABBCCDDEEFF).or_insert(LatestReadings::default()).record(Metric::Humidity, 68.0, 1696891938);
+        heard.entry(0xAABBCCDDEEFF).or_insert(LatestReadings::default()).record(Metric::Battery, 15.0, 1696891938);
+        let mut state = State::default();
+        state.record_synced(0x112233445566, 1696800000);
+        state.record_seen(0x112233445566, 1696890000);
+
+        let devices = devices(&config, &heard, &state);
+        assert_eq!(serde_json::to_string(&devices).unwrap(), concat!(
+            r#"[{"address":"11:22:33:44:55:66","name":"Tokyo","group":"bedroom","temperature":null,"humidity":null,"battery":null,"low_battery":false,"#,
+            r#""last_seen":1696890000,"last_synced":1696800000,"last_drift_seconds":null},"#,
+            r#"{"address":"AA:BB:CC:DD:EE:FF","name":null,"group":null,"temperature":null,"humidity":68.0,"battery":15.0,"low_battery":true,"#,
+            r#""last_seen":1696891938,"last_synced":null,"last_drift_seconds":null}]"#));
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("/devices"), Some(Route::Devices));
+        assert_eq!(route("/devices/"), Some(Route::Devices));
+        assert_eq!(route("/devices/Living%20room/readings"), Some(Route::Readings("Living room".to_string())));
+        assert_eq!(route("/devices/Tokyo/history"), Some(Route::History("Tokyo".to_string())));
+        assert_eq!(route("/devices/AA:BB:CC:DD:EE:FF/sync"), Some(Route::Sync("AA:BB:CC:DD:EE:FF".to_string())));
+        assert_eq!(route("/syncs/3"), Some(Route::SyncJob(3)));
+        assert_eq!(route("/syncs/latest"), None);
+        assert_eq!(route("/devices/Tokyo"), None);
+        assert_eq!(route("/metrics"), None);
+    }
+
+    #[test]
+    fn test_sync_jobs() {
+        let mut jobs = SyncJobs::default();
+        let job = jobs.start("Tokyo", 1696891938).unwrap();
+        assert_eq!(job.id, 1);
+        assert!(jobs.start("Osaka", 1696891940).is_none());
+        assert_eq!(serde_json::to_string(jobs.get(1).unwrap()).unwrap(),
+            r#"{"id":1,"device":"Tokyo","started":1696891938,"status":"running","report":null}"#);
+
+        jobs.finish(1, SyncReport { time: 1696891970, success: true, devices: vec![] });
+        assert_eq!(jobs.get(1).unwrap().status, "done");
+        assert_eq!(jobs.start("Osaka", 1696891980).unwrap().id, 2);
+        assert!(jobs.get(3).is_none());
+
+        let response = sync_started(jobs.get(2).unwrap());
+        let mut written: Vec<u8> = Vec::new();
+        http::write_response(&mut written, &response).unwrap();
+        assert!(String::from_utf8(written).unwrap().starts_with("HTTP/1.1 202 Accepted\r\n"));
+        assert_eq!(String::from_utf8(response.body).unwrap(), r#"{"id":2,"status":"/syncs/2"}"#);
+
+        // the oldest are forgotten.
+        for id in 2..=(SYNC_JOBS_KEPT as u64 + 1) {
+            jobs.finish(id, SyncReport { time: 1696891990, success: true, devices: vec![] });
+            jobs.start("Tokyo", 1696892000);
+        }
+        assert_eq!(jobs.get(2), None);
+        assert_eq!(jobs.get(3).unwrap().status, "done");
+    }
+}
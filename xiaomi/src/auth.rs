@@ -0,0 +1,215 @@
+// Authentication of every endpoint of `serve`, declared as [serve] in toml. Without it, anyone reaching the port
+// reads the readings and syncs clocks, which is fine on localhost, less so on a network shared with guests.
+//  Authorization: Bearer <token>                      Prometheus, scripts and other software
+//  Authorization: Basic <base64 of username:password>  browsers, which ask for them on the dashboard
+// Both are sent in clear text over http. With `cert` and `key`, every listener serves https instead.
+// Only this computer is served without them. Away from home, `bind` to the address of a WireGuard interface, or keep
+// 127.0.0.1 and forward the port with `ssh -L`.
+
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const REALM: &str = "xiaomi";
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Declared as [serve] in toml. either or both of them. nothing is asked when neither is set.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ServeConfig {
+    pub token: Option<String>,
+    // set with password.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // PEM files of the certificate chain and its private key. set both to serve https.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // where addresses like ":9184" listen. default is every interface with a token or a password, 127.0.0.1 without.
+    pub bind: Option<String>,
+}
+
+impl ServeConfig {
+    pub fn is_enabled(&self) -> bool {
+        return self.token.is_some() || self.username.is_some() || self.password.is_some();
+    }
+
+    pub fn is_tls(&self) -> bool {
+        return self.cert.is_some() && self.key.is_some();
+    }
+
+    // host of addresses given as ":port".
+    pub fn bind_host(&self) -> String {
+        let default = if self.is_enabled() { "0.0.0.0" } else { "127.0.0.1" };
+        return self.bind.clone().unwrap_or(default.to_string());
+    }
+
+    // cert and key, relative ones from `dir` of the toml. the service doesn't run where it's installed.
+    pub fn tls_paths(&self, dir: &Path) -> Option<(PathBuf, PathBuf)> {
+        return match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some((dir.join(cert), dir.join(key))),
+            _ => None,
+        };
+    }
+}
+
+pub fn check_config(config: &ServeConfig) -> Result<(), String> {
+    if config.token.as_deref().is_some_and(|token| token.is_empty()) {
+        return Err("empty token in [serve]".to_string());
+    }
+    match (&config.username, &config.password) {
+        (Some(_), None) => return Err("username without password in [serve]".to_string()),
+        (None, Some(_)) => return Err("password without username in [serve]".to_string()),
+        (Some(username), Some(_)) if username.is_empty() || username.contains(':') => {
+            return Err(format!("invalid username \"{}\" in [serve]. Use a name without ':'", username));
+        },
+        _ => {},
+    }
+    match (&config.cert, &config.key) {
+        (Some(_), None) => return Err("cert without key in [serve]".to_string()),
+        (None, Some(_)) => return Err("key without cert in [serve]".to_string()),
+        _ => {},
+    }
+    if let Some(bind) = &config.bind {
+        let ip: IpAddr = bind.parse().map_err(|_| format!("invalid bind \"{}\" in [serve]. Use an IP address, like 127.0.0.1", bind))?;
+        check_listen(config, ip)?;
+    }
+    return Ok(());
+}
+
+// other computers can reach any address but loopback. they're served with a token or a password only.
+pub fn check_listen(config: &ServeConfig, ip: IpAddr) -> Result<(), String> {
+    if ip.is_loopback() || config.is_enabled() {
+        return Ok(());
+    }
+    return Err(format!("serving {} needs a token, or a username and password, in [serve]. Other computers can reach it", ip));
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    return encoded;
+}
+
+// takes as long wherever they differ, so a token can't be guessed a character at a time.
+fn equals(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0;
+}
+
+// `authorization` is the value of the Authorization header of a request.
+pub fn authorized(config: &ServeConfig, authorization: Option<&str>) -> bool {
+    if !config.is_enabled() {
+        return true;
+    }
+    let (scheme, credentials) = match authorization.and_then(|value| value.split_once(' ')) {
+        Some((scheme, credentials)) => (scheme.to_ascii_lowercase(), credentials.trim()),
+        None => return false,
+    };
+    return match (scheme.as_str(), &config.token, &config.username, &config.password) {
+        ("bearer", Some(token), _, _) => equals(credentials, token),
+        ("basic", _, Some(username), Some(password)) => equals(credentials, &encode_base64(format!("{}:{}", username, password).as_bytes())),
+        _ => false,
+    };
+}
+
+// what clients can send, for discovery. "none" without [serve].
+pub fn schemes(config: &ServeConfig) -> String {
+    let mut schemes: Vec<&str> = Vec::new();
+    if config.token.is_some() {
+        schemes.push("bearer");
+    }
+    if config.username.is_some() {
+        schemes.push("basic");
+    }
+    if schemes.is_empty() {
+        return "none".to_string();
+    }
+    return schemes.join(",");
+}
+
+// WWW-Authenticate header of a 401. browsers ask for a username and password on Basic only.
+pub fn challenge(config: &ServeConfig) -> String {
+    if config.username.is_some() {
+        return format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM);
+    }
+    return format!("Bearer realm=\"{}\"", REALM);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b"family:s3cret"), "ZmFtaWx5OnMzY3JldA==");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(encode_base64(b"abc"), "YWJj");
+        assert_eq!(encode_base64(b""), "");
+    }
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(&ServeConfig::default(), None));
+        assert_eq!(schemes(&ServeConfig::default()), "none");
+
+        let config: ServeConfig = toml::from_str("token = \"abc\"\nusername = \"family\"\npassword = \"s3cret\"").unwrap();
+        assert!(authorized(&config, Some("Bearer abc")));
+        assert!(authorized(&config, Some("bearer abc")));
+        assert!(authorized(&config, Some("Basic ZmFtaWx5OnMzY3JldA==")));
+        assert!(!authorized(&config, Some("Bearer abd")));
+        assert!(!authorized(&config, Some("Basic ZmFtaWx5OnMzY3JldB==")));
+        assert!(!authorized(&config, Some("Token abc")));
+        assert!(!authorized(&config, None));
+        assert_eq!(schemes(&config), "bearer,basic");
+
+        let config: ServeConfig = toml::from_str("token = \"abc\"").unwrap();
+        assert!(!authorized(&config, Some("Basic ZmFtaWx5OnMzY3JldA==")));
+        assert_eq!(challenge(&config), "Bearer realm=\"xiaomi\"");
+    }
+
+    #[test]
+    fn test_check_config() {
+        assert!(check_config(&toml::from_str("token = \"abc\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("username = \"family\"\npassword = \"s3cret\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("token = \"\"").unwrap()).is_err());
+        assert!(check_config(&toml::from_str("username = \"family\"").unwrap()).is_err());
+        assert!(check_config(&toml::from_str("username = \"a:b\"\npassword = \"s3cret\"").unwrap()).is_err());
+        assert!(check_config(&toml::from_str("cert = \"xiaomi.crt\"\nkey = \"xiaomi.key\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("cert = \"xiaomi.crt\"").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_bind() {
+        assert_eq!(ServeConfig::default().bind_host(), "127.0.0.1");
+        let config: ServeConfig = toml::from_str("token = \"abc\"").unwrap();
+        assert_eq!(config.bind_host(), "0.0.0.0");
+        assert!(check_config(&toml::from_str("token = \"abc\"\nbind = \"10.8.0.2\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("bind = \"::1\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("bind = \"10.8.0.2\"").unwrap()).is_err());
+        assert!(check_config(&toml::from_str("token = \"abc\"\nbind = \"tokyo-pc\"").unwrap()).is_err());
+
+        // addresses given to `serve` are checked the same.
+        assert!(check_listen(&ServeConfig::default(), "127.0.0.1".parse().unwrap()).is_ok());
+        assert!(check_listen(&ServeConfig::default(), "0.0.0.0".parse().unwrap()).is_err());
+        assert!(check_listen(&config, "0.0.0.0".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_tls_paths() {
+        let config: ServeConfig = toml::from_str("cert = \"xiaomi.crt\"\nkey = \"/etc/xiaomi/xiaomi.key\"").unwrap();
+        let (cert, key) = config.tls_paths(Path::new("/opt/xiaomi")).unwrap();
+        assert_eq!(cert, PathBuf::from("/opt/xiaomi/xiaomi.crt"));
+        assert_eq!(key, PathBuf::from("/etc/xiaomi/xiaomi.key"));
+        assert!(ServeConfig::default().tls_paths(Path::new("/opt/xiaomi")).is_none());
+    }
+}
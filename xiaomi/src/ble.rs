@@ -2,124 +2,299 @@ use std::{
     collections::{HashMap, HashSet},
     sync::mpsc::Sender,
     sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
 use windows::{
-    core::GUID,
+    core::{GUID, RuntimeType},
     Devices::Bluetooth::{
         Advertisement::{*},
+        BluetoothAdapter,
+        BluetoothAddressType,
+        BluetoothCacheMode,
+        BluetoothError,
         BluetoothLEDevice,
         GenericAttributeProfile::{
             GattDeviceService,
-            GattCommunicationStatus, GattCharacteristic},
+            GattClientCharacteristicConfigurationDescriptorValue,
+            GattCommunicationStatus, GattCharacteristic,
+            GattValueChangedEventArgs},
     },
-    // Foundation::TypedEventHandler
+    Devices::Radios::RadioState,
+    Foundation::{AsyncStatus, EventRegistrationToken, IAsyncOperation, TypedEventHandler},
+    Storage::Streams::{DataReader, IBuffer},
 };
 
-use xiaomi::get_unix_epoc;
-use xiaomi::DeviceConfig;
+use xiaomi::bus::Publisher;
+use xiaomi::capture::encode_hex;
+use xiaomi::clock::{self, ClockTime, TimezonePolicy, target_clock_time};
+use xiaomi::decoder::{self, Reading};
+use xiaomi::firmware;
+use xiaomi::gatt;
+use xiaomi::store::Metric;
+use xiaomi::history::HistoryRecord;
+use xiaomi::humanize::format_signed_duration;
+use xiaomi::{DeviceConfig, DeviceFilter};
+use xiaomi::{format_bluetooth_address, resolve_device_address};
+use xiaomi::RetryPolicy;
+use xiaomi::timesource::TimeProvider;
+use xiaomi::unit::TemperatureUnit;
 
-// this is not xiaomi specific, it could be reported from any other BLE devices.
-const ENVIRONMENTAL_SENSING_SERVICE_UUID: GUID = GUID::from_u128(0x0000181a00001000800000805f9b34fb);   // "0000181a-0000-1000-8000-00805f9b34fb"
 const LYWSD02_SERVICE_UUID: GUID = GUID::from_u128(0xEBE0CCB07A0A4B0C8A1A6FF2997DA3A6); // "EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6"
 const LYWSD02_CHARACTERISTIC_TIME_UUID: GUID = GUID::from_u128(0xEBE0CCB77A0A4B0C8A1A6FF2997DA3A6); // "EBE0CCB7-7A0A-4B0C-8A1A-6FF2997DA3A6"
+const LYWSD02_CHARACTERISTIC_UNIT_UUID: GUID = GUID::from_u128(0xEBE0CCBE7A0A4B0C8A1A6FF2997DA3A6); // "EBE0CCBE-7A0A-4B0C-8A1A-6FF2997DA3A6"
+const LYWSD02_CHARACTERISTIC_BATTERY_UUID: GUID = GUID::from_u128(0xEBE0CCC47A0A4B0C8A1A6FF2997DA3A6); // "EBE0CCC4-7A0A-4B0C-8A1A-6FF2997DA3A6"
+const LYWSD02_CHARACTERISTIC_HISTORY_UUID: GUID = GUID::from_u128(0xEBE0CCBC7A0A4B0C8A1A6FF2997DA3A6); // "EBE0CCBC-7A0A-4B0C-8A1A-6FF2997DA3A6"
+// standard Device Information service, and its Firmware Revision String.
+const DEVICE_INFORMATION_SERVICE_UUID: GUID = GUID::from_u128(0x0000180A00001000800000805F9B34FB); // "0000180A-0000-1000-8000-00805F9B34FB"
+const FIRMWARE_REVISION_CHARACTERISTIC_UUID: GUID = GUID::from_u128(0x00002A2600001000800000805F9B34FB); // "00002A26-0000-1000-8000-00805F9B34FB"
 
+// history download ends when no record arrives for this long.
+const HISTORY_QUIET_PERIOD: Duration = Duration::from_secs(3);
+
+#[derive(Clone)]
 pub struct SensorValue {
     pub address: u64,
     pub value: f32,
 }
 
+// raw service data from xiaomi device. used for diagnostics and capture files.
+#[derive(Clone)]
+pub struct ServiceData {
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone)]
 pub enum AdvertisementKind {
-    // For now all other advertisements are unknown.
-    Unknown,
-    // Omit is from xiaomi device. Having 'address' field might be better in future.
-    Omit,
     // Following 3 are data sent from xiaomi device.
     Temperature(SensorValue),
     Humidity(SensorValue),
     Battery(SensorValue),
+    // Every MiBeacon / BTHome frame, whether it's decoded or not.
+    Frame(ServiceData),
+}
+
+impl AdvertisementKind {
+    // None for frames, which aren't a reading.
+    pub fn metric(&self) -> Option<Metric> {
+        return match self {
+            AdvertisementKind::Temperature(_) => Some(Metric::Temperature),
+            AdvertisementKind::Humidity(_) => Some(Metric::Humidity),
+            AdvertisementKind::Battery(_) => Some(Metric::Battery),
+            AdvertisementKind::Frame(_) => None,
+        };
+    }
 }
 
 // decode advertisement packet. especially, decode the xiaomi's temperature / humidity packet.
-pub fn decode_advertisement(args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> AdvertisementKind {
-    if let Some(args) = args {
-        let advertisement = args.Advertisement().unwrap();
-        let services = advertisement.ServiceUuids().unwrap();
-        let has_services = services.Size().unwrap() != 0;
-        let has_xiaomi_service = has_services && services.into_iter().find(|&x| x == ENVIRONMENTAL_SENSING_SERVICE_UUID) == Some(ENVIRONMENTAL_SENSING_SERVICE_UUID);
-
-        if has_xiaomi_service {
-            //let address_type = args.BluetoothAddressType().unwrap();
-            let address64 = args.BluetoothAddress().unwrap();
-
-            for section in advertisement.DataSections().unwrap() {
-                let data_type = section.DataType().unwrap();
-
-                // ServiceData
-                if data_type == 0x16 {
-                    let data = section.Data().unwrap();
-                    let reader = windows::Storage::Streams::DataReader::FromBuffer(&data).unwrap();
-                    let mut vector: Vec<u8> = Vec::new();
-                    vector.resize(data.Length().unwrap() as usize, 0);
-                    reader.ReadBytes(vector.as_mut_slice()).ok();
-
-                    // Temperature and Humidity are using 2 bytes. Combine them and convert into f32.
-                    // Battery is percentage, just single byte.
-                    match vector[14] {
-                        4 => { // temperature
-                            let v1 = (vector[17] as i32) + (vector[18] as i32) * 256;
-                            let v2 = (v1 as f32) / 10.0;
-                            return AdvertisementKind::Temperature(SensorValue{ address: address64, value: v2 });
-                        },
-                        6 => { // humidity
-                            let v1 = (vector[17] as i32) + (vector[18] as i32) * 256;
-                            let v2 = (v1 as f32) / 10.0;
-                            return AdvertisementKind::Humidity(SensorValue{ address: address64, value: v2 });
-                        },
-                        10 => { // battery
-                            let v = vector[17] as f32;
-                            return AdvertisementKind::Battery(SensorValue{ address: address64, value: v });
-                        },
-                        _ => {
-                            return AdvertisementKind::Unknown;
-                        }
-                    }
+// MiBeacon and BTHome frames are decoded into same kinds, so they are merged by the address.
+// Advertisements from unknown devices return nothing.
+pub fn decode_advertisement(args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> Vec<AdvertisementKind> {
+    // reading the advertisement fails when the adapter is gone. the watcher stops then, nothing to decode.
+    return match args {
+        Some(args) => try_decode_advertisement(args).unwrap_or_default(),
+        None => Vec::new(),
+    };
+}
+
+fn try_decode_advertisement(args: &BluetoothLEAdvertisementReceivedEventArgs) -> windows::core::Result<Vec<AdvertisementKind>> {
+    let mut kinds: Vec<AdvertisementKind> = Vec::new();
+
+    let advertisement = args.Advertisement()?;
+    let address64 = args.BluetoothAddress()?;
+
+    for section in advertisement.DataSections()? {
+        let data_type = section.DataType()?;
+
+        // ServiceData
+        if data_type == 0x16 {
+            let vector = read_buffer(&section.Data()?);
+
+            if vector.len() >= 2 {
+                let uuid = u16::from_le_bytes([vector[0], vector[1]]);
+                if uuid == decoder::MIBEACON_UUID16 || uuid == decoder::BTHOME_UUID16 {
+                    log::trace!("{} {}", format_bluetooth_address(address64), encode_hex(&vector));
+                    kinds.push(AdvertisementKind::Frame(ServiceData{ address: address64, data: vector.clone() }));
                 }
             }
 
-            // This has xiaomi service data, but we don't know the format. Let's omit.
-            return AdvertisementKind::Omit;
+            for reading in decoder::decode_service_data(&vector) {
+                kinds.push(match reading {
+                    Reading::Temperature(value) => AdvertisementKind::Temperature(SensorValue{ address: address64, value: value }),
+                    Reading::Humidity(value) => AdvertisementKind::Humidity(SensorValue{ address: address64, value: value }),
+                    Reading::Battery(value) => AdvertisementKind::Battery(SensorValue{ address: address64, value: value }),
+                });
+            }
         }
     }
 
-    // Advertisement from Unknown device.
-    return AdvertisementKind::Unknown;
+    return Ok(kinds);
 }
 
+// random addresses change over time, so they can't be a configured device.
+// false when the address type can't be read. older Windows doesn't have it.
+pub fn is_random_address(args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> bool {
+    return match args {
+        Some(args) => args.BluetoothAddressType().is_ok_and(|t| t == BluetoothAddressType::Random),
+        None => false,
+    };
+}
+
+// signal strength of the advertisement, in dBm.
+pub fn signal_strength(args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> Option<i16> {
+    return args.as_ref().and_then(|a| a.RawSignalStrengthInDBm().ok());
+}
+
+// weaker than `rssi_min`, like a device of the neighbour. kept when the strength can't be read.
+pub fn is_weak_signal(args: &Option<BluetoothLEAdvertisementReceivedEventArgs>, rssi_min: Option<i16>) -> bool {
+    return match (rssi_min, signal_strength(args)) {
+        (Some(min), Some(rssi)) => rssi < min,
+        _ => false,
+    };
+}
+
+// handler of watcher's Stopped event. sends the reason, when the watcher stopped by itself.
+pub fn on_watcher_stopped(sender: Sender<String>) -> TypedEventHandler<BluetoothLEAdvertisementWatcher, BluetoothLEAdvertisementWatcherStoppedEventArgs> {
+    return TypedEventHandler::new(move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementWatcherStoppedEventArgs>| {
+        if let Some(args) = args {
+            let error = args.Error()?;
+            if error != BluetoothError::Success {
+                sender.send(describe_bluetooth_error(error)).ok();
+            }
+        }
+        Ok(())
+    });
+}
+
+pub fn describe_bluetooth_error(error: BluetoothError) -> String {
+    let reasons = [
+        (BluetoothError::RadioNotAvailable, "Bluetooth radio is not available"),
+        (BluetoothError::ResourceInUse, "Bluetooth adapter is in use"),
+        (BluetoothError::DeviceNotConnected, "Bluetooth device is not connected"),
+        (BluetoothError::DisabledByPolicy, "Bluetooth is disabled by policy"),
+        (BluetoothError::NotSupported, "Bluetooth operation is not supported"),
+        (BluetoothError::DisabledByUser, "Bluetooth is turned off"),
+        (BluetoothError::ConsentRequired, "Bluetooth access is not allowed. Check privacy settings"),
+        (BluetoothError::TransportNotSupported, "Bluetooth LE is not supported by the adapter"),
+    ];
+    return reasons.iter().find(|(e, _)| *e == error).map_or(format!("Bluetooth error {}", error.0), |(_, msg)| msg.to_string());
+}
+
+// the default adapter, as `doctor` sees it.
+pub struct AdapterReport {
+    pub address: u64,
+    // None when it can't be read, like when access is denied.
+    pub radio_state: Option<RadioState>,
+    pub low_energy: bool,
+    // needed to connect to devices.
+    pub central_role: bool,
+}
+
+pub fn read_adapter(timeout: Duration) -> Result<AdapterReport, String> {
+    let adapter = get_with_timeout(BluetoothAdapter::GetDefaultAsync().map_err(|e| e.to_string())?, timeout, "find Bluetooth adapter")?;
+    let radio_state = get_with_timeout(adapter.GetRadioAsync().map_err(|e| e.to_string())?, timeout, "read radio")
+        .ok()
+        .and_then(|radio| radio.State().ok());
+    return Ok(AdapterReport {
+        address: adapter.BluetoothAddress().map_err(|e| e.to_string())?,
+        radio_state: radio_state,
+        low_energy: adapter.IsLowEnergySupported().unwrap_or(false),
+        central_role: adapter.IsCentralRoleSupported().unwrap_or(false),
+    });
+}
+
+// options controlling how clocks are synced.
+#[derive(Clone)]
+pub struct SyncOptions {
+    pub retry: RetryPolicy,
+    // how long each GATT operation can take.
+    pub timeout: Duration,
+    // clock is not written when drift is within this.
+    pub drift_threshold_seconds: u64,
+    // time read back after writing can differ by this.
+    pub verify_tolerance_seconds: u64,
+    // do everything but writing.
+    pub dry_run: bool,
+    // write even when drift is within threshold. still once per device in a run.
+    pub force: bool,
+    // how to show timezones not on the hour.
+    pub timezone_policy: TimezonePolicy,
+    // time written to clocks comes from this.
+    pub clock: Arc<dyn TimeProvider>,
+    // overrides device config for this run. applied to unconfigured devices too.
+    pub timezone: Option<String>,
+    pub offset_seconds: Option<i32>,
+    // devices which are only read, as in a dry run. drift is measured, nothing is written.
+    pub check_only: HashSet<u64>,
+}
+
+// how a device is handled successfully.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncResult {
+    Synced,
+    // drift is within threshold.
+    Skipped,
+    Omitted,
+    DryRun,
+    // drift measured on a device of `check_only`.
+    Checked,
+}
+
+#[derive(Clone)]
 pub enum SyncLogKind {
+    // waiting for a free sync worker.
+    Queued{ address: u64 },
     Progress{ address: u64, log: String },
     Error{ address: u64, log: String },
+    // clock drift measured before writing.
+    Drift{ address: u64, seconds: i64 },
+    // battery level read while connected, in percent.
+    Battery{ address: u64, percent: u8 },
+    // the device is handled successfully. last progress log tells how.
+    Done{ address: u64, result: SyncResult },
 }
 
-pub fn sync_device_args(config: &Arc<Mutex<HashMap<u64, DeviceConfig>>>, handled_devices: &Arc<Mutex<HashSet<u64>>>, sender: &Sender<SyncLogKind>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) {
+impl SyncLogKind {
+    pub fn address(&self) -> u64 {
+        return match self {
+            SyncLogKind::Queued { address } |
+            SyncLogKind::Progress { address, .. } |
+            SyncLogKind::Error { address, .. } |
+            SyncLogKind::Drift { address, .. } |
+            SyncLogKind::Battery { address, .. } |
+            SyncLogKind::Done { address, .. } => *address,
+        };
+    }
+}
+
+// called from the advertisement event handler, so it must return quickly.
+// returns the address to sync, if the advertisement is from a device which needs sync.
+// the device is marked as handled, so it's queued only once.
+pub fn select_device_args(config: &Arc<Mutex<HashMap<u64, DeviceConfig>>>, filter: &DeviceFilter, handled_devices: &Arc<Mutex<HashSet<u64>>>, sender: &Publisher<SyncLogKind>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> Option<u64> {
     // decode advertisement and return the address if it is xiaomi temperature sensor.
     // otherwise, we will omit this advertisement.
-    let get_address = |args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| -> Option<u64> {
-        match decode_advertisement(&args) {
-            AdvertisementKind::Temperature(v) | 
-            AdvertisementKind::Humidity(v) => Some(v.address),
-
-            // Battery might be sent from other devices. So omit this.
-            _ => None,
+    for kind in decode_advertisement(&args) {
+        if let Some(address) = sensor_address(&kind) {
+            return select_device(config, filter, handled_devices, sender, address);
         }
-    };
+    }
+    return None;
+}
 
-    // return true if this device is already handled successfully.
-    let is_handled = |address: u64| -> bool {
-        let handled_devices = handled_devices.lock().unwrap();
-        return handled_devices.contains(&address);
+// address of a temperature or humidity reading. Battery might be sent from other devices. So omit this.
+pub fn sensor_address(kind: &AdvertisementKind) -> Option<u64> {
+    return match kind {
+        AdvertisementKind::Temperature(v) |
+        AdvertisementKind::Humidity(v) => Some(v.address),
+        _ => None,
     };
+}
 
+// `address` is of a xiaomi temperature sensor, heard advertising. returns it when it's to be synced,
+// after publishing Queued. devices not selected, handled before, or configured as omit return None.
+pub fn select_device(config: &Arc<Mutex<HashMap<u64, DeviceConfig>>>, filter: &DeviceFilter, handled_devices: &Arc<Mutex<HashSet<u64>>>, sender: &Publisher<SyncLogKind>, address: u64) -> Option<u64> {
     // see if this device is omitable.
     let is_omit = |address: u64| -> bool {
         if let Some(device) = config.lock().unwrap().get(&address) {
@@ -130,59 +305,192 @@ pub fn sync_device_args(config: &Arc<Mutex<HashMap<u64, DeviceConfig>>>, handled
         return false;
     };
 
-    // advertisement looks xiaomi temperature sensor,
-    // and we didn't handle the device before.
-    if let Some(address) = get_address(&args) {
-        if is_handled(address) {
-            // do nothing
+    // aliased addresses share the handled state of the logical device.
+    let device_address = resolve_device_address(&config.lock().unwrap(), address);
+
+    // skip devices not selected on command line.
+    // then mark this device is handled. insert returns false if it's already handled.
+    if !filter.matches(device_address) || !handled_devices.lock().unwrap().insert(device_address) {
+        // do nothing
+    }
+    else if is_omit(address) {
+        sender.publish(SyncLogKind::Progress { address: address, log: "Configured as Omit".to_string() });
+        sender.publish(SyncLogKind::Done { address: address, result: SyncResult::Omitted });
+    }
+    else {
+        sender.publish(SyncLogKind::Queued { address: address });
+        return Some(address);
+    }
+    return None;
+}
+
+// called from sync worker threads. when sync fails, the device is unmarked as handled,
+// so it's queued again on its next advertisement. a synced device stays handled until the run ends, with `force` too.
+pub fn sync_device(config: &Arc<Mutex<HashMap<u64, DeviceConfig>>>, handled_devices: &Arc<Mutex<HashSet<u64>>>, options: &SyncOptions, sender: &Publisher<SyncLogKind>, address: u64) {
+    let mut device_config = config.lock().unwrap().get(&address).cloned().unwrap_or(DeviceConfig { address: address, ..Default::default() });
+    if options.timezone.is_some() {
+        device_config.timezone = options.timezone.clone();
+    }
+    if options.offset_seconds.is_some() {
+        device_config.offset_seconds = options.offset_seconds;
+    }
+
+    let timezone_hour = match device_config.get_timezone_hour(options.timezone_policy) {
+        Ok(hour) => hour,
+        Err(msg) => {
+            // retrying doesn't help. keep it marked as handled.
+            sender.publish(SyncLogKind::Error { address: address, log: msg });
+            return;
         }
-        else if is_omit(address) {
-            // mark this device is handled.
-            let mut handled_devices = handled_devices.lock().unwrap();
-            handled_devices.insert(address);
+    };
+    let offset_seconds = device_config.offset_seconds;
 
-            sender.send(SyncLogKind::Progress { address: address, log: "Configured as Omit".to_string() }).unwrap();
+    // the clock can't show minutes of the timezone.
+    if let (Ok(Some(diff_seconds)), Some(hour)) = (device_config.timezone_offset_seconds(), timezone_hour) {
+        if diff_seconds % 3600 != 0 {
+            log_sync_progress(sender, address, &format!("Timezone {} can't be shown exactly, using {:+}", clock::format_utc_offset(diff_seconds), hour));
         }
-        else {
-            let mut timezone_hour: Option<i8> = None;
-            let mut offset_seconds: Option<i32> = None;
+    }
 
-            if let Some(device_config) = config.lock().unwrap().get(&address) {
-                timezone_hour = device_config.get_timezone_diff_hour();
-                offset_seconds = device_config.offset_seconds;
-            }
+    let checking = !options.dry_run && options.check_only.contains(&resolve_device_address(&config.lock().unwrap(), address));
+    let options = &SyncOptions { dry_run: options.dry_run || checking, ..options.clone() };
+    match sync_xiaomi_clock(sender, options, address, timezone_hour, offset_seconds, device_config.unit) {
+        Ok(result) => {
+            let result = if checking { SyncResult::Checked } else { result };
+            sender.publish(SyncLogKind::Done { address: address, result: result });
+        },
+        Err(msg) => {
+            let device_address = resolve_device_address(&config.lock().unwrap(), address);
+            handled_devices.lock().unwrap().remove(&device_address);
+            sender.publish(SyncLogKind::Error { address: address, log: msg });
+        }
+    }
+}
+
+fn log_sync_progress(sender: &Publisher<SyncLogKind>, address: u64, msg: &str) {
+    sender.publish(SyncLogKind::Progress { address: address, log: msg.to_string() });
+}
+
+// sync the clock, retrying with exponential backoff when any step fails.
+fn sync_xiaomi_clock(sender: &Publisher<SyncLogKind>, options: &SyncOptions, address: u64, timezone_diff_hour: Option<i8>, offset_seconds: Option<i32>, unit: Option<TemperatureUnit>) -> Result<SyncResult, String> {
+    let mut retry: u32 = 0;
+    loop {
+        if retry > 0 {
+            log_sync_progress(sender, address, &format!("Attempt {}/{}", retry + 1, options.retry.count + 1));
+        }
 
-            match sync_xiaomi_clock(sender, address, timezone_hour, offset_seconds) {
-                Ok(_) => {
-                    let mut handled_devices = handled_devices.lock().unwrap();
-                    handled_devices.insert(address);
-                },
-                Err(msg) => {
-                    sender.send(SyncLogKind::Error { address: address, log: msg }).unwrap();
+        match try_sync_xiaomi_clock(sender, options, address, timezone_diff_hour, offset_seconds, unit) {
+            Ok(result) => { return Ok(result); },
+            Err(msg) => {
+                if retry >= options.retry.count {
+                    return Err(msg);
                 }
+
+                retry += 1;
+                let delay = options.retry.delay_before(retry);
+                log_sync_progress(sender, address, &format!("{}, retrying in {} ms", msg, delay.as_millis()));
+                std::thread::sleep(delay);
             }
         }
     }
 }
 
-fn log_sync_progress(sender: &Sender<SyncLogKind>, address: u64, msg: &str) {
-    sender.send(SyncLogKind::Progress { address: address, log: msg.to_string() }).unwrap();
+// wait for WinRT async operation to complete. `.get()` can block forever when the device
+// doesn't respond, so poll the status and cancel the operation after timeout.
+fn get_with_timeout<T: RuntimeType + 'static>(operation: IAsyncOperation<T>, timeout: Duration, what: &str) -> Result<T, String> {
+    let start_time = Instant::now();
+    loop {
+        match operation.Status() {
+            Ok(AsyncStatus::Started) => {},
+            Ok(_) => { break; },
+            Err(_) => { return Err(format!("Failed to {}", what)); }
+        }
+
+        if start_time.elapsed() >= timeout {
+            operation.Cancel().ok();
+            log::debug!("{}: timed out", what);
+            return Err(format!("Failed to {}: timed out after {} s", what, timeout.as_secs_f32()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    log::debug!("{}: done in {} ms", what, start_time.elapsed().as_millis());
+    return operation.GetResults().map_err(|_| format!("Failed to {}", what));
+}
+
+// read all bytes in the buffer.
+fn read_buffer(buffer: &IBuffer) -> Vec<u8> {
+    let reader = DataReader::FromBuffer(buffer).unwrap();
+    let mut vector: Vec<u8> = Vec::new();
+    vector.resize(buffer.Length().unwrap() as usize, 0);
+    reader.ReadBytes(vector.as_mut_slice()).ok();
+    return vector;
 }
 
-fn sync_xiaomi_clock(sender: &Sender<SyncLogKind>, address: u64, timezone_diff_hour: Option<i8>, offset_seconds: Option<i32>) -> Result<(), String> {
-    log_sync_progress(sender, address, "Connecting...");
-    let device: Option<BluetoothLEDevice>;
-    match BluetoothLEDevice::FromBluetoothAddressAsync(address).unwrap().get() {
-        Err(_) => { return Err("Failed to connect".to_string()); }
-        Ok(d) => {
-            device = Some(d);
+// bypass the cache, we want to know the value right now.
+fn read_bytes(character: &GattCharacteristic, timeout: Duration, what: &str) -> Result<Vec<u8>, String> {
+    let result = get_with_timeout(character.ReadValueWithCacheModeAsync(BluetoothCacheMode::Uncached).unwrap(), timeout, what)?;
+    if result.Status().unwrap() != GattCommunicationStatus::Success {
+        return Err("Communication error".to_string());
+    }
+    return Ok(read_buffer(&result.Value().unwrap()));
+}
+
+fn write_bytes(character: &GattCharacteristic, data: &[u8], timeout: Duration, what: &str) -> Result<(), String> {
+    // Create a buffer to write
+    use windows::Storage::Streams::DataWriter;
+    let buffer: Option<IBuffer>;
+    {
+        let data_writer = DataWriter::new().unwrap();
+        data_writer.WriteBytes(data).ok();
+        buffer = Some(data_writer.DetachBuffer().unwrap());
+    }
+
+    match get_with_timeout(character.WriteValueAsync(&buffer.unwrap()).unwrap(), timeout, what) {
+        Err(msg) => { return Err(msg); },
+        Ok(status) => {
+            if status != GattCommunicationStatus::Success {
+                return Err("Communication error".to_string());
+            }
         }
     }
+    return Ok(());
+}
+
+// read current time of the clock.
+fn read_clock_time(character: &GattCharacteristic, timeout: Duration) -> Result<ClockTime, String> {
+    let data = read_bytes(character, timeout, "read time")?;
+    return ClockTime::decode(&data).ok_or(format!("Unexpected time format: {} bytes", data.len()));
+}
+
+fn read_unit(character: &GattCharacteristic, timeout: Duration) -> Result<TemperatureUnit, String> {
+    let data = read_bytes(character, timeout, "read unit")?;
+    return TemperatureUnit::decode(&data).ok_or(format!("Unexpected unit: {}", encode_hex(&data)));
+}
+
+// connect to the device and find LYWSD02 time characteristic. `log` is called on each step.
+fn get_time_characteristic(address: u64, timeout: Duration, log: &dyn Fn(&str)) -> Result<GattCharacteristic, String> {
+    let service = get_device_service(address, LYWSD02_SERVICE_UUID, timeout, log)?;
+    log(&format!("Querying characteristic, UUID={:x}", LYWSD02_CHARACTERISTIC_TIME_UUID.to_u128()));
+    return get_service_characteristic(&service, LYWSD02_CHARACTERISTIC_TIME_UUID, timeout);
+}
+
+// connect to the device and find the service.
+fn get_device_service(address: u64, uuid: GUID, timeout: Duration, log: &dyn Fn(&str)) -> Result<GattDeviceService, String> {
+    let device = connect_device(address, timeout, log)?;
+    return get_service(&device, uuid, timeout, log);
+}
+
+fn connect_device(address: u64, timeout: Duration, log: &dyn Fn(&str)) -> Result<BluetoothLEDevice, String> {
+    log("Connecting...");
+    log::debug!("connect to {}", format_bluetooth_address(address));
+    return get_with_timeout(BluetoothLEDevice::FromBluetoothAddressAsync(address).unwrap(), timeout, "connect");
+}
 
-    log_sync_progress(sender, address, &format!("Querying service, UUID={:x}", LYWSD02_SERVICE_UUID.to_u128()));
-    let service: Option<GattDeviceService>;
-    match device.unwrap().GetGattServicesForUuidAsync(LYWSD02_SERVICE_UUID).unwrap().get() {
-        Err(_) => { return Err("Failed to query service".to_string()); }
+fn get_service(device: &BluetoothLEDevice, uuid: GUID, timeout: Duration, log: &dyn Fn(&str)) -> Result<GattDeviceService, String> {
+    log(&format!("Querying service, UUID={:x}", uuid.to_u128()));
+    match get_with_timeout(device.GetGattServicesForUuidAsync(uuid).unwrap(), timeout, "query service") {
+        Err(msg) => { return Err(msg); }
         Ok(ss) => {
             if ss.Status().unwrap() != GattCommunicationStatus::Success {
                 return Err("Communication error".to_string());
@@ -193,14 +501,14 @@ fn sync_xiaomi_clock(sender: &Sender<SyncLogKind>, address: u64, timezone_diff_h
                 return Err("No services returned".to_string());
             }
 
-            service = Some(services.GetAt(0).unwrap());
+            return Ok(services.GetAt(0).unwrap());
         }
     }
+}
 
-    log_sync_progress(sender, address, &format!("Querying characteristic, UUID={:x}", LYWSD02_CHARACTERISTIC_TIME_UUID.to_u128()));
-    let character: Option<GattCharacteristic>;
-    match service.unwrap().GetCharacteristicsForUuidAsync(LYWSD02_CHARACTERISTIC_TIME_UUID).unwrap().get() {
-        Err(_) => { return Err("Failed to query characteristic".to_string()); }
+fn get_service_characteristic(service: &GattDeviceService, uuid: GUID, timeout: Duration) -> Result<GattCharacteristic, String> {
+    match get_with_timeout(service.GetCharacteristicsForUuidAsync(uuid).unwrap(), timeout, "query characteristic") {
+        Err(msg) => { return Err(msg); }
         Ok(res) => {
             if res.Status().unwrap() != GattCommunicationStatus::Success {
                 return Err("Communication error".to_string());
@@ -211,46 +519,285 @@ fn sync_xiaomi_clock(sender: &Sender<SyncLogKind>, address: u64, timezone_diff_h
                 return Err("No characteristic returned".to_string());
             }
 
-            character = Some(chars.GetAt(0).unwrap());
+            return Ok(chars.GetAt(0).unwrap());
+        }
+    }
+}
+
+// Send time to device.
+fn write_clock_time(character: &GattCharacteristic, clock_time: &ClockTime, timeout: Duration) -> Result<(), String> {
+    return write_bytes(character, &clock_time.encode(), timeout, "sync time");
+}
+
+// battery characteristic is in the same service as the time characteristic. 1 byte, in percent.
+fn read_battery(time_characteristic: &GattCharacteristic, timeout: Duration) -> Result<u8, String> {
+    let service = time_characteristic.Service().map_err(|e| e.to_string())?;
+    let character = get_service_characteristic(&service, LYWSD02_CHARACTERISTIC_BATTERY_UUID, timeout)?;
+    let data = read_bytes(&character, timeout, "read battery")?;
+    return match data.first() {
+        Some(percent) if *percent <= 100 => Ok(*percent),
+        _ => Err(format!("Unexpected battery level: {}", encode_hex(&data))),
+    };
+}
+
+// unit characteristic is in the same service as the time characteristic.
+fn get_unit_characteristic(time_characteristic: &GattCharacteristic, timeout: Duration) -> Result<GattCharacteristic, String> {
+    let service = time_characteristic.Service().map_err(|e| e.to_string())?;
+    return get_service_characteristic(&service, LYWSD02_CHARACTERISTIC_UNIT_UUID, timeout);
+}
+
+// connect to the device and set the temperature unit. returns the unit read back.
+pub fn set_device_unit(address: u64, unit: TemperatureUnit, timeout: Duration) -> Result<TemperatureUnit, String> {
+    let character = get_unit_characteristic(&get_time_characteristic(address, timeout, &|_| {})?, timeout)?;
+    write_bytes(&character, &[unit.encode()], timeout, "set unit")?;
+    return read_unit(&character, timeout);
+}
+
+// write the unit only when it's different. done before the clock, so skipping the clock doesn't skip it.
+fn sync_unit(sender: &Publisher<SyncLogKind>, options: &SyncOptions, address: u64, time_characteristic: &GattCharacteristic, unit: TemperatureUnit) -> Result<(), String> {
+    let character = get_unit_characteristic(time_characteristic, options.timeout)?;
+    if read_unit(&character, options.timeout)? == unit {
+        log_sync_progress(sender, address, &format!("Unit {}", unit.symbol()));
+        return Ok(());
+    }
+
+    if options.dry_run {
+        log_sync_progress(sender, address, &format!("Dry run: would set unit {}", unit.symbol()));
+        return Ok(());
+    }
+
+    write_bytes(&character, &[unit.encode()], options.timeout, "set unit")?;
+    if read_unit(&character, options.timeout)? != unit {
+        return Err(format!("Verification failed: unit is not {}", unit.symbol()));
+    }
+    log_sync_progress(sender, address, &format!("Set unit {}", unit.symbol()));
+    return Ok(());
+}
+
+// connect to the device and write given time, as it is. returns the time read back.
+pub fn write_device_time(address: u64, clock_time: &ClockTime, timeout: Duration) -> Result<ClockTime, String> {
+    let character = get_time_characteristic(address, timeout, &|_| {})?;
+    write_clock_time(&character, clock_time, timeout)?;
+    return read_clock_time(&character, timeout);
+}
+
+// connect to the device and read its current time, without changing anything.
+pub fn read_device_time(address: u64, timeout: Duration) -> Result<ClockTime, String> {
+    let character = get_time_characteristic(address, timeout, &|_| {})?;
+    return read_clock_time(&character, timeout);
+}
+
+// connect to the device and read its battery level, in percent. only LYWSD02 has the characteristic.
+pub fn read_device_battery(address: u64, timeout: Duration) -> Result<u8, String> {
+    let character = get_time_characteristic(address, timeout, &|_| {})?;
+    return read_battery(&character, timeout);
+}
+
+// connect to the device and read its firmware version. `log` is called on each step.
+pub fn read_firmware_revision(address: u64, timeout: Duration, log: &dyn Fn(&str)) -> Result<String, String> {
+    let service = get_device_service(address, DEVICE_INFORMATION_SERVICE_UUID, timeout, log)?;
+    log("Reading firmware revision...");
+    let character = get_service_characteristic(&service, FIRMWARE_REVISION_CHARACTERISTIC_UUID, timeout)?;
+    let data = read_bytes(&character, timeout, "read firmware revision")?;
+    return firmware::decode_revision(&data).ok_or(format!("Unexpected firmware revision: {}", encode_hex(&data)));
+}
+
+// read by `info`. characteristics the device doesn't have are None.
+pub struct DeviceInformation {
+    // name and value, in the order of `gatt::DEVICE_INFORMATION_CHARACTERISTICS`.
+    pub fields: Vec<(&'static str, Option<String>)>,
+    // UUIDs of every GATT service of the device.
+    pub services: Vec<u128>,
+}
+
+pub fn read_device_information(address: u64, timeout: Duration, log: &dyn Fn(&str)) -> Result<DeviceInformation, String> {
+    let device = connect_device(address, timeout, log)?;
+    log("Querying services...");
+    let result = get_with_timeout(device.GetGattServicesAsync().unwrap(), timeout, "query services")?;
+    if result.Status().unwrap() != GattCommunicationStatus::Success {
+        return Err("Communication error".to_string());
+    }
+    let services: Vec<u128> = result.Services().unwrap().into_iter().filter_map(|s| s.Uuid().ok()).map(|uuid| uuid.to_u128()).collect();
+
+    // some devices don't have the service. services are listed anyway.
+    let service = get_service(&device, DEVICE_INFORMATION_SERVICE_UUID, timeout, log).ok();
+    let mut fields: Vec<(&'static str, Option<String>)> = Vec::new();
+    for (uuid, name) in gatt::DEVICE_INFORMATION_CHARACTERISTICS {
+        log(&format!("Reading {}...", name.to_lowercase()));
+        let value = service.as_ref()
+            .and_then(|service| get_service_characteristic(service, GUID::from_u128(uuid), timeout).ok())
+            .and_then(|character| read_bytes(&character, timeout, name).ok())
+            .and_then(|data| gatt::decode_string(&data));
+        fields.push((name, value));
+    }
+    return Ok(DeviceInformation { fields: fields, services: services });
+}
+
+fn set_notify(character: &GattCharacteristic, notify: bool, timeout: Duration) -> Result<(), String> {
+    let value = if notify { GattClientCharacteristicConfigurationDescriptorValue::Notify } else { GattClientCharacteristicConfigurationDescriptorValue::None };
+    let status = get_with_timeout(character.WriteClientCharacteristicConfigurationDescriptorAsync(value).unwrap(), timeout, "subscribe")?;
+    if status != GattCommunicationStatus::Success {
+        return Err("Communication error".to_string());
+    }
+    return Ok(());
+}
+
+fn get_characteristic(address: u64, service: u128, characteristic: u128, timeout: Duration, log: &dyn Fn(&str)) -> Result<GattCharacteristic, String> {
+    let service = get_device_service(address, GUID::from_u128(service), timeout, log)?;
+    log(&format!("Querying characteristic, UUID={:x}", characteristic));
+    return get_service_characteristic(&service, GUID::from_u128(characteristic), timeout);
+}
+
+// `gatt read`
+pub fn gatt_read(address: u64, service: u128, characteristic: u128, timeout: Duration, log: &dyn Fn(&str)) -> Result<Vec<u8>, String> {
+    let character = get_characteristic(address, service, characteristic, timeout, log)?;
+    log("Reading...");
+    return read_bytes(&character, timeout, "read");
+}
+
+// `gatt write`
+pub fn gatt_write(address: u64, service: u128, characteristic: u128, data: &[u8], timeout: Duration, log: &dyn Fn(&str)) -> Result<(), String> {
+    let character = get_characteristic(address, service, characteristic, timeout, log)?;
+    log("Writing...");
+    return write_bytes(&character, data, timeout, "write");
+}
+
+// `gatt subscribe`. values are published until the subscription is stopped.
+pub struct GattSubscription {
+    character: GattCharacteristic,
+    token: EventRegistrationToken,
+    timeout: Duration,
+}
+
+impl GattSubscription {
+    pub fn stop(self) {
+        self.character.RemoveValueChanged(self.token).ok();
+        set_notify(&self.character, false, self.timeout).ok();
+    }
+}
+
+pub fn gatt_subscribe(address: u64, service: u128, characteristic: u128, publisher: Publisher<Vec<u8>>, timeout: Duration, log: &dyn Fn(&str)) -> Result<GattSubscription, String> {
+    let character = get_characteristic(address, service, characteristic, timeout, log)?;
+    let token = character.ValueChanged(&TypedEventHandler::new(move |_sender: &Option<GattCharacteristic>, args: &Option<GattValueChangedEventArgs>| {
+        if let Some(args) = args {
+            publisher.publish(read_buffer(&args.CharacteristicValue()?));
         }
+        Ok(())
+    })).map_err(|e| e.to_string())?;
+
+    log("Subscribing...");
+    if let Err(msg) = set_notify(&character, true, timeout) {
+        character.RemoveValueChanged(token).ok();
+        return Err(msg);
     }
+    return Ok(GattSubscription { character: character, token: token, timeout: timeout });
+}
 
-    let mut epoch_time: u64 = get_unix_epoc();
-    let mut timezone: i8 = 9;   // Default to Korean standard time
+// connect to the device and download its hourly history, sorted by index.
+// device starts sending records as soon as notification is enabled, and there's no end marker.
+pub fn read_device_history(address: u64, timeout: Duration, log: &dyn Fn(&str)) -> Result<Vec<HistoryRecord>, String> {
+    let time_character = get_time_characteristic(address, timeout, log)?;
+    let service = time_character.Service().map_err(|e| e.to_string())?;
+    let character = get_service_characteristic(&service, LYWSD02_CHARACTERISTIC_HISTORY_UUID, timeout)?;
+
+    let records: Arc<Mutex<Vec<HistoryRecord>>> = Arc::new(Mutex::new(Vec::new()));
+    let last_received = Arc::new(Mutex::new(Instant::now()));
+    let handler_records = records.clone();
+    let handler_last_received = last_received.clone();
+    let token = character.ValueChanged(&TypedEventHandler::new(move |_sender: &Option<GattCharacteristic>, args: &Option<GattValueChangedEventArgs>| {
+        if let Some(args) = args {
+            let data = read_buffer(&args.CharacteristicValue()?);
+            if let Some(record) = HistoryRecord::decode(&data) {
+                handler_records.lock().unwrap().push(record);
+            }
+            *handler_last_received.lock().unwrap() = Instant::now();
+        }
+        Ok(())
+    })).map_err(|e| e.to_string())?;
+
+    log("Requesting history...");
+    *last_received.lock().unwrap() = Instant::now();
+    if let Err(msg) = set_notify(&character, true, timeout) {
+        character.RemoveValueChanged(token).ok();
+        return Err(msg);
+    }
 
-    if let Some(tz) = timezone_diff_hour {
-        if tz >= -24 && tz <= 24 {
-            timezone = tz;
+    let mut count = 0;
+    while last_received.lock().unwrap().elapsed() < HISTORY_QUIET_PERIOD {
+        thread::sleep(Duration::from_millis(100));
+        let received = records.lock().unwrap().len();
+        if received != count {
+            count = received;
+            log(&format!("Received {} records", count));
         }
     }
 
+    character.RemoveValueChanged(token).ok();
+    set_notify(&character, false, timeout).ok();
+
+    let mut records = records.lock().unwrap().clone();
+    records.sort_by_key(|r| r.index);
+    records.dedup_by_key(|r| r.index);
+    return Ok(records);
+}
+
+fn try_sync_xiaomi_clock(sender: &Publisher<SyncLogKind>, options: &SyncOptions, address: u64, timezone_diff_hour: Option<i8>, offset_seconds: Option<i32>, unit: Option<TemperatureUnit>) -> Result<SyncResult, String> {
+    let character = get_time_characteristic(address, options.timeout, &|msg| log_sync_progress(sender, address, msg))?;
+
+    if let Some(unit) = unit {
+        sync_unit(sender, options, address, &character, unit)?;
+    }
+
+    // battery is only for the report. sync goes on without it.
+    match read_battery(&character, options.timeout) {
+        Ok(percent) => {
+            sender.publish(SyncLogKind::Battery { address: address, percent: percent });
+        },
+        Err(msg) => log_sync_progress(sender, address, &format!("Reading battery failed: {}", msg)),
+    }
+
     // Adjust offset
     if let Some(diff) = offset_seconds {
-        let mut temp = epoch_time as i64;
-        temp = temp + (diff as i64);
-        if temp > 0 {
-            epoch_time = temp as u64;
-            log_sync_progress(sender, address, &format!("Adjust clock {:+}:{:02} ", diff / 60, diff % 60));
+        log_sync_progress(sender, address, &format!("Adjust clock {}", format_signed_duration(diff as i64)));
+    }
+    let clock_time = target_clock_time(options.clock.now(), timezone_diff_hour, offset_seconds);
+    let epoch_time = clock_time.epoch;
+    let timezone = clock_time.timezone;
+
+
+    // Skip writing when the clock is close enough. Writing wears the battery.
+    match read_clock_time(&character, options.timeout) {
+        Ok(device_time) => {
+            let drift = device_time.drift_seconds(&clock_time);
+            sender.publish(SyncLogKind::Drift { address: address, seconds: drift });
+            if !options.force && device_time.is_close_to(&clock_time, options.drift_threshold_seconds) {
+                log_sync_progress(sender, address, &format!("drift {}, skipping", format_signed_duration(drift)));
+                return Ok(SyncResult::Skipped);
+            }
+            log_sync_progress(sender, address, &format!("drift {} [timezone:{:+}]", format_signed_duration(drift), device_time.timezone));
+        },
+        Err(msg) => {
+            log_sync_progress(sender, address, &format!("{}, writing anyway", msg));
         }
     }
 
-    // Create a buffer to sync
-    use windows::Storage::Streams::{DataWriter, IBuffer, ByteOrder};
-    let buffer: Option<IBuffer>;
-    {
-        let data_writer = DataWriter::new().unwrap();
-        data_writer.SetByteOrder(ByteOrder::LittleEndian).ok();
-        data_writer.WriteUInt32(epoch_time as u32).ok();
-        data_writer.WriteByte(timezone as u8).ok();
-        buffer = Some(data_writer.DetachBuffer().unwrap());
+    if options.dry_run {
+        log_sync_progress(sender, address, &format!("Dry run: would write epoch {} timezone byte 0x{:02X} [timezone:{:+}], bytes={}",
+            clock_time.epoch, clock_time.timezone as u8, clock_time.timezone, encode_hex(&clock_time.encode())));
+        return Ok(SyncResult::DryRun);
     }
-    
-    // Send time to device.
-    match character.unwrap().WriteValueAsync(&buffer.unwrap()).unwrap().get() {
-        Err(_) => { return Err("Failed to sync time".to_string()); },
-        Ok(_) => {}
+
+    write_clock_time(&character, &clock_time, options.timeout)?;
+    let written_time = Instant::now();
+
+    // Read back, and see the device accepted the time. The clock kept ticking since written.
+    let device_time = read_clock_time(&character, options.timeout)?;
+    let expected = ClockTime { epoch: clock_time.epoch + written_time.elapsed().as_secs() as u32, timezone: timezone };
+    if !device_time.is_close_to(&expected, options.verify_tolerance_seconds) {
+        return Err(format!("Verification failed: device shows {} [timezone:{:+}], expected {} [timezone:{:+}]",
+            device_time.epoch, device_time.timezone, expected.epoch, expected.timezone));
     }
 
     log_sync_progress(sender, address, &format!("Sync clock {} [timezone:{:+}]", epoch_time, timezone));
-    return Ok(());
+    return Ok(SyncResult::Synced);
 }
@@ -0,0 +1,206 @@
+// Events from BLE threads to the thread drawing the UI.
+// Every subscriber gets every event, in its own bounded queue. A full queue blocks the publisher, so a slow
+// UI slows down BLE handlers instead of growing memory. Publishing never panics: after `shutdown`, or when
+// every subscriber is gone, events are dropped and `publish` returns false.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+// how often a blocked publisher checks for shutdown.
+const FULL_QUEUE_POLL: Duration = Duration::from_millis(1);
+
+// queue of a subscriber, with the number of events in it.
+struct Queue<T> {
+    tx: SyncSender<T>,
+    pending: Arc<AtomicUsize>,
+}
+
+struct Shared<T> {
+    subscribers: Mutex<Vec<Queue<T>>>,
+    shutdown: AtomicBool,
+}
+
+pub struct EventBus<T> {
+    shared: Arc<Shared<T>>,
+    // events each subscriber can have unread.
+    capacity: usize,
+}
+
+impl<T: Clone> EventBus<T> {
+    pub fn new(capacity: usize) -> EventBus<T> {
+        let shared = Shared { subscribers: Mutex::new(Vec::new()), shutdown: AtomicBool::new(false) };
+        return EventBus { shared: Arc::new(shared), capacity: capacity.max(1) };
+    }
+
+    // receives events published after this.
+    pub fn subscribe(&self) -> Subscription<T> {
+        let (tx, rx) = mpsc::sync_channel(self.capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        if !self.is_shutdown() {
+            self.shared.subscribers.lock().unwrap().push(Queue { tx: tx, pending: pending.clone() });
+        }
+        return Subscription { rx: rx, pending: pending };
+    }
+
+    pub fn publisher(&self) -> Publisher<T> {
+        return Publisher { shared: self.shared.clone() };
+    }
+
+    // stops taking events. subscribers still receive the ones queued, then they're disconnected.
+    // publishers blocked by a full queue return.
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.subscribers.lock().unwrap().clear();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        return self.shared.shutdown.load(Ordering::SeqCst);
+    }
+}
+
+// handed to threads producing events. cheap to clone.
+pub struct Publisher<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Publisher<T> {
+    fn clone(&self) -> Self {
+        return Publisher { shared: self.shared.clone() };
+    }
+}
+
+impl<T: Clone> Publisher<T> {
+    // returns false when nobody receives it anymore. the caller can stop producing events then.
+    pub fn publish(&self, event: T) -> bool {
+        if self.is_shutdown() {
+            return false;
+        }
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        // subscribers that are dropped are removed.
+        subscribers.retain(|queue| self.send(queue, event.clone()));
+        return !subscribers.is_empty();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        return self.shared.shutdown.load(Ordering::SeqCst);
+    }
+
+    // waits while the queue is full. false when the subscriber is gone, or the bus is shut down.
+    fn send(&self, queue: &Queue<T>, event: T) -> bool {
+        let mut event = event;
+        loop {
+            // counted first, so it's never below zero when received right away.
+            queue.pending.fetch_add(1, Ordering::SeqCst);
+            let result = queue.tx.try_send(event);
+            if result.is_err() {
+                queue.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            match result {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(returned)) => {
+                    if self.is_shutdown() {
+                        return false;
+                    }
+                    event = returned;
+                    thread::sleep(FULL_QUEUE_POLL);
+                }
+            }
+        }
+    }
+}
+
+pub struct Subscription<T> {
+    rx: Receiver<T>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl<T> Subscription<T> {
+    // disconnected after shutdown, once queued events are received.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let event = self.rx.recv_timeout(timeout)?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        return Ok(event);
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let event = self.rx.try_recv()?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        return Ok(event);
+    }
+
+    // events queued and not received yet. tells how far behind the subscriber is.
+    pub fn pending(&self) -> usize {
+        return self.pending.load(Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers() {
+        let bus: EventBus<u32> = EventBus::new(4);
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        let publisher = bus.publisher();
+        assert!(publisher.publish(1));
+        assert_eq!(first.pending(), 1);
+        assert_eq!(first.try_recv(), Ok(1));
+        assert_eq!(first.pending(), 0);
+        assert_eq!(second.try_recv(), Ok(1));
+
+        // the other one still receives.
+        drop(first);
+        assert!(publisher.publish(2));
+        assert_eq!(second.try_recv(), Ok(2));
+
+        drop(second);
+        assert!(!publisher.publish(3));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let bus: EventBus<u32> = EventBus::new(4);
+        let subscription = bus.subscribe();
+        let publisher = bus.publisher();
+        assert!(publisher.publish(1));
+        bus.shutdown();
+        assert!(!publisher.publish(2));
+        assert_eq!(subscription.try_recv(), Ok(1));
+        assert_eq!(subscription.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let bus: EventBus<u32> = EventBus::new(1);
+        let subscription = bus.subscribe();
+        let publisher = bus.publisher();
+        assert!(publisher.publish(1));
+
+        // blocked until the first one is received.
+        let blocked = publisher.clone();
+        let handle = thread::spawn(move || blocked.publish(2));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        assert_eq!(subscription.recv_timeout(Duration::from_secs(1)), Ok(1));
+        assert!(handle.join().unwrap());
+        assert_eq!(subscription.recv_timeout(Duration::from_secs(1)), Ok(2));
+
+        // shutdown releases a blocked publisher.
+        assert!(publisher.publish(3));
+        let blocked = publisher.clone();
+        let handle = thread::spawn(move || blocked.publish(4));
+        thread::sleep(Duration::from_millis(20));
+        bus.shutdown();
+        assert!(!handle.join().unwrap());
+    }
+}
@@ -0,0 +1,126 @@
+// Capture file keeps raw service data frames seen during scan, one frame per line.
+// This is useful when a user reports an unsupported device.
+//  time=1696891938 address=AA:BB:CC:DD:EE:FF data=95fe7020... product_id=0x045B frame_counter=17 frame_control=0x2070 capability=0x09
+// time, address and data are required. frame metadata is informational, and ignored when parsing.
+
+use crate::decoder::{self, MIBEACON_UUID16};
+use crate::{decode_bluetooth_adddress, format_bluetooth_address};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedFrame {
+    // unix epoch when the frame is received.
+    pub time: u64,
+    pub address: u64,
+    // service data including 16 bit service UUID.
+    pub data: Vec<u8>,
+}
+
+pub fn format_capture_line(frame: &CapturedFrame) -> String {
+    let mut line = format!("time={} address={} data={}",
+        frame.time,
+        format_bluetooth_address(frame.address),
+        encode_hex(&frame.data));
+
+    if let Some(metadata) = describe_frame(&frame.data) {
+        line.push(' ');
+        line.push_str(&metadata);
+    }
+    return line;
+}
+
+pub fn parse_capture_line(line: &str) -> Result<CapturedFrame, String> {
+    let mut time: Option<u64> = None;
+    let mut address: Option<u64> = None;
+    let mut data: Option<Vec<u8>> = None;
+
+    for token in line.split_whitespace() {
+        match token.split_once('=') {
+            Some(("time", v)) => time = Some(v.parse().map_err(|_| format!("invalid time: {}", v))?),
+            Some(("address", v)) => address = Some(decode_bluetooth_adddress(v)?),
+            Some(("data", v)) => data = Some(decode_hex(v)?),
+            _ => {}
+        }
+    }
+
+    return match (time, address, data) {
+        (Some(time), Some(address), Some(data)) => Ok(CapturedFrame { time: time, address: address, data: data }),
+        _ => Err("time, address and data are required".to_string()),
+    };
+}
+
+// describe frame metadata. only MiBeacon frames have metadata.
+pub fn describe_frame(data: &[u8]) -> Option<String> {
+    if data.len() < 2 || u16::from_le_bytes([data[0], data[1]]) != MIBEACON_UUID16 {
+        return None;
+    }
+
+    let header = decoder::decode_mibeacon_header(&data[2..])?;
+    let mut description = format!("product_id=0x{:04X} frame_counter={} frame_control=0x{:04X}",
+        header.product_id,
+        header.frame_counter,
+        header.frame_control);
+    if let Some(capability) = header.capability {
+        description.push_str(&format!(" capability=0x{:02X}", capability));
+    }
+    return Some(description);
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    return data.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+pub fn decode_hex(value: &str) -> Result<Vec<u8>, String> {
+    if !value.len().is_multiple_of(2) {
+        return Err(format!("odd length hex string: {}", value));
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in (0..value.len()).step_by(2) {
+        match value.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()) {
+            Some(b) => bytes.push(b),
+            None => return Err(format!("invalid hex string: {}", value)),
+        }
+    }
+    return Ok(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_line() {
+        let frame = CapturedFrame {
+            time: 1696891938,
+            address: 0x112233445566,
+            data: vec![
+                0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x11, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x09,
+                0x04, 0x10, 0x02, 0xEA, 0x00,
+            ],
+        };
+
+        let line = format_capture_line(&frame);
+        assert_eq!(line, "time=1696891938 address=11:22:33:44:55:66 data=95fe70205b041166554433221109041002ea00 \
+            product_id=0x045B frame_counter=17 frame_control=0x2070 capability=0x09");
+        assert_eq!(parse_capture_line(&line).unwrap(), frame);
+
+        // BTHome frame doesn't have metadata.
+        let frame = CapturedFrame { time: 1, address: 0x112233445566, data: vec![0xD2, 0xFC, 0x40, 0x01, 0x0E] };
+        let line = format_capture_line(&frame);
+        assert_eq!(line, "time=1 address=11:22:33:44:55:66 data=d2fc40010e");
+        assert_eq!(parse_capture_line(&line).unwrap(), frame);
+
+        assert!(parse_capture_line("time=1 address=11:22:33:44:55:66").is_err());
+        assert!(parse_capture_line("time=1 address=11:22:33:44:55:66 data=d2f").is_err());
+        assert!(parse_capture_line("time=x address=11:22:33:44:55:66 data=d2fc").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xAB, 0x0F]), "00ab0f");
+        assert_eq!(decode_hex("00AB0f").unwrap(), vec![0x00, 0xAB, 0x0F]);
+        assert!(decode_hex("0").is_err());
+        assert!(decode_hex("zz").is_err());
+        assert!(decode_hex("é0").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+// Values computed from temperature and humidity of a device, for condensation monitoring and comfort.
+// Temperature is in celsius, as devices advertise it, and humidity is relative, in percent.
+
+use serde::Deserialize;
+
+// Magnus formula, with constants of Sonntag 1990. within 0.1 degree from -45 to 60 celsius.
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+// temperature at which water condenses, in celsius. None for 0% humidity, air never condenses then.
+pub fn dew_point(temperature: f32, humidity: f32) -> Option<f32> {
+    if humidity <= 0.0 {
+        return None;
+    }
+    let t = temperature as f64;
+    let gamma = (humidity as f64 / 100.0).ln() + MAGNUS_A * t / (MAGNUS_B + t);
+    return Some((MAGNUS_B * gamma / (MAGNUS_A - gamma)) as f32);
+}
+
+// water in the air, in g/m³.
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature as f64;
+    // saturation vapour pressure in hPa, then the ideal gas law for water vapour.
+    let saturation = 6.112 * (MAGNUS_A * t / (MAGNUS_B + t)).exp();
+    return (saturation * humidity as f64 * 2.1674 / (273.15 + t)) as f32;
+}
+
+// formula of "feels like" temperature. set by apparent_temperature of [general].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApparentFormula {
+    // heat index of US National Weather Service.
+    #[default]
+    HeatIndex,
+    // humidex of Environment Canada.
+    Humidex,
+}
+
+// "feels like" temperature, in celsius.
+pub fn apparent_temperature(formula: ApparentFormula, temperature: f32, humidity: f32) -> f32 {
+    return match formula {
+        ApparentFormula::HeatIndex => heat_index(temperature, humidity),
+        ApparentFormula::Humidex => humidex(temperature, humidity),
+    };
+}
+
+// regression of Rothfusz, in fahrenheit, with adjustments of NWS. below 80F a simpler formula is used, as NWS does.
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature as f64 * 9.0 / 5.0 + 32.0;
+    let rh = humidity as f64;
+    let mut index = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (index + t) / 2.0 >= 80.0 {
+        index = -42.379 + 2.04901523 * t + 10.14333127 * rh - 0.22475541 * t * rh - 0.00683783 * t * t - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh + 0.00085282 * t * rh * rh - 0.00000199 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+    }
+    return ((index - 32.0) * 5.0 / 9.0) as f32;
+}
+
+pub fn humidex(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature as f64;
+    // vapour pressure in hPa.
+    let vapour = 6.112 * (MAGNUS_A * t / (MAGNUS_B + t)).exp() * humidity as f64 / 100.0;
+    return (t + 0.5555 * (vapour - 10.0)) as f32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comfort {
+    Dry,
+    Comfortable,
+    Humid,
+}
+
+impl Comfort {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Comfort::Dry => "dry",
+            Comfort::Comfortable => "comfortable",
+            Comfort::Humid => "humid",
+        };
+    }
+}
+
+// indoor humidity of 30% to 60% is comfortable, as ASHRAE recommends.
+pub fn comfort(humidity: f32) -> Comfort {
+    if humidity < 30.0 {
+        return Comfort::Dry;
+    }
+    if humidity > 60.0 {
+        return Comfort::Humid;
+    }
+    return Comfort::Comfortable;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(value: f32) -> f32 {
+        return (value * 10.0).round() / 10.0;
+    }
+
+    // reference tables have 17.3 g/m³ for 20 degrees. the formula is within 0.1 of them.
+    fn close(value: f32, expected: f32) -> bool {
+        return (value - expected).abs() < 0.1;
+    }
+
+    #[test]
+    fn test_dew_point() {
+        assert_eq!(dew_point(25.0, 50.0).map(round), Some(13.9));
+        assert_eq!(dew_point(20.0, 100.0).map(round), Some(20.0));
+        assert_eq!(dew_point(-5.0, 80.0).map(round), Some(-7.9));
+        assert_eq!(dew_point(25.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_absolute_humidity() {
+        assert!(close(absolute_humidity(25.0, 50.0), 11.5));
+        assert!(close(absolute_humidity(20.0, 100.0), 17.3));
+        assert_eq!(absolute_humidity(25.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_apparent_temperature() {
+        // 90F and 70% is 106F in the NWS table.
+        assert!(close(heat_index(32.2222, 70.0), 41.1));
+        // NWS adds for high humidity around 80F.
+        assert!(close(heat_index(26.6667, 90.0), 30.2));
+        assert!(close(heat_index(20.0, 50.0), 19.4));
+        // 30 degrees with dew point 15 is 34 in the humidex table.
+        assert!(close(humidex(30.0, 39.6), 33.8));
+        assert_eq!(apparent_temperature(ApparentFormula::Humidex, 30.0, 39.6), humidex(30.0, 39.6));
+        assert_eq!(apparent_temperature(ApparentFormula::default(), 30.0, 39.6), heat_index(30.0, 39.6));
+    }
+
+    #[test]
+    fn test_comfort() {
+        assert_eq!(comfort(25.0), Comfort::Dry);
+        assert_eq!(comfort(30.0), Comfort::Comfortable);
+        assert_eq!(comfort(60.0).name(), "comfortable");
+        assert_eq!(comfort(68.0).name(), "humid");
+    }
+}
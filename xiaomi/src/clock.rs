@@ -0,0 +1,209 @@
+// LYWSD02 time characteristic. It's 5 bytes, readable and writable.
+//  unix epoch (u32, little endian) | timezone hour (i8)
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockTime {
+    // unix epoch, UTC.
+    pub epoch: u32,
+    // difference from UTC in hours.
+    pub timezone: i8,
+}
+
+impl ClockTime {
+    pub fn encode(&self) -> [u8; 5] {
+        let epoch = self.epoch.to_le_bytes();
+        return [epoch[0], epoch[1], epoch[2], epoch[3], self.timezone as u8];
+    }
+
+    pub fn decode(data: &[u8]) -> Option<ClockTime> {
+        if data.len() < 5 {
+            return None;
+        }
+        return Some(ClockTime {
+            epoch: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            timezone: data[4] as i8,
+        });
+    }
+
+    // seconds this clock is ahead of `reference`. negative when behind.
+    pub fn drift_seconds(&self, reference: &ClockTime) -> i64 {
+        return self.epoch as i64 - reference.epoch as i64;
+    }
+
+    // true when this clock shows the same timezone, and drift is within threshold.
+    pub fn is_close_to(&self, reference: &ClockTime, threshold_seconds: u64) -> bool {
+        return self.timezone == reference.timezone
+            && self.drift_seconds(reference).unsigned_abs() <= threshold_seconds;
+    }
+}
+
+// Default to Korean standard time, when timezone is not configured.
+pub const DEFAULT_TIMEZONE: i8 = 9;
+
+// time to write to the clock. `now` is unix epoch, UTC.
+// `offset_seconds` sets the clock ahead (or behind, when negative).
+pub fn target_clock_time(now: u64, timezone_diff_hour: Option<i8>, offset_seconds: Option<i32>) -> ClockTime {
+    let mut epoch_time = now;
+    let mut timezone = DEFAULT_TIMEZONE;
+
+    if let Some(tz) = timezone_diff_hour {
+        if (-24..=24).contains(&tz) {
+            timezone = tz;
+        }
+    }
+
+    // Adjust offset
+    if let Some(diff) = offset_seconds {
+        let temp = epoch_time as i64 + diff as i64;
+        if temp > 0 {
+            epoch_time = temp as u64;
+        }
+    }
+
+    return ClockTime { epoch: epoch_time as u32, timezone: timezone };
+}
+
+// The clock only takes whole hours. This decides what to do with timezones like +5:30 (India) or +5:45 (Nepal).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimezonePolicy {
+    // +5:30 is shown as +5.
+    #[default]
+    Truncate,
+    // +5:30 is shown as +6, +5:15 as +5.
+    RoundNearest,
+    // the device is not synced.
+    Error,
+}
+
+// timezone hour to write to the clock. `diff_seconds` is difference from UTC.
+pub fn timezone_hour(diff_seconds: i32, policy: TimezonePolicy) -> Result<i8, String> {
+    if diff_seconds % 3600 == 0 {
+        return Ok((diff_seconds / 3600) as i8);
+    }
+
+    return match policy {
+        TimezonePolicy::Truncate => Ok((diff_seconds / 3600) as i8),
+        TimezonePolicy::RoundNearest => Ok((diff_seconds as f64 / 3600.0).round() as i8),
+        TimezonePolicy::Error => Err(format!("Timezone {} can't be shown on the clock", format_utc_offset(diff_seconds))),
+    };
+}
+
+// "2023-10-10T07:52:18+09:00" or "2023-10-09T22:52:18Z" into unix epoch.
+pub fn parse_iso_time(text: &str) -> Result<u32, String> {
+    let time = chrono::DateTime::parse_from_rfc3339(text)
+        .map_err(|e| format!("{}. Use a form like 2023-10-10T07:52:18+09:00", e))?;
+    return u32::try_from(time.timestamp()).map_err(|_| format!("{} is out of the clock's range", text));
+}
+
+// "+9:00", "-3:30", "+5:45"
+pub fn format_utc_offset(diff_seconds: i32) -> String {
+    let sign = if diff_seconds < 0 { '-' } else { '+' };
+    let minutes = diff_seconds.unsigned_abs() / 60;
+    return format!("{}{}:{:02}", sign, minutes / 60, minutes % 60);
+}
+
+impl ClockTime {
+    // local time shown on the clock.
+    pub fn format_local(&self) -> String {
+        use chrono::{DateTime, FixedOffset};
+
+        let offset = FixedOffset::east_opt(self.timezone as i32 * 3600).unwrap_or(FixedOffset::east_opt(0).unwrap());
+        return match DateTime::from_timestamp(self.epoch as i64, 0) {
+            Some(utc) => utc.with_timezone(&offset).format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => "-".to_string(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_time() {
+        let time = ClockTime { epoch: 1696891938, timezone: 9 };
+        let encoded = time.encode();
+        assert_eq!(encoded, [0x22, 0x84, 0x24, 0x65, 0x09]);
+        assert_eq!(ClockTime::decode(&encoded).unwrap(), time);
+
+        // negative timezone
+        let time = ClockTime { epoch: 1696891938, timezone: -8 };
+        assert_eq!(ClockTime::decode(&time.encode()).unwrap(), time);
+
+        assert!(ClockTime::decode(&encoded[..4]).is_none());
+    }
+
+    #[test]
+    fn test_target_clock_time() {
+        assert_eq!(target_clock_time(1000, Some(-8), None), ClockTime { epoch: 1000, timezone: -8 });
+        assert_eq!(target_clock_time(1000, None, None), ClockTime { epoch: 1000, timezone: 9 });
+        // out of range timezone falls back to default.
+        assert_eq!(target_clock_time(1000, Some(30), None), ClockTime { epoch: 1000, timezone: 9 });
+
+        assert_eq!(target_clock_time(1000, Some(0), Some(300)), ClockTime { epoch: 1300, timezone: 0 });
+        assert_eq!(target_clock_time(1000, Some(0), Some(-300)), ClockTime { epoch: 700, timezone: 0 });
+        // offset can't go before epoch.
+        assert_eq!(target_clock_time(1000, Some(0), Some(-3000)), ClockTime { epoch: 1000, timezone: 0 });
+    }
+
+    #[test]
+    fn test_format_local() {
+        assert_eq!(ClockTime { epoch: 1696891938, timezone: 9 }.format_local(), "2023-10-10 07:52:18");
+        assert_eq!(ClockTime { epoch: 1696891938, timezone: -7 }.format_local(), "2023-10-09 15:52:18");
+    }
+
+    #[test]
+    fn test_timezone_hour() {
+        assert_eq!(timezone_hour(9 * 3600, TimezonePolicy::Error), Ok(9));
+        assert_eq!(timezone_hour(-8 * 3600, TimezonePolicy::Error), Ok(-8));
+
+        // India
+        assert_eq!(timezone_hour(19800, TimezonePolicy::Truncate), Ok(5));
+        assert_eq!(timezone_hour(19800, TimezonePolicy::RoundNearest), Ok(6));
+        assert!(timezone_hour(19800, TimezonePolicy::Error).unwrap_err().contains("+5:30"));
+        // Nepal
+        assert_eq!(timezone_hour(20700, TimezonePolicy::RoundNearest), Ok(6));
+        // Newfoundland
+        assert_eq!(timezone_hour(-12600, TimezonePolicy::Truncate), Ok(-3));
+        assert_eq!(timezone_hour(-12600, TimezonePolicy::RoundNearest), Ok(-4));
+        // Chatham islands
+        assert_eq!(timezone_hour(45900, TimezonePolicy::RoundNearest), Ok(13));
+    }
+
+    #[test]
+    fn test_parse_iso_time() {
+        assert_eq!(parse_iso_time("2023-10-10T07:52:18+09:00"), Ok(1696891938));
+        assert_eq!(parse_iso_time("2023-10-09T22:52:18Z"), Ok(1696891938));
+        assert!(parse_iso_time("2023-10-10 07:52:18").is_err());
+        assert!(parse_iso_time("1960-01-01T00:00:00Z").is_err());
+    }
+
+    #[test]
+    fn test_format_utc_offset() {
+        assert_eq!(format_utc_offset(0), "+0:00");
+        assert_eq!(format_utc_offset(9 * 3600), "+9:00");
+        assert_eq!(format_utc_offset(20700), "+5:45");
+        assert_eq!(format_utc_offset(-12600), "-3:30");
+    }
+
+    #[test]
+    fn test_drift() {
+        let reference = ClockTime { epoch: 1000, timezone: 9 };
+        let ahead = ClockTime { epoch: 1003, timezone: 9 };
+        let behind = ClockTime { epoch: 990, timezone: 9 };
+
+        assert_eq!(ahead.drift_seconds(&reference), 3);
+        assert_eq!(behind.drift_seconds(&reference), -10);
+
+        assert!(ahead.is_close_to(&reference, 5));
+        assert!(!behind.is_close_to(&reference, 5));
+        assert!(behind.is_close_to(&reference, 10));
+
+        // different timezone is never close.
+        let other_timezone = ClockTime { epoch: 1000, timezone: 8 };
+        assert!(!other_timezone.is_close_to(&reference, 5));
+    }
+}
@@ -0,0 +1,161 @@
+// Shared by command handlers. Built once in `main` and passed to every command that needs it.
+// Config is read when a command first asks for it, so commands like `models` run without toml, and it's kept
+// for the rest of the process. Sinks asked for on the command line are opened from it for each run.
+
+use std::{
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, Sender},
+    sync::{Arc, Mutex},
+};
+
+use windows::{
+    Devices::Bluetooth::Advertisement::{*},
+    Foundation::{EventRegistrationToken, TypedEventHandler},
+};
+
+use xiaomi::{Config, DeviceFilter};
+use xiaomi::output::OutputFormat;
+use xiaomi::sinks::{SinkSelection, Sinks};
+use xiaomi::state::State;
+use xiaomi::timesource::TimeProvider;
+
+use crate::{ble, set_display, theme, DeviceSelection, EXCLAMATION};
+
+pub struct AppContext {
+    // --format. commands without machine readable output ignore it.
+    pub format: OutputFormat,
+    // next to the config file. xiaomi.state.toml
+    state_path: PathBuf,
+    // --store, --mqtt and others of the command.
+    sinks: SinkSelection,
+    // None until a command asks for it.
+    config: Mutex<Option<Config>>,
+    // time of readings, state and runs. the system clock, a fixed one to test with.
+    clock: Arc<dyn TimeProvider>,
+}
+
+impl AppContext {
+    // state is kept next to xiaomi.exe, so Err when its path can't be found.
+    pub fn new(format: OutputFormat, sinks: SinkSelection, clock: Arc<dyn TimeProvider>) -> Result<AppContext, String> {
+        let exe_path = std::env::current_exe().map_err(|e| format!("Cannot find exe path: {}", e))?;
+        return Ok(AppContext {
+            format: format, state_path: exe_path.with_extension("state.toml"), sinks: sinks, config: Mutex::new(None), clock: clock,
+        });
+    }
+
+    // same context printing in another format.
+    pub fn with_format(&self, format: OutputFormat) -> AppContext {
+        let config = self.config.lock().unwrap().clone();
+        return AppContext {
+            format: format, state_path: self.state_path.clone(), sinks: self.sinks.clone(), config: Mutex::new(config), clock: self.clock.clone(),
+        };
+    }
+
+    // unix epoch, UTC, by the clock of the context.
+    pub fn now(&self) -> u64 {
+        return self.clock.now();
+    }
+
+    // messages besides the output. they go to stderr with --format json or csv, so stdout can be parsed.
+    pub fn note(&self, text: &str) {
+        if self.format.is_machine_readable() {
+            eprintln!("{}", text);
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    // config is optional. it's read once, later calls get the same. a broken config is Err, and the command
+    // decides: one-shot commands stop, running with wrong timezones is worse, `daemon` tries again next run.
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = self.config.lock().unwrap();
+        if let Some(loaded) = config.as_ref() {
+            return Ok(loaded.clone());
+        }
+        let loaded = Config::discover().map_err(|e| e.to_string())?;
+        set_display(&loaded);
+        *config = Some(loaded.clone());
+        return Ok(loaded);
+    }
+
+    // reads the config again, for edits made while running. the old one is kept when the new one is broken.
+    pub fn reload_config(&self) -> Result<Config, String> {
+        let loaded = Config::discover().map_err(|e| e.to_string())?;
+        set_display(&loaded);
+        *self.config.lock().unwrap() = Some(loaded.clone());
+        return Ok(loaded);
+    }
+
+    // sinks asked for on the command line, with [[webhook]]s of `loaded`. `command` is the job of Pushgateway.
+    pub fn open_sinks(&self, loaded: &Config, command: &str) -> Result<Sinks, String> {
+        return Sinks::open(loaded, &self.sinks, command);
+    }
+
+    // broken state file is not fatal. it's rebuilt by following runs.
+    pub fn load_state(&self) -> State {
+        return match State::load(&self.state_path) {
+            Ok(state) => state,
+            Err(msg) => {
+                log::warn!("Ignoring state file: {}", msg);
+                State::default()
+            }
+        };
+    }
+
+    pub fn save_state(&self, state: &State) {
+        if let Err(msg) = state.save(&self.state_path) {
+            log::warn!("Saving state file failed: {}", msg);
+        }
+    }
+
+    // unknown names and groups are fatal. `strict` leaves out devices not in toml.
+    pub fn device_filter(&self, loaded: &Config, selection: &DeviceSelection, strict: bool) -> DeviceFilter {
+        let mut filter = match loaded.device_filter(selection.names, selection.groups, selection.exclude) {
+            Ok(filter) => filter,
+            Err(msg) => {
+                self.note(&format!("{} {}", EXCLAMATION, theme().warning(msg)));
+                std::process::exit(1);
+            }
+        };
+        if strict {
+            filter.restrict(&loaded.configured_addresses());
+        }
+        return filter;
+    }
+
+    // starts listening to BLE advertisements passively. `on_received` is called from a background thread.
+    pub fn watch_advertisements<F>(&self, on_received: F) -> Result<AdvertisementWatch, String>
+    where F: FnMut(&Option<BluetoothLEAdvertisementWatcher>, &Option<BluetoothLEAdvertisementReceivedEventArgs>) -> windows::core::Result<()> + Send + 'static {
+        let watcher = BluetoothLEAdvertisementWatcher::new().map_err(|e| format!("Creating BLE watcher failed: {}", e))?;
+        watcher.SetScanningMode(BluetoothLEScanningMode::Passive).map_err(|e| format!("Changing scanning mode failed: {}", e))?;
+        let received_token = watcher.Received(&TypedEventHandler::new(on_received)).map_err(|e| e.to_string())?;
+        // watcher stops by itself when the adapter is reset, turned off, or access is denied.
+        let (stopped_tx, stopped_rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let stopped_token = watcher.Stopped(&ble::on_watcher_stopped(stopped_tx)).map_err(|e| e.to_string())?;
+        let watch = AdvertisementWatch { watcher: watcher, received_token: received_token, stopped_token: stopped_token, stopped_rx: stopped_rx };
+        watch.watcher.Start().map_err(|e| format!("Starting BLE watcher failed: {}", e))?;
+        return Ok(watch);
+    }
+}
+
+pub struct AdvertisementWatch {
+    watcher: BluetoothLEAdvertisementWatcher,
+    received_token: EventRegistrationToken,
+    stopped_token: EventRegistrationToken,
+    stopped_rx: Receiver<String>,
+}
+
+impl AdvertisementWatch {
+    // why the watcher stopped by itself, if it did.
+    pub fn stopped(&self) -> Option<String> {
+        return self.stopped_rx.try_recv().ok();
+    }
+
+    // handler isn't called after this returns, but one running may still be finishing.
+    // stopping fails when the watcher has already stopped by an error.
+    pub fn stop(self) {
+        self.watcher.RemoveReceived(self.received_token).ok();
+        self.watcher.RemoveStopped(self.stopped_token).ok();
+        self.watcher.Stop().ok();
+    }
+}
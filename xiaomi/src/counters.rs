@@ -0,0 +1,128 @@
+// Performance counters of `serve --perf-counters`, see `xiaomi::perfcounter`.
+// The counter set is registered with lodctr by `xiaomi counters install`. serve only starts the provider,
+// and sets an instance of each device it hears.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use windows::{
+    core::{GUID, HSTRING},
+    Win32::{
+        Foundation::HANDLE,
+        System::Performance::{
+            PerfCreateInstance, PerfDeleteInstance, PerfSetCounterSetInfo, PerfSetULongCounterValue, PerfStartProvider,
+            PerfStopProvider, PerfProviderHandle, PERF_COUNTERSET_INFO, PERF_COUNTERSET_INSTANCE,
+            PERF_COUNTERSET_MULTI_INSTANCES, PERF_COUNTER_INFO, PERF_DETAIL_NOVICE,
+        },
+    },
+};
+use xiaomi::perfcounter::{self, COUNTERS, COUNTERSET_GUID, PROVIDER_GUID};
+use xiaomi::store::Metric;
+
+// not in the windows crate. a DWORD shown as it is.
+const PERF_COUNTER_RAWCOUNT: u32 = 0x0001_0000;
+
+// template given to PerfSetCounterSetInfo, laid out as perflib reads it.
+#[repr(C)]
+struct CounterSetTemplate {
+    info: PERF_COUNTERSET_INFO,
+    counters: [PERF_COUNTER_INFO; COUNTERS.len()],
+}
+
+pub struct Provider {
+    handle: PerfProviderHandle,
+    instances: HashMap<u64, *mut PERF_COUNTERSET_INSTANCE>,
+}
+
+impl Provider {
+    pub fn start() -> Result<Provider, String> {
+        let mut handle = PerfProviderHandle::default();
+        let status = unsafe { PerfStartProvider(&GUID::from_u128(PROVIDER_GUID), None, &mut handle) };
+        if status != 0 {
+            return Err(format!("Starting performance counters failed: error {}", status));
+        }
+        let mut template = CounterSetTemplate {
+            info: PERF_COUNTERSET_INFO {
+                CounterSetGuid: GUID::from_u128(COUNTERSET_GUID),
+                ProviderGuid: GUID::from_u128(PROVIDER_GUID),
+                NumCounters: COUNTERS.len() as u32,
+                InstanceType: PERF_COUNTERSET_MULTI_INSTANCES,
+            },
+            // each counter is a u32 in the instance block, in the order of COUNTERS.
+            counters: std::array::from_fn(|i| PERF_COUNTER_INFO {
+                CounterId: COUNTERS[i].id,
+                Type: PERF_COUNTER_RAWCOUNT,
+                Attrib: 0,
+                Size: 4,
+                DetailLevel: PERF_DETAIL_NOVICE.0,
+                Scale: COUNTERS[i].scale,
+                Offset: 4 * i as u32,
+            }),
+        };
+        let size = std::mem::size_of::<CounterSetTemplate>() as u32;
+        let status = unsafe { PerfSetCounterSetInfo(HANDLE(handle.0), &mut template as *mut CounterSetTemplate as *mut PERF_COUNTERSET_INFO, size) };
+        if status != 0 {
+            unsafe { PerfStopProvider(handle) };
+            return Err(format!("Declaring performance counters failed: error {}. Install them with `xiaomi counters install`", status));
+        }
+        return Ok(Provider { handle: handle, instances: HashMap::new() });
+    }
+
+    // the instance is made when a device is heard first, named by its name in config or its address.
+    pub fn set(&mut self, address: u64, name: &str, metric: Metric, value: f32) {
+        let handle = self.handle;
+        let instance = *self.instances.entry(address).or_insert_with(|| unsafe {
+            PerfCreateInstance(handle, &GUID::from_u128(COUNTERSET_GUID), &HSTRING::from(name), 0)
+        });
+        if instance.is_null() {
+            // not made, likely a name used by another device. tried again with the next reading.
+            self.instances.remove(&address);
+            log::warn!("Making performance counter instance \"{}\" failed", name);
+            return;
+        }
+        let counter = perfcounter::counter(metric);
+        unsafe { PerfSetULongCounterValue(HANDLE(handle.0), instance, counter.id, perfcounter::counter_value(counter, value)) };
+    }
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        for instance in self.instances.values() {
+            unsafe { PerfDeleteInstance(self.handle, *instance) };
+        }
+        unsafe { PerfStopProvider(self.handle) };
+    }
+}
+
+// next to xiaomi.exe, where unlodctr finds it again.
+fn manifest_path() -> Result<(PathBuf, PathBuf), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Cannot find exe path: {}", e))?;
+    let path = exe.with_file_name(perfcounter::MANIFEST_FILE);
+    return Ok((exe, path));
+}
+
+fn run(program: &str, manifest: &PathBuf) -> Result<(), String> {
+    let status = std::process::Command::new(program).arg(format!("/m:{}", manifest.display())).status()
+        .map_err(|e| format!("Running {} failed: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} failed: {}", program, status));
+    }
+    return Ok(());
+}
+
+// registers the counter set of this exe, as it is now. install again after moving it.
+pub fn install() -> Result<(), String> {
+    let (exe, manifest) = manifest_path()?;
+    std::fs::write(&manifest, perfcounter::manifest(&exe.display().to_string()))
+        .map_err(|e| format!("Writing {} failed: {}", manifest.display(), e))?;
+    return run("lodctr", &manifest);
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let (exe, manifest) = manifest_path()?;
+    // written again, in case it was removed since installing.
+    std::fs::write(&manifest, perfcounter::manifest(&exe.display().to_string()))
+        .map_err(|e| format!("Writing {} failed: {}", manifest.display(), e))?;
+    run("unlodctr", &manifest)?;
+    std::fs::remove_file(&manifest).ok();
+    return Ok(());
+}
@@ -0,0 +1,187 @@
+// State of `dashboard`, updated as advertisements arrive, and drawn with ratatui.
+// Nothing is kept after it quits. Use `scan --store` to keep readings.
+
+use std::collections::{HashMap, VecDeque};
+
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+    Frame,
+};
+
+use crate::decoder::Reading;
+use crate::humanize::format_age;
+use crate::model::{self, Model};
+use crate::store::Metric;
+use crate::theme::Theme;
+
+// temperatures kept for the trend of each device.
+pub const TREND_LENGTH: usize = 30;
+// a device not heard for this long is alerted.
+pub const STALE_SECONDS: u64 = 300;
+
+const TREND_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceView {
+    pub name: String,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<f32>,
+    // signal strength of the last advertisement, in dBm.
+    pub rssi: Option<i16>,
+    // unix epoch.
+    pub last_seen: u64,
+    // oldest first.
+    pub temperatures: VecDeque<f32>,
+}
+
+pub struct Dashboard {
+    // keyed by the address identifying the logical device.
+    pub devices: HashMap<u64, DeviceView>,
+    // from MiBeacon frames. missing metrics of known models are "n/a".
+    pub models: HashMap<u64, &'static Model>,
+    pub low_battery_percent: u8,
+    // digits after the point. as received when None.
+    pub precision: Option<usize>,
+}
+
+impl Dashboard {
+    pub fn new(low_battery_percent: u8, precision: Option<usize>) -> Dashboard {
+        return Dashboard { devices: HashMap::new(), models: HashMap::new(), low_battery_percent: low_battery_percent, precision: precision };
+    }
+
+    pub fn record(&mut self, address: u64, name: &str, reading: Reading, rssi: Option<i16>, now: u64) {
+        let device = self.devices.entry(address).or_default();
+        device.name = name.to_string();
+        device.last_seen = now;
+        if rssi.is_some() {
+            device.rssi = rssi;
+        }
+        match reading {
+            Reading::Temperature(value) => {
+                device.temperature = Some(value);
+                device.temperatures.push_back(value);
+                if device.temperatures.len() > TREND_LENGTH {
+                    device.temperatures.pop_front();
+                }
+            },
+            Reading::Humidity(value) => device.humidity = Some(value),
+            Reading::Battery(value) => device.battery = Some(value),
+        }
+    }
+
+    pub fn record_model(&mut self, address: u64, product_id: u16) {
+        if let Some(found) = model::find_model(product_id) {
+            self.models.insert(address, found);
+        }
+    }
+
+    // sorted by name, so devices don't jump around on redraw.
+    pub fn sorted_devices(&self) -> Vec<(u64, &DeviceView)> {
+        let mut devices: Vec<(u64, &DeviceView)> = self.devices.iter().map(|(address, device)| (*address, device)).collect();
+        devices.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        return devices;
+    }
+
+    // low battery, and devices not heard for a while.
+    pub fn alerts(&self, now: u64) -> Vec<String> {
+        let mut alerts: Vec<String> = Vec::new();
+        for (_, device) in self.sorted_devices() {
+            if let Some(battery) = device.battery {
+                if battery < self.low_battery_percent as f32 {
+                    alerts.push(format!("{}: battery {} %", device.name, battery));
+                }
+            }
+            let age = now.saturating_sub(device.last_seen);
+            if age >= STALE_SECONDS {
+                alerts.push(format!("{}: not heard for {}", device.name, format_age(age).trim_end_matches(" ago")));
+            }
+        }
+        return alerts;
+    }
+
+    pub fn render(&self, frame: &mut Frame, theme: &Theme, now: u64) {
+        let alerts = self.alerts(now);
+        let [devices_area, alerts_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(alerts.len().max(1) as u16 + 2),
+        ]).areas(frame.area());
+
+        let rows: Vec<Row> = self.sorted_devices().into_iter().map(|(address, d)| {
+            let known_model = self.models.get(&address).copied();
+            return Row::new(vec![
+                d.name.clone(),
+                model::format_metric(d.temperature, known_model, Metric::Temperature, self.precision),
+                model::format_metric(d.humidity, known_model, Metric::Humidity, self.precision),
+                model::format_metric(d.battery, known_model, Metric::Battery, self.precision),
+                d.rssi.map_or("-".to_string(), |v| v.to_string()),
+                format_age(now.saturating_sub(d.last_seen)),
+                format_trend(&d.temperatures),
+            ]);
+        }).collect();
+        let widths = [
+            Constraint::Min(17),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(TREND_LENGTH as u16),
+        ];
+        let header = Row::new(vec!["Device ID", "Temp.", "Humidity %", "Battery %", "RSSI", "Last seen", "Trend"]).bold();
+        let title = format!(" {} devices. q to quit ", self.devices.len());
+        frame.render_widget(Table::new(rows, widths).header(header).block(Block::bordered().title(title)), devices_area);
+
+        let lines: Vec<Line> = if alerts.is_empty() {
+            vec![Line::from("None")]
+        } else {
+            alerts.into_iter().map(|alert| Line::styled(alert, Style::new().fg(theme.error.ratatui_color()))).collect()
+        };
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Alerts ")), alerts_area);
+    }
+}
+
+// one character per value, scaled between the lowest and the highest value.
+pub fn format_trend(values: &VecDeque<f32>) -> String {
+    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let top = (TREND_CHARS.len() - 1) as f32;
+    return values.iter().map(|v| {
+        if max <= min {
+            return TREND_CHARS[0];
+        }
+        return TREND_CHARS[((v - min) / (max - min) * top).round() as usize];
+    }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_trend() {
+        assert_eq!(format_trend(&VecDeque::new()), "");
+        assert_eq!(format_trend(&VecDeque::from(vec![23.4, 23.4])), "▁▁");
+        assert_eq!(format_trend(&VecDeque::from(vec![20.0, 21.0, 22.0, 23.5, 21.0])), "▁▃▅█▃");
+    }
+
+    #[test]
+    fn test_alerts() {
+        let mut dashboard = Dashboard::new(20, None);
+        dashboard.record(0x112233445566, "Tokyo", Reading::Battery(14.0), Some(-70), 1000);
+        dashboard.record(0xAABBCCDDEEFF, "Osaka", Reading::Temperature(23.4), None, 1000);
+        dashboard.record(0xAABBCCDDEEFF, "Osaka", Reading::Battery(87.0), Some(-55), 1500);
+        assert_eq!(dashboard.devices[&0xAABBCCDDEEFF].rssi, Some(-55));
+        assert_eq!(dashboard.alerts(1500), vec!["Tokyo: battery 14 %", "Tokyo: not heard for 8m 20s"]);
+        assert_eq!(dashboard.alerts(1000).len(), 1);
+
+        for i in 0..TREND_LENGTH + 5 {
+            dashboard.record(0xAABBCCDDEEFF, "Osaka", Reading::Temperature(i as f32), None, 1500);
+        }
+        assert_eq!(dashboard.devices[&0xAABBCCDDEEFF].temperatures.len(), TREND_LENGTH);
+        assert_eq!(dashboard.devices[&0xAABBCCDDEEFF].temperatures.front(), Some(&5.0));
+    }
+}
@@ -0,0 +1,332 @@
+// Decoders for BLE advertisement service data sent by xiaomi sensors.
+// Stock firmware sends MiBeacon frames, custom firmware (pvvx, ATC) can send BTHome frames.
+// Both decoders produce the same `Reading`s, so a device alternating between them is
+// reported as a single device.
+
+// 16 bit service UUID of MiBeacon service data. "0000fe95-0000-1000-8000-00805f9b34fb"
+pub const MIBEACON_UUID16: u16 = 0xFE95;
+// 16 bit service UUID of BTHome service data. "0000fcd2-0000-1000-8000-00805f9b34fb"
+pub const BTHOME_UUID16: u16 = 0xFCD2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    // degree celsius
+    Temperature(f32),
+    // percent
+    Humidity(f32),
+    // percent
+    Battery(f32),
+}
+
+// decode the payload of a ServiceData section (AD type 0x16).
+// first 2 bytes are the 16 bit service UUID in little endian.
+pub fn decode_service_data(data: &[u8]) -> Vec<Reading> {
+    if data.len() < 2 {
+        return Vec::new();
+    }
+
+    let uuid = u16::from_le_bytes([data[0], data[1]]);
+    match uuid {
+        MIBEACON_UUID16 => decode_mibeacon(&data[2..]),
+        BTHOME_UUID16 => decode_bthome(&data[2..]),
+        _ => Vec::new(),
+    }
+}
+
+// product id of MiBeacon service data, including service UUID.
+pub fn decode_product_id(data: &[u8]) -> Option<u16> {
+    if data.len() < 2 || u16::from_le_bytes([data[0], data[1]]) != MIBEACON_UUID16 {
+        return None;
+    }
+    return decode_mibeacon_header(&data[2..]).map(|header| header.product_id);
+}
+
+// counter identifying the frame, including service UUID. a device repeats the same frame several times.
+// MiBeacon always has one. BTHome has it when the packet id object comes first.
+pub fn decode_frame_counter(data: &[u8]) -> Option<u8> {
+    if data.len() < 2 {
+        return None;
+    }
+    return match u16::from_le_bytes([data[0], data[1]]) {
+        MIBEACON_UUID16 => decode_mibeacon_header(&data[2..]).map(|header| header.frame_counter),
+        BTHOME_UUID16 if data.len() >= 5 && data[2] & 0x01 == 0 && data[3] == 0x00 => Some(data[4]),
+        _ => None,
+    };
+}
+
+// MiBeacon frame control bits.
+const MIBEACON_FLAG_ENCRYPTED: u16 = 0x0008;
+const MIBEACON_FLAG_MAC: u16 = 0x0010;
+const MIBEACON_FLAG_CAPABILITY: u16 = 0x0020;
+const MIBEACON_FLAG_OBJECT: u16 = 0x0040;
+
+// Header of MiBeacon frame. This identifies the device model and the frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MiBeaconHeader {
+    pub frame_control: u16,
+    pub product_id: u16,
+    pub frame_counter: u8,
+    pub capability: Option<u8>,
+    // offset of the object in the frame, if the frame has one.
+    pub object_offset: Option<usize>,
+}
+
+impl MiBeaconHeader {
+    pub fn is_encrypted(&self) -> bool {
+        return self.frame_control & MIBEACON_FLAG_ENCRYPTED != 0;
+    }
+}
+
+// decode MiBeacon header, without service UUID.
+//  frame control (2) | product id (2) | frame counter (1) | [mac (6)] | [capability (1)] | [object]
+pub fn decode_mibeacon_header(data: &[u8]) -> Option<MiBeaconHeader> {
+    if data.len() < 5 {
+        return None;
+    }
+
+    let frame_control = u16::from_le_bytes([data[0], data[1]]);
+    let mut header = MiBeaconHeader {
+        frame_control: frame_control,
+        product_id: u16::from_le_bytes([data[2], data[3]]),
+        frame_counter: data[4],
+        capability: None,
+        object_offset: None,
+    };
+
+    let mut pos: usize = 5;
+    if frame_control & MIBEACON_FLAG_MAC != 0 {
+        pos += 6;
+    }
+    if frame_control & MIBEACON_FLAG_CAPABILITY != 0 {
+        header.capability = data.get(pos).copied();
+        // io capability follows when bit 5 of capability is set.
+        if let Some(capability) = header.capability {
+            pos += if capability & 0x20 != 0 { 3 } else { 1 };
+        }
+    }
+    if frame_control & MIBEACON_FLAG_OBJECT != 0 && pos < data.len() {
+        header.object_offset = Some(pos);
+    }
+
+    return Some(header);
+}
+
+// decode MiBeacon frame, without service UUID.
+// object is: object id (2) | length (1) | data
+pub fn decode_mibeacon(data: &[u8]) -> Vec<Reading> {
+    let mut readings: Vec<Reading> = Vec::new();
+    let header = match decode_mibeacon_header(data) {
+        Some(header) => header,
+        None => return readings,
+    };
+
+    // we don't have a key to decrypt, or there's nothing to decode.
+    let pos = match header.object_offset {
+        Some(pos) if !header.is_encrypted() => pos,
+        Some(_) => {
+            log::debug!("MiBeacon frame is encrypted, product id 0x{:04X}", header.product_id);
+            return readings;
+        },
+        None => return readings,
+    };
+
+    if data.len() < pos + 3 {
+        return readings;
+    }
+    let object_id = u16::from_le_bytes([data[pos], data[pos + 1]]);
+    let length = data[pos + 2] as usize;
+    let value = &data[pos + 3..];
+    if value.len() < length {
+        log::debug!("MiBeacon object 0x{:04X} is cut short, {} of {} bytes", object_id, value.len(), length);
+        return readings;
+    }
+
+    // Temperature and Humidity are using 2 bytes, scaled by 10. Battery is percentage, just single byte.
+    match (object_id, length) {
+        (0x1004, 2) => {
+            readings.push(Reading::Temperature(i16::from_le_bytes([value[0], value[1]]) as f32 / 10.0));
+        },
+        (0x1006, 2) => {
+            readings.push(Reading::Humidity(u16::from_le_bytes([value[0], value[1]]) as f32 / 10.0));
+        },
+        (0x100A, 1) => {
+            readings.push(Reading::Battery(value[0] as f32));
+        },
+        (0x100D, 4) => {
+            readings.push(Reading::Temperature(i16::from_le_bytes([value[0], value[1]]) as f32 / 10.0));
+            readings.push(Reading::Humidity(u16::from_le_bytes([value[2], value[3]]) as f32 / 10.0));
+        },
+        _ => log::debug!("MiBeacon object 0x{:04X} of {} bytes isn't decoded", object_id, length),
+    }
+
+    return readings;
+}
+
+// size of BTHome v2 object data, by object id. unknown objects stop decoding
+// since we can't tell where the next object starts.
+fn bthome_object_size(object_id: u8) -> Option<usize> {
+    match object_id {
+        0x00 | 0x01 | 0x09 | 0x0F | 0x10 | 0x11 | 0x15..=0x2D | 0x2E | 0x2F | 0x3A | 0x46 => Some(1),
+        0x02 | 0x03 | 0x06 | 0x07 | 0x08 | 0x0C | 0x0D | 0x0E | 0x12 | 0x13 | 0x14 | 0x3C | 0x3D
+        | 0x3F | 0x40 | 0x41 | 0x43 | 0x44 | 0x45 | 0x47 | 0x48 | 0x49 | 0x4A | 0x51 | 0x52 => Some(2),
+        0x04 | 0x05 | 0x0A | 0x0B | 0x42 | 0x4B => Some(3),
+        0x3E | 0x4C | 0x4D | 0x4E | 0x4F | 0x50 => Some(4),
+        _ => None,
+    }
+}
+
+// decode BTHome v2 frame, without service UUID.
+//  device info (1) | object id (1) | data | object id (1) | data ...
+pub fn decode_bthome(data: &[u8]) -> Vec<Reading> {
+    let mut readings: Vec<Reading> = Vec::new();
+    if data.is_empty() {
+        return readings;
+    }
+
+    let device_info = data[0];
+    let encrypted = device_info & 0x01 != 0;
+    let version = device_info >> 5;
+    if encrypted || version != 2 {
+        log::debug!("BTHome frame isn't decoded, encrypted: {}, version: {}", encrypted, version);
+        return readings;
+    }
+
+    let mut pos: usize = 1;
+    while pos < data.len() {
+        let object_id = data[pos];
+        let size = match bthome_object_size(object_id) {
+            Some(size) => size,
+            None => {
+                log::debug!("BTHome object 0x{:02X} is unknown, the rest of the frame is skipped", object_id);
+                break;
+            },
+        };
+        let value = match data.get(pos + 1..pos + 1 + size) {
+            Some(value) => value,
+            None => break,
+        };
+
+        match object_id {
+            0x01 => readings.push(Reading::Battery(value[0] as f32)),
+            0x02 => readings.push(Reading::Temperature(i16::from_le_bytes([value[0], value[1]]) as f32 / 100.0)),
+            0x03 => readings.push(Reading::Humidity(u16::from_le_bytes([value[0], value[1]]) as f32 / 100.0)),
+            0x2E => readings.push(Reading::Humidity(value[0] as f32)),
+            0x45 => readings.push(Reading::Temperature(i16::from_le_bytes([value[0], value[1]]) as f32 / 10.0)),
+            _ => {}
+        }
+        pos += 1 + size;
+    }
+
+    return readings;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_mibeacon() {
+        // LYWSD02 temperature, 23.4 'C. frame control has mac, capability and object.
+        let data = [
+            0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x11, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x09,
+            0x04, 0x10, 0x02, 0xEA, 0x00,
+        ];
+        assert_eq!(decode_service_data(&data), vec![Reading::Temperature(23.4)]);
+
+        // humidity 68 %
+        let data = [
+            0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x12, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x09,
+            0x06, 0x10, 0x02, 0xA8, 0x02,
+        ];
+        assert_eq!(decode_service_data(&data), vec![Reading::Humidity(68.0)]);
+
+        // battery 14 %
+        let data = [
+            0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x13, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x09,
+            0x0A, 0x10, 0x01, 0x0E,
+        ];
+        assert_eq!(decode_service_data(&data), vec![Reading::Battery(14.0)]);
+
+        // negative temperature, without capability.
+        let data = [
+            0x95, 0xFE, 0x50, 0x20, 0x5B, 0x04, 0x14, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+            0x04, 0x10, 0x02, 0xF6, 0xFF,
+        ];
+        assert_eq!(decode_service_data(&data), vec![Reading::Temperature(-1.0)]);
+
+        // truncated and encrypted frames are ignored.
+        assert!(decode_service_data(&data[..16]).is_empty());
+        let data = [
+            0x95, 0xFE, 0x58, 0x20, 0x5B, 0x04, 0x14, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+            0x04, 0x10, 0x02, 0xF6, 0xFF,
+        ];
+        assert!(decode_service_data(&data).is_empty());
+    }
+
+    #[test]
+    fn test_decode_mibeacon_header() {
+        let data = [
+            0x70, 0x20, 0x5B, 0x04, 0x11, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x09,
+            0x04, 0x10, 0x02, 0xEA, 0x00,
+        ];
+        let header = decode_mibeacon_header(&data).unwrap();
+        assert_eq!(header.frame_control, 0x2070);
+        assert_eq!(header.product_id, 0x045B);
+        assert_eq!(header.frame_counter, 0x11);
+        assert_eq!(header.capability, Some(0x09));
+        assert_eq!(header.object_offset, Some(12));
+        assert!(!header.is_encrypted());
+
+        // frame without mac, capability and object.
+        let header = decode_mibeacon_header(&[0x00, 0x20, 0x5B, 0x04, 0x12]).unwrap();
+        assert_eq!(header.capability, None);
+        assert_eq!(header.object_offset, None);
+
+        assert!(decode_mibeacon_header(&[0x00, 0x20, 0x5B, 0x04]).is_none());
+    }
+
+    #[test]
+    fn test_decode_product_id() {
+        let data = [0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x11];
+        assert_eq!(decode_product_id(&data), Some(0x045B));
+        assert_eq!(decode_product_id(&data[..6]), None);
+        assert_eq!(decode_product_id(&[0xD2, 0xFC, 0x40, 0x01, 0x0E, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_decode_bthome() {
+        // pvvx firmware: packet id, battery 14 %, temperature 23.45 'C, humidity 68.5 %
+        let data = [0xD2, 0xFC, 0x40, 0x00, 0x01, 0x01, 0x0E, 0x02, 0x29, 0x09, 0x03, 0xC2, 0x1A];
+        assert_eq!(decode_service_data(&data), vec![
+            Reading::Battery(14.0),
+            Reading::Temperature(23.45),
+            Reading::Humidity(68.5),
+        ]);
+
+        // unknown object stops decoding, but keeps what's decoded.
+        let data = [0xD2, 0xFC, 0x40, 0x01, 0x0E, 0xFF, 0x01, 0x02];
+        assert_eq!(decode_service_data(&data), vec![Reading::Battery(14.0)]);
+
+        // encrypted, and BTHome v1 are not supported.
+        assert!(decode_service_data(&[0xD2, 0xFC, 0x41, 0x01, 0x0E]).is_empty());
+        assert!(decode_service_data(&[0xD2, 0xFC, 0x20, 0x01, 0x0E]).is_empty());
+    }
+
+    #[test]
+    fn test_decode_frame_counter() {
+        let bthome = [0xD2, 0xFC, 0x40, 0x00, 0x07, 0x01, 0x0E];
+        assert_eq!(decode_frame_counter(&bthome), Some(0x07));
+        // no packet id.
+        assert_eq!(decode_frame_counter(&[0xD2, 0xFC, 0x40, 0x01, 0x0E]), None);
+
+        let mibeacon = [0x95, 0xFE, 0x70, 0x20, 0x5B, 0x04, 0x11];
+        assert_eq!(decode_frame_counter(&mibeacon), Some(0x11));
+        assert_eq!(decode_frame_counter(&[0x1A, 0x18, 0x01, 0x02]), None);
+    }
+
+    #[test]
+    fn test_decode_unknown_service_data() {
+        assert!(decode_service_data(&[]).is_empty());
+        assert!(decode_service_data(&[0x1A, 0x18, 0x01, 0x02]).is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// How fast a battery runs down, from levels stored over weeks by `scan --store` and `battery --store`. A coin cell
+// loses a few percent a month, and levels wander with temperature from hour to hour, so levels are averaged by
+// day, and a line is drawn through the days.
+//  failing       losing more than FAILING_PERCENT_PER_DAY, or DROP_PERCENT from one day to the next
+//  misreporting  RISE_PERCENT above a level before. batteries don't charge themselves, it's the firmware
+
+use serde::Serialize;
+
+use crate::store::StoredReading;
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+// fewer days aren't a curve.
+const MIN_DAYS: usize = 3;
+// a CR2032 lasts about a year, a third of a percent a day.
+const FAILING_PERCENT_PER_DAY: f32 = 1.0;
+const DROP_PERCENT: f32 = 20.0;
+const RISE_PERCENT: f32 = 10.0;
+// levels of a day are shown as these, from empty to full.
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Normal,
+    Failing,
+    Misreporting,
+}
+
+impl Verdict {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Verdict::Normal => "normal",
+            Verdict::Failing => "failing",
+            Verdict::Misreporting => "misreporting",
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discharge {
+    // average level of each day with readings, oldest first. days since unix epoch, UTC.
+    pub days: Vec<(u64, f32)>,
+    // percent lost a day, by the line through `days`. negative when levels go up.
+    pub percent_per_day: f32,
+    pub verdict: Verdict,
+}
+
+impl Discharge {
+    // None with readings on fewer than MIN_DAYS days.
+    pub fn of(readings: &[StoredReading]) -> Option<Discharge> {
+        let mut days: Vec<(u64, f32, usize)> = Vec::new();
+        for reading in readings {
+            let day = reading.time / SECONDS_PER_DAY;
+            match days.iter_mut().find(|(d, _, _)| *d == day) {
+                Some((_, sum, count)) => {
+                    *sum += reading.value;
+                    *count += 1;
+                },
+                None => days.push((day, reading.value, 1)),
+            }
+        }
+        days.sort_by_key(|(day, _, _)| *day);
+        let days: Vec<(u64, f32)> = days.into_iter().map(|(day, sum, count)| (day, sum / count as f32)).collect();
+        if days.len() < MIN_DAYS {
+            return None;
+        }
+
+        // least squares, with days from the first one so they stay small.
+        let first = days[0].0;
+        let n = days.len() as f32;
+        let mean_x = days.iter().map(|(day, _)| (day - first) as f32).sum::<f32>() / n;
+        let mean_y = days.iter().map(|(_, level)| *level).sum::<f32>() / n;
+        let covariance: f32 = days.iter().map(|(day, level)| ((day - first) as f32 - mean_x) * (level - mean_y)).sum();
+        let variance: f32 = days.iter().map(|(day, _)| ((day - first) as f32 - mean_x).powi(2)).sum();
+        let percent_per_day = -covariance / variance;
+
+        let mut lowest = f32::INFINITY;
+        let mut verdict = Verdict::Normal;
+        for (i, (_, level)) in days.iter().enumerate() {
+            if *level > lowest + RISE_PERCENT {
+                verdict = Verdict::Misreporting;
+                break;
+            }
+            if i > 0 && days[i - 1].1 - level > DROP_PERCENT {
+                verdict = Verdict::Failing;
+            }
+            lowest = lowest.min(*level);
+        }
+        if verdict == Verdict::Normal && percent_per_day > FAILING_PERCENT_PER_DAY {
+            verdict = Verdict::Failing;
+        }
+        return Some(Discharge { days: days, percent_per_day: percent_per_day, verdict: verdict });
+    }
+
+    // days until the line reaches 0 from the level of the last day. None when it isn't going down.
+    pub fn days_left(&self) -> Option<u32> {
+        let level = self.days.last()?.1;
+        if self.percent_per_day <= 0.0 {
+            return None;
+        }
+        return Some((level / self.percent_per_day).round() as u32);
+    }
+
+    // last `width` days as a line, one character a day. days without readings are blank.
+    // `ascii` shows tens of percent, 9 for full.
+    pub fn curve(&self, width: usize, ascii: bool) -> String {
+        let last = self.days.last().map_or(0, |(day, _)| *day);
+        let first = self.days[0].0.max((last + 1).saturating_sub(width as u64));
+        return (first..=last).map(|day| {
+            return match self.days.iter().find(|(d, _)| *d == day) {
+                Some((_, level)) if ascii => char::from_digit((level.clamp(0.0, 99.0) / 10.0) as u32, 10).unwrap(),
+                Some((_, level)) => BLOCKS[(level.clamp(0.0, 100.0) / 100.0 * 7.0).round() as usize],
+                None => ' ',
+            };
+        }).collect();
+    }
+}
+
+// YYYY-MM-DD of days since unix epoch.
+pub fn format_day(day: u64) -> String {
+    return chrono::DateTime::from_timestamp((day * SECONDS_PER_DAY) as i64, 0).map_or(String::new(), |time| time.format("%Y-%m-%d").to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Metric;
+
+    fn readings(levels: &[(u64, f32)]) -> Vec<StoredReading> {
+        return levels.iter().map(|(day, value)| StoredReading {
+            time: day * SECONDS_PER_DAY + 3600, address: 0x112233445566, metric: Metric::Battery, value: *value, frame_counter: None,
+        }).collect();
+    }
+
+    #[test]
+    fn test_of() {
+        assert_eq!(Discharge::of(&readings(&[(100, 90.0), (101, 89.0)])), None);
+
+        // two readings on the first day are averaged.
+        let mut stored = readings(&[(100, 90.0), (110, 87.0), (120, 84.0)]);
+        stored.push(StoredReading { time: 100 * SECONDS_PER_DAY + 7200, ..stored[0].clone() });
+        let discharge = Discharge::of(&stored).unwrap();
+        assert_eq!(discharge.days, vec![(100, 90.0), (110, 87.0), (120, 84.0)]);
+        assert!((discharge.percent_per_day - 0.3).abs() < 0.001, "{}", discharge.percent_per_day);
+        assert_eq!(discharge.verdict, Verdict::Normal);
+        assert_eq!(discharge.days_left(), Some(280));
+
+        let discharge = Discharge::of(&readings(&[(100, 90.0), (101, 88.0), (102, 85.0), (103, 83.0)])).unwrap();
+        assert_eq!(discharge.verdict, Verdict::Failing);
+        let discharge = Discharge::of(&readings(&[(100, 90.0), (130, 89.0), (131, 60.0), (160, 59.0)])).unwrap();
+        assert_eq!(discharge.verdict, Verdict::Failing);
+
+        let discharge = Discharge::of(&readings(&[(100, 60.0), (101, 80.0), (102, 58.0)])).unwrap();
+        assert_eq!(discharge.verdict, Verdict::Misreporting);
+        let discharge = Discharge::of(&readings(&[(100, 60.0), (101, 61.0), (102, 62.0)])).unwrap();
+        assert_eq!(discharge.days_left(), None);
+    }
+
+    #[test]
+    fn test_curve() {
+        let discharge = Discharge::of(&readings(&[(100, 100.0), (101, 50.0), (103, 0.0)])).unwrap();
+        assert_eq!(discharge.curve(30, false), "█▅ ▁");
+        assert_eq!(discharge.curve(30, true), "95 0");
+        assert_eq!(discharge.curve(2, true), " 0");
+        assert_eq!(format_day(19813), "2024-03-31");
+    }
+}
@@ -0,0 +1,73 @@
+// Firmware versions of devices, and what's known about them.
+// The version is the Firmware Revision String (0x2A26) of the standard Device Information service (0x180A).
+
+pub struct KnownFirmware {
+    // model name, same as model.rs.
+    pub model: &'static str,
+    // as the device reports it.
+    pub version: &'static str,
+    // clock drifts or resets after a sync, so syncing more often won't help.
+    pub time_sync_bug: bool,
+    pub note: &'static str,
+}
+
+// versions seen on devices, with what's known about their clock. custom firmware of LYWSD03MMC tells its
+// project as the firmware revision, and its version as the software revision.
+pub static KNOWN_FIRMWARE: &[KnownFirmware] = &[
+    KnownFirmware { model: "LYWSD03MMC", version: "1.0.0_0106", time_sync_bug: false,
+        note: "Stock firmware. The display has no clock, the clock only stamps hourly history. It starts over when the battery is out." },
+    KnownFirmware { model: "LYWSD03MMC", version: "1.0.0_0109", time_sync_bug: false,
+        note: "Stock firmware. The display has no clock, the clock only stamps hourly history. It starts over when the battery is out." },
+    KnownFirmware { model: "LYWSD03MMC", version: "1.0.0_0130", time_sync_bug: false,
+        note: "Stock firmware. The display has no clock, the clock only stamps hourly history. It starts over when the battery is out." },
+    KnownFirmware { model: "LYWSD03MMC", version: "github.com/atc1441", time_sync_bug: true,
+        note: "Old custom firmware of atc1441, without the clock service of stock firmware. Nothing is kept of a sync. Flash pvvx firmware instead." },
+    KnownFirmware { model: "LYWSD03MMC", version: "github.com/pvvx", time_sync_bug: false,
+        note: "Custom firmware of pvvx. Its clock is set and trimmed with its own app, not the clock service `sync` writes." },
+];
+
+pub fn decode_revision(data: &[u8]) -> Option<String> {
+    return crate::gatt::decode_string(data);
+}
+
+pub fn find_firmware<'a>(table: &'a [KnownFirmware], version: &str) -> Option<&'a KnownFirmware> {
+    return table.iter().find(|f| f.version.eq_ignore_ascii_case(version));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_revision() {
+        assert_eq!(decode_revision(b"1.0.0_0109"), Some("1.0.0_0109".to_string()));
+        assert_eq!(decode_revision(b"1.0.0_0109\0\0"), Some("1.0.0_0109".to_string()));
+        assert_eq!(decode_revision(b"\0\0"), None);
+        assert_eq!(decode_revision(&[0xFF, 0xFE]), None);
+    }
+
+    #[test]
+    fn test_find_firmware() {
+        let table = [
+            KnownFirmware { model: "LYWSD02", version: "1.0.0_0100", time_sync_bug: true, note: "drifts" },
+            KnownFirmware { model: "LYWSD02", version: "1.0.0_0109", time_sync_bug: false, note: "" },
+        ];
+        assert!(find_firmware(&table, "1.0.0_0100").unwrap().time_sync_bug);
+        assert!(!find_firmware(&table, "1.0.0_0109").unwrap().time_sync_bug);
+        assert!(find_firmware(&table, "1.0.0_0110").is_none());
+    }
+
+    #[test]
+    fn test_known_firmware() {
+        // replaced by pvvx firmware, it doesn't keep the clock.
+        let atc = find_firmware(KNOWN_FIRMWARE, "github.com/atc1441").unwrap();
+        assert_eq!(atc.model, "LYWSD03MMC");
+        assert!(atc.time_sync_bug);
+        assert!(!find_firmware(KNOWN_FIRMWARE, "github.com/pvvx").unwrap().time_sync_bug);
+        assert!(!find_firmware(KNOWN_FIRMWARE, "1.0.0_0109").unwrap().time_sync_bug);
+        // each version once.
+        for firmware in KNOWN_FIRMWARE {
+            assert!(std::ptr::eq(find_firmware(KNOWN_FIRMWARE, firmware.version).unwrap(), firmware), "{}", firmware.version);
+        }
+    }
+}
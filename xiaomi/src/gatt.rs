@@ -0,0 +1,91 @@
+// GATT services and characteristics, named for people. Used by `info` to describe what a device offers,
+// and by `gatt` commands to take UUIDs from the command line.
+
+// 16 bit UUIDs assigned by Bluetooth SIG are 0000XXXX-0000-1000-8000-00805F9B34FB.
+const BLUETOOTH_BASE_UUID: u128 = 0x0000000000001000800000805F9B34FB;
+const SHORT_UUID_MASK: u128 = 0xFFFF_FFFF << 96;
+
+pub const DEVICE_INFORMATION_SERVICE: u128 = 0x0000180A00001000800000805F9B34FB;
+
+// characteristics of Device Information service, in the order `info` prints them.
+pub const DEVICE_INFORMATION_CHARACTERISTICS: [(u128, &str); 6] = [
+    (0x00002A2900001000800000805F9B34FB, "Manufacturer"),
+    (0x00002A2400001000800000805F9B34FB, "Model number"),
+    (0x00002A2500001000800000805F9B34FB, "Serial number"),
+    (0x00002A2700001000800000805F9B34FB, "Hardware revision"),
+    (0x00002A2600001000800000805F9B34FB, "Firmware revision"),
+    (0x00002A2800001000800000805F9B34FB, "Software revision"),
+];
+
+static KNOWN_SERVICES: &[(u128, &str)] = &[
+    (0x0000180000001000800000805F9B34FB, "Generic Access"),
+    (0x0000180100001000800000805F9B34FB, "Generic Attribute"),
+    (DEVICE_INFORMATION_SERVICE, "Device Information"),
+    (0x0000180F00001000800000805F9B34FB, "Battery"),
+    (0x0000FE9500001000800000805F9B34FB, "Xiaomi"),
+    (0xEBE0CCB07A0A4B0C8A1A6FF2997DA3A6, "LYWSD02 clock"),
+];
+
+// 0x180A for assigned numbers, full form otherwise.
+pub fn format_uuid(uuid: u128) -> String {
+    if uuid & !SHORT_UUID_MASK == BLUETOOTH_BASE_UUID && uuid >> 96 <= 0xFFFF {
+        return format!("0x{:04X}", uuid >> 96);
+    }
+    return format!("{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+        (uuid >> 96) as u32,
+        (uuid >> 80) as u16,
+        (uuid >> 64) as u16,
+        (uuid >> 48) as u16,
+        uuid & 0xFFFF_FFFF_FFFF);
+}
+
+// "180A", "0x180A" or "EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6". case doesn't matter.
+pub fn parse_uuid(value: &str) -> Result<u128, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X").replace('-', "");
+    let invalid = || format!("invalid UUID \"{}\". Use 180A, or the full form like EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6", value);
+    return match digits.len() {
+        4 | 8 => u32::from_str_radix(&digits, 16).map(|short| ((short as u128) << 96) | BLUETOOTH_BASE_UUID).map_err(|_| invalid()),
+        32 => u128::from_str_radix(&digits, 16).map_err(|_| invalid()),
+        _ => Err(invalid()),
+    };
+}
+
+pub fn service_name(uuid: u128) -> Option<&'static str> {
+    return KNOWN_SERVICES.iter().find(|(known, _)| *known == uuid).map(|(_, name)| *name);
+}
+
+// string characteristics are UTF-8. some devices pad them with NULs.
+pub fn decode_string(data: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(data).ok()?;
+    let text = text.trim_end_matches('\0').trim();
+    if text.is_empty() {
+        return None;
+    }
+    return Some(text.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uuid() {
+        assert_eq!(format_uuid(DEVICE_INFORMATION_SERVICE), "0x180A");
+        assert_eq!(format_uuid(0xEBE0CCB07A0A4B0C8A1A6FF2997DA3A6), "EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6");
+        assert_eq!(service_name(0x0000FE9500001000800000805F9B34FB), Some("Xiaomi"));
+        assert_eq!(service_name(0xEBE0CCB17A0A4B0C8A1A6FF2997DA3A6), None);
+    }
+
+    #[test]
+    fn test_parse_uuid() {
+        assert_eq!(parse_uuid("180A"), Ok(DEVICE_INFORMATION_SERVICE));
+        assert_eq!(parse_uuid("0x180a"), Ok(DEVICE_INFORMATION_SERVICE));
+        assert_eq!(parse_uuid("0000180A-0000-1000-8000-00805F9B34FB"), Ok(DEVICE_INFORMATION_SERVICE));
+        assert_eq!(parse_uuid("ebe0ccb0-7a0a-4b0c-8a1a-6ff2997da3a6"), Ok(0xEBE0CCB07A0A4B0C8A1A6FF2997DA3A6));
+        assert!(parse_uuid("180").is_err());
+        assert!(parse_uuid("18ZZ").is_err());
+        for uuid in [DEVICE_INFORMATION_SERVICE, 0xEBE0CCB07A0A4B0C8A1A6FF2997DA3A6] {
+            assert_eq!(parse_uuid(&format_uuid(uuid)), Ok(uuid));
+        }
+    }
+}
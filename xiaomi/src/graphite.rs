@@ -0,0 +1,152 @@
+// Readings sent to Graphite (Carbon) with the plaintext protocol, a line for each reading over TCP:
+//  xiaomi.Tokyo.temperature 23.4 1696891938
+// Carbon doesn't answer, so a lost connection is only noticed when writing fails.
+
+use serde::Deserialize;
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_PORT: u16 = 2003;
+pub const DEFAULT_PREFIX: &str = "xiaomi";
+// a Carbon gone away isn't connected again on every reading.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Declared as [graphite] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct GraphiteConfig {
+    // "graphite.local"
+    pub host: Option<String>,
+    // default is 2003.
+    pub port: Option<u16>,
+    // first nodes of every path. default is "xiaomi".
+    pub prefix: Option<String>,
+}
+
+// dots separate nodes of a path, and a space ends it. anything but letters, digits, '-' and '_' becomes '_'.
+fn node(text: &str) -> String {
+    return text.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+}
+
+// prefix is kept as it is, so it can have nodes of its own like "home.sensors".
+pub fn metric_path(prefix: &str, name: &str, metric: &str) -> String {
+    let prefix = prefix.trim_matches('.');
+    if prefix.is_empty() {
+        return format!("{}.{}", node(name), metric);
+    }
+    return format!("{}.{}.{}", prefix, node(name), metric);
+}
+
+pub fn format_line(path: &str, value: f32, time: u64) -> String {
+    return format!("{} {} {}\n", path, value, time);
+}
+
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let addresses = (host, port).to_socket_addrs().map_err(|e| format!("{}: {}", host, e))?;
+    let mut error = format!("{}: no address", host);
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+                return Ok(stream);
+            },
+            Err(e) => error = format!("{}: {}", address, e),
+        }
+    }
+    return Err(error);
+}
+
+pub struct Sink {
+    host: String,
+    port: u16,
+    prefix: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    // connecting failed. it's tried again after this.
+    retry_at: Option<Instant>,
+}
+
+impl Sink {
+    // the first connection has to work, so a wrong host is told right away.
+    pub fn open(config: &GraphiteConfig, timeout: Duration) -> Result<Sink, String> {
+        let host = config.host.clone().ok_or("no host in [graphite]")?;
+        let port = config.port.unwrap_or(DEFAULT_PORT);
+        let stream = connect(&host, port, timeout)?;
+        return Ok(Sink {
+            host: host,
+            port: port,
+            prefix: config.prefix.clone().unwrap_or(DEFAULT_PREFIX.to_string()),
+            timeout: timeout,
+            stream: Some(stream),
+            retry_at: None,
+        });
+    }
+
+    pub fn path(&self, name: &str, metric: &str) -> String {
+        return metric_path(&self.prefix, name, metric);
+    }
+
+    // Err when the connection is lost, or connecting again failed. Ok when it's dropped waiting to connect again.
+    pub fn send(&mut self, path: &str, value: f32, time: u64) -> Result<(), String> {
+        if self.stream.is_none() {
+            if self.retry_at.is_some_and(|at| at > Instant::now()) {
+                return Ok(());
+            }
+            match connect(&self.host, self.port, self.timeout) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.retry_at = None;
+                },
+                Err(msg) => {
+                    self.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                    return Err(msg);
+                },
+            }
+        }
+        let line = format_line(path, value, time);
+        if let Some(Err(e)) = self.stream.as_mut().map(|stream| stream.write_all(line.as_bytes())) {
+            self.stream = None;
+            self.retry_at = Some(Instant::now());
+            return Err(format!("{}:{}: {}", self.host, self.port, e));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_metric_path() {
+        assert_eq!(metric_path("xiaomi", "Tokyo", "temperature"), "xiaomi.Tokyo.temperature");
+        assert_eq!(metric_path("home.sensors.", "Living room", "humidity"), "home.sensors.Living_room.humidity");
+        assert_eq!(metric_path("", "AA:BB:CC:DD:EE:FF", "battery"), "AA_BB_CC_DD_EE_FF.battery");
+    }
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(format_line("xiaomi.Tokyo.temperature", 23.4, 1696891938), "xiaomi.Tokyo.temperature 23.4 1696891938\n");
+        assert_eq!(format_line("xiaomi.Tokyo.humidity", 68.0, 1696891938), "xiaomi.Tokyo.humidity 68 1696891938\n");
+    }
+
+    #[test]
+    fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = GraphiteConfig { host: Some("127.0.0.1".to_string()), port: Some(listener.local_addr().unwrap().port()), prefix: None };
+        let mut sink = Sink::open(&config, Duration::from_secs(5)).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let path = sink.path("Tokyo", "temperature");
+        sink.send(&path, 23.4, 1696891938).unwrap();
+        drop(sink);
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "xiaomi.Tokyo.temperature 23.4 1696891938\n");
+
+        assert!(Sink::open(&GraphiteConfig::default(), Duration::from_secs(5)).is_err());
+    }
+}
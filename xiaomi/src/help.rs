@@ -0,0 +1,481 @@
+// Long help of commands, shown by `--help` and in man pages. Each tells which settings of toml the command reads,
+// so a binary release documents itself. Lines indented by 4 spaces are toml, tests check they're read as written.
+
+pub const CLI: &str = "\
+Scan Xiaomi BLE sensors, and sync the clock of LYWSD02.
+
+Settings are read from a toml file next to the exe, with the same name: xiaomi.toml for xiaomi.exe.
+The file is optional. Without it, devices are shown by address and every command still works.
+It has three sections, all optional:
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"Tokyo\"
+    timezone = \"Asia/Tokyo\"
+
+    [general]
+    gatt_timeout_seconds = 10
+
+    [theme]
+    preset = \"light\"
+
+Repeat [[device]] for every device. Names can be used instead of addresses on the command line.
+`xiaomi toml` shows how the file is read, and `xiaomi <command> --help` which settings a command reads.
+Messages go to stderr. -q shows errors only, -v steps of bluetooth operations, -vv GATT and decoder diagnostics,
+and -vvv every frame received.";
+
+pub const SCAN: &str = "\
+Scan Xiaomi BLE devices
+
+Listens to advertisements passively, printing readings as they arrive, then a summary of every device seen.
+Devices in toml are shown by name. Readings from `addresses` of a device are merged into it.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"Tokyo\"
+    addresses = [\"AA:BB:CC:DD:EE:00\"]
+    group = \"bedroom\"
+
+    [general]
+    scan_duration_seconds = 10
+    fleet_mode = false
+    summary_page_size = 50
+    strict_scan = false
+    rssi_min = -80
+    apparent_temperature = \"heat-index\"
+    precision = 1
+
+--duration overrides scan_duration_seconds, and --fleet turns fleet_mode on.
+With strict_scan, devices not in toml are left out. Advertisements weaker than rssi_min dBm, or --rssi-min, are ignored.
+The summary is sorted by name, or by --sort. Devices without the value sorted by go last, with --desc too.
+--dew-point adds dew point and absolute humidity, computed from the latest temperature and humidity.
+--comfort adds \"feels like\" temperature, by heat index or humidex as apparent_temperature tells, and comfort:
+dry below 30% humidity, humid above 60%, comfortable between.
+
+--mqtt publishes every reading to [mqtt], to a topic where {name}, {address} and {metric} are replaced.
+
+    [mqtt]
+    url = \"mqtts://broker.local:8883\"
+    topic = \"xiaomi/{name}/{metric}\"
+    qos = 1
+    retain = true
+    username = \"xiaomi\"
+    password = \"secret\"
+
+--influxdb writes readings to [influxdb] in batches, tagged with address, name and group. database is for
+InfluxDB 1.x, bucket, org and token for 2.x.
+
+    [influxdb]
+    url = \"http://localhost:8086\"
+    bucket = \"sensors\"
+    org = \"home\"
+    token = \"my-token\"
+    batch_size = 100
+    flush_seconds = 10
+
+--pushgateway pushes the latest readings of each device to [pushgateway] when the scan ends, as `serve --prometheus`
+serves them. Metrics of the last scan are replaced. Scans of --watch and --stream push when Ctrl-C ends them.
+
+    [pushgateway]
+    url = \"http://localhost:9091\"
+    job = \"xiaomi\"
+
+--otlp exports gauges of the latest readings and a span of the scan to an OpenTelemetry collector in [otlp],
+with OTLP/HTTP. Scans of --watch and --stream export readings every export_seconds too.
+
+    [otlp]
+    endpoint = \"http://localhost:4318\"
+    service_name = \"xiaomi\"
+    export_seconds = 60
+
+--graphite sends every reading to Carbon in [graphite], as a line like \"xiaomi.Tokyo.temperature 23.4 1696891938\".
+Devices without a name use the address, with '_' for ':'.
+
+    [graphite]
+    host = \"graphite.local\"
+    port = 2003
+    prefix = \"xiaomi\"
+
+Every [[webhook]] in toml is POSTed readings at most every reading_interval_seconds with \"reading\" in events, and
+readings going over or under its limits with \"threshold\". A limit crossed is told again only after it was back within.
+The body is the event as JSON, or template with {event}, {time}, {address}, {name}, {metric}, {value}, {threshold}
+and {message} replaced. `serve` sends them too, and `sync` and `daemon` send \"sync-failure\".
+
+    [[webhook]]
+    url = \"https://ntfy.sh/my-sensors\"
+    events = [\"threshold\", \"sync-failure\"]
+    temperature_above = 28.0
+    humidity_below = 30.0
+    battery_below = 20
+    template = \"{message}\"
+    headers = { Title = \"Xiaomi sensors\", Content-Type = \"text/plain\" }";
+
+pub const DASHBOARD: &str = "\
+Full screen view of devices around, with trends and alerts. q to quit
+
+Devices in toml are shown by name, and --group takes `group` of [[device]].
+A battery below low_battery_percent is an alert. With strict_scan, devices not in toml are left out.
+
+    [general]
+    low_battery_percent = 20
+    strict_scan = false";
+
+pub const SOAK: &str = "\
+Keep monitoring for hours, checking for panics, a stuck watcher, memory and queue depth. Prints pass or fail
+
+For testing a build before leaving it running unattended. Doesn't read toml.
+Every second the memory of the process and events not handled yet are sampled.
+The run fails on a panic, when the watcher stops or hears nothing for 5 minutes, or when a limit is exceeded.";
+
+pub const SYNC: &str = "\
+Sync xiaomi clock devices
+
+Listens to advertisements, and writes the time to every LYWSD02 heard, in the timezone of its [[device]].
+The clock shows UTC+9 for a device without timezone. Devices with `omit` are never written.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"Tokyo\"
+    timezone = \"Asia/Tokyo\"
+    offset_seconds = 300
+    unit = \"c\"
+    omit = false
+
+    [general]
+    sync_duration_seconds = 30
+    sync_workers = 2
+    sync_retry_count = 2
+    sync_retry_delay_milliseconds = 500
+    gatt_timeout_seconds = 10
+    sync_drift_threshold_seconds = 5
+    sync_verify_tolerance_seconds = 2
+    timezone_policy = \"truncate\"
+    time_source = \"system\"
+    ntp_servers = [\"time.windows.com\", \"pool.ntp.org\"]
+    low_battery_percent = 20
+    strict = false
+    ignore_random_addresses = false
+    rssi_min = -80
+
+--duration, --timezone, --offset-seconds and --rssi-min override the config for one run.
+timezone_policy is \"truncate\", \"round-nearest\" or \"error\", for timezones not on the hour, like +05:30.
+With strict, devices not in toml are never written, even when an address is given.
+--pushgateway pushes the summary to [pushgateway] when the run ends: whether it succeeded, and the result, time taken,
+drift and battery of each device. Metrics of the last sync run are replaced.
+--otlp exports a histogram of how long devices took, a count of devices failed or not seen with their address,
+and a span of the run to [otlp].
+Every [[webhook]] with \"sync-failure\" in events is POSTed each device which failed, was unfinished or not seen.
+With \"sync\", it's POSTed the summary of the run as JSON, for Node-RED, n8n or Home Assistant.
+
+    [pushgateway]
+    url = \"http://localhost:9091\"
+
+    [otlp]
+    endpoint = \"http://localhost:4318\"
+    headers = { api-key = \"secret\" }
+
+    [[webhook]]
+    url = \"https://ntfy.sh/my-sensors\"
+    events = [\"sync-failure\"]
+
+    [[webhook]]
+    url = \"http://homeassistant.local:8123/api/webhook/xiaomi-sync\"
+    events = [\"sync\"]";
+
+pub const READ: &str = "\
+Wait for a reading of a device and print it. With --metric, only the value, for scripts
+
+Listens to advertisements of the device until temperature and humidity are heard, or the metric given. Battery is
+advertised every few minutes, so a LYWSD02 not heard is connected to and read. Exits with 1 when it's not heard
+within --duration. The device is a name of [[device]] in toml or an address, and readings from its `addresses` count.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"kitchen\"
+    addresses = [\"AA:BB:CC:DD:EE:00\"]
+
+    [general]
+    gatt_timeout_seconds = 10";
+
+pub const DRIFT: &str = "\
+Read time of configured clocks and show drift, without writing
+
+Connects to every [[device]] in toml, one at a time. Drift is measured against the time `sync` would write,
+with timezone, offset_seconds and timezone_policy applied.
+
+    [general]
+    gatt_timeout_seconds = 10
+    timezone_policy = \"truncate\"";
+
+// commands connecting to a single device share the part about toml.
+macro_rules! device_command {
+    ($about:literal) => {
+        concat!($about, "
+
+The device is a name of [[device]] in toml, or an address like AA:BB:CC:DD:EE:FF.
+A single bluetooth operation can take up to gatt_timeout_seconds.
+
+    [general]
+    gatt_timeout_seconds = 10")
+    };
+}
+
+pub const GET_TIME: &str = device_command!("Read time of a clock, or all configured clocks, as it is");
+pub const SET_TIME: &str = device_command!("Write given time to a clock, as it is. For testing drift, or offsetting a clock on purpose");
+pub const SET_UNIT: &str = device_command!("Set the temperature unit shown on the display");
+pub const HISTORY: &str = device_command!("Hourly min/max history kept by the device, or readings stored by `scan --store`");
+pub const INFO: &str = device_command!(
+    "Read the Device Information service and list GATT services of a device. For diagnosing firmware specific issues");
+pub const GATT: &str = device_command!("Read, write or subscribe to any GATT characteristic. For exploring new devices");
+pub const FIRMWARE: &str = device_command!("Firmware version of the device");
+
+pub const DAEMON: &str = "\
+Stay running, and sync devices not synced for a while on every interval
+
+Every interval, toml is read again, and devices not synced for daemon_sync_age_hours are synced
+with the settings of `sync`. Devices synced since are only read for their drift, and synced on the
+next run when it's over sync_drift_threshold_seconds. Changes to toml are picked up without a restart.
+
+    [general]
+    daemon_interval_minutes = 60
+    daemon_sync_age_hours = 24
+
+--interval overrides daemon_interval_minutes.
+
+To sync before the next run, create xiaomi.sync next to xiaomi.exe. It's removed, and a run starts within
+a second. An empty file syncs every device, even ones synced recently. Otherwise it lists names in toml or
+addresses, one per line. It works the same for the service.
+
+Devices with `timezone` are synced again a few seconds after daylight saving time starts or ends, or their
+offset changes otherwise, so clocks don't show the wrong hour until the next run.";
+
+pub const SERVE: &str = "\
+Keep scanning until Ctrl-C, serving the latest readings over HTTP
+
+--prometheus serves /metrics for Prometheus to scrape, with gauges of temperature, humidity, battery and
+seconds since the last reading of each device, labelled with address, and `name` and `group` of [[device]].
+\":9184\" listens on `bind` of [serve], \"127.0.0.1:9184\" on this computer only.
+--api serves a REST API answering JSON. It can listen on the same address as --prometheus.
+  GET  /devices                  devices in toml and devices heard, with the latest readings and last sync
+  GET  /devices/{name}/readings  latest reading of each metric
+  POST /devices/{name}/sync      starts syncing the device as `sync {name}` does, answered with 202 and its id
+  GET  /syncs/{id}               status of the sync, with the summary of `sync --format json` once it's done
+{name} is a name in toml or an address. One sync runs at a time, another gets 409 until it ended. It syncs
+devices `serve` hears, while it goes on serving.
+--web serves a dashboard page at / for a browser, with the latest readings, battery, last sync and charts of
+the last day of each device. Charts start when `serve` starts. It reads with the GET routes of the API, without
+--api too, but it can't sync. A battery below low_battery_percent is shown in red.
+--gateway merges readings other computers publish to [mqtt] with `scan --mqtt`, so one dashboard shows every room.
+Topics are read with `topic` of [mqtt], the same as theirs. A device is known by its name in toml, or the address.
+With [serve], every request needs \"Authorization: Bearer <token>\", or the username and password with Basic,
+which browsers ask for. Others get 401. Without a token or a password, only this computer is served: \":9184\" listens
+on 127.0.0.1, and other addresses stop `serve`. With them, it's every interface, or `bind`. Away from home, bind to
+the address of a WireGuard interface, or keep 127.0.0.1 and forward the port with `ssh -L 9184:127.0.0.1:9184`.
+With `cert` and `key` of [serve], PEM files of a certificate and its private key, every address serves https, so
+the token and password aren't sent in clear text.
+Addresses reachable from other computers are advertised with mDNS as _xiaomi-bridge._tcp, so dashboards and
+Home Assistant find them. TXT of each says what it serves, like \"api=/devices\" and \"auth=bearer\", and \"tls=1\"
+with https. --no-mdns turns it off.
+--perf-counters sets Windows performance counters of each device heard, for PerfMon and SCOM, in the \"Xiaomi Sensors\"
+counter set with an instance named by its name in toml, or the address. Install them first with `xiaomi counters install`.
+With strict_scan, devices not in toml are left out. rssi_min leaves out devices next door.
+
+    [general]
+    strict_scan = false
+    rssi_min = -80
+    low_battery_percent = 20
+
+    [serve]
+    token = \"change-me\"
+    username = \"family\"
+    password = \"change-me\"
+    cert = \"xiaomi.crt\"
+    key = \"xiaomi.key\"
+    bind = \"0.0.0.0\"";
+
+pub const SERVICE: &str = "\
+Run `daemon` as a Windows service
+
+The service runs xiaomi.exe where it's installed, so toml next to it is read. Keep both in place after installing.
+It reads toml the same way `daemon` does.";
+
+pub const COUNTERS: &str = "\
+Register Windows performance counters of `serve --perf-counters`
+
+install writes xiaomi.man next to xiaomi.exe and registers it with lodctr, uninstall removes it with unlodctr.
+Both need administrator. Install again after moving xiaomi.exe.
+Counters are whole numbers: temperature and humidity are in tenths, so 21.5 °C reads 215, and a temperature
+below zero reads 0. PerfMon graphs them with a scale of 0.1. Battery is in percent.";
+
+pub const SCHEDULE: &str = "\
+Run `sync` periodically with Task Scheduler, while you're logged on
+
+The task runs xiaomi.exe where it is, so toml next to it is read on every run with the settings of `sync`.
+With --pushgateway, every run pushes its summary to [pushgateway], as `sync --pushgateway` does.";
+
+pub const EXPORT: &str = "\
+Write readings as CSV or JSON, from a store of `scan --store` or a scan for a while
+
+Readings are CSV, or a JSON array with --format json. They're printed, or written to --out.
+--parquet writes a Parquet file to --out instead, a row group of 65536 readings at a time, with time as a timestamp.
+It needs a build with `cargo build --release --features parquet`.
+--since and --until pick readings of a store by how long ago they are, like 30m, 2h or 7d. --until takes now too.
+Devices are written by address. With --scan, readings from `addresses` of a [[device]] are written
+with its `address`, as `scan --store` does. Values are rounded to precision digits when it's set.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    addresses = [\"AA:BB:CC:DD:EE:00\"]
+
+    [general]
+    precision = 1";
+
+pub const BATTERY: &str = "\
+List battery levels of configured devices, lowest first. Exits with 1 when any is low
+
+Listens until every [[device]] in toml advertised its battery, or for --duration. Devices not in toml are left out.
+A level below low_battery_percent is low, --below overrides it. With --read, LYWSD02 not heard are connected to,
+and the level is read. Battery age is counted from battery_changed, or purchased.
+
+With --store, levels are stored in a SQLite database, with the ones of `scan --store`, and levels stored since
+battery_changed (a year without it) are averaged by day. Per month is how much a battery loses, by a line through
+the days, and Empty in is when the line reaches 0. A battery losing more than 1% a day, or 20% from one day to the
+next, is failing. One whose level went up by 10% is misreporting, the firmware's doing, unless it was replaced
+without battery_changed. Levels of 3 days at least are needed. Run it daily from `schedule`, or keep `scan --store`.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    battery_changed = \"2023-10-01\"
+
+    [general]
+    low_battery_percent = 20
+    gatt_timeout_seconds = 10";
+
+pub const STATUS: &str = "\
+Show when configured devices were last seen and synced
+
+Lists every [[device]] in toml. Last seen and synced times are recorded by `sync` and `scan`, in xiaomi.state.toml
+next to the exe. Devices not seen for a day are highlighted. Battery age is counted from battery_changed,
+or purchased when it's never changed.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    purchased = \"2023-01-15\"
+    battery_changed = \"2023-10-01\"";
+
+pub const TOML: &str = "\
+Read toml file and print
+
+Prints every [[device]] as it's read, with `notes`, and warns about settings which are likely mistakes,
+with a suggestion how to fix them.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    notes = \"kitchen, above the fridge\"
+
+Colors and table borders of every command are set in [theme]. preset is \"dark\", \"light\" or \"plain\",
+borders are \"ascii\", \"box\" or \"none\", and colors override the preset. ascii, or --ascii, replaces emoji, box borders
+and spinners with ASCII. It's automatic when output isn't a UTF-8 terminal, like a file.
+
+    [theme]
+    preset = \"dark\"
+    borders = \"box\"
+    ok = \"green\"
+    warning = \"yellow\"
+    error = \"red\"
+    bold_header = true
+    ascii = false";
+
+pub const SETUP: &str = "\
+Find devices around, ask a name and timezone for each, and write toml. For the first run
+
+Scans for scan_duration_seconds, and lists devices not in toml with their readings. For each, a name and timezone
+are asked, and a [[device]] is appended to toml, keeping what's in the file. A new file starts as `config init` writes it.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"kitchen\"
+    timezone = \"Asia/Seoul\"
+
+    [general]
+    scan_duration_seconds = 10";
+
+pub const CONFIG: &str = "\
+Create or edit the toml file
+
+`config init` writes a starter toml next to the exe, with every setting explained and commented out,
+so it changes nothing until edited. Uncomment [[device]], set the address of each device,
+and check it with `xiaomi toml`.
+
+`config add` appends a [[device]] to the end of the file, with the name and timezone given.
+What's already in the file, comments included, is kept as it is.
+
+`config check` exits with 1 when a setting can't work as written: an unknown timezone, an address in two [[device]],
+or offset_seconds of more than an hour. Likely mistakes and keys no setting reads are warnings.
+With --strict, unknown keys are errors too.
+
+    [[device]]
+    address = \"AA:BB:CC:DD:EE:FF\"
+    name = \"kitchen\"
+    timezone = \"Asia/Seoul\"";
+
+pub const MODELS: &str = "\
+List known device models, and what can be done with each
+
+Doesn't read toml. The model of a device is told by its advertisements, it isn't set in toml.";
+
+pub const DOCTOR: &str = "\
+Check Bluetooth adapter, config file and timezones, and tell how to fix what's wrong
+
+toml is checked first: whether it's read, likely mistakes as `toml` shows them, and timezones not known.
+A missing file is fine, devices are shown by address then.";
+
+pub const COMPLETIONS: &str = "\
+Print a tab completion script for the shell. Device names and groups in toml are completed as they're now
+
+`name` and `group` of every [[device]] are put in the script. Generate it again after editing toml.";
+
+pub const MANPAGE: &str = "\
+Write man pages of xiaomi and its commands
+
+Without --out-dir, the page of xiaomi is printed. With it, a page is written for every command,
+like xiaomi-sync.1, for packaging a release.";
+
+pub const GEN_FIXTURES: &str = "\
+Print a decoder test case for frames in a capture file. For developers
+
+Doesn't read toml. Readings are decoded as they're now, and printed as expected values of the test.";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[&str] = &[CLI, SCAN, DASHBOARD, SOAK, SYNC, READ, DRIFT, GET_TIME, SET_TIME, SET_UNIT, HISTORY, INFO, GATT,
+        FIRMWARE, DAEMON, SERVE, SERVICE, SCHEDULE, EXPORT, BATTERY, STATUS, TOML, SETUP, CONFIG, MODELS, DOCTOR, COMPLETIONS, MANPAGE, GEN_FIXTURES];
+
+    // toml lines of a help text, as a file.
+    fn example(text: &str) -> String {
+        let lines: Vec<&str> = text.lines().filter(|l| l.starts_with("    ")).map(|l| l.trim_start()).collect();
+        return lines.join("\n");
+    }
+
+    #[test]
+    fn test_examples_are_read() {
+        for text in ALL {
+            let example = example(text);
+            if let Err(e) = toml::from_str::<crate::Config>(&example) {
+                panic!("{}\n{}", e, example);
+            }
+        }
+        assert!(example(SYNC).contains("events = [\"sync\"]"));
+    }
+
+    #[test]
+    fn test_first_line_is_about() {
+        for text in ALL {
+            let first = text.lines().next().unwrap();
+            assert!(!first.is_empty() && first.len() <= 120, "{}", first);
+            assert!(text.lines().nth(1).unwrap_or("").is_empty(), "{}", first);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Hourly history kept by LYWSD02. Records are sent as notifications of the history characteristic,
+// oldest first, one record per notification.
+//  [0..4] index, [4..8] time, [8..10] max temperature, [10] max humidity, [11..13] min temperature, [13] min humidity
+// All little endian. Temperatures are signed, in 0.1 'C.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryRecord {
+    pub index: u32,
+    // start of the hour, as the clock had it. same epoch as the time characteristic.
+    pub time: u32,
+    pub max_temperature: f32,
+    pub max_humidity: u8,
+    pub min_temperature: f32,
+    pub min_humidity: u8,
+}
+
+pub const RECORD_LENGTH: usize = 14;
+
+impl HistoryRecord {
+    pub fn decode(data: &[u8]) -> Option<HistoryRecord> {
+        if data.len() < RECORD_LENGTH {
+            return None;
+        }
+        return Some(HistoryRecord {
+            index: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            time: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            max_temperature: i16::from_le_bytes([data[8], data[9]]) as f32 / 10.0,
+            max_humidity: data[10],
+            min_temperature: i16::from_le_bytes([data[11], data[12]]) as f32 / 10.0,
+            min_humidity: data[13],
+        });
+    }
+}
+
+pub const CSV_HEADER: &str = "index,time,max_temperature,max_humidity,min_temperature,min_humidity";
+
+// one line, without the newline.
+pub fn format_csv(record: &HistoryRecord) -> String {
+    return format!("{},{},{:.1},{},{:.1},{}",
+        record.index, record.time, record.max_temperature, record.max_humidity, record.min_temperature, record.min_humidity);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let data = [0x2A, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x25, 0x65, 0xF0, 0x00, 0x44, 0xF6, 0xFF, 0x30];
+        let record = HistoryRecord::decode(&data).unwrap();
+        assert_eq!(record, HistoryRecord {
+            index: 42, time: 1696927248, max_temperature: 24.0, max_humidity: 68, min_temperature: -1.0, min_humidity: 48 });
+        assert_eq!(format_csv(&record), "42,1696927248,24.0,68,-1.0,48");
+
+        assert_eq!(HistoryRecord::decode(&data[..13]), None);
+    }
+}
@@ -0,0 +1,150 @@
+// Human readable formatting for tables and progress logs.
+// Machine readable outputs should keep raw values instead.
+
+// "45s", "2m 13s", "1h 5m", "3d 2h". only two biggest units are shown.
+pub fn format_duration(seconds: u64) -> String {
+    let units: [(u64, &str); 4] = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+
+    let mut parts: Vec<String> = Vec::new();
+    let mut remaining = seconds;
+    for (size, unit) in units {
+        if remaining >= size || (size == 1 && parts.is_empty()) {
+            parts.push(format!("{}{}", remaining / size, unit));
+            remaining %= size;
+        }
+        else if !parts.is_empty() {
+            // keep the unit right below the biggest one, even when it's zero. "1h 0m"
+            parts.push(format!("0{}", unit));
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    return parts.join(" ");
+}
+
+// signed duration, for clock drift. "+2m 13s", "-45s", "0s"
+pub fn format_signed_duration(seconds: i64) -> String {
+    return match seconds {
+        0 => "0s".to_string(),
+        s if s > 0 => format!("+{}", format_duration(s as u64)),
+        s => format!("-{}", format_duration(s.unsigned_abs())),
+    };
+}
+
+// how long ago something happened. "just now", "12s ago", "5m 3s ago"
+pub fn format_age(seconds: u64) -> String {
+    if seconds < 5 {
+        return "just now".to_string();
+    }
+    return format!("{} ago", format_duration(seconds));
+}
+
+// "90s", "30m", "24h" or "7d" into seconds, for ranges like --since.
+pub fn parse_duration(text: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration \"{}\". Use like 90s, 30m, 24h or 7d", text);
+    if text.len() < 2 || !text.is_ascii() {
+        return Err(invalid());
+    }
+    let (number, unit) = text.split_at(text.len() - 1);
+    let n: u64 = number.parse().map_err(|_| invalid())?;
+    let size = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(invalid()),
+    };
+    return n.checked_mul(size).ok_or_else(invalid);
+}
+
+// value with `precision` digits after the point, like 23.40. as received without it.
+pub fn format_value(value: f32, precision: Option<usize>) -> String {
+    return match precision {
+        Some(digits) => format!("{:.*}", digits, value),
+        None => value.to_string(),
+    };
+}
+
+// value rounded to `precision` digits, for JSON and CSV. as received without it.
+pub fn round_value(value: f32, precision: Option<usize>) -> f32 {
+    return match precision {
+        Some(digits) => {
+            let scale = 10f64.powi(digits.min(9) as i32);
+            ((value as f64 * scale).round() / scale) as f32
+        },
+        None => value,
+    };
+}
+
+// "now", or a duration ago as parse_duration takes it. seconds before now, for ends of ranges like --until.
+pub fn parse_ago(text: &str) -> Result<u64, String> {
+    if text.eq_ignore_ascii_case("now") {
+        return Ok(0);
+    }
+    return parse_duration(text).map_err(|_| format!("invalid time \"{}\". Use now, or a time ago like 90s, 30m, 24h or 7d", text));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(60), "1m 0s");
+        assert_eq!(format_duration(133), "2m 13s");
+        assert_eq!(format_duration(3600), "1h 0m");
+        assert_eq!(format_duration(3900), "1h 5m");
+        assert_eq!(format_duration(3 * 86400 + 2 * 3600 + 59), "3d 2h");
+        assert_eq!(format_duration(86400 + 30), "1d 0h");
+    }
+
+    #[test]
+    fn test_format_signed_duration() {
+        assert_eq!(format_signed_duration(0), "0s");
+        assert_eq!(format_signed_duration(133), "+2m 13s");
+        assert_eq!(format_signed_duration(-62), "-1m 2s");
+        assert_eq!(format_signed_duration(i64::MIN), format!("-{}", format_duration(i64::MIN.unsigned_abs())));
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(0), "just now");
+        assert_eq!(format_age(4), "just now");
+        assert_eq!(format_age(12), "12s ago");
+        assert_eq!(format_age(303), "5m 3s ago");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("24h"), Ok(86400));
+        assert_eq!(parse_duration("7d"), Ok(7 * 86400));
+        assert!(parse_duration("24").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1h").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(23.45, None), "23.45");
+        assert_eq!(format_value(23.45, Some(0)), "23");
+        assert_eq!(format_value(23.4, Some(2)), "23.40");
+        assert_eq!(round_value(23.46, Some(1)), 23.5);
+        assert_eq!(round_value(68.0, Some(0)), 68.0);
+        assert_eq!(round_value(23.456, None), 23.456);
+    }
+
+    #[test]
+    fn test_parse_ago() {
+        assert_eq!(parse_ago("now"), Ok(0));
+        assert_eq!(parse_ago("NOW"), Ok(0));
+        assert_eq!(parse_ago("2h"), Ok(7200));
+        assert!(parse_ago("yesterday").unwrap_err().contains("now"));
+    }
+}
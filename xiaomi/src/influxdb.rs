@@ -0,0 +1,242 @@
+// Readings written to InfluxDB in line protocol, batched so a busy scan isn't a request per reading.
+// InfluxDB 1.x takes `database`, 2.x takes `org`, `bucket` and `token`. Each reading is a line like
+//  xiaomi,address=AA:BB:CC:DD:EE:FF,name=Tokyo,group=bedroom temperature=23.4 1696891938
+
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MEASUREMENT: &str = "xiaomi";
+const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_FLUSH_SECONDS: u64 = 10;
+// while InfluxDB is away, older readings are dropped past this.
+const MAX_PENDING: usize = 10000;
+// after a failed write, the next one waits the flush interval, doubled on every failure up to this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+// Declared as [influxdb] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct InfluxConfig {
+    // "http://localhost:8086"
+    pub url: Option<String>,
+    // 1.x
+    pub database: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // 2.x
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+    // default is "xiaomi".
+    pub measurement: Option<String>,
+    // readings sent in one request. default is 100.
+    pub batch_size: Option<usize>,
+    // readings are sent at least this often, in seconds. default is 10.
+    pub flush_seconds: Option<u64>,
+}
+
+// where readings are written to, and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteRequest {
+    pub url: String,
+    pub query: Vec<(&'static str, String)>,
+    pub authorization: Option<String>,
+}
+
+// 2.x when there's a bucket, 1.x otherwise. timestamps are in seconds, as readings are.
+pub fn write_request(config: &InfluxConfig) -> Result<WriteRequest, String> {
+    let url = config.url.as_deref().ok_or("no url in [influxdb]")?;
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("invalid InfluxDB url \"{}\". Use http://host:8086", url));
+    }
+    let url = url.trim_end_matches('/');
+    if let Some(bucket) = &config.bucket {
+        let mut query = vec![("bucket", bucket.clone()), ("precision", "s".to_string())];
+        if let Some(org) = &config.org {
+            query.push(("org", org.clone()));
+        }
+        return Ok(WriteRequest {
+            url: format!("{}/api/v2/write", url),
+            query: query,
+            authorization: config.token.as_ref().map(|token| format!("Token {}", token)),
+        });
+    }
+    let database = config.database.as_ref().ok_or("[influxdb] needs `database` for InfluxDB 1.x, or `bucket` for 2.x")?;
+    let mut query = vec![("db", database.clone()), ("precision", "s".to_string())];
+    if let Some(username) = &config.username {
+        query.push(("u", username.clone()));
+    }
+    if let Some(password) = &config.password {
+        query.push(("p", password.clone()));
+    }
+    return Ok(WriteRequest { url: format!("{}/write", url), query: query, authorization: None });
+}
+
+// commas, spaces and equal signs are escaped in tags. measurements escape commas and spaces only.
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    return escaped;
+}
+
+// tags without a value are left out. line protocol has no empty tags.
+pub fn format_line(measurement: &str, tags: &[(&str, Option<&str>)], metric: &str, value: f32, time: u64) -> String {
+    let mut line = escape(measurement, &[',', ' ']);
+    for (key, value) in tags {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            line.push_str(&format!(",{}={}", key, escape(value, &[',', ' ', '='])));
+        }
+    }
+    return format!("{} {}={} {}", line, metric, value, time);
+}
+
+// lines waiting to be written. sent when the batch is full, or it's been a while.
+// while InfluxDB is away, writes are tried less and less often, so readings aren't held up by a request each.
+pub struct Batch {
+    request: WriteRequest,
+    measurement: String,
+    batch_size: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    lines: VecDeque<String>,
+    last_flush: Instant,
+    // writes failed in a row.
+    failures: u32,
+}
+
+impl Batch {
+    pub fn new(config: &InfluxConfig, timeout: Duration) -> Result<Batch, String> {
+        return Ok(Batch {
+            request: write_request(config)?,
+            measurement: config.measurement.clone().unwrap_or(DEFAULT_MEASUREMENT.to_string()),
+            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+            flush_interval: Duration::from_secs(config.flush_seconds.unwrap_or(DEFAULT_FLUSH_SECONDS)),
+            timeout: timeout,
+            lines: VecDeque::new(),
+            last_flush: Instant::now(),
+            failures: 0,
+        });
+    }
+
+    pub fn add(&mut self, tags: &[(&str, Option<&str>)], metric: &str, value: f32, time: u64) -> Result<(), String> {
+        if self.lines.len() >= MAX_PENDING {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(format_line(&self.measurement, tags, metric, value, time));
+        // a full batch waits for the retry after a failure, like `flush_if_due`.
+        if self.lines.len() >= self.batch_size && self.last_flush.elapsed() >= self.retry_delay() {
+            return self.flush();
+        }
+        return Ok(());
+    }
+
+    // zero until a write fails.
+    fn retry_delay(&self) -> Duration {
+        if self.failures == 0 {
+            return Duration::ZERO;
+        }
+        // a second at least, with flush_seconds = 0.
+        let delay = self.flush_interval.max(Duration::from_secs(1));
+        return delay.saturating_mul(1 << (self.failures - 1).min(16)).min(MAX_RETRY_DELAY);
+    }
+
+    // readings not written yet.
+    pub fn pending(&self) -> usize {
+        return self.lines.len();
+    }
+
+    pub fn flush_if_due(&mut self) -> Result<(), String> {
+        if self.last_flush.elapsed() < self.flush_interval.max(self.retry_delay()) {
+            return Ok(());
+        }
+        return self.flush();
+    }
+
+    // lines are kept when writing fails, and sent with the next batch.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.last_flush = Instant::now();
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(self.timeout)).build().into();
+        let mut request = agent.post(&self.request.url).header("Content-Type", "text/plain; charset=utf-8");
+        for (key, value) in &self.request.query {
+            request = request.query(key, value);
+        }
+        if let Some(authorization) = &self.request.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        if let Err(e) = request.send(self.lines.make_contiguous().join("\n")) {
+            self.failures += 1;
+            return Err(format!("{}: {}", self.request.url, e));
+        }
+        self.failures = 0;
+        self.lines.clear();
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_request() {
+        let config: InfluxConfig = toml::from_str("url = \"http://localhost:8086/\"\ndatabase = \"home\"\nusername = \"me\"").unwrap();
+        let request = write_request(&config).unwrap();
+        assert_eq!(request.url, "http://localhost:8086/write");
+        assert_eq!(request.query, [("db", "home".to_string()), ("precision", "s".to_string()), ("u", "me".to_string())]);
+        assert_eq!(request.authorization, None);
+
+        let config: InfluxConfig = toml::from_str("url = \"https://influx.local\"\norg = \"home\"\nbucket = \"sensors\"\ntoken = \"abc\"").unwrap();
+        let request = write_request(&config).unwrap();
+        assert_eq!(request.url, "https://influx.local/api/v2/write");
+        assert_eq!(request.query, [("bucket", "sensors".to_string()), ("precision", "s".to_string()), ("org", "home".to_string())]);
+        assert_eq!(request.authorization.as_deref(), Some("Token abc"));
+
+        assert!(write_request(&toml::from_str("url = \"http://localhost:8086\"").unwrap()).is_err());
+        assert!(write_request(&toml::from_str("url = \"localhost:8086\"\ndatabase = \"home\"").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_format_line() {
+        let tags = [("address", Some("AA:BB:CC:DD:EE:FF")), ("name", Some("Tokyo")), ("group", None)];
+        assert_eq!(format_line("xiaomi", &tags, "temperature", 23.4, 1696891938),
+            "xiaomi,address=AA:BB:CC:DD:EE:FF,name=Tokyo temperature=23.4 1696891938");
+
+        let tags = [("name", Some("Living room, north=1"))];
+        assert_eq!(format_line("my sensors", &tags, "humidity", 68.0, 1),
+            "my\\ sensors,name=Living\\ room\\,\\ north\\=1 humidity=68 1");
+    }
+
+    #[test]
+    fn test_batch_retry() {
+        // nothing listens on port 1, so writes fail right away.
+        let config: InfluxConfig = toml::from_str("url = \"http://127.0.0.1:1\"\ndatabase = \"home\"\nbatch_size = 1").unwrap();
+        let mut batch = Batch::new(&config, Duration::from_secs(1)).unwrap();
+        assert!(batch.add(&[], "temperature", 23.4, 1).is_err());
+        // the next write waits for the retry delay, instead of another request per reading.
+        let started = Instant::now();
+        for time in 2..100 {
+            assert!(batch.add(&[], "temperature", 23.4, time).is_ok());
+            assert!(batch.flush_if_due().is_ok());
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(batch.pending(), 99);
+        assert_eq!(batch.retry_delay(), Duration::from_secs(10));
+        batch.failures = 9;
+        assert_eq!(batch.retry_delay(), MAX_RETRY_DELAY);
+
+        // the oldest are dropped.
+        for time in 100..(MAX_PENDING as u64 + 10) {
+            batch.add(&[], "temperature", 23.4, time).unwrap();
+        }
+        assert_eq!(batch.pending(), MAX_PENDING);
+        assert_eq!(batch.lines.front().unwrap(), "xiaomi temperature=23.4 10");
+    }
+}
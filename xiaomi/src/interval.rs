@@ -0,0 +1,93 @@
+// Advertising interval of a device, estimated from when its advertisements are heard.
+// Scanning misses some advertisements, so a gap can be 2 or 3 intervals. Median of the gaps
+// ignores those, as long as most advertisements are heard.
+
+// gaps kept for the estimate. old ones are dropped, so a changed interval shows up.
+const MAX_GAPS: usize = 32;
+// gaps shorter than this are the same advertisement heard twice, on another channel.
+const MIN_GAP_MILLISECONDS: u64 = 20;
+
+#[derive(Debug, Default)]
+pub struct IntervalEstimator {
+    last_heard: Option<u64>,
+    gaps: Vec<u64>,
+}
+
+impl IntervalEstimator {
+    // `time` is in milliseconds, from any fixed point.
+    pub fn record(&mut self, time: u64) {
+        if let Some(last) = self.last_heard {
+            let gap = time.saturating_sub(last);
+            if gap < MIN_GAP_MILLISECONDS {
+                return;
+            }
+            if self.gaps.len() >= MAX_GAPS {
+                self.gaps.remove(0);
+            }
+            self.gaps.push(gap);
+        }
+        self.last_heard = Some(time);
+    }
+
+    // estimated interval in milliseconds. None until a few advertisements are heard.
+    pub fn estimate(&self) -> Option<u64> {
+        if self.gaps.len() < 3 {
+            return None;
+        }
+        let mut sorted = self.gaps.clone();
+        sorted.sort();
+        return Some(sorted[sorted.len() / 2]);
+    }
+}
+
+// "850ms", "1.5s", "1m 0s"
+pub fn format_interval(milliseconds: u64) -> String {
+    if milliseconds < 1000 {
+        return format!("{}ms", milliseconds);
+    }
+    if milliseconds < 60000 {
+        return format!("{:.1}s", milliseconds as f64 / 1000.0);
+    }
+    return crate::humanize::format_duration(milliseconds / 1000);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let mut estimator = IntervalEstimator::default();
+        estimator.record(0);
+        estimator.record(1500);
+        estimator.record(3010);
+        assert_eq!(estimator.estimate(), None);
+
+        // missed one advertisement, and heard one twice.
+        estimator.record(6000);
+        estimator.record(6005);
+        estimator.record(7490);
+        estimator.record(9000);
+        assert_eq!(estimator.estimate(), Some(1510));
+    }
+
+    #[test]
+    fn test_estimate_changed_interval() {
+        let mut estimator = IntervalEstimator::default();
+        for i in 0..MAX_GAPS as u64 {
+            estimator.record(i * 1000);
+        }
+        let start = MAX_GAPS as u64 * 1000;
+        for i in 0..MAX_GAPS as u64 {
+            estimator.record(start + i * 5000);
+        }
+        assert_eq!(estimator.estimate(), Some(5000));
+    }
+
+    #[test]
+    fn test_format_interval() {
+        assert_eq!(format_interval(850), "850ms");
+        assert_eq!(format_interval(1510), "1.5s");
+        assert_eq!(format_interval(60000), "1m 0s");
+    }
+}
@@ -1,5 +1,50 @@
 // This file contains utilities
 
+pub mod api;
+pub mod auth;
+pub mod bus;
+pub mod capture;
+pub mod climate;
+pub mod clock;
+pub mod dashboard;
+pub mod decoder;
+pub mod discharge;
+pub mod firmware;
+pub mod fixtures;
+pub mod gatt;
+pub mod graphite;
+pub mod help;
+pub mod history;
+pub mod http;
+pub mod humanize;
+pub mod influxdb;
+pub mod interval;
+pub mod lint;
+pub mod logging;
+pub mod mdns;
+pub mod model;
+pub mod mqtt;
+pub mod ntp;
+pub mod otlp;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_file;
+pub mod perfcounter;
+pub mod prometheus;
+pub mod schedule;
+pub mod sinks;
+pub mod soak;
+pub mod state;
+pub mod store;
+pub mod template;
+pub mod theme;
+pub mod timesource;
+pub mod trigger;
+pub mod unit;
+pub mod web;
+pub mod webhook;
+pub mod workers;
+
 // bluetooth address is 6 bytes. put ':' character as a seperator.
 pub fn format_bluetooth_address(value: u64) -> String {
     let bytes = [
@@ -63,20 +108,211 @@ pub fn get_unix_epoc() -> u64 {
     return duration.as_secs();
 }
 
-use chrono::Offset;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use chrono::{NaiveDate, Offset};
 use serde::{Deserialize, Deserializer, de::Error};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub general: GeneralConfig,
     #[serde(rename = "device")]
     pub devices: Option<Vec<DeviceConfig>>,
+    #[serde(default)]
+    pub theme: theme::ThemeConfig,
+    #[serde(default)]
+    pub mqtt: mqtt::MqttConfig,
+    #[serde(default)]
+    pub influxdb: influxdb::InfluxConfig,
+    #[serde(default)]
+    pub pushgateway: prometheus::PushgatewayConfig,
+    #[serde(default)]
+    pub otlp: otlp::OtlpConfig,
+    #[serde(default)]
+    pub graphite: graphite::GraphiteConfig,
+    #[serde(default)]
+    pub serve: auth::ServeConfig,
+    #[serde(rename = "webhook")]
+    pub webhooks: Option<Vec<webhook::WebhookConfig>>,
+}
+
+// Filesystem access used to load config. Tests use a fake one.
+pub trait ConfigFs {
+    fn exists(&self, path: &Path) -> bool;
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String>;
+}
+
+pub struct RealFs;
+
+impl ConfigFs for RealFs {
+    fn exists(&self, path: &Path) -> bool {
+        return path.exists();
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        return std::fs::read_to_string(path);
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NoExePath(std::io::Error),
+    NotFound(PathBuf),
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            ConfigError::NoExePath(e) => write!(f, "Cannot find exe path: {}", e),
+            ConfigError::NotFound(path) => write!(f, "Cannot find toml at {}", path.display()),
+            ConfigError::Read(path, e) => write!(f, "Reading {} failed: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Parsing {} failed: {}", path.display(), e),
+        };
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // config file is next to the exe, with the same name. xiaomi.toml for xiaomi.exe
+    pub fn default_path() -> Result<PathBuf, ConfigError> {
+        let exe_path = std::env::current_exe().map_err(ConfigError::NoExePath)?;
+        return Ok(exe_path.with_extension("toml"));
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        return Config::load_with(&RealFs, path);
+    }
+
+    pub fn load_with(fs: &dyn ConfigFs, path: &Path) -> Result<Config, ConfigError> {
+        if !fs.exists(path) {
+            return Err(ConfigError::NotFound(path.to_path_buf()));
+        }
+        let content = fs.read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        return toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e));
+    }
+
+    // config at the default path. config file is optional, so missing file is an empty config.
+    pub fn discover() -> Result<Config, ConfigError> {
+        return Config::discover_with(&RealFs, &Config::default_path()?);
+    }
+
+    pub fn discover_with(fs: &dyn ConfigFs, path: &Path) -> Result<Config, ConfigError> {
+        return match Config::load_with(fs, path) {
+            Err(ConfigError::NotFound(_)) => Ok(Config::default()),
+            result => result,
+        };
+    }
+}
+
+// Settings applied to all devices. Declared as [general] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct GeneralConfig {
+    // How long `sync` listens to BLE advertisements. Can be overridden by --duration.
+    pub sync_duration_seconds: Option<u64>,
+    // How long `scan` listens to BLE advertisements. Can be overridden by --duration. Default is 10.
+    pub scan_duration_seconds: Option<u64>,
+    // How many times a failed sync is retried. Default is 2.
+    pub sync_retry_count: Option<u32>,
+    // Delay before the first retry. Doubled on every retry. Default is 500.
+    pub sync_retry_delay_milliseconds: Option<u64>,
+    // How long a single bluetooth GATT operation can take. Default is 10.
+    pub gatt_timeout_seconds: Option<u64>,
+    // Clock is not written when it's off by this or less. Default is 5.
+    pub sync_drift_threshold_seconds: Option<u64>,
+    // Time read back after sync can differ from written time by this. Default is 2.
+    pub sync_verify_tolerance_seconds: Option<u64>,
+    // How many devices are synced at the same time. Default is 2.
+    pub sync_workers: Option<usize>,
+    // Always run `scan` in fleet mode. Same as --fleet.
+    pub fleet_mode: Option<bool>,
+    // Split summary tables by this many rows. Default is 50 in fleet mode, unlimited otherwise.
+    pub summary_page_size: Option<usize>,
+    // How to show timezones not on the hour. "truncate", "round-nearest" or "error". Default is "truncate".
+    pub timezone_policy: Option<clock::TimezonePolicy>,
+    // Where the time written to clocks comes from. "system" or "ntp". Default is "system".
+    pub time_source: Option<TimeSource>,
+    // NTP servers tried in order. Default is time.windows.com, then pool.ntp.org.
+    pub ntp_servers: Option<Vec<String>>,
+    // `sync` warns when battery read from a device is below this, in percent. Default is 20.
+    pub low_battery_percent: Option<u8>,
+    // Minutes between sync runs of `daemon`. Can be overridden by --interval. Default is 60.
+    pub daemon_interval_minutes: Option<u64>,
+    // `daemon` syncs devices not synced for this long, and reads drift of the others. Default is 24.
+    pub daemon_sync_age_hours: Option<u64>,
+    // Summary of every sync run is POSTed to this URL as JSON. Deprecated, it's a [[webhook]] with "sync" in events.
+    pub sync_webhook_url: Option<String>,
+    // `sync` only touches devices in toml. For apartment buildings, full of other people's sensors.
+    pub strict: Option<bool>,
+    // `scan` only shows devices in toml.
+    pub strict_scan: Option<bool>,
+    // `sync` ignores advertisements from random addresses. Phones and earbuds use them, xiaomi clocks don't.
+    pub ignore_random_addresses: Option<bool>,
+    // Formula of "feels like" temperature of `scan --comfort`. "heat-index" or "humidex". Default is "heat-index".
+    pub apparent_temperature: Option<climate::ApparentFormula>,
+    // Digits after the point of readings shown and exported. Readings are shown as received without it.
+    pub precision: Option<usize>,
+    // `scan` and `sync` ignore advertisements weaker than this, in dBm. Can be overridden by --rssi-min.
+    pub rssi_min: Option<i16>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimeSource {
+    #[default]
+    System,
+    Ntp,
+}
+
+impl GeneralConfig {
+    pub fn gatt_timeout(&self) -> std::time::Duration {
+        return std::time::Duration::from_secs(self.gatt_timeout_seconds.unwrap_or(10));
+    }
+
+    pub fn ntp_servers(&self) -> Vec<String> {
+        return match &self.ntp_servers {
+            Some(servers) => servers.clone(),
+            None => ntp::DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect(),
+        };
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        return RetryPolicy {
+            count: self.sync_retry_count.unwrap_or(2),
+            delay: std::time::Duration::from_millis(self.sync_retry_delay_milliseconds.unwrap_or(500)),
+        };
+    }
 }
 
-#[derive(Debug, Deserialize)]
+// Retry with exponential backoff.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // number of retries after the first attempt.
+    pub count: u32,
+    // delay before the first retry.
+    pub delay: std::time::Duration,
+}
+
+impl RetryPolicy {
+    // Delay before given retry. retry starts from 1. Capped to 1 minute.
+    pub fn delay_before(&self, retry: u32) -> std::time::Duration {
+        let max = std::time::Duration::from_secs(60);
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        return self.delay.checked_mul(factor).map_or(max, |d| d.min(max));
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct DeviceConfig {
     // Bluetooth device's address
     #[serde(deserialize_with = "string_to_bluetooth_address")]
     pub address: u64,
+    // Other addresses of the same device. Some firmware changes MAC after battery swap.
+    #[serde(default, deserialize_with = "strings_to_bluetooth_addresses")]
+    pub addresses: Vec<u64>,
     // Name of device
     pub name: Option<String>,
     // Omit this device. We will not sync.
@@ -84,6 +320,17 @@ pub struct DeviceConfig {
     // Timezone declared by https://docs.rs/chrono-tz/latest/chrono_tz/
     pub timezone: Option<String>,
     pub offset_seconds: Option<i32>,
+    // Temperature unit shown on the display, "c" or "f". Set during sync when it's different.
+    pub unit: Option<unit::TemperatureUnit>,
+    // Free text, like where the device is.
+    pub notes: Option<String>,
+    // Devices in a group are synced or scanned together, with --group.
+    pub group: Option<String>,
+    // Dates as "2023-10-01". Battery age is counted from `battery_changed`, or `purchased` when it's never changed.
+    #[serde(default, deserialize_with = "string_to_date")]
+    pub battery_changed: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "string_to_date")]
+    pub purchased: Option<NaiveDate>,
 }
 
 // Custom parser for bluetooth address string.
@@ -98,6 +345,35 @@ where
     }
 }
 
+// Custom parser for a list of bluetooth address strings.
+fn strings_to_bluetooth_addresses<'de, D>(deserializer: D) -> Result<Vec<u64>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let list = Vec::<String>::deserialize(deserializer)?;
+    let mut addresses: Vec<u64> = Vec::new();
+    for s in list {
+        match decode_bluetooth_adddress(&s) {
+            Ok(decoded) => addresses.push(decoded),
+            Err(msg) => return Err(D::Error::custom(msg)),
+        }
+    }
+    return Ok(addresses);
+}
+
+// Custom parser for a date string like "2023-10-01".
+fn string_to_date<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    return match Option::<String>::deserialize(deserializer)? {
+        Some(s) => NaiveDate::parse_from_str(&s, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| D::Error::custom(format!("invalid date \"{}\". Use YYYY-MM-DD", s))),
+        None => Ok(None),
+    };
+}
+
 #[allow(dead_code)]
 impl Config {
     pub fn get_device_by_name(&self, name: &str) -> Option<&DeviceConfig> {
@@ -110,24 +386,195 @@ impl Config {
         }
         return None;
     }
+
+    // Address identifying the device with given name or address. Addresses not in config are taken as they are,
+    // only in full 6 bytes form, so a mistyped name is not read as a short hex number.
+    pub fn resolve_device(&self, name_or_address: &str) -> Result<u64, String> {
+        if let Some(device) = self.get_device_by_name(name_or_address) {
+            return Ok(device.address);
+        }
+
+        if name_or_address.len() == 12 || name_or_address.contains(':') {
+            if let Ok(address) = decode_bluetooth_adddress(name_or_address) {
+                for d in self.devices.iter().flatten() {
+                    if d.address == address || d.addresses.contains(&address) {
+                        return Ok(d.address);
+                    }
+                }
+                return Ok(address);
+            }
+        }
+
+        return Err(format!("Unknown device \"{}\". Use a name in toml, or a bluetooth address.", name_or_address));
+    }
+
+    // Devices in any of `groups` are included along with `include`.
+    pub fn device_filter(&self, include: &[String], groups: &[String], exclude: &[String]) -> Result<DeviceFilter, String> {
+        let mut filter = DeviceFilter::default();
+        if !include.is_empty() || !groups.is_empty() {
+            let mut addresses: HashSet<u64> = HashSet::new();
+            for name in include {
+                addresses.insert(self.resolve_device(name)?);
+            }
+            for group in groups {
+                addresses.extend(self.group_addresses(group)?);
+            }
+            filter.include = Some(addresses);
+        }
+        for name in exclude {
+            filter.exclude.insert(self.resolve_device(name)?);
+        }
+        return Ok(filter);
+    }
+
+    // First change of difference from UTC of devices in `within` seconds after `after`, like daylight saving time,
+    // and addresses of the devices changing then. Omitted devices are left out.
+    pub fn next_offset_change(&self, after: i64, within: i64) -> Option<(i64, HashSet<u64>)> {
+        let changes: Vec<(i64, u64)> = self.devices.iter().flatten()
+            .filter(|d| !d.omit.unwrap_or(false))
+            .filter_map(|d| d.next_offset_change(after, within).map(|time| (time, d.address)))
+            .collect();
+        let first = changes.iter().map(|(time, _)| *time).min()?;
+        return Some((first, changes.iter().filter(|(time, _)| *time == first).map(|(_, address)| *address).collect()));
+    }
+
+    // [[webhook]]s, and sync_webhook_url as one of them.
+    pub fn webhooks(&self) -> Vec<webhook::WebhookConfig> {
+        let mut webhooks = self.webhooks.clone().unwrap_or_default();
+        if let Some(url) = &self.general.sync_webhook_url {
+            webhooks.push(webhook::sync_webhook(url));
+        }
+        return webhooks;
+    }
+
+    // Addresses identifying configured devices. Aliases are not included.
+    pub fn configured_addresses(&self) -> HashSet<u64> {
+        return self.devices.iter().flatten().map(|d| d.address).collect();
+    }
+
+    // Addresses of devices in the group. Group names are case insensitive.
+    pub fn group_addresses(&self, group: &str) -> Result<Vec<u64>, String> {
+        let addresses: Vec<u64> = self.devices.iter().flatten()
+            .filter(|d| d.group.as_ref().is_some_and(|g| g.to_lowercase() == group.to_lowercase()))
+            .map(|d| d.address)
+            .collect();
+        if addresses.is_empty() {
+            return Err(format!("Unknown group \"{}\". Set `group` of devices in toml.", group));
+        }
+        return Ok(addresses);
+    }
+
+    // Index devices by bluetooth address. Aliases in `addresses` map to the same device,
+    // so `map[alias].address` is the address identifying the logical device.
+    pub fn into_device_map(self) -> HashMap<u64, DeviceConfig> {
+        let mut map: HashMap<u64, DeviceConfig> = HashMap::new();
+        for d in self.devices.into_iter().flatten() {
+            for alias in &d.addresses {
+                map.insert(*alias, d.clone());
+            }
+            map.insert(d.address, d);
+        }
+        return map;
+    }
+}
+
+// Devices selected on command line. Addresses identify logical devices, see `resolve_device_address`.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceFilter {
+    // None selects every device.
+    pub include: Option<HashSet<u64>>,
+    pub exclude: HashSet<u64>,
+}
+
+impl DeviceFilter {
+    // leaves out devices not in `allowed`.
+    pub fn restrict(&mut self, allowed: &HashSet<u64>) {
+        self.include = Some(match &self.include {
+            Some(include) => include.intersection(allowed).copied().collect(),
+            None => allowed.clone(),
+        });
+    }
+
+    pub fn matches(&self, device_address: u64) -> bool {
+        if self.exclude.contains(&device_address) {
+            return false;
+        }
+        return self.include.as_ref().is_none_or(|include| include.contains(&device_address));
+    }
+}
+
+// Returns the address identifying the logical device, following `addresses` aliases.
+pub fn resolve_device_address(devices: &HashMap<u64, DeviceConfig>, address: u64) -> u64 {
+    return devices.get(&address).map_or(address, |d| d.address);
 }
 
 impl DeviceConfig {
     pub fn get_timezone_diff_hour(&self) -> Option<i8> {
+        return self.get_timezone_hour(clock::TimezonePolicy::Truncate).unwrap_or(None);
+    }
+
+    // Timezone hour to write to the clock. Err when the timezone is unknown, or not on the hour and policy is Error.
+    pub fn get_timezone_hour(&self, policy: clock::TimezonePolicy) -> Result<Option<i8>, String> {
+        return self.timezone_offset_seconds()?.map(|diff_seconds| clock::timezone_hour(diff_seconds, policy)).transpose();
+    }
+
+    // Days since the battery was put in. None when neither date is set.
+    pub fn battery_age_days(&self, today: NaiveDate) -> Option<i64> {
+        let since = self.battery_changed.or(self.purchased)?;
+        return Some((today - since).num_days());
+    }
+
+    // Current difference from UTC of the timezone. None when timezone is not set.
+    // Err when it's unknown, instead of falling back to the default timezone and writing a wrong hour.
+    pub fn timezone_offset_seconds(&self) -> Result<Option<i32>, String> {
+        return self.timezone_offset_seconds_at(chrono::Utc::now().timestamp());
+    }
+
+    // Difference from UTC of the timezone at `unix` seconds, daylight saving time included.
+    pub fn timezone_offset_seconds_at(&self, unix: i64) -> Result<Option<i32>, String> {
         use chrono::{Utc, DateTime};
         use chrono_tz::Tz;
 
         if let Some(name) = &self.timezone {
-            let tz: Tz = name.parse().unwrap();
+            let tz: Tz = match name.parse() {
+                Ok(tz) => tz,
+                Err(_) => return Err(format!("unknown timezone \"{}\". Use a name from the tz database, like \"Asia/Seoul\"", name)),
+            };
+
+            let utc: DateTime<Utc> = match DateTime::from_timestamp(unix, 0) {
+                Some(utc) => utc,
+                None => return Err(format!("time {} is out of range", unix)),
+            };
+            let local: DateTime<Tz> = utc.with_timezone(&tz);
+            return Ok(Some(local.offset().fix().local_minus_utc()));
+        }
 
-            // Get utc time.
-            let utc_now: DateTime<Utc> = Utc::now();
-            let local_now: DateTime<Tz> = utc_now.with_timezone(&tz);
-            let diff_seconds = local_now.offset().fix().local_minus_utc();
+        return Ok(None);
+    }
 
-            return Some((diff_seconds / 3600) as i8);
+    // First second in `within` seconds after `after` with another difference from UTC, like when daylight saving
+    // time starts or ends. None when it stays the same, or timezone is not set.
+    pub fn next_offset_change(&self, after: i64, within: i64) -> Option<i64> {
+        let offset = self.timezone_offset_seconds_at(after).ok()??;
+        let end = after + within;
+        // changes are months apart. looked for an hour at a time, then the hour is halved down to the second.
+        let mut time = after;
+        while time < end {
+            let next = (time + 3600).min(end);
+            if self.timezone_offset_seconds_at(next).ok()?? != offset {
+                let (mut low, mut high) = (time, next);
+                while high - low > 1 {
+                    let middle = low + (high - low) / 2;
+                    if self.timezone_offset_seconds_at(middle).ok()?? == offset {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+                return Some(high);
+            }
+            time = next;
         }
-
         return None;
     }
 }
@@ -173,6 +620,75 @@ mod tests {
 
         let config: Config = toml::from_str(&s).unwrap();
         assert!(config.devices.is_some());
+        assert!(config.general.sync_duration_seconds.is_none());
+    }
+
+    #[test]
+    fn test_toml_general() {
+        let s = r#"
+        [general]
+        sync_duration_seconds = 60
+        "#;
+
+        let config: Config = toml::from_str(&s).unwrap();
+        assert!(config.devices.is_none());
+        assert_eq!(config.general.sync_duration_seconds.unwrap(), 60);
+        assert!(config.general.timezone_policy.is_none());
+
+        let config: Config = toml::from_str("[general]\ntimezone_policy = \"round-nearest\"").unwrap();
+        assert_eq!(config.general.timezone_policy, Some(clock::TimezonePolicy::RoundNearest));
+        assert!(toml::from_str::<Config>("[general]\ntimezone_policy = \"floor\"").is_err());
+
+        let config: Config = toml::from_str("[general]\napparent_temperature = \"humidex\"").unwrap();
+        assert_eq!(config.general.apparent_temperature, Some(climate::ApparentFormula::Humidex));
+    }
+
+    #[test]
+    fn test_time_source() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.general.time_source.unwrap_or_default(), TimeSource::System);
+        assert_eq!(config.general.ntp_servers(), vec!["time.windows.com", "pool.ntp.org"]);
+
+        let s = r#"
+        [general]
+        time_source = "ntp"
+        ntp_servers = ["ntp.example.com"]
+        "#;
+        let config: Config = toml::from_str(&s).unwrap();
+        assert_eq!(config.general.time_source, Some(TimeSource::Ntp));
+        assert_eq!(config.general.ntp_servers(), vec!["ntp.example.com"]);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let config: Config = toml::from_str("").unwrap();
+        let policy = config.general.retry_policy();
+        assert_eq!(policy.count, 2);
+        assert_eq!(policy.delay_before(1).as_millis(), 500);
+
+        let s = r#"
+        [general]
+        sync_retry_count = 5
+        sync_retry_delay_milliseconds = 100
+        "#;
+        let config: Config = toml::from_str(&s).unwrap();
+        let policy = config.general.retry_policy();
+        assert_eq!(policy.count, 5);
+        assert_eq!(policy.delay_before(1).as_millis(), 100);
+        assert_eq!(policy.delay_before(2).as_millis(), 200);
+        assert_eq!(policy.delay_before(3).as_millis(), 400);
+
+        // never waits too long.
+        assert_eq!(policy.delay_before(40).as_secs(), 60);
+    }
+
+    #[test]
+    fn test_gatt_timeout() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.general.gatt_timeout().as_secs(), 10);
+
+        let config: Config = toml::from_str("[general]\ngatt_timeout_seconds = 3").unwrap();
+        assert_eq!(config.general.gatt_timeout().as_secs(), 3);
     }
 
     #[test]
@@ -218,4 +734,196 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_unknown_timezone() {
+        // misspelled. it must not fall back to the default timezone.
+        let device = DeviceConfig { address: 0x112233445566, timezone: Some("Asia/Seol".to_string()), ..Default::default() };
+        assert!(device.timezone_offset_seconds().is_err());
+        assert!(device.get_timezone_hour(clock::TimezonePolicy::Truncate).unwrap_err().contains("Asia/Seol"));
+
+        let device = DeviceConfig { address: 0x112233445566, ..Default::default() };
+        assert_eq!(device.get_timezone_hour(clock::TimezonePolicy::Truncate), Ok(None));
+    }
+
+    #[test]
+    fn test_next_offset_change() {
+        // 2024-03-31 01:00 UTC, summer time starts in Berlin.
+        let change = 1711846800;
+        let berlin = DeviceConfig { address: 0x112233445566, timezone: Some("Europe/Berlin".to_string()), ..Default::default() };
+        assert_eq!(berlin.timezone_offset_seconds_at(change - 1), Ok(Some(3600)));
+        assert_eq!(berlin.timezone_offset_seconds_at(change), Ok(Some(7200)));
+        assert_eq!(berlin.next_offset_change(change - 3 * 3600 - 17, 6 * 3600), Some(change));
+        assert_eq!(berlin.next_offset_change(change - 3600, 600), None);
+        assert_eq!(berlin.next_offset_change(change, 3600), None);
+
+        let seoul = DeviceConfig { address: 0xAABBCCDDEEFF, timezone: Some("Asia/Seoul".to_string()), ..Default::default() };
+        assert_eq!(seoul.next_offset_change(change - 3600, 3600), None);
+        assert_eq!(DeviceConfig::default().next_offset_change(change - 3600, 3600), None);
+
+        let config = Config { devices: Some(vec![berlin.clone(), seoul]), ..Default::default() };
+        assert_eq!(config.next_offset_change(change - 3600, 3600), Some((change, HashSet::from([0x112233445566]))));
+        let config = Config { devices: Some(vec![DeviceConfig { omit: Some(true), ..berlin }]), ..Default::default() };
+        assert_eq!(config.next_offset_change(change - 3600, 3600), None);
+    }
+
+    #[test]
+    fn test_toml_addresses() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        addresses = ["AA:BB:CC:DD:EE:FF", "010203040506"]
+        name = "test1"
+
+        [[device]]
+        address = "665544332211"
+        "#;
+
+        let config: Config = toml::from_str(&s).unwrap();
+        let test1 = config.get_device_by_name("test1").unwrap();
+        assert_eq!(test1.addresses, vec![0xAABBCCDDEEFF, 0x010203040506]);
+
+        let devices = config.into_device_map();
+        assert_eq!(devices.len(), 4);
+        assert_eq!(resolve_device_address(&devices, 0xAABBCCDDEEFF), 0x112233445566);
+        assert_eq!(resolve_device_address(&devices, 0x010203040506), 0x112233445566);
+        assert_eq!(resolve_device_address(&devices, 0x665544332211), 0x665544332211);
+        assert!(devices.get(&0x665544332211).unwrap().addresses.is_empty());
+
+        // not configured device is identified by its own address.
+        assert_eq!(resolve_device_address(&devices, 0x0A0B0C0D0E0F), 0x0A0B0C0D0E0F);
+
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        addresses = ["11:22"]
+        "#;
+        assert!(toml::from_str::<Config>(&s).is_err());
+    }
+
+    #[test]
+    fn test_toml_metadata() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        name = "test1"
+        notes = "kitchen, above the fridge"
+        purchased = "2023-01-15"
+
+        [[device]]
+        address = "665544332211"
+        name = "test2"
+        purchased = "2023-01-15"
+        battery_changed = "2023-10-01"
+        "#;
+
+        let config: Config = toml::from_str(&s).unwrap();
+        let today = NaiveDate::from_ymd_opt(2023, 10, 10).unwrap();
+        let test1 = config.get_device_by_name("test1").unwrap();
+        assert_eq!(test1.notes.as_deref(), Some("kitchen, above the fridge"));
+        assert_eq!(test1.battery_changed, None);
+        assert_eq!(test1.battery_age_days(today), Some(268));
+        assert_eq!(config.get_device_by_name("test2").unwrap().battery_age_days(today), Some(9));
+
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        battery_changed = "10/01/2023"
+        "#;
+        assert!(toml::from_str::<Config>(&s).is_err());
+    }
+
+    // files in memory.
+    struct FakeFs(HashMap<PathBuf, String>);
+
+    impl ConfigFs for FakeFs {
+        fn exists(&self, path: &Path) -> bool {
+            return self.0.contains_key(path);
+        }
+
+        fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+            return self.0.get(path).cloned().ok_or(std::io::Error::from(std::io::ErrorKind::NotFound));
+        }
+    }
+
+    #[test]
+    fn test_load_config() {
+        let mut files: HashMap<PathBuf, String> = HashMap::new();
+        files.insert(PathBuf::from("good.toml"), "[[device]]\naddress = \"112233445566\"\nname = \"test\"".to_string());
+        files.insert(PathBuf::from("bad.toml"), "[[device]]\nname = \"no address\"".to_string());
+        let fs = FakeFs(files);
+
+        let config = Config::load_with(&fs, Path::new("good.toml")).unwrap();
+        assert_eq!(config.get_device_by_name("test").unwrap().address, 0x112233445566);
+
+        let error = Config::load_with(&fs, Path::new("bad.toml")).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse(_, _)));
+        assert!(error.to_string().starts_with("Parsing bad.toml failed"));
+
+        assert!(matches!(Config::load_with(&fs, Path::new("missing.toml")), Err(ConfigError::NotFound(_))));
+
+        // config file is optional.
+        let config = Config::discover_with(&fs, Path::new("missing.toml")).unwrap();
+        assert!(config.devices.is_none());
+        assert!(Config::discover_with(&fs, Path::new("bad.toml")).is_err());
+    }
+
+    #[test]
+    fn test_device_filter() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        addresses = ["AA:BB:CC:DD:EE:FF"]
+        name = "Bedroom"
+
+        group = "upstairs"
+
+        [[device]]
+        address = "665544332211"
+        name = "Kitchen"
+
+        [[device]]
+        address = "010203040506"
+        name = "Attic"
+        group = "Upstairs"
+        "#;
+        let config: Config = toml::from_str(&s).unwrap();
+
+        assert_eq!(config.resolve_device("bedroom"), Ok(0x112233445566));
+        assert_eq!(config.resolve_device("AA:BB:CC:DD:EE:FF"), Ok(0x112233445566));
+        assert_eq!(config.resolve_device("0A0B0C0D0E0F"), Ok(0x0A0B0C0D0E0F));
+        // short hex is not an address.
+        assert!(config.resolve_device("bed").is_err());
+        assert!(config.resolve_device("garage").is_err());
+
+        let all = config.device_filter(&[], &[], &[]).unwrap();
+        assert!(all.matches(0x112233445566) && all.matches(0x0A0B0C0D0E0F));
+
+        let filter = config.device_filter(&["Bedroom".to_string(), "kitchen".to_string()], &[], &[]).unwrap();
+        assert!(filter.matches(0x112233445566));
+        assert!(filter.matches(0x665544332211));
+        assert!(!filter.matches(0x0A0B0C0D0E0F));
+
+        let filter = config.device_filter(&[], &[], &["AA:BB:CC:DD:EE:FF".to_string()]).unwrap();
+        assert!(!filter.matches(0x112233445566));
+        assert!(filter.matches(0x665544332211));
+
+        assert!(config.device_filter(&["garage".to_string()], &[], &[]).is_err());
+
+        // group, and a device not in the group.
+        let filter = config.device_filter(&["kitchen".to_string()], &["UPSTAIRS".to_string()], &["attic".to_string()]).unwrap();
+        assert!(filter.matches(0x112233445566));
+        assert!(filter.matches(0x665544332211));
+        assert!(!filter.matches(0x010203040506));
+        assert!(config.device_filter(&[], &["cellar".to_string()], &[]).is_err());
+
+        // strict mode. an address given on command line is still out, when it's not in toml.
+        let mut filter = config.device_filter(&["kitchen".to_string(), "0A0B0C0D0E0F".to_string()], &[], &[]).unwrap();
+        filter.restrict(&config.configured_addresses());
+        assert!(filter.matches(0x665544332211));
+        assert!(!filter.matches(0x0A0B0C0D0E0F));
+        let mut filter = config.device_filter(&[], &[], &[]).unwrap();
+        filter.restrict(&config.configured_addresses());
+        assert!(filter.matches(0x112233445566));
+        assert!(!filter.matches(0x0A0B0C0D0E0F));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,414 @@
+// Semantic checks of the config. Each config is valid on its own, but some combinations
+// are likely mistakes, and some can't work. Every lint comes with a suggestion how to fix it.
+
+use std::collections::HashMap;
+
+use crate::{Config, format_bluetooth_address};
+
+// offsets beyond this are likely meant to be a timezone.
+const MAX_SENSIBLE_OFFSET_SECONDS: i32 = 3600;
+
+#[derive(Debug, PartialEq)]
+pub struct Lint {
+    // name or address of the device. None for general settings.
+    pub device: Option<String>,
+    pub message: String,
+    pub suggestion: String,
+    // settings that can't work as written, as opposed to likely mistakes. `config check` fails on them.
+    pub error: bool,
+}
+
+pub fn lint_config(config: &Config) -> Vec<Lint> {
+    let mut lints: Vec<Lint> = Vec::new();
+    // strict and strict_scan only touch addresses of [[device]].
+    let strict = config.general.strict.unwrap_or(false) || config.general.strict_scan.unwrap_or(false);
+
+    for device in config.devices.iter().flatten() {
+        let name = device.name.clone().unwrap_or(format_bluetooth_address(device.address));
+        let mut add = |error: bool, message: String, suggestion: &str| {
+            lints.push(Lint { device: Some(name.clone()), message: message, suggestion: suggestion.to_string(), error: error });
+        };
+
+        if let Some(offset) = device.offset_seconds {
+            if offset.abs() > MAX_SENSIBLE_OFFSET_SECONDS {
+                add(true, format!("offset_seconds {} is more than an hour", offset),
+                    "Use `timezone` to change the hour, and keep `offset_seconds` for minutes ahead or behind.");
+            }
+        }
+
+        if let Some(timezone) = &device.timezone {
+            if timezone.parse::<chrono_tz::Tz>().is_err() {
+                add(true, format!("unknown timezone \"{}\"", timezone),
+                    "Use a name from the tz database, for example \"Asia/Seoul\".");
+            }
+            else if config.general.timezone_policy.is_none() && device.timezone_offset_seconds().is_ok_and(|s| s.is_some_and(|s| s % 3600 != 0)) {
+                add(false, format!("timezone \"{}\" doesn't fall on the hour", timezone),
+                    "The clock can only show whole hours, and minutes are truncated. Set `timezone_policy` in [general] to choose how they are shown.");
+            }
+        }
+
+        if device.omit == Some(true) && (device.timezone.is_some() || device.offset_seconds.is_some()) {
+            add(false, "omit = true, so timezone and offset_seconds are never used".to_string(),
+                "Remove `omit` to sync this device, or remove the unused settings.");
+        }
+
+        for address in [device.address].iter().chain(&device.addresses) {
+            if strict && (*address == 0 || *address == 0xFFFFFFFFFFFF) {
+                add(false, format!("{} is not an address of a device, so strict never matches it", format_bluetooth_address(*address)),
+                    "Use the address `scan` shows for this device.");
+            }
+        }
+
+        if device.addresses.contains(&device.address) {
+            add(false, "`addresses` repeats `address`".to_string(),
+                "List only other addresses of this device in `addresses`.");
+        }
+    }
+
+    // omitted devices aren't synced either.
+    let synced = config.devices.iter().flatten().filter(|d| d.omit != Some(true)).count();
+    let scanned = config.devices.iter().flatten().count();
+    let allowlists = [
+        ("strict", config.general.strict, synced, "`sync` and `daemon` touch"),
+        ("strict_scan", config.general.strict_scan, scanned, "`scan` shows"),
+    ];
+    for (setting, on, devices, commands) in allowlists {
+        if on == Some(true) && devices == 0 {
+            lints.push(Lint {
+                device: None,
+                message: format!("{} = true without a [[device]] to match, so {} no device", setting, commands),
+                suggestion: "Add devices with `setup` or `config add`, or remove the setting.".to_string(),
+                error: false,
+            });
+        }
+    }
+
+    if let Some(rssi_min) = config.general.rssi_min {
+        if rssi_min >= 0 {
+            lints.push(Lint {
+                device: None,
+                message: format!("rssi_min {} is not negative, so every advertisement is ignored", rssi_min),
+                suggestion: "Signal strength is negative dBm. -80 ignores weak ones, like devices next door.".to_string(),
+                error: true,
+            });
+        }
+    }
+
+    let mqtt = &config.mqtt;
+    if let Some(Err(msg)) = mqtt.url.as_deref().map(crate::mqtt::parse_url) {
+        lints.push(Lint { device: None, message: msg, suggestion: "`scan --mqtt` can't connect with it.".to_string(), error: true });
+    }
+    if let Some(qos) = mqtt.qos.filter(|qos| *qos > 1) {
+        lints.push(Lint {
+            device: None,
+            message: format!("mqtt qos {} isn't supported", qos),
+            suggestion: "Use 0, or 1 for readings the broker acknowledges.".to_string(),
+            error: true,
+        });
+    }
+    if mqtt.topic.as_deref().is_some_and(|topic| !topic.contains("{name}") && !topic.contains("{address}")) {
+        lints.push(Lint {
+            device: None,
+            message: "mqtt topic has neither {name} nor {address}, so every device publishes to the same topic".to_string(),
+            suggestion: "Put {name} or {address} in `topic`, like \"xiaomi/{name}/{metric}\".".to_string(),
+            error: false,
+        });
+    }
+    else if let Some(Err(msg)) = mqtt.topic.as_deref().map(crate::mqtt::subscription) {
+        lints.push(Lint { device: None, message: msg, suggestion: "`serve --gateway` can't read readings with it.".to_string(), error: false });
+    }
+
+    if config.influxdb.url.is_some() {
+        if let Err(msg) = crate::influxdb::write_request(&config.influxdb) {
+            lints.push(Lint { device: None, message: msg, suggestion: "`scan --influxdb` can't write with it.".to_string(), error: true });
+        }
+    }
+    if config.pushgateway.url.is_some() || config.pushgateway.job.is_some() {
+        if let Err(msg) = crate::prometheus::push_url(&config.pushgateway, "scan") {
+            lints.push(Lint { device: None, message: msg, suggestion: "`--pushgateway` can't push with it.".to_string(), error: true });
+        }
+    }
+    let otlp = &config.otlp;
+    if otlp.endpoint.is_some() || otlp.service_name.is_some() || otlp.headers.is_some() {
+        if let Err(msg) = crate::otlp::check_config(otlp) {
+            lints.push(Lint { device: None, message: msg, suggestion: "`--otlp` can't export with it.".to_string(), error: true });
+        }
+    }
+    let graphite = &config.graphite;
+    if graphite.host.is_none() && (graphite.port.is_some() || graphite.prefix.is_some()) {
+        lints.push(Lint {
+            device: None,
+            message: "no host in [graphite]".to_string(),
+            suggestion: "`scan --graphite` can't connect without it. Set `host`, like \"graphite.local\".".to_string(),
+            error: true,
+        });
+    }
+    if let Err(msg) = crate::auth::check_config(&config.serve) {
+        lints.push(Lint { device: None, message: msg, suggestion: "`serve` can't start with it.".to_string(), error: true });
+    }
+    for webhook in config.webhooks() {
+        if let Err(msg) = crate::webhook::check_config(&webhook) {
+            lints.push(Lint { device: None, message: msg, suggestion: "`scan` and `serve` can't start with it.".to_string(), error: true });
+        }
+    }
+    if let Some(url) = &config.general.sync_webhook_url {
+        lints.push(Lint {
+            device: None,
+            message: "sync_webhook_url is deprecated".to_string(),
+            suggestion: format!("Move it to a [[webhook]] with url = \"{}\" and events = [\"sync\"].", url),
+            error: false,
+        });
+    }
+
+    // readings of an address go to one device. which one is up to the order of the file.
+    let mut owners: HashMap<u64, String> = HashMap::new();
+    for device in config.devices.iter().flatten() {
+        let name = device.name.clone().unwrap_or(format_bluetooth_address(device.address));
+        let mut addresses = vec![device.address];
+        addresses.extend(device.addresses.iter().filter(|a| **a != device.address));
+        for address in addresses {
+            match owners.get(&address) {
+                Some(owner) => lints.push(Lint {
+                    device: Some(name.clone()),
+                    message: format!("{} is also an address of {}", format_bluetooth_address(address), owner),
+                    suggestion: "Keep each address in one [[device]]. Use `addresses` of one device for its other addresses.".to_string(),
+                    error: true,
+                }),
+                None => {
+                    owners.insert(address, name.clone());
+                },
+            }
+        }
+    }
+
+    return lints;
+}
+
+// keys in toml that no setting reads, like "timezon", as paths like "device.0.timezon".
+// they are ignored when config is loaded, so a typo silently falls back to the default.
+pub fn unknown_keys(text: &str) -> Result<Vec<String>, String> {
+    let mut unknown: Vec<String> = Vec::new();
+    let deserializer = toml::Deserializer::new(text);
+    // optional settings are shown as "?" in the path. they aren't keys.
+    serde_ignored::deserialize(deserializer, |path| unknown.push(path.to_string().replace("?.", "")))
+        .map(|_: Config| ())
+        .map_err(|e| e.to_string())?;
+    return Ok(unknown);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_config() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        name = "fine"
+        timezone = "Asia/Seoul"
+        offset_seconds = 300
+
+        [[device]]
+        address = "11:22:33:44:55:67"
+        name = "offset"
+        timezone = "Asia/Seoul"
+        offset_seconds = 32400
+
+        [[device]]
+        address = "11:22:33:44:55:68"
+        timezone = "Asia/Kolkata"
+
+        [[device]]
+        address = "11:22:33:44:55:69"
+        name = "omit"
+        omit = true
+        timezone = "Asia/Seoul"
+
+        [[device]]
+        address = "11:22:33:44:55:6A"
+        name = "alias"
+        addresses = ["11:22:33:44:55:6A"]
+        timezone = "Mars/Olympus"
+        "#;
+
+        let config: Config = toml::from_str(&s).unwrap();
+        let lints = lint_config(&config);
+        let devices: Vec<&str> = lints.iter().map(|l| l.device.as_deref().unwrap()).collect();
+        assert_eq!(devices, vec!["offset", "11:22:33:44:55:68", "omit", "alias", "alias"]);
+        assert!(lints[1].message.contains("Asia/Kolkata"));
+        assert!(lints[3].message.contains("Mars/Olympus"));
+        assert!(lints.iter().all(|l| !l.suggestion.is_empty()));
+        let errors: Vec<bool> = lints.iter().map(|l| l.error).collect();
+        assert_eq!(errors, vec![true, false, false, true, false]);
+    }
+
+    #[test]
+    fn test_strict() {
+        let config: Config = toml::from_str("[general]\nstrict = true\nstrict_scan = true").unwrap();
+        let lints = lint_config(&config);
+        assert_eq!(lints.len(), 2);
+        assert!(lints[0].message.starts_with("strict = true") && lints[1].message.starts_with("strict_scan = true"));
+        assert!(lints.iter().all(|l| !l.error && l.device.is_none()));
+
+        // omitted devices are scanned, never synced.
+        let s = r#"
+        [general]
+        strict = true
+        strict_scan = true
+
+        [[device]]
+        address = "11:22:33:44:55:66"
+        omit = true
+        "#;
+        let lints = lint_config(&toml::from_str(s).unwrap());
+        assert_eq!(lints.len(), 1);
+        assert!(lints[0].message.starts_with("strict = true"));
+
+        let s = r#"
+        [general]
+        strict = true
+
+        [[device]]
+        address = "00:00:00:00:00:00"
+        name = "Tokyo"
+
+        [[device]]
+        address = "11:22:33:44:55:66"
+        addresses = ["FF:FF:FF:FF:FF:FF"]
+        "#;
+        let lints = lint_config(&toml::from_str(s).unwrap());
+        assert_eq!(lints.len(), 2);
+        assert_eq!(lints[0].device.as_deref(), Some("Tokyo"));
+        assert!(lints[1].message.contains("FF:FF:FF:FF:FF:FF"));
+
+        // the same devices are fine without strict.
+        let lints = lint_config(&toml::from_str(&s.replace("strict = true", "")).unwrap());
+        assert!(lints.is_empty());
+    }
+
+    #[test]
+    fn test_rssi_min() {
+        let config: Config = toml::from_str("[general]\nrssi_min = 80").unwrap();
+        let lints = lint_config(&config);
+        assert_eq!(lints.len(), 1);
+        assert!(lints[0].error && lints[0].device.is_none());
+
+        let config: Config = toml::from_str("[general]\nrssi_min = -80").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_mqtt() {
+        let config: Config = toml::from_str("[mqtt]\nurl = \"tcp://broker\"\nqos = 2\ntopic = \"xiaomi/{metric}\"").unwrap();
+        let lints = lint_config(&config);
+        assert_eq!(lints.iter().map(|l| l.error).collect::<Vec<_>>(), [true, true, false]);
+
+        let config: Config = toml::from_str("[mqtt]\nurl = \"mqtts://broker:8883\"\nqos = 1\ntopic = \"home/{address}/{metric}\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+
+        let config: Config = toml::from_str("[mqtt]\ntopic = \"xiaomi/{name}-{metric}\"").unwrap();
+        let lints = lint_config(&config);
+        assert_eq!(lints.len(), 1);
+        assert!(!lints[0].error && lints[0].suggestion.contains("--gateway"));
+    }
+
+    #[test]
+    fn test_influxdb() {
+        let config: Config = toml::from_str("[influxdb]\nurl = \"http://localhost:8086\"").unwrap();
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[influxdb]\nurl = \"http://localhost:8086\"\ndatabase = \"home\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_otlp() {
+        let config: Config = toml::from_str("[otlp]\nendpoint = \"localhost:4317\"").unwrap();
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[otlp]\nendpoint = \"http://localhost:4318\"\nservice_name = \"bedroom-pc\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_graphite() {
+        let config: Config = toml::from_str("[graphite]\nport = 2003").unwrap();
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[graphite]\nhost = \"graphite.local\"\nprefix = \"home.sensors\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_serve() {
+        let config: Config = toml::from_str("[serve]\nusername = \"family\"").unwrap();
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[serve]\ntoken = \"abc\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_webhooks() {
+        let config: Config = toml::from_str("[[webhook]]\nurl = \"https://ntfy.sh/xiaomi\"\n\n[[webhook]]\nevents = [\"reading\"]").unwrap();
+        assert_eq!(lint_config(&config).len(), 1);
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[general]\nsync_webhook_url = \"http://localhost:1880/sync\"").unwrap();
+        let lints = lint_config(&config);
+        assert_eq!(lints.len(), 1);
+        assert!(!lints[0].error);
+        assert_eq!(lints[0].suggestion, "Move it to a [[webhook]] with url = \"http://localhost:1880/sync\" and events = [\"sync\"].");
+    }
+
+    #[test]
+    fn test_pushgateway() {
+        let config: Config = toml::from_str("[pushgateway]\njob = \"bedroom-pc\"").unwrap();
+        assert!(lint_config(&config)[0].error);
+
+        let config: Config = toml::from_str("[pushgateway]\nurl = \"http://localhost:9091\"").unwrap();
+        assert!(lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_addresses() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        name = "Tokyo"
+        addresses = ["11:22:33:44:55:00"]
+
+        [[device]]
+        address = "11:22:33:44:55:00"
+        name = "Osaka"
+
+        [[device]]
+        address = "11:22:33:44:55:66"
+        "#;
+
+        let config: Config = toml::from_str(&s).unwrap();
+        let messages: Vec<String> = lint_config(&config).into_iter().filter(|l| l.error).map(|l| l.message).collect();
+        assert_eq!(messages, vec!["11:22:33:44:55:00 is also an address of Tokyo", "11:22:33:44:55:66 is also an address of Tokyo"]);
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let s = r#"
+        [[device]]
+        address = "11:22:33:44:55:66"
+        timezon = "Asia/Seoul"
+
+        [general]
+        sync_workers = 2
+        sync_worker = 4
+
+        [colors]
+        ok = "blue"
+        "#;
+
+        assert_eq!(unknown_keys(s), Ok(vec!["device.0.timezon".to_string(), "general.sync_worker".to_string(), "colors".to_string()]));
+        assert_eq!(unknown_keys(crate::template::CONFIG), Ok(vec![]));
+        assert!(unknown_keys("[[device]]\naddress = 1").is_err());
+    }
+}
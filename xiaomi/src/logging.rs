@@ -0,0 +1,78 @@
+// Diagnostics of every command go through `log` macros, filtered by -q and -v. Output of commands doesn't,
+// it's printed whatever the level is.
+//
+//   -q    errors only
+//         warnings, like a state file which can't be read
+//   -v    steps of syncing and other bluetooth operations, intervals of devices
+//   -vv   connecting and querying GATT, frames the decoder skips, frame metadata
+//   -vvv  every frame received, in hex
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+pub fn level_filter(quiet: bool, verbose: u8) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    return match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+}
+
+// messages of dependencies are left out. they're about HTTP and TLS, not devices.
+fn is_ours(target: &str) -> bool {
+    return target == "xiaomi" || target.starts_with("xiaomi::");
+}
+
+struct Logger {
+    level: LevelFilter,
+    // prints a message. the binary knows about spinners and colors.
+    write: fn(Level, &str),
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.level() <= self.level && is_ours(metadata.target());
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            (self.write)(record.level(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// once, before anything is logged. messages logged before are dropped.
+pub fn init(level: LevelFilter, write: fn(Level, &str)) {
+    if log::set_boxed_logger(Box::new(Logger { level: level, write: write })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        assert_eq!(level_filter(false, 0), LevelFilter::Warn);
+        assert_eq!(level_filter(false, 2), LevelFilter::Debug);
+        assert_eq!(level_filter(false, 5), LevelFilter::Trace);
+        assert_eq!(level_filter(true, 0), LevelFilter::Error);
+    }
+
+    #[test]
+    fn test_enabled() {
+        let logger = Logger { level: LevelFilter::Info, write: |_, _| {} };
+        let metadata = |level: Level, target: &'static str| Metadata::builder().level(level).target(target).build();
+        assert!(logger.enabled(&metadata(Level::Info, "xiaomi::decoder")));
+        assert!(logger.enabled(&metadata(Level::Warn, "xiaomi")));
+        assert!(!logger.enabled(&metadata(Level::Debug, "xiaomi::decoder")));
+        assert!(!logger.enabled(&metadata(Level::Warn, "ureq::unversioned")));
+        assert!(!logger.enabled(&metadata(Level::Info, "xiaomi_other")));
+    }
+}
@@ -1,241 +1,1639 @@
 // How to use clap:
 // https://github.com/clap-rs/clap/blob/master/examples/tutorial_derive/01_quick.rs
 // https://docs.rs/clap/latest/clap/_derive/index.html
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use std::{
     collections::{HashMap, HashSet},
     error::Error,
+    fmt,
+    io::{IsTerminal, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    thread,
     time,
-    sync::{Arc, Mutex},
-    sync::mpsc::{Sender, Receiver},
+    sync::{Arc, Mutex, OnceLock},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
     sync::mpsc,
 };
-use windows::{
-    Devices::Bluetooth::Advertisement::{*},
-    Foundation::TypedEventHandler
-};
+use windows::Devices::Bluetooth::Advertisement::{*};
+use windows::Devices::Radios::RadioState;
+use windows::Win32::System::ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+use windows::Win32::System::Threading::GetCurrentProcess;
 #[macro_use] extern crate prettytable;
-use prettytable::Table;
-use indicatif::{ProgressBar, ProgressStyle};
-use console::{style, Emoji};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use console::style;
 
 mod ble;
+mod context;
+#[cfg(windows)]
+mod counters;
+#[cfg(windows)]
+mod service;
 use ble::AdvertisementKind;
-use xiaomi::{Config, DeviceConfig, format_bluetooth_address};
+use context::AppContext;
+use xiaomi::{Config, ConfigError, DeviceConfig, DeviceFilter, GeneralConfig, decode_bluetooth_adddress, format_bluetooth_address, resolve_device_address};
+use xiaomi::bus::EventBus;
+use xiaomi::capture::{self, CapturedFrame};
+use xiaomi::{api, auth, climate, decoder, discharge, firmware, fixtures, gatt, help, http, humanize, interval, lint, logging, mdns, model, mqtt, otlp, prometheus, schedule, soak, template, web, webhook};
+use xiaomi::interval::IntervalEstimator;
+use xiaomi::store::{self, Metric, Store, StoredReading};
+use xiaomi::timesource::{self, TimeProvider};
+use xiaomi::clock::{self, ClockTime, target_clock_time};
+use xiaomi::history::{self, HistoryRecord};
+use xiaomi::theme::Theme;
+use xiaomi::unit::TemperatureUnit;
+use xiaomi::sinks::{self, SinkSelection, Sinks};
+use xiaomi::state::State;
+use xiaomi::trigger::Trigger;
+use xiaomi::workers::WorkerPool;
+use xiaomi::output::{self, OutputFormat, ScanSort, StreamFormat};
+use xiaomi::decoder::Reading;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+
+static CHECKBOX: Symbol = Symbol("✅ ", "* ");
+static TEMPERATURE: Symbol = Symbol("🌡️", "Temp");
+static HUMIDITY: Symbol = Symbol("💧", "Humid");
+static BATTERY: Symbol = Symbol("🔋", "Batt");
+static EXCLAMATION: Symbol = Symbol("⚠️", "<!>");
+
+// emoji, and the text shown instead in ASCII mode.
+struct Symbol(&'static str, &'static str);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if theme().ascii {
+            return f.write_str(self.1);
+        }
+        return f.write_str(self.0);
+    }
+}
+
+// events BLE threads can queue before the UI thread reads them. BLE handlers wait when it's full.
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+// daemon looks for xiaomi.sync this often while it waits for the next run.
+const TRIGGER_POLL: time::Duration = time::Duration::from_secs(1);
+// daemon syncs devices this long after their timezone offset changed.
+const OFFSET_CHANGE_DELAY_SECONDS: i64 = 5;
+
+// set once the config is loaded. messages before that use the default theme.
+static THEME: OnceLock<Theme> = OnceLock::new();
+// digits after the point of readings, from precision of [general].
+static PRECISION: OnceLock<Option<usize>> = OnceLock::new();
+
+// --ascii, or stdout can't show emoji: a file, or a terminal without UTF-8. set before anything is printed.
+static ASCII: AtomicBool = AtomicBool::new(false);
+// --no-color, NO_COLOR, or output which isn't a terminal, where escape sequences would end up in a log.
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+
+// log messages are printed above it while it's drawn, instead of over it. the last spinner made by `new_spinner`.
+static LOG_SPINNER: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+// set by Ctrl-C. scan, sync and daemon stop listening, let devices being synced finish, and print the summary.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn stop_requested() -> bool {
+    return STOP_REQUESTED.load(Ordering::SeqCst);
+}
+
+// exit code of a shell for Ctrl-C.
+fn exit_if_stopped() {
+    if stop_requested() {
+        std::process::exit(130);
+    }
+}
+
+fn theme() -> &'static Theme {
+    return THEME.get_or_init(|| for_terminal(Theme::default()));
+}
+
+// --ascii and --no-color, or what the terminal can show, override [theme].
+fn for_terminal(theme: Theme) -> Theme {
+    let mut theme = theme;
+    theme.ascii |= ASCII.load(Ordering::SeqCst);
+    if NO_COLOR.load(Ordering::SeqCst) {
+        return theme.without_colors();
+    }
+    return theme;
+}
+
+// readings are shown as received until the config is loaded.
+fn precision() -> Option<usize> {
+    return *PRECISION.get_or_init(|| None);
+}
 
-static CHECKBOX: Emoji<'_, '_> = Emoji("✅ ", "* ");
-static TEMPERATURE: Emoji<'_, '_> = Emoji("🌡️", "Temp");
-static HUMIDITY: Emoji<'_, '_> = Emoji("💧", "Humid");
-static BATTERY: Emoji<'_, '_> = Emoji("🔋", "Batt");
-static EXCLAMATION: Emoji<'_, '_> = Emoji("⚠️", "<!>");
+fn set_display(config: &Config) {
+    THEME.set(for_terminal(Theme::from_config(&config.theme))).ok();
+    PRECISION.set(config.general.precision).ok();
+}
 
 #[derive(Parser)]
-#[command(long_about = None)]
+#[command(long_about = help::CLI)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    /// Show detailed messages
-    #[arg(short, long, global = true)]
-    verbose: bool,
+    /// Show detailed messages: -v steps of bluetooth operations, -vv GATT and decoder diagnostics, -vvv every frame
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Show errors only, without warnings
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Output of scan, sync, status, battery and toml: table, json or csv. Messages go to stderr with json and csv
+    #[arg(long, global = true, default_value = "table")]
+    format: OutputFormat,
+
+    /// Plain ASCII instead of emoji, box borders and spinners. Automatic when output isn't a UTF-8 terminal
+    #[arg(long, global = true)]
+    ascii: bool,
+
+    /// No colors. Also when NO_COLOR is set, or output isn't a terminal
+    #[arg(long, global = true)]
+    no_color: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Scan Xiaomi BLE devices
-    Scan,
+    #[command(long_about = help::SCAN)]
+    Scan {
+        /// Append raw advertisement frames to this file
+        #[arg(long)]
+        capture: Option<PathBuf>,
+
+        /// Show device count instead of every reading, and page the summary. For hundreds of devices
+        #[arg(long)]
+        fleet: bool,
+
+        /// Store readings in this SQLite database. Repeated frames are stored once
+        #[arg(long)]
+        store: Option<PathBuf>,
+
+        /// Only devices in these groups
+        #[arg(long, value_delimiter = ',')]
+        group: Vec<String>,
+
+        /// Seconds to listen to BLE advertisements [default: 10]
+        #[arg(short, long)]
+        duration: Option<u64>,
+
+        /// Keep scanning until Ctrl-C, redrawing a table of the latest values in place
+        #[arg(short, long, conflicts_with = "duration")]
+        watch: bool,
+
+        /// Print each reading as it arrives until Ctrl-C, without spinner or table. ndjson is a JSON object per line
+        #[arg(long, value_name = "FORMAT", conflicts_with_all = ["duration", "watch", "fleet"])]
+        stream: Option<StreamFormat>,
+
+        /// Order of the summary: name, temperature, humidity, battery or rssi. Devices without the value go last
+        #[arg(long, default_value = "name", conflicts_with = "stream")]
+        sort: ScanSort,
+
+        /// Sort in descending order, like hottest or strongest signal first
+        #[arg(long)]
+        desc: bool,
+
+        /// Add dew point and absolute humidity to the summary, and to --stream
+        #[arg(long)]
+        dew_point: bool,
+
+        /// Add "feels like" temperature and comfort (dry, comfortable or humid) to the summary
+        #[arg(long)]
+        comfort: bool,
+
+        /// Ignore advertisements weaker than this, in dBm, like -80 for devices next door
+        #[arg(long, value_name = "DBM", allow_negative_numbers = true)]
+        rssi_min: Option<i16>,
+
+        /// Publish readings to the MQTT broker in [mqtt] of toml. With --watch or --stream, until Ctrl-C
+        #[arg(long)]
+        mqtt: bool,
+
+        /// Write readings to InfluxDB in [influxdb] of toml, in batches
+        #[arg(long)]
+        influxdb: bool,
+
+        /// Push the latest readings to the Pushgateway in [pushgateway] of toml, when the scan ends
+        #[arg(long)]
+        pushgateway: bool,
+
+        /// Export readings and a span of the scan to the OpenTelemetry collector in [otlp] of toml
+        #[arg(long)]
+        otlp: bool,
+
+        /// Send readings to Graphite in [graphite] of toml, with the plaintext protocol
+        #[arg(long)]
+        graphite: bool,
+    },
+    /// Full screen view of devices around, with trends and alerts. q to quit
+    #[command(long_about = help::DASHBOARD)]
+    Dashboard {
+        /// Only devices in these groups
+        #[arg(long, value_delimiter = ',')]
+        group: Vec<String>,
+    },
+    /// Keep monitoring for hours, checking for panics, a stuck watcher, memory and queue depth. Prints pass or fail
+    #[command(long_about = help::SOAK)]
+    Soak {
+        /// How long to run. Ctrl-C ends it early, with the report so far
+        #[arg(long, default_value_t = 24.0)]
+        hours: f64,
+
+        /// Memory ceiling of the process, in MB
+        #[arg(long, default_value_t = 200)]
+        memory_mb: u64,
+    },
     /// Sync xiaomi clock devices
-    Sync { name: Option<String> },
+    #[command(long_about = help::SYNC)]
+    Sync {
+        /// Names or addresses of devices to sync. Every device when omitted
+        names: Vec<String>,
+
+        /// Names or addresses of devices not to sync
+        #[arg(long, value_delimiter = ',')]
+        exclude: Vec<String>,
+
+        /// Sync devices in these groups, along with names given
+        #[arg(long, value_delimiter = ',')]
+        group: Vec<String>,
+
+        /// Seconds to listen to BLE advertisements [default: 30]
+        #[arg(short, long)]
+        duration: Option<u64>,
+
+        /// Connect and measure drift, print what would be written, but never write
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Write even when the clock is close enough. Each device is still written once per run
+        #[arg(long)]
+        force: bool,
+
+        /// Use this timezone for every device instead of the config, like "Asia/Tokyo"
+        #[arg(long, value_parser = parse_timezone)]
+        timezone: Option<String>,
+
+        /// Set every clock ahead (or behind, when negative) by this instead of the config
+        #[arg(long, allow_negative_numbers = true)]
+        offset_seconds: Option<i32>,
+
+        /// Ignore advertisements weaker than this, in dBm, like -80 for devices next door
+        #[arg(long, value_name = "DBM", allow_negative_numbers = true)]
+        rssi_min: Option<i16>,
+
+        /// Push the summary to the Pushgateway in [pushgateway] of toml, when the run ends
+        #[arg(long)]
+        pushgateway: bool,
+
+        /// Export sync metrics and a span of the run to the OpenTelemetry collector in [otlp] of toml
+        #[arg(long)]
+        otlp: bool,
+    },
+
+    /// Wait for a reading of a device and print it. With --metric, only the value, for scripts
+    #[command(long_about = help::READ)]
+    Read {
+        /// Name or address of the device
+        device: String,
+
+        /// temperature, humidity or battery. Temperature and humidity when omitted
+        #[arg(long)]
+        metric: Option<Metric>,
+
+        /// Seconds to wait for the reading [default: 60]
+        #[arg(short, long)]
+        duration: Option<u64>,
+    },
+
+    /// Read time of configured clocks and show drift, without writing
+    #[command(long_about = help::DRIFT)]
+    Drift,
+
+    /// Read time of a clock, or all configured clocks, as it is
+    #[command(long_about = help::GET_TIME)]
+    GetTime {
+        /// Name or address of the device. All configured devices when omitted
+        device: Option<String>,
+    },
+
+    /// Write given time to a clock, as it is. For testing drift, or offsetting a clock on purpose
+    #[command(long_about = help::SET_TIME)]
+    #[command(group(ArgGroup::new("time").required(true).args(["epoch", "iso"])))]
+    SetTime {
+        /// Name or address of the device
+        device: String,
+
+        /// Unix epoch to write
+        #[arg(long)]
+        epoch: Option<u32>,
+
+        /// Date and time to write, like 2023-10-10T07:52:18+09:00
+        #[arg(long, value_parser = clock::parse_iso_time)]
+        iso: Option<u32>,
+
+        /// Timezone hour shown on the clock. From config when omitted
+        #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i8).range(-12..=14))]
+        timezone_hour: Option<i8>,
+    },
+
+    /// Set the temperature unit shown on the display
+    #[command(long_about = help::SET_UNIT)]
+    SetUnit {
+        /// Name or address of the device
+        device: String,
+
+        /// c or f
+        unit: TemperatureUnit,
+    },
+
+    /// Hourly min/max history kept by the device, or readings stored by `scan --store`
+    #[command(long_about = help::HISTORY)]
+    History {
+        #[command(subcommand)]
+        command: HistoryCommands,
+    },
+
+    /// Read the Device Information service and list GATT services of a device. For diagnosing firmware specific issues
+    #[command(long_about = help::INFO)]
+    Info {
+        /// Name or address of the device
+        device: String,
+    },
+
+    /// Read, write or subscribe to any GATT characteristic. For exploring new devices
+    #[command(long_about = help::GATT)]
+    Gatt {
+        #[command(subcommand)]
+        command: GattCommands,
+    },
+
+    /// Firmware version of the device
+    #[command(long_about = help::FIRMWARE)]
+    Firmware {
+        #[command(subcommand)]
+        command: FirmwareCommands,
+    },
+
+    /// Stay running, and sync devices not synced for a while on every interval
+    #[command(long_about = help::DAEMON)]
+    Daemon {
+        /// Minutes between sync runs. From config when omitted
+        #[arg(long)]
+        interval: Option<u64>,
+    },
+
+    /// Keep scanning until Ctrl-C, serving the latest readings over HTTP
+    #[command(long_about = help::SERVE)]
+    #[command(group(ArgGroup::new("endpoints").required(true).multiple(true).args(["prometheus", "api", "web", "perf_counters"])))]
+    Serve {
+        /// Serve Prometheus metrics at /metrics, listening on this address like :9184 or 127.0.0.1:9184
+        #[arg(long, value_name = "ADDRESS")]
+        prometheus: Option<String>,
+
+        /// Serve the REST API at /devices, listening on this address like :8080. Can be the same as --prometheus
+        #[arg(long, value_name = "ADDRESS")]
+        api: Option<String>,
+
+        /// Serve a dashboard page at /, listening on this address like :8080. Can be the same as --api
+        #[arg(long, value_name = "ADDRESS")]
+        web: Option<String>,
+
+        /// Set Windows performance counters of each device, installed with `xiaomi counters install`
+        #[arg(long)]
+        perf_counters: bool,
+
+        /// Don't advertise on the local network with mDNS
+        #[arg(long)]
+        no_mdns: bool,
+
+        /// Merge readings other computers publish to [mqtt] with `scan --mqtt`
+        #[arg(long)]
+        gateway: bool,
+
+        /// Only devices in these groups
+        #[arg(long, value_delimiter = ',')]
+        group: Vec<String>,
+    },
+
+    /// Run `daemon` as a Windows service
+    #[command(long_about = help::SERVICE)]
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommands,
+    },
+
+    /// Register Windows performance counters of `serve --perf-counters`
+    #[command(long_about = help::COUNTERS)]
+    Counters {
+        #[command(subcommand)]
+        command: CounterCommands,
+    },
+
+    /// Run `sync` periodically with Task Scheduler, while you're logged on
+    #[command(long_about = help::SCHEDULE)]
+    Schedule {
+        /// How often, like 30m, 12h or 1d
+        #[arg(long, value_parser = schedule::parse_every, required_unless_present = "remove")]
+        every: Option<schedule::Every>,
+
+        /// Remove the task instead
+        #[arg(long, conflicts_with = "every")]
+        remove: bool,
+
+        /// Names or addresses passed to `sync`. All devices when omitted
+        names: Vec<String>,
+
+        /// Pass --pushgateway to `sync`
+        #[arg(long, conflicts_with = "remove")]
+        pushgateway: bool,
+    },
+
+    /// Write readings as CSV or JSON, from a store of `scan --store` or a scan for a while
+    #[command(long_about = help::EXPORT)]
+    #[command(group(ArgGroup::new("source").required(true).args(["store", "scan"])))]
+    Export {
+        /// SQLite database written by `scan --store`
+        #[arg(long)]
+        store: Option<PathBuf>,
+
+        /// Scan for this many seconds, and write readings heard
+        #[arg(long, value_name = "SECONDS")]
+        scan: Option<u64>,
+
+        /// Only readings since this long ago, like 30m, 2h or 7d. All readings when omitted
+        #[arg(long, value_parser = humanize::parse_duration, conflicts_with = "scan")]
+        since: Option<u64>,
+
+        /// Only readings until this long ago, or now
+        #[arg(long, value_parser = humanize::parse_ago, conflicts_with = "scan")]
+        until: Option<u64>,
+
+        /// File to write. Readings are printed when omitted
+        #[arg(long, alias = "csv")]
+        out: Option<PathBuf>,
+
+        /// Write Parquet to --out instead of CSV. Needs a build with `--features parquet`
+        #[arg(long, requires = "out")]
+        parquet: bool,
+    },
+
+    /// List battery levels of configured devices, lowest first. Exits with 1 when any is low
+    #[command(long_about = help::BATTERY)]
+    Battery {
+        /// Only devices in these groups
+        #[arg(long, value_delimiter = ',')]
+        group: Vec<String>,
+
+        /// Seconds to listen to BLE advertisements. Ends early when every device is heard [default: 60]
+        #[arg(short, long)]
+        duration: Option<u64>,
+
+        /// Connect to devices whose battery wasn't advertised, and read it. LYWSD02 only
+        #[arg(long)]
+        read: bool,
+
+        /// Low battery threshold in percent, instead of low_battery_percent of config
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        below: Option<u8>,
+
+        /// Store levels in this SQLite database, and show how fast each battery runs down from levels stored there
+        #[arg(long)]
+        store: Option<PathBuf>,
+    },
+
+    /// Show when configured devices were last seen and synced
+    #[command(long_about = help::STATUS)]
+    Status,
 
     /// Read toml file and print
+    #[command(long_about = help::TOML)]
     Toml,
+
+    /// Find devices around, ask a name and timezone for each, and write toml. For the first run
+    #[command(long_about = help::SETUP)]
+    Setup {
+        /// Seconds to listen to BLE advertisements [default: 10]
+        #[arg(short, long)]
+        duration: Option<u64>,
+    },
+
+    /// Create or edit the toml file
+    #[command(long_about = help::CONFIG)]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// List known device models, and what can be done with each
+    #[command(long_about = help::MODELS)]
+    Models,
+
+    /// Check Bluetooth adapter, config file and timezones, and tell how to fix what's wrong
+    #[command(long_about = help::DOCTOR)]
+    Doctor,
+
+    /// Print a tab completion script for the shell. Device names and groups in toml are completed as they're now
+    #[command(long_about = help::COMPLETIONS)]
+    Completions {
+        /// bash, zsh, fish, powershell or elvish
+        shell: Shell,
+    },
+
+    /// Write man pages of xiaomi and its commands
+    #[command(long_about = help::MANPAGE)]
+    Manpage {
+        /// Write a page for every command to this directory, instead of printing the page of xiaomi
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+
+    /// Print a decoder test case for frames in a capture file. For developers
+    #[command(long_about = help::GEN_FIXTURES)]
+    GenFixtures {
+        /// Capture file written by `scan --capture`
+        capture: PathBuf,
+
+        /// Test name, test_capture_<name>. File name when omitted
+        #[arg(long)]
+        name: Option<String>,
+    },
 }
 
 fn main() -> Result<(), Box<dyn Error>>{
     let cli = Cli::parse();
+    ASCII.store(cli.ascii || !console::Term::stdout().features().wants_emoji(), Ordering::SeqCst);
+    // styled text is printed to stderr too, so both have to be terminals. https://no-color.org
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if cli.no_color || no_color_env || !std::io::stdout().is_terminal() || !std::io::stderr().is_terminal() {
+        NO_COLOR.store(true, Ordering::SeqCst);
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+
+    // second Ctrl-C doesn't wait.
+    ctrlc::set_handler(|| {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    }).ok();
+    logging::init(logging::level_filter(cli.quiet, cli.verbose), write_log);
+    // opened by the command from the context, for each of its runs.
+    let sinks = match &cli.command {
+        Commands::Scan { store, mqtt, influxdb, pushgateway, otlp, graphite, .. } => SinkSelection {
+            store: store.clone(), mqtt: *mqtt, influxdb: *influxdb, graphite: *graphite, pushgateway: *pushgateway, otlp: *otlp,
+        },
+        Commands::Sync { pushgateway, otlp, .. } => SinkSelection { pushgateway: *pushgateway, otlp: *otlp, ..Default::default() },
+        _ => SinkSelection::default(),
+    };
+    let ctx = match AppContext::new(cli.format, sinks, Arc::new(timesource::SystemClock)) {
+        Ok(ctx) => ctx,
+        Err(msg) => {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            std::process::exit(1);
+        }
+    };
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
     match &cli.command {
-        Commands::Scan => {
-            scan(cli.verbose);
+        Commands::Scan { capture, fleet, group, duration, watch, stream, sort, desc, dew_point, comfort, rssi_min, .. } => {
+            let mode = match stream {
+                Some(StreamFormat::Ndjson) => ScanMode::Stream,
+                None if *watch => ScanMode::Watch,
+                None => ScanMode::Summary,
+            };
+            // stream owns stdout, as with --format json. notes go to stderr.
+            let ctx = if mode == ScanMode::Stream { ctx.with_format(OutputFormat::Json) } else { ctx };
+            // 1 is for failed syncs. interrupted scan has its own code.
+            let options = ScanOptions {
+                capture: capture, fleet: *fleet, groups: group, duration: *duration, mode: mode, sort: *sort, descending: *desc,
+                dew_point: *dew_point, comfort: *comfort, rssi_min: *rssi_min,
+            };
+            let completed = scan(&ctx, &options);
+            // Ctrl-C is how watch and stream modes end.
+            if mode == ScanMode::Summary {
+                exit_if_stopped();
+            }
+            if !completed {
+                std::process::exit(2);
+            }
+        },
+        Commands::Dashboard { group } => {
+            if !dashboard(&ctx, group) {
+                std::process::exit(2);
+            }
+        },
+        Commands::Soak { hours, memory_mb } => {
+            // Ctrl-C is how a soak ends early. the report tells whether it passed.
+            if !soak(&ctx, *hours, *memory_mb) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Sync { names, exclude, group, duration, dry_run, force, timezone, offset_seconds, rssi_min, .. } => {
+            let overrides = SyncOverrides { duration: *duration, timezone: timezone.clone(), offset_seconds: *offset_seconds, rssi_min: *rssi_min, check_only: HashSet::new() };
+            // scripts can tell a device failed, or was never seen.
+            let selection = DeviceSelection { names: names, groups: group, exclude: exclude };
+            let success = sync(&ctx, &selection, *dry_run, *force, overrides);
+            exit_if_stopped();
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Read { device, metric, duration } => {
+            let success = read(&ctx, device, *metric, *duration);
+            exit_if_stopped();
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Drift => {
+            drift(&ctx);
+        },
+        Commands::GetTime { device } => {
+            get_time(&ctx, device);
+        },
+        Commands::SetTime { device, epoch, iso, timezone_hour } => {
+            // clap makes sure one of them is given.
+            let epoch = epoch.or(*iso).unwrap();
+            if !set_time(&ctx, device, epoch, *timezone_hour) {
+                std::process::exit(1);
+            }
+        },
+        Commands::SetUnit { device, unit } => {
+            if !set_unit(&ctx, device, *unit) {
+                std::process::exit(1);
+            }
         },
-        Commands::Sync { name } => {
-            sync(cli.verbose, name);
+        Commands::History { command: HistoryCommands::Pull { device, csv } } => {
+            if !history_pull(&ctx, device, csv) {
+                std::process::exit(1);
+            }
+        },
+        Commands::History { command: HistoryCommands::Query { device, store, since, metric } } => {
+            if !history_query(&ctx, device, store, *since, *metric) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Info { device } => {
+            if !info(&ctx, device) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Gatt { command } => {
+            let success = gatt_command(&ctx, command);
+            // Ctrl-C is how subscribe ends.
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Firmware { command: FirmwareCommands::Check { device } } => {
+            if !firmware_check(&ctx, device) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Daemon { interval } => {
+            // runs until the process is killed.
+            daemon(&ctx, *interval, &|duration| {
+                let start = time::Instant::now();
+                while start.elapsed() < duration && !stop_requested() {
+                    thread::sleep(time::Duration::from_millis(200));
+                }
+                stop_requested()
+            });
+            exit_if_stopped();
+        },
+        Commands::Serve { prometheus, api, web, perf_counters, no_mdns, gateway, group } => {
+            let endpoints = ServeEndpoints { prometheus: prometheus.as_deref(), api: api.as_deref(), web: web.as_deref(), perf_counters: *perf_counters };
+            let completed = serve(&ctx, &endpoints, !no_mdns, *gateway, group);
+            exit_if_stopped();
+            if !completed {
+                std::process::exit(2);
+            }
+        },
+        Commands::Service { command } => {
+            if !service_command(command) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Counters { command } => {
+            if !counters_command(command) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Schedule { every, remove, names, pushgateway } => {
+            if !schedule_task(*every, *remove, names, *pushgateway) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Export { store, scan, since, until, out, parquet } => {
+            // before scanning, rather than after.
+            if *parquet && !cfg!(feature = "parquet") {
+                eprintln!("{} Parquet isn't built in. Build with `cargo build --release --features parquet`", theme().error("ERROR:"));
+                std::process::exit(1);
+            }
+            let success = match (store, scan) {
+                (Some(store), _) => export(&ctx, store, *since, *until, out, *parquet),
+                // clap requires one of them.
+                (None, scan) => export_scan(&ctx, scan.unwrap_or_default(), out, *parquet),
+            };
+            // readings until Ctrl-C are written.
+            exit_if_stopped();
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Battery { group, duration, read, below, store } => {
+            let success = battery(&ctx, group, *duration, *read, *below, store);
+            exit_if_stopped();
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Status => {
+            status(&ctx);
         },
         Commands::Toml => {
-            check_config();
+            check_config(&ctx);
+        },
+        Commands::Setup { duration } => {
+            let success = setup(&ctx, *duration);
+            exit_if_stopped();
+            if !success {
+                std::process::exit(1);
+            }
+        },
+        Commands::Config { command: ConfigCommands::Init { path, force } } => {
+            if !config_init(path, *force) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Config { command: ConfigCommands::Check { strict } } => {
+            if !config_check(*strict) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Config { command: ConfigCommands::Add { address, name, timezone } } => {
+            if !config_add(address, name, timezone) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Models => {
+            models();
+        },
+        Commands::Completions { shell } => {
+            completions(&ctx, *shell);
+        },
+        Commands::Manpage { out_dir } => {
+            if !manpage(out_dir) {
+                std::process::exit(1);
+            }
+        },
+        Commands::Doctor => {
+            if !doctor(&ctx) {
+                std::process::exit(1);
+            }
+        },
+        Commands::GenFixtures { capture, name } => {
+            if !gen_fixtures(capture, name) {
+                std::process::exit(1);
+            }
         }
     }
 
     Ok(())
 }
 
-fn sync(_verbose: bool, _filter: &Option<String>) {
+#[derive(Subcommand)]
+enum HistoryCommands {
+    /// Download the history and print it
+    Pull {
+        /// Name or address of the device
+        device: String,
+
+        /// Write records to this CSV file instead of printing them
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Print min, max and average of readings stored by `scan --store`, and every sample
+    Query {
+        /// Name or address of the device
+        device: String,
+
+        /// SQLite database written by `scan --store`
+        #[arg(long)]
+        store: PathBuf,
+
+        /// How far back, like 30m, 24h or 7d
+        #[arg(long, default_value = "24h", value_parser = humanize::parse_duration)]
+        since: u64,
+
+        /// temperature, humidity or battery
+        #[arg(long, default_value = "temperature")]
+        metric: Metric,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Write a starter toml next to the exe, with every setting explained and commented out
+    Init {
+        /// Write it here instead. xiaomi only reads the one next to the exe
+        #[arg(long)]
+        path: Option<PathBuf>,
+
+        /// Overwrite the file when it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Append a device to toml, keeping what's in the file. Creates the file when there's none
+    Add {
+        /// Address of the device, like AA:BB:CC:DD:EE:FF. `scan` shows addresses around
+        address: String,
+
+        /// Name used in output, and instead of the address on command line
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Timezone of the clock, like "Asia/Seoul"
+        #[arg(long, value_parser = parse_timezone)]
+        timezone: Option<String>,
+    },
+    /// Check toml, and exit with 1 when a setting can't work as written. Likely mistakes are warnings
+    Check {
+        /// Keys no setting reads are errors too, instead of warnings
+        #[arg(long)]
+        strict: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum GattCommands {
+    /// Read a characteristic, and print the value in hex
+    Read {
+        #[command(flatten)]
+        target: GattTarget,
+    },
+    /// Write a value to a characteristic
+    Write {
+        #[command(flatten)]
+        target: GattTarget,
+
+        /// Value in hex, like 0a01ff
+        value: String,
+    },
+    /// Print notifications of a characteristic in hex, until Ctrl-C
+    Subscribe {
+        #[command(flatten)]
+        target: GattTarget,
+    },
+}
+
+#[derive(clap::Args)]
+struct GattTarget {
+    /// Name or address of the device
+    device: String,
+
+    /// Service UUID, like 180A or EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6
+    #[arg(value_parser = gatt::parse_uuid)]
+    service: u128,
+
+    /// Characteristic UUID, in the same form
+    #[arg(value_parser = gatt::parse_uuid)]
+    characteristic: u128,
+}
+
+#[derive(Subcommand)]
+enum FirmwareCommands {
+    /// Read the firmware version, and warn when it has known time sync bugs
+    Check {
+        /// Name or address of the device
+        device: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceCommands {
+    /// Register this exe as a service, started at boot. Needs administrator
+    Install,
+    /// Stop and remove the service. Needs administrator
+    Uninstall,
+    Start,
+    Stop,
+    /// Entry point used by the service manager
+    #[command(hide = true)]
+    Run,
+}
+
+#[derive(Subcommand)]
+enum CounterCommands {
+    /// Register the counter set with lodctr. Needs administrator
+    Install,
+    /// Remove the counter set with unlodctr. Needs administrator
+    Uninstall,
+}
+
+// devices given on command line, see `Config::device_filter`.
+struct DeviceSelection<'a> {
+    names: &'a [String],
+    groups: &'a [String],
+    exclude: &'a [String],
+}
+
+// command line of `scan`.
+struct ScanOptions<'a> {
+    capture: &'a Option<PathBuf>,
+    fleet: bool,
+    groups: &'a [String],
+    duration: Option<u64>,
+    mode: ScanMode,
+    sort: ScanSort,
+    descending: bool,
+    // dew point and absolute humidity are added.
+    dew_point: bool,
+    // apparent temperature and comfort are added to the summary.
+    comfort: bool,
+    // overrides rssi_min of [general].
+    rssi_min: Option<i16>,
+}
+
+// how `scan` shows readings.
+#[derive(Clone, Copy, PartialEq)]
+enum ScanMode {
+    // every reading as it arrives, then a summary.
+    Summary,
+    // table of the latest values, redrawn until Ctrl-C.
+    Watch,
+    // a JSON line per reading until Ctrl-C. nothing else goes to stdout.
+    Stream,
+}
+
+// config values given on command line, for this run only.
+struct SyncOverrides {
+    // overrides sync_duration_seconds of [general].
+    duration: Option<u64>,
+    timezone: Option<String>,
+    offset_seconds: Option<i32>,
+    rssi_min: Option<i16>,
+    // devices only read for their drift. `daemon` checks the ones synced recently.
+    check_only: HashSet<u64>,
+}
+
+// validate timezone name early, instead of failing every device.
+fn parse_timezone(name: &str) -> Result<String, String> {
+    return match name.parse::<chrono_tz::Tz>() {
+        Ok(_) => Ok(name.to_string()),
+        Err(_) => Err(format!("unknown timezone \"{}\". Use a name from the tz database, like \"Asia/Seoul\"", name)),
+    };
+}
+
+// result of a device in a sync run, for the summary.
+struct SyncOutcome {
+    started: time::Instant,
+    elapsed: time::Duration,
+    // None while syncing.
+    result: Option<Result<ble::SyncResult, String>>,
+}
+
+// what the workers of a sync run told, for the summary. keyed by the address identifying the logical device.
+#[derive(Default)]
+struct SyncTally {
+    outcomes: HashMap<u64, SyncOutcome>,
+    // battery level read during sync.
+    batteries: HashMap<u64, u8>,
+    // drift measured in this run, for the webhook.
+    drifts: HashMap<u64, i64>,
+}
+
+impl SyncTally {
+    // when the device was seen and synced, and its drift, go to `state` too.
+    fn record(&mut self, state: &mut State, device_address: u64, log: &ble::SyncLogKind, now: u64) {
+        state.record_seen(device_address, now);
+        // time in the queue isn't counted.
+        if let ble::SyncLogKind::Queued { .. } = log {
+            return;
+        }
+        // timing restarts when the device is tried again.
+        let outcome = self.outcomes.entry(device_address).or_insert(SyncOutcome { started: time::Instant::now(), elapsed: time::Duration::ZERO, result: None });
+        if outcome.result.is_some() {
+            *outcome = SyncOutcome { started: time::Instant::now(), elapsed: time::Duration::ZERO, result: None };
+        }
+        match log {
+            ble::SyncLogKind::Error { log, .. } => {
+                outcome.elapsed = outcome.started.elapsed();
+                outcome.result = Some(Err(log.clone()));
+            },
+            ble::SyncLogKind::Drift { seconds, .. } => {
+                state.get_mut(device_address).last_drift_seconds = Some(*seconds);
+                self.drifts.insert(device_address, *seconds);
+            },
+            ble::SyncLogKind::Battery { percent, .. } => {
+                self.batteries.insert(device_address, *percent);
+            },
+            ble::SyncLogKind::Done { result, .. } => {
+                if *result == ble::SyncResult::Synced || *result == ble::SyncResult::Skipped {
+                    state.record_synced(device_address, now);
+                }
+                outcome.elapsed = outcome.started.elapsed();
+                outcome.result = Some(Ok(*result));
+            },
+            ble::SyncLogKind::Queued { .. } | ble::SyncLogKind::Progress { .. } => {},
+        }
+    }
+
+    // `expected` are configured devices selected to sync. all of them are in the summary, even when they're not seen.
+    // configured devices first, in toml order. then devices not in toml.
+    fn report(&self, config: &HashMap<u64, DeviceConfig>, expected: &[DeviceConfig], now: u64) -> webhook::SyncReport {
+        let mut addresses: Vec<u64> = expected.iter().map(|d| d.address).collect();
+        let mut others: Vec<u64> = self.outcomes.keys().copied().filter(|a| !addresses.contains(a)).collect();
+        others.sort();
+        addresses.extend(others);
+
+        let mut devices: Vec<webhook::DeviceReport> = Vec::new();
+        for address in addresses {
+            let outcome = self.outcomes.get(&address);
+            devices.push(webhook::DeviceReport {
+                address: format_bluetooth_address(address),
+                name: config.get(&address).and_then(|d| d.name.clone()),
+                result: match outcome.map(|o| &o.result) {
+                    Some(Some(Ok(ble::SyncResult::Synced))) => "synced",
+                    Some(Some(Ok(ble::SyncResult::Skipped))) => "skipped",
+                    Some(Some(Ok(ble::SyncResult::Omitted))) => "omitted",
+                    Some(Some(Ok(ble::SyncResult::DryRun))) => "dry-run",
+                    Some(Some(Ok(ble::SyncResult::Checked))) => "checked",
+                    Some(Some(Err(_))) => "failed",
+                    Some(None) => "unfinished",
+                    None if config.get(&address).and_then(|d| d.omit).unwrap_or(false) => "omitted",
+                    None => "not-seen",
+                }.to_string(),
+                error: outcome.and_then(|o| o.result.clone()).and_then(|r| r.err()),
+                seconds: outcome.map(|o| o.elapsed.as_secs()),
+                drift_seconds: self.drifts.get(&address).copied(),
+                battery_percent: self.batteries.get(&address).copied(),
+            });
+        }
+        let success = !devices.iter().any(|d| d.failed());
+        return webhook::SyncReport { time: now, success: success, devices: devices };
+    }
+}
+
+// 'sync' command handler. returns false when any device failed, or a configured device was never seen.
+fn sync(ctx: &AppContext, selection: &DeviceSelection, dry_run: bool, force: bool, overrides: SyncOverrides) -> bool {
     // Load toml config file. This contains device name and timezone information.
-    let config: Arc<Mutex<HashMap<u64, DeviceConfig>>> = Arc::new(Mutex::new(load_config()));
+    let loaded = config_or_exit(ctx);
+    let filter = ctx.device_filter(&loaded, selection, false);
+    // a Pushgateway which isn't configured fails the run before syncing, one which can't be reached is a warning after.
+    let sinks = sinks_or_exit(ctx, &loaded, "sync");
+    return run_sync(ctx, loaded, sinks, &filter, dry_run, force, overrides).success;
+}
+
+// listens to advertisements and syncs devices selected by the filter, then prints the summary and sends it to `sinks`.
+// returns the summary. it has no devices when listening failed.
+fn run_sync(ctx: &AppContext, loaded: Config, sinks: Sinks, filter: &DeviceFilter, dry_run: bool, force: bool, overrides: SyncOverrides) -> webhook::SyncReport {
+    let mut filter = filter.clone();
+    if loaded.general.strict.unwrap_or(false) {
+        filter.restrict(&loaded.configured_addresses());
+    }
+    // command line takes precedence over config file.
+    let monitoring_period = overrides.duration.or(loaded.general.sync_duration_seconds).unwrap_or(30);
+    let options = sync_options(&loaded.general, dry_run, force, &overrides);
+    let worker_count = loaded.general.sync_workers.unwrap_or(2);
+    let ignore_random_addresses = loaded.general.ignore_random_addresses.unwrap_or(false);
+    let rssi_min = overrides.rssi_min.or(loaded.general.rssi_min);
+    let low_battery_percent = loaded.general.low_battery_percent.unwrap_or(20);
+    let started = ctx.now();
+    let expected: Vec<DeviceConfig> = loaded.devices.iter().flatten().filter(|d| filter.matches(d.address)).cloned().collect();
+    let mut tally = SyncTally::default();
+    let mut state = ctx.load_state();
+    let config: Arc<Mutex<HashMap<u64, DeviceConfig>>> = Arc::new(Mutex::new(loaded.into_device_map()));
     // lock prevents destroying watcher object before completing event handler.
     let lock: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    // devices keeps the record of successfully synced devices. perhaps we can use HashSet instead.
+    // devices keeps the record of synced devices, and devices being synced.
     let devices: Arc<Mutex<HashSet<u64>>> = Arc::new(Mutex::new(HashSet::new()));
     // event handler runs in a background thread, so we don't print anything from there.
-    // instead, log messages are transferred to main thread and printed along with a progress bar.
-    let (tx, rx): (Sender<ble::SyncLogKind>, Receiver<ble::SyncLogKind>) = mpsc::channel();
-    
+    // instead, log messages are published to main thread and printed along with a progress bar.
+    let bus: EventBus<ble::SyncLogKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let logs = bus.subscribe();
+    let publisher = bus.publisher();
+
     {
-        let monitoring_period = 30;
-        let spinner = ProgressBar::new_spinner();
+        let multi = MultiProgress::new();
+        let spinner = multi.add(new_spinner());
+
+        // connecting to a device takes seconds. event handler queues addresses, and workers sync them.
+        let config_clone = config.clone();
+        let devices_clone = devices.clone();
+        let publisher_clone = publisher.clone();
+        let workers: WorkerPool<u64> = WorkerPool::new(worker_count, move |address| {
+            // devices queued but not started are left, when stopping.
+            if !stop_requested() {
+                ble::sync_device(&config_clone, &devices_clone, &options, &publisher_clone, address);
+            }
+        });
 
         let config_clone = config.clone();
+        let filter_clone = filter.clone();
         let lock_clone = lock.clone();
         let devices_clone = devices.clone();
+        let queue = workers.queue();
         let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
             let mut _lifetime = lock_clone.lock().unwrap();
-            ble::sync_device_args(&config_clone, &devices_clone, &tx, &args);
+            // not even decoded. busy places have hundreds of them.
+            if ignore_random_addresses && ble::is_random_address(&args) {
+                return Ok(());
+            }
+            // devices of the neighbour aren't selected, even when they're configured.
+            if ble::is_weak_signal(&args, rssi_min) {
+                return Ok(());
+            }
+            if let Some(address) = ble::select_device_args(&config_clone, &filter_clone, &devices_clone, &publisher, &args) {
+                queue.push(address);
+            }
             Ok(())
         };
 
-        let process_data = |wait: time::Duration| -> bool {
-            match rx.recv_timeout(wait) {
+        // each device has its own progress bar, showing the last log.
+        // with --verbose, every log is printed as well.
+        let mut bars: HashMap<u64, ProgressBar> = HashMap::new();
+        // devices queued, and not picked up by a worker yet.
+        let mut waiting: HashSet<u64> = HashSet::new();
+        let mut process_data = |wait: time::Duration| -> bool {
+            match logs.recv_timeout(wait) {
                 Err(_) => {
                     // Perhaps timeout. Do nothing.
                     return false;
                 },
                 Ok(data) => {
+                    let address = data.address();
+                    let device_name = get_device_name(&config.lock().unwrap(), address);
+                    let device_address = resolve_device_address(&config.lock().unwrap(), address);
+
+                    // a failed device gets a new bar when it's tried again.
+                    if bars.get(&device_address).is_none_or(|bar| bar.is_finished()) {
+                        let bar = multi.insert_before(&spinner, ProgressBar::new_spinner());
+                        bar.set_style(spinner_style(&theme().spinner_template(theme().error, "{prefix}: {msg}")));
+                        bar.set_prefix(device_name.clone());
+                        bar.enable_steady_tick(time::Duration::from_millis(120));
+                        bars.insert(device_address, bar);
+                    }
+                    let bar = &bars[&device_address];
+                    tally.record(&mut state, device_address, &data, ctx.now());
+
+                    if let ble::SyncLogKind::Queued { .. } = data {
+                        waiting.insert(device_address);
+                        bar.set_message("Waiting for a free worker");
+                        spinner.set_message(listening_message(&bars, &waiting));
+                        return true;
+                    }
+                    waiting.remove(&device_address);
+
                     match data {
-                        ble::SyncLogKind::Progress { address, log } => {
-                            let mut device_name = format_bluetooth_address(address);
-                            if let Some(device_config) = config.lock().unwrap().get(&address) {
-                                if let Some(name) = &device_config.name {
-                                    device_name = name.to_string();
-                                }
-                            }
-    
-                            spinner.println(format!("{}: {}", device_name, log));
+                        ble::SyncLogKind::Progress { log, .. } => {
+                            log::info!("{}: {}", device_name, log);
+                            bar.set_message(log);
                         },
-                        ble::SyncLogKind::Error { address, log } => {
-                            let mut device_name = format_bluetooth_address(address);
-                            if let Some(device_config) = config.lock().unwrap().get(&address) {
-                                if let Some(name) = &device_config.name {
-                                    device_name = name.to_string();
-                                }
+                        ble::SyncLogKind::Error { log, .. } => {
+                            log::info!("{}: {}", device_name, theme().error(&log));
+                            bar.finish_with_message(theme().error(&log).to_string());
+                        },
+                        ble::SyncLogKind::Battery { percent, .. } => {
+                            if percent < low_battery_percent {
+                                multi.print_above(format!("{} {}: {}", EXCLAMATION, device_name, theme().warning(format!("battery {}%, replace it soon", percent))));
                             }
-    
-                            spinner.println(format!("{}: {}", device_name, style(log).red()));
-                        }
+                            else {
+                                log::info!("{}: battery {}%", device_name, percent);
+                            }
+                        },
+                        ble::SyncLogKind::Done { .. } => {
+                            bar.finish_with_message(format!("{}{}", CHECKBOX, bar.message()));
+                        },
+                        ble::SyncLogKind::Queued { .. } | ble::SyncLogKind::Drift { .. } => {},
                     }
+                    spinner.set_message(listening_message(&bars, &waiting));
                     return true;
                 }
             };
         };
 
-        // initialize bluetooth watcher
-        let watcher = BluetoothLEAdvertisementWatcher::new().expect("Creating BluetoothLEAdvertisementWatcher failed!");
-        watcher.SetScanningMode(BluetoothLEScanningMode::Passive).expect("Changing ScanningMode failed");
-        let token = watcher.Received(&TypedEventHandler::new(on_received)).unwrap();
-    
         // start listening to advertisement.
-        spinner.println(format!("Start monitoring BLE advertisement... {}", CHECKBOX));
-        watcher.Start().expect("Starting BLE watcher failed");
+        let watch = match ctx.watch_advertisements(on_received) {
+            Ok(watch) => watch,
+            Err(msg) => {
+                multi.print_above(format!("{} {}", EXCLAMATION, theme().error(msg)));
+                multi.print_above("    Run `xiaomi doctor` to check Bluetooth.");
+                workers.join();
+                spinner.finish_and_clear();
+                return webhook::SyncReport { time: ctx.now(), success: false, devices: vec![] };
+            }
+        };
+        multi.print_above(format!("Start monitoring BLE advertisement... {}", CHECKBOX));
         let start_time = time::Instant::now();
 
         spinner.enable_steady_tick(time::Duration::from_millis(120));
-        spinner.set_style(
-            ProgressStyle::with_template("{spinner:.red} {msg}")
-                .unwrap()
-                // For more spinners check out the cli-spinners project:
-                // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-                .tick_strings(&[
-                    "▹▹▹▹▹",
-                    "▸▹▹▹▹",
-                    "▹▸▹▹▹",
-                    "▹▹▸▹▹",
-                    "▹▹▹▸▹",
-                    "▹▹▹▹▸",
-                    "▪▪▪▪▪",
-                ]),
-        );
+        spinner.set_style(spinner_style(&theme().spinner_template(theme().error, "{msg}")));
         spinner.set_message("Listening...");
 
         // wait for messages
-        while start_time.elapsed() < time::Duration::from_secs(monitoring_period) {
+        while start_time.elapsed() < time::Duration::from_secs(monitoring_period) && !stop_requested() {
             process_data(time::Duration::from_millis(300));
         }
 
         // shutting down - remove the listener first.
-        watcher.RemoveReceived(token).ok();
+        watch.stop();
 
         // wait until existing event handler completes.
-        spinner.println("Waiting worker thread complete...");
+        multi.print_above("Waiting worker thread complete...");
         spinner.set_message("Stopping...");
-        let mut _lifetime = lock.lock().unwrap();
-        while process_data(time::Duration::from_millis(0)) {}
+        // logs are read meanwhile. a handler waiting for room in the queue would never complete otherwise.
+        let _lifetime = loop {
+            if let Ok(guard) = lock.try_lock() {
+                break guard;
+            }
+            process_data(time::Duration::from_millis(10));
+        };
+
+        // close the queue. workers finish devices already queued, then exit.
+        workers.close();
+        while !workers.is_finished() {
+            process_data(time::Duration::from_millis(100));
+        }
+        workers.join();
+        // a handler still finishing can't publish anymore. logs queued until now are printed.
+        bus.shutdown();
+        while process_data(time::Duration::from_millis(0)) {}
         spinner.finish_and_clear();
 
-        // stop the BLE watcher.
-        watcher.Stop().expect("Stopping BLE watcher failed");
-        spinner.println(format!("Stop monitoring BLE advertisement... {}", CHECKBOX));
-        drop(watcher);
+        multi.print_above(format!("Stop monitoring BLE advertisement... {}", CHECKBOX));
+    }
+
+    ctx.save_state(&state);
+
+    let report = tally.report(&config.lock().unwrap(), &expected, ctx.now());
+    let mut table = theme().table(&["Device ID", "Result", "Time", "Battery"]);
+    for device in &report.devices {
+        let result = match device.result.as_str() {
+            "synced" => theme().ok("synced".to_string()),
+            "failed" => theme().error(format!("failed: {}", device.error.as_deref().unwrap_or_default())),
+            "unfinished" => theme().error("unfinished".to_string()),
+            "not-seen" => theme().warning("not seen".to_string()),
+            "dry-run" => style("dry run".to_string()),
+            other => style(other.to_string()),
+        };
+        let elapsed = device.seconds.map(humanize::format_duration).unwrap_or("-".to_string());
+        let battery = match device.battery_percent {
+            Some(percent) if percent < low_battery_percent => theme().warning(format!("{}%", percent)).to_string(),
+            Some(percent) => format!("{}%", percent),
+            None => "-".to_string(),
+        };
+        table.add_row(row![device.name.as_deref().unwrap_or(&device.address), result, elapsed, battery]);
+    }
+
+    if stop_requested() {
+        ctx.note(&format!("{} Sync was stopped by Ctrl-C. Summary is partial.", EXCLAMATION));
+    }
+    match ctx.format {
+        // header only, when nothing is configured nor seen.
+        OutputFormat::Table => if table.len() > 1 {
+            println!("Summary:");
+            table.print_tty(true).ok();
+        },
+        OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+        OutputFormat::Csv => {
+            println!("{}", output::SYNC_CSV_HEADER);
+            for device in &report.devices {
+                println!("{}", output::sync_csv(device));
+            }
+        },
+    }
+
+    send_sync_report(&report, sinks, started);
+    return report;
+}
+
+fn sync_options(general: &GeneralConfig, dry_run: bool, force: bool, overrides: &SyncOverrides) -> ble::SyncOptions {
+    return ble::SyncOptions {
+        retry: general.retry_policy(),
+        timeout: general.gatt_timeout(),
+        drift_threshold_seconds: general.sync_drift_threshold_seconds.unwrap_or(5),
+        verify_tolerance_seconds: general.sync_verify_tolerance_seconds.unwrap_or(2),
+        dry_run: dry_run,
+        force: force,
+        timezone_policy: general.timezone_policy.unwrap_or_default(),
+        clock: get_time_provider(general),
+        timezone: overrides.timezone.clone(),
+        offset_seconds: overrides.offset_seconds,
+        check_only: overrides.check_only.clone(),
+    };
+}
+
+// `sinks` get the summary of a run which started at `started`.
+fn send_sync_report(report: &webhook::SyncReport, sinks: Sinks, started: u64) {
+    // waits for them to be sent, when it's dropped.
+    if let Some(webhooks) = sinks.webhooks {
+        webhooks.sync(report);
+    }
+    if let Some(url) = sinks.push_url {
+        if let Err(msg) = prometheus::push(&url, &prometheus::format_sync_report(report), sinks::HTTP_TIMEOUT) {
+            log::warn!("Pushing to Pushgateway failed: {}", msg);
+        }
+    }
+    if let Some(otlp) = sinks.otlp {
+        let failures = report.devices.iter().filter(|d| d.failed()).count();
+        let error = (failures > 0).then(|| format!("{} devices failed or were not seen", failures));
+        let attributes = otlp::attributes(&[("sync.devices", Some(&report.devices.len().to_string())), ("sync.failures", Some(&failures.to_string()))]);
+        let exported = otlp.export_metrics(otlp::sync_metrics(report, started))
+            .and_then(|_| otlp.export_span(otlp::run_span("sync", started, report.time, error.as_deref(), attributes)));
+        if let Err(msg) = exported {
+            log::warn!("Exporting to OpenTelemetry failed: {}", msg);
+        }
+    }
+}
+
+// why a run of `daemon` starts.
+enum DaemonRun {
+    // the interval passed.
+    Scheduled,
+    // xiaomi.sync was created, with these names in it. empty for every device.
+    Requested(Vec<String>),
+    // difference from UTC of these devices just changed, like when daylight saving time starts or ends.
+    OffsetChange(HashSet<u64>),
+}
+
+// 'daemon' command handler. runs until `wait` returns true. `wait` blocks for the given duration, or until stopped.
+// config is read again on every run, so edits apply without a restart. creating xiaomi.sync starts a run right away,
+// and devices with `timezone` are synced again when daylight saving time starts or ends.
+fn daemon(ctx: &AppContext, interval: Option<u64>, wait: &dyn Fn(time::Duration) -> bool) {
+    let mut interval_minutes = interval.unwrap_or(60);
+    let trigger = Trigger::new(&std::env::current_exe().unwrap().with_extension("sync"));
+    // a file left from before the start is taken for the first run.
+    let mut run = trigger.take().map_or(DaemonRun::Scheduled, DaemonRun::Requested);
+    loop {
+        let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+        match &run {
+            DaemonRun::Scheduled => println!("{} Sync run started", now),
+            DaemonRun::Requested(_) => println!("{} Sync run requested with {}", now, trigger.path().display()),
+            DaemonRun::OffsetChange(addresses) => println!("{} Sync run for the change of timezone offset of {} devices", now, addresses.len()),
+        }
+        let mut offset_change: Option<(i64, HashSet<u64>)> = None;
+        // a broken [[webhook]] is like a broken config. nothing is synced until it's fixed.
+        let prepared = ctx.reload_config()
+            .and_then(|loaded| daemon_filter(ctx, &loaded, run).map(|selection| (loaded, selection)))
+            .and_then(|(loaded, selection)| ctx.open_sinks(&loaded, "daemon").map(|sinks| (loaded, selection, sinks)));
+        match prepared {
+            Ok((loaded, (filter, check_only), sinks)) => {
+                interval_minutes = interval.or(loaded.general.daemon_interval_minutes).unwrap_or(60);
+                // twice the interval, as the wait starts after the run, which takes a while.
+                offset_change = loaded.next_offset_change(ctx.now() as i64, 2 * interval_minutes as i64 * 60);
+                let overrides = SyncOverrides { duration: None, timezone: None, offset_seconds: None, rssi_min: None, check_only: check_only };
+                if !run_sync(ctx, loaded, sinks, &filter, false, false, overrides).success {
+                    println!("{} Some devices failed or were not seen. They're tried again next run.", EXCLAMATION);
+                }
+            },
+            // keep running with a broken config. it may be fixed before the next run.
+            Err(msg) => {
+                eprintln!("{} {}", theme().error("ERROR:"), msg);
+            }
+        }
+        let mut left = time::Duration::from_secs(interval_minutes * 60);
+        run = DaemonRun::Scheduled;
+        if let Some((at, addresses)) = offset_change {
+            // a moment after the change, so clocks are written with the new offset. right away when it passed during the run.
+            let until = time::Duration::from_secs((at + OFFSET_CHANGE_DELAY_SECONDS - ctx.now() as i64).max(0) as u64);
+            if until < left {
+                println!("Timezone offset of {} devices changes in {}. They're synced then.", addresses.len(), humanize::format_duration(until.as_secs()));
+                left = until;
+                run = DaemonRun::OffsetChange(addresses);
+            }
+        }
+        println!("Next run in {}", humanize::format_duration(left.as_secs()));
+        // waits in slices, looking for the trigger file between them.
+        while !left.is_zero() {
+            let slice = left.min(TRIGGER_POLL);
+            if wait(slice) {
+                println!("Stopped");
+                return;
+            }
+            left -= slice;
+            if let Some(names) = trigger.take() {
+                run = DaemonRun::Requested(names);
+                break;
+            }
+        }
     }
 }
 
-// 'scan' command handler.
-fn scan(_verbose: bool) {
+// devices of a daemon run, and those of them only checked. on schedule, devices synced recently are only read
+// for their drift, and others are written only when drift is over the threshold. the rest are every device asked
+// for, however recently it's synced.
+fn daemon_filter(ctx: &AppContext, loaded: &Config, run: DaemonRun) -> Result<(DeviceFilter, HashSet<u64>), String> {
+    return match run {
+        DaemonRun::Scheduled => {
+            let max_age = loaded.general.daemon_sync_age_hours.unwrap_or(24) * 3600;
+            let threshold = loaded.general.sync_drift_threshold_seconds.unwrap_or(5);
+            let check_only = ctx.load_state().drift_checks(ctx.now(), max_age, threshold);
+            println!("{} devices synced in last {}, checking drift only", check_only.len(), humanize::format_duration(max_age));
+            Ok((DeviceFilter::default(), check_only))
+        },
+        DaemonRun::Requested(names) => loaded.device_filter(&names, &[], &[]).map(|filter| (filter, HashSet::new())),
+        DaemonRun::OffsetChange(addresses) => Ok((DeviceFilter { include: Some(addresses), exclude: Default::default() }, HashSet::new())),
+    };
+}
+
+// 'service' command handler. returns false when it failed.
+#[cfg(windows)]
+fn service_command(command: &ServiceCommands) -> bool {
+    let result = match command {
+        ServiceCommands::Install => service::install().map(|_| "Service installed. Start it with `xiaomi service start`"),
+        ServiceCommands::Uninstall => service::uninstall().map(|_| "Service uninstalled"),
+        ServiceCommands::Start => service::start().map(|_| "Service started"),
+        ServiceCommands::Stop => service::stop().map(|_| "Service stopping"),
+        ServiceCommands::Run => service::run().map(|_| "Service stopped"),
+    };
+    return match result {
+        Ok(msg) => {
+            println!("{} {}", msg, CHECKBOX);
+            true
+        },
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            false
+        }
+    };
+}
+
+#[cfg(not(windows))]
+fn service_command(_command: &ServiceCommands) -> bool {
+    println!("{} {}", EXCLAMATION, theme().error("Services are only on Windows"));
+    return false;
+}
+
+// 'counters' command handler. returns false when lodctr fails.
+#[cfg(windows)]
+fn counters_command(command: &CounterCommands) -> bool {
+    let result = match command {
+        CounterCommands::Install => counters::install().map(|_| "Performance counters installed. Set them with `xiaomi serve --perf-counters`"),
+        CounterCommands::Uninstall => counters::uninstall().map(|_| "Performance counters uninstalled"),
+    };
+    return match result {
+        Ok(msg) => {
+            println!("{} {}", msg, CHECKBOX);
+            true
+        },
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            false
+        }
+    };
+}
+
+#[cfg(not(windows))]
+fn counters_command(_command: &CounterCommands) -> bool {
+    println!("{} {}", EXCLAMATION, theme().error("Performance counters are only on Windows"));
+    return false;
+}
+
+// performance counters of `serve --perf-counters`. they stop being set when it ends.
+#[cfg(windows)]
+fn start_perf_counters() -> counters::Provider {
+    return counters::Provider::start().unwrap_or_else(|msg| {
+        eprintln!("{} {}", theme().error("ERROR:"), msg);
+        std::process::exit(1);
+    });
+}
+
+#[cfg(not(windows))]
+fn start_perf_counters() -> PerfCounters {
+    eprintln!("{} Performance counters are only on Windows", theme().error("ERROR:"));
+    std::process::exit(1);
+}
+
+// stands in for counters::Provider, so `serve` builds everywhere.
+#[cfg(not(windows))]
+struct PerfCounters;
+
+#[cfg(not(windows))]
+impl PerfCounters {
+    fn set(&mut self, _address: u64, _name: &str, _metric: Metric, _value: f32) {}
+}
+
+// 'scan' command handler. returns false when the scan is interrupted by a bluetooth error.
+// summary is printed anyway, with devices seen until then.
+fn scan(ctx: &AppContext, options: &ScanOptions) -> bool {
+    let ScanOptions { capture, fleet, groups, duration, mode, sort, descending, dew_point, comfort, rssi_min } = *options;
+    let watch = mode == ScanMode::Watch;
+    let stream = mode == ScanMode::Stream;
     // Load toml config file. This contains device name and timezone information.
-    let config = load_config();
-    let (tx, rx): (Sender<ble::AdvertisementKind>, Receiver<ble::AdvertisementKind>) = mpsc::channel();
+    let loaded = config_or_exit(ctx);
+    let selection = DeviceSelection { names: &[], groups: groups, exclude: &[] };
+    let filter = ctx.device_filter(&loaded, &selection, loaded.general.strict_scan.unwrap_or(false));
+    let fleet = fleet || loaded.general.fleet_mode.unwrap_or(false);
+    // command line takes precedence over config file.
+    let monitoring_period = duration.or(loaded.general.scan_duration_seconds).unwrap_or(10);
+    // fleet mode pages the summary by default, so the header stays visible.
+    let page_size = loaded.general.summary_page_size.or(if fleet { Some(50) } else { None });
+    let formula = loaded.general.apparent_temperature.unwrap_or_default();
+    let rssi_min = rssi_min.or(loaded.general.rssi_min);
+    // a broker which can't be reached fails the scan before listening, like a store which can't be opened.
+    let mut sinks = sinks_or_exit(ctx, &loaded, "scan");
+    let started = ctx.now();
+    let config = loaded.into_device_map();
+    // readings with the signal strength of the advertisement they came in.
+    let bus: EventBus<(AdvertisementKind, Option<i16>)> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let readings = bus.subscribe();
     let mut sensors: HashMap<u64, SensorData> = HashMap::new();
+    // product id of devices. only MiBeacon frames have it.
+    let mut product_ids: HashMap<u64, u16> = HashMap::new();
+    // advertising interval of each device, from when its frames are heard.
+    let mut intervals: HashMap<u64, IntervalEstimator> = HashMap::new();
+    // frame counter of the last frame from each address. readings follow the frame they're decoded from.
+    let mut frame_counters: HashMap<u64, u8> = HashMap::new();
+    // reason the scan stopped early.
+    let mut interrupted: Option<String> = None;
+    // capture file keeps raw frames. append, so frames from several scans can be collected.
+    let mut capture_file = capture.as_ref().map(|path| {
+        std::fs::OpenOptions::new().create(true).append(true).open(path).expect("Opening capture file failed")
+    });
 
     // Watch on BLE advertisements
     {
-        let monitoring_period = 10;
-        let spinner = ProgressBar::new_spinner();
+        // stdout is for readings only when streaming. messages go to stderr.
+        let spinner = if stream { ProgressBar::hidden() } else { new_spinner() };
+        let print_line = |line: String| {
+            if stream {
+                eprintln!("{}", line);
+            } else {
+                spinner.print_above(line);
+            }
+        };
+        // watch mode redraws the table in the spinner message, at most once a second.
+        let mut last_draw: Option<time::Instant> = None;
     
+        let publisher = bus.publisher();
         let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
-            let value = ble::decode_advertisement(&args);
-            match value {
-                AdvertisementKind::Temperature(_) |
-                AdvertisementKind::Humidity(_) |
-                AdvertisementKind::Battery(_) => {
-                    tx.send(value).unwrap();
-                },
-                _ => {}, // do nothing
+            // not even decoded, as if it wasn't heard.
+            if ble::is_weak_signal(&args, rssi_min) {
+                return Ok(());
+            }
+            let rssi = ble::signal_strength(&args);
+            for value in ble::decode_advertisement(&args) {
+                publisher.publish((value, rssi));
             }
             Ok(())
         };
-        let watcher = BluetoothLEAdvertisementWatcher::new().expect("Creating BluetoothLEAdvertisementWatcher failed!");
-        watcher.SetScanningMode(BluetoothLEScanningMode::Passive).expect("Changing ScanningMode failed");
-        let token = watcher.Received(&TypedEventHandler::new(on_received)).unwrap();
-    
+
         // Start watcher and set the progress bar (spinner)
-        watcher.Start().expect("Starting BLE watcher failed");
+        let advertisement_watch = match ctx.watch_advertisements(on_received) {
+            Ok(advertisement_watch) => Some(advertisement_watch),
+            Err(msg) => {
+                interrupted = Some(msg);
+                None
+            }
+        };
         spinner.enable_steady_tick(time::Duration::from_millis(120));
-        spinner.set_style(
-            ProgressStyle::with_template("{spinner:.green} {msg}")
-                .unwrap()
-                // For more spinners check out the cli-spinners project:
-                // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-                .tick_strings(&[
-                    "▹▹▹▹▹",
-                    "▸▹▹▹▹",
-                    "▹▸▹▹▹",
-                    "▹▹▸▹▹",
-                    "▹▹▹▸▹",
-                    "▹▹▹▹▸",
-                    "▪▪▪▪▪",
-                ]),
-        );
-        spinner.println(format!("Start monitoring BLE advertisement... {}", CHECKBOX));
-        spinner.set_message("Listening...");
+        spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+        if interrupted.is_none() {
+            print_line(format!("Start monitoring BLE advertisement... {}", CHECKBOX));
+        }
+        spinner.set_message(if watch { "Listening... Ctrl-C to stop" } else { "Listening..." });
         let start_time = time::Instant::now();
 
         let mut process_data = |wait: time::Duration| -> bool {
-            match rx.recv_timeout(wait) {
+            sinks.tick();
+            if let Some(otlp) = sinks.otlp.as_mut() {
+                if otlp.is_due() {
+                    if let Err(msg) = otlp.export_metrics(otlp::reading_metrics(&device_metrics(&config, &sensors))) {
+                        log::warn!("Exporting to OpenTelemetry failed: {}", msg);
+                    }
+                }
+            }
+            if watch && !sensors.is_empty() && last_draw.is_none_or(|t| t.elapsed() >= time::Duration::from_secs(1)) {
+                let mut table = theme().table(&["Device ID", "Model", "Temp.", "Humidity %", "Battery %", "Interval", "RSSI", "Last seen"]);
+                let mut devices = scan_devices(&config, &sensors, &product_ids, &intervals, formula);
+                output::sort_scan_devices(&mut devices, sort, descending);
+                for device in devices {
+                    table.add_row(scan_row(&device, Some(ctx.now())));
+                }
+                spinner.set_message(format!("Listening... Ctrl-C to stop\n{}", table.to_string().trim_end()));
+                last_draw = Some(time::Instant::now());
+            }
+            match readings.recv_timeout(wait) {
                 Err(_) => {
                     // Perhaps timeout. Do nothing.
                     return false;
                 },
-                Ok(data) => {
+                Ok((data, rssi)) => {
+                    // devices not selected by --group are left out, from the capture file too.
+                    let data_address = match &data {
+                        AdvertisementKind::Temperature(value) |
+                        AdvertisementKind::Humidity(value) |
+                        AdvertisementKind::Battery(value) => value.address,
+                        AdvertisementKind::Frame(frame) => frame.address,
+                    };
+                    if !filter.matches(resolve_device_address(&config, data_address)) {
+                        return true;
+                    }
+
                     match &data {
                         AdvertisementKind::Temperature(value) | 
                         AdvertisementKind::Humidity(value) |
@@ -247,26 +1645,137 @@ fn scan(_verbose: bool) {
                                 }
                             }
     
+                            // readings from aliased addresses are merged into one device.
+                            let address = resolve_device_address(&config, value.address);
+
                             // create a new entry for this device, if it didn't exist.
-                            if !sensors.contains_key(&value.address) {
-                                sensors.insert(value.address, SensorData::new());
+                            if !sensors.contains_key(&address) {
+                                sensors.insert(address, SensorData::new());
+                            }
+                            sensors.get_mut(&address).map(|val| val.set_seen(ctx.now()));
+                            if let Some(rssi) = rssi {
+                                sensors.get_mut(&address).map(|val| val.set_rssi(rssi));
                             }
     
-                            // Print the sensor value, and update sensor data.
+                            // the same for every sink. frames are the other arm, this is always a reading.
+                            let metric = data.metric().unwrap();
+                            if let Some(store) = &sinks.store {
+                                let reading = StoredReading { time: ctx.now(), address: address, metric: metric, value: value.value, frame_counter: frame_counters.get(&value.address).copied() };
+                                if let Err(msg) = store.insert(&reading) {
+                                    log::warn!("Storing reading failed: {}", msg);
+                                }
+                            }
+                            if let Some(mqtt) = &mut sinks.mqtt {
+                                let device_name = config.get(&address).and_then(|d| d.name.as_deref());
+                                let topic = mqtt.topic(device_name, &format_bluetooth_address(address), metric.name());
+                                if let Err(msg) = mqtt.publish(&topic, &humanize::format_value(value.value, precision())) {
+                                    log::warn!("Publishing to MQTT broker failed: {}", msg);
+                                }
+                            }
+                            if let Some(influxdb) = &mut sinks.influxdb {
+                                let device = config.get(&address);
+                                let address_text = format_bluetooth_address(address);
+                                let tags = [
+                                    ("address", Some(address_text.as_str())),
+                                    ("name", device.and_then(|d| d.name.as_deref())),
+                                    ("group", device.and_then(|d| d.group.as_deref())),
+                                ];
+                                let value = humanize::round_value(value.value, precision());
+                                if let Err(msg) = influxdb.add(&tags, metric.name(), value, ctx.now()) {
+                                    log::warn!("Writing to InfluxDB failed: {}", msg);
+                                }
+                            }
+                            if let Some(graphite) = &mut sinks.graphite {
+                                let path = graphite.path(&get_device_name(&config, address), metric.name());
+                                if let Err(msg) = graphite.send(&path, humanize::round_value(value.value, precision()), ctx.now()) {
+                                    log::warn!("Sending to Graphite failed: {}", msg);
+                                }
+                            }
+                            if let Some(webhooks) = &mut sinks.webhooks {
+                                let device = webhook::DeviceName {
+                                    address: address,
+                                    address_text: format_bluetooth_address(address),
+                                    name: config.get(&address).and_then(|d| d.name.clone()),
+                                };
+                                webhooks.reading(&device, metric, humanize::round_value(value.value, precision()), ctx.now());
+                            }
+
+                            // Update sensor data, and print the sensor value.
+                            let mut line = String::new();
                             if let AdvertisementKind::Temperature(_) = &data {
-                                spinner.println(format!("{} - {} {} 'C", name, TEMPERATURE, value.value));
-                                sensors.get_mut(&(value.address)).map(|val| val.set_temperature(value.value));
+                                line = format!("{} - {} {} 'C", name, TEMPERATURE, humanize::format_value(value.value, precision()));
+                                sensors.get_mut(&address).map(|val| val.set_temperature(value.value));
                             }
                             else if let AdvertisementKind::Humidity(_) = &data {
-                                spinner.println(format!("{} - {} {} %", name, HUMIDITY, value.value));
-                                sensors.get_mut(&(value.address)).map(|val| val.set_humidity(value.value));
+                                line = format!("{} - {} {} %", name, HUMIDITY, humanize::format_value(value.value, precision()));
+                                sensors.get_mut(&address).map(|val| val.set_humidity(value.value));
                             }
                             else if let AdvertisementKind::Battery(_) = &data {
-                                spinner.println(format!("{} - {} {} %", name, BATTERY, value.value));
-                                sensors.get_mut(&(value.address)).map(|val| val.set_battery(value.value));
+                                line = format!("{} - {} {} %", name, BATTERY, humanize::format_value(value.value, precision()));
+                                sensors.get_mut(&address).map(|val| val.set_battery(value.value));
+                            }
+
+                            if log::log_enabled!(log::Level::Info) {
+                                if let Some(interval) = intervals.get(&address).and_then(|i| i.estimate()) {
+                                    line = format!("{} (every ~{})", line, interval::format_interval(interval));
+                                }
+                            }
+
+                            // with hundreds of devices, every reading scrolls away too fast to read.
+                            // watch mode has them in the table.
+                            if watch {
+                                // nothing to print.
+                            }
+                            else if stream {
+                                let mut values = vec![(metric.name(), value.value)];
+                                // computed again whenever either of them changes.
+                                if dew_point && metric != Metric::Battery {
+                                    if let Some(sensor) = sensors.get(&address) {
+                                        values.extend(sensor.dew_point().map(|v| ("dew_point", v)));
+                                        values.extend(sensor.absolute_humidity().map(|v| ("absolute_humidity", v)));
+                                    }
+                                }
+                                // collectors read line by line. don't keep it in the buffer.
+                                let mut stdout = std::io::stdout().lock();
+                                for (metric, value) in values {
+                                    let reading = output::StreamReading {
+                                        time: ctx.now(),
+                                        address: format_bluetooth_address(address),
+                                        name: config.get(&address).and_then(|d| d.name.clone()),
+                                        metric: metric,
+                                        value: humanize::round_value(value, precision()),
+                                    };
+                                    writeln!(stdout, "{}", serde_json::to_string(&reading).unwrap()).ok();
+                                }
+                                stdout.flush().ok();
+                            }
+                            else if fleet {
+                                spinner.set_message(format!("Listening... {} devices", sensors.len()));
+                            } else {
+                                print_line(line);
+                            }
+                        },
+                        AdvertisementKind::Frame(frame) => {
+                            if let Some(product_id) = decoder::decode_product_id(&frame.data) {
+                                product_ids.insert(resolve_device_address(&config, frame.address), product_id);
+                            }
+                            intervals.entry(resolve_device_address(&config, frame.address)).or_default()
+                                .record(start_time.elapsed().as_millis() as u64);
+                            match decoder::decode_frame_counter(&frame.data) {
+                                Some(counter) => frame_counters.insert(frame.address, counter),
+                                None => frame_counters.remove(&frame.address),
+                            };
+
+                            // product id and frame counter are essential to diagnose unsupported devices.
+                            if let Some(metadata) = capture::describe_frame(&frame.data) {
+                                log::debug!("{} - {}", get_device_name(&config, frame.address), metadata);
+                            }
+
+                            if let Some(file) = &mut capture_file {
+                                let captured = CapturedFrame { time: ctx.now(), address: frame.address, data: frame.data.clone() };
+                                writeln!(file, "{}", capture::format_capture_line(&captured)).ok();
                             }
                         },
-                        _ => {}, // do nothing
                     }
                     return true;
                 }
@@ -274,123 +1783,2708 @@ fn scan(_verbose: bool) {
         };
 
         // Process transmitted messages
-        while start_time.elapsed() < time::Duration::from_secs(monitoring_period) {
+        while interrupted.is_none() && (watch || stream || start_time.elapsed() < time::Duration::from_secs(monitoring_period)) {
             process_data(time::Duration::from_millis(300));
+            if let Some(msg) = advertisement_watch.as_ref().and_then(|w| w.stopped()) {
+                interrupted = Some(msg);
+            }
+            else if stop_requested() {
+                if watch || stream {
+                    break;
+                }
+                interrupted = Some("Ctrl-C".to_string());
+            }
         }
 
         // stop listening to the BLE advertisement, and handle all received data.
-        watcher.RemoveReceived(token).ok();
-        watcher.Stop().expect("Stopping BLE watcher failed");
+        // a handler waiting for room in the queue returns. readings queued until now are handled.
+        bus.shutdown();
+        if let Some(advertisement_watch) = advertisement_watch {
+            advertisement_watch.stop();
+        }
         while process_data(time::Duration::from_millis(0)) {}
+        sinks.close();
 
-        spinner.println(format!("Stop monitoring BLE advertisement... {}", CHECKBOX));
+        if interrupted.is_none() {
+            print_line(format!("Stop monitoring BLE advertisement... {}", CHECKBOX));
+        }
         spinner.finish_and_clear();
-        drop(watcher);
-    }
-    drop(rx); // done using channel.
-
-    // This is for printing summary.
-    println!("Summary:");
-    let mut table = Table::new();
-    table.add_row(row!["Device ID", "Temp.", "Humidity %", "Battery %"]);
-    for (k, v) in sensors.iter() {
-        let device_name: String;
-        if let Some(d) = &config.get(k) {
-            if let Some(n) = &d.name {
-                device_name = n.clone();
-            } else {
-                device_name = format_bluetooth_address(*k);
+    }
+
+    let mut state = ctx.load_state();
+    let now = ctx.now();
+    // when it was heard, not when the scan ended. `status` tells how long a device is silent.
+    for (address, sensor) in &sensors {
+        state.record_seen(*address, sensor.last_seen.unwrap_or(now));
+    }
+    ctx.save_state(&state);
+
+    if let Some(url) = &sinks.push_url {
+        if let Err(msg) = prometheus::push(url, &prometheus::format_metrics(&device_metrics(&config, &sensors), now), sinks::HTTP_TIMEOUT) {
+            log::warn!("Pushing to Pushgateway failed: {}", msg);
+        }
+    }
+    if let Some(otlp) = &sinks.otlp {
+        let attributes = otlp::attributes(&[("scan.devices", Some(&sensors.len().to_string()))]);
+        let exported = otlp.export_metrics(otlp::reading_metrics(&device_metrics(&config, &sensors)))
+            .and_then(|_| otlp.export_span(otlp::run_span("scan", started, now, interrupted.as_deref(), attributes)));
+        if let Err(msg) = exported {
+            log::warn!("Exporting to OpenTelemetry failed: {}", msg);
+        }
+    }
+
+    // readings are already printed.
+    if stream {
+        if let Some(msg) = &interrupted {
+            ctx.note(&format!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg)));
+        }
+        return interrupted.is_none();
+    }
+
+    let mut devices = scan_devices(&config, &sensors, &product_ids, &intervals, formula);
+    output::sort_scan_devices(&mut devices, sort, descending);
+    if let Some(msg) = &interrupted {
+        ctx.note(&format!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg)));
+        ctx.note(&format!("{} Summary is partial, with devices seen until then.", EXCLAMATION));
+    }
+    match ctx.format {
+        OutputFormat::Table => {
+            println!("Summary: {} devices", devices.len());
+            for page in devices.chunks(page_size.unwrap_or(usize::MAX).max(1)) {
+                let mut header = vec!["Device ID", "Model", "Temp.", "Humidity %", "Battery %", "Interval", "RSSI", "Last seen",
+                    "Temp. min/avg/max", "Humidity min/avg/max"];
+                if dew_point {
+                    header.extend(["Dew point", "Abs. humidity g/m³"]);
+                }
+                if comfort {
+                    header.extend(["Feels like", "Comfort"]);
+                }
+                let mut table = theme().table(&header);
+                for device in page {
+                    let mut row = scan_row(device, Some(now));
+                    row.add_cell(prettytable::Cell::new(&format_range(device.temperature_min, device.temperature_average, device.temperature_max)));
+                    row.add_cell(prettytable::Cell::new(&format_range(device.humidity_min, device.humidity_average, device.humidity_max)));
+                    if dew_point {
+                        row.add_cell(prettytable::Cell::new(&format_computed(device.dew_point)));
+                        row.add_cell(prettytable::Cell::new(&format_computed(device.absolute_humidity)));
+                    }
+                    if comfort {
+                        row.add_cell(prettytable::Cell::new(&format_computed(device.apparent_temperature)));
+                        row.add_cell(prettytable::Cell::new(device.comfort.unwrap_or("-")));
+                    }
+                    table.add_row(row);
+                }
+                table.print_tty(true).ok();
             }
-        } else {
-            device_name = format_bluetooth_address(*k);
-        };
+        },
+        OutputFormat::Json => {
+            let report = output::ScanReport { time: ctx.now(), interrupted: interrupted.clone(), devices: devices };
+            println!("{}", serde_json::to_string(&report).unwrap());
+        },
+        OutputFormat::Csv => {
+            println!("{}", output::SCAN_CSV_HEADER);
+            for device in &devices {
+                println!("{}", device.to_csv());
+            }
+        },
+    }
+    return interrupted.is_none();
+}
 
-        table.add_row(row![
-            device_name,
-            v.temperature.map_or("-".to_string(), |vv| vv.to_string()),
-            v.humidity.map_or("-".to_string(), |vv| vv.to_string()),
-            v.battery.map_or("-".to_string(), |vv| vv.to_string())]
-        );
+// 'dashboard' command handler. same as `scan --watch`, but full screen.
+// returns false when the watcher stopped by itself.
+fn dashboard(ctx: &AppContext, groups: &[String]) -> bool {
+    let loaded = config_or_exit(ctx);
+    let selection = DeviceSelection { names: &[], groups: groups, exclude: &[] };
+    let filter = ctx.device_filter(&loaded, &selection, loaded.general.strict_scan.unwrap_or(false));
+    let mut board = xiaomi::dashboard::Dashboard::new(loaded.general.low_battery_percent.unwrap_or(20), loaded.general.precision);
+    let config = loaded.into_device_map();
+
+    // readings with the signal strength of the advertisement they came in.
+    let bus: EventBus<(AdvertisementKind, Option<i16>)> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let readings = bus.subscribe();
+    let publisher = bus.publisher();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        let rssi = ble::signal_strength(&args);
+        for value in ble::decode_advertisement(&args) {
+            publisher.publish((value, rssi));
+        }
+        Ok(())
+    };
+    let advertisement_watch = match ctx.watch_advertisements(on_received) {
+        Ok(advertisement_watch) => advertisement_watch,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            return false;
+        }
+    };
+
+    // raw mode turns Ctrl-C into a key press.
+    let mut terminal = ratatui::init();
+    let mut interrupted: Option<String> = None;
+    while interrupted.is_none() {
+        while let Ok((data, rssi)) = readings.try_recv() {
+            let (value, reading) = match &data {
+                AdvertisementKind::Temperature(value) => (value, Reading::Temperature(value.value)),
+                AdvertisementKind::Humidity(value) => (value, Reading::Humidity(value.value)),
+                AdvertisementKind::Battery(value) => (value, Reading::Battery(value.value)),
+                AdvertisementKind::Frame(frame) => {
+                    if let Some(product_id) = decoder::decode_product_id(&frame.data) {
+                        board.record_model(resolve_device_address(&config, frame.address), product_id);
+                    }
+                    continue;
+                },
+            };
+            let address = resolve_device_address(&config, value.address);
+            if filter.matches(address) {
+                board.record(address, &get_device_name(&config, address), reading, rssi, ctx.now());
+            }
+        }
+        terminal.draw(|frame| board.render(frame, theme(), ctx.now())).ok();
+
+        if let Some(msg) = advertisement_watch.stopped() {
+            interrupted = Some(msg);
+        }
+        else if event::poll(time::Duration::from_millis(250)).unwrap_or(false) {
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || key.code == KeyCode::Esc || ctrl_c) {
+                    break;
+                }
+            }
+        }
     }
-    table.print_tty(true).ok();
+    ratatui::restore();
+    // nothing reads readings anymore. a handler waiting for room in the queue returns.
+    bus.shutdown();
+    advertisement_watch.stop();
+
+    let mut state = ctx.load_state();
+    for (address, device) in &board.devices {
+        state.record_seen(*address, device.last_seen);
+    }
+    ctx.save_state(&state);
+
+    if let Some(msg) = &interrupted {
+        println!("{} Dashboard was interrupted: {}", EXCLAMATION, theme().error(msg));
+        return false;
+    }
+    return true;
 }
 
-struct SensorData {
-    temperature: Option<f32>,
-    humidity: Option<f32>,
-    battery: Option<f32>
+// shared by the HTTP threads of `serve`.
+struct ServeState {
+    ctx: AppContext,
+    // [serve] when serving started.
+    auth: auth::ServeConfig,
+    // config when serving started. names and groups of /metrics.
+    config: HashMap<u64, DeviceConfig>,
+    // latest readings of each device, keyed by the address identifying the logical device.
+    devices: Mutex<HashMap<u64, api::LatestReadings>>,
+    // charts of the dashboard. keyed the same way.
+    history: Mutex<HashMap<u64, api::History>>,
+    // syncs requested over the API.
+    syncs: Mutex<api::SyncJobs>,
+    // addresses of sensors heard, while a sync requested over the API listens.
+    heard: Mutex<Option<mpsc::Sender<u64>>>,
 }
 
-impl SensorData {
-    pub fn new() -> Self {
-        SensorData {humidity: None, temperature: None, battery: None}
+// addresses given on the command line.
+struct ServeEndpoints<'a> {
+    prometheus: Option<&'a str>,
+    api: Option<&'a str>,
+    web: Option<&'a str>,
+    perf_counters: bool,
+}
+
+// what a listener serves. endpoints given the same address share a listener.
+#[derive(Clone, Copy, Default)]
+struct Served {
+    metrics: bool,
+    api: bool,
+    web: bool,
+}
+
+// answers a request to an address serving any of metrics, the API and the dashboard.
+fn serve_request(state: &Arc<ServeState>, request: &http::Request, served: Served) -> http::Response {
+    if !auth::authorized(&state.auth, request.header("authorization")) {
+        return http::Response::unauthorized(auth::challenge(&state.auth));
+    }
+    if served.web && request.path == "/" {
+        if request.method != "GET" {
+            return http::Response::method_not_allowed();
+        }
+        return http::Response::new(200, web::CONTENT_TYPE, web::PAGE);
+    }
+    if served.metrics && request.path == "/metrics" {
+        if request.method != "GET" {
+            return http::Response::method_not_allowed();
+        }
+        let mut samples: Vec<prometheus::DeviceMetrics> = state.devices.lock().unwrap().iter().map(|(address, readings)| prometheus::DeviceMetrics {
+            address: format_bluetooth_address(*address),
+            name: state.config.get(address).and_then(|d| d.name.clone()),
+            group: state.config.get(address).and_then(|d| d.group.clone()),
+            temperature: readings.get(Metric::Temperature),
+            humidity: readings.get(Metric::Humidity),
+            battery: readings.get(Metric::Battery),
+            last_seen: readings.last_seen(),
+        }).collect();
+        samples.sort_by(|a, b| a.address.cmp(&b.address));
+        return http::Response::new(200, prometheus::CONTENT_TYPE, prometheus::format_metrics(&samples, state.ctx.now()));
+    }
+    // the dashboard reads with the GET routes of the API. syncing is for --api only.
+    let route = match api::route(&request.path) {
+        Some(api::Route::Sync(_)) if !served.api => return http::Response::not_found(),
+        Some(route) if served.api || served.web => route,
+        _ => return http::Response::not_found(),
+    };
+    let method = if let api::Route::Sync(_) = route { "POST" } else { "GET" };
+    if request.method != method {
+        return api::error(405, &format!("Use {} for {}", method, request.path));
     }
+    // the config `serve` started with.
+    let loaded = match state.ctx.load_config() {
+        Ok(loaded) => loaded,
+        Err(msg) => return api::error(500, &msg),
+    };
+    return match route {
+        api::Route::Devices => {
+            let devices = api::devices(&loaded, &state.devices.lock().unwrap(), &state.ctx.load_state());
+            api::json(200, &devices)
+        },
+        api::Route::Readings(name) => match loaded.resolve_device(&name) {
+            Ok(address) => {
+                let readings = state.devices.lock().unwrap().get(&address).map(|r| r.readings()).unwrap_or_default();
+                api::json(200, &readings)
+            },
+            Err(msg) => api::error(404, &msg),
+        },
+        api::Route::History(name) => match loaded.resolve_device(&name) {
+            Ok(address) => {
+                let readings = state.history.lock().unwrap().get(&address).map(|h| h.readings()).unwrap_or_default();
+                api::json(200, &readings)
+            },
+            Err(msg) => api::error(404, &msg),
+        },
+        api::Route::Sync(name) => {
+            let filter = match loaded.device_filter(std::slice::from_ref(&name), &[], &[]) {
+                Ok(filter) => filter,
+                Err(msg) => return api::error(404, &msg),
+            };
+            let sinks = match state.ctx.open_sinks(&loaded, "sync") {
+                Ok(sinks) => sinks,
+                Err(msg) => return api::error(500, &msg),
+            };
+            let job = match state.syncs.lock().unwrap().start(&name, state.ctx.now()) {
+                Some(job) => job,
+                None => return api::error(409, "A sync is already running. Try again when it ended."),
+            };
+            // answered at once. the run listens for sync_duration_seconds, GET /syncs/{id} tells when it's done.
+            let shared = state.clone();
+            let id = job.id;
+            thread::spawn(move || {
+                let report = sync_job(&shared, loaded, sinks, &filter);
+                shared.syncs.lock().unwrap().finish(id, report);
+            });
+            api::sync_started(&job)
+        },
+        api::Route::SyncJob(id) => match state.syncs.lock().unwrap().get(id) {
+            Some(job) => api::json(200, job),
+            None => api::error(404, &format!("Unknown sync {}. Only the last ones are kept.", id)),
+        },
+    };
+}
 
-    pub fn set_temperature(&mut self, value: f32) {
-        self.temperature = Some(value);
+// a sync requested over the API, run in the background. devices are selected from advertisements `serve` hears,
+// instead of a watcher of its own. nothing is printed, the summary is the answer of GET /syncs/{id}.
+fn sync_job(state: &ServeState, loaded: Config, sinks: Sinks, filter: &DeviceFilter) -> webhook::SyncReport {
+    let mut filter = filter.clone();
+    if loaded.general.strict.unwrap_or(false) {
+        filter.restrict(&loaded.configured_addresses());
     }
+    let monitoring_period = loaded.general.sync_duration_seconds.unwrap_or(30);
+    let overrides = SyncOverrides { duration: None, timezone: None, offset_seconds: None, rssi_min: None, check_only: HashSet::new() };
+    let options = sync_options(&loaded.general, false, false, &overrides);
+    let worker_count = loaded.general.sync_workers.unwrap_or(2);
+    let started = state.ctx.now();
+    let expected: Vec<DeviceConfig> = loaded.devices.iter().flatten().filter(|d| filter.matches(d.address)).cloned().collect();
+    let mut tally = SyncTally::default();
+    let mut sync_state = state.ctx.load_state();
+    let config: Arc<Mutex<HashMap<u64, DeviceConfig>>> = Arc::new(Mutex::new(loaded.into_device_map()));
+    let devices: Arc<Mutex<HashSet<u64>>> = Arc::new(Mutex::new(HashSet::new()));
+    let bus: EventBus<ble::SyncLogKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let logs = bus.subscribe();
+    let publisher = bus.publisher();
 
-    pub fn set_humidity(&mut self, value: f32) {
-        self.humidity = Some(value);
+    let config_clone = config.clone();
+    let devices_clone = devices.clone();
+    let publisher_clone = publisher.clone();
+    let workers: WorkerPool<u64> = WorkerPool::new(worker_count, move |address| {
+        if !stop_requested() {
+            ble::sync_device(&config_clone, &devices_clone, &options, &publisher_clone, address);
+        }
+    });
+    let queue = workers.queue();
+    let (tx, heard) = mpsc::channel();
+    *state.heard.lock().unwrap() = Some(tx);
+
+    let mut process_data = |wait: time::Duration| -> bool {
+        return match logs.recv_timeout(wait) {
+            Ok(data) => {
+                let device_address = resolve_device_address(&config.lock().unwrap(), data.address());
+                tally.record(&mut sync_state, device_address, &data, state.ctx.now());
+                true
+            },
+            Err(_) => false,
+        };
+    };
+    let start_time = time::Instant::now();
+    while start_time.elapsed() < time::Duration::from_secs(monitoring_period) && !stop_requested() {
+        while let Ok(address) = heard.try_recv() {
+            if let Some(address) = ble::select_device(&config, &filter, &devices, &publisher, address) {
+                queue.push(address);
+            }
+        }
+        process_data(time::Duration::from_millis(300));
     }
 
-    pub fn set_battery(&mut self, value: f32) {
-        self.battery = Some(value);
+    // stop listening, then workers finish devices already queued.
+    state.heard.lock().unwrap().take();
+    workers.close();
+    while !workers.is_finished() {
+        process_data(time::Duration::from_millis(100));
     }
+    workers.join();
+    bus.shutdown();
+    while process_data(time::Duration::from_millis(0)) {}
+    state.ctx.save_state(&sync_state);
+
+    let report = tally.report(&config.lock().unwrap(), &expected, state.ctx.now());
+    send_sync_report(&report, sinks, started);
+    return report;
 }
 
-fn check_config() {
-    // get exe name of this process.
-    let exe_path = std::env::current_exe().unwrap();
-    let toml_name = std::path::Path::new(&exe_path).with_extension("toml");
+// advertises listeners reachable from other computers as _xiaomi-bridge._tcp. TXT says what each one serves:
+//  version=1.0.0 auth=bearer api=/devices metrics=/metrics dashboard=/
+// and tls=1 when they're https.
+// None when there's nothing to advertise, or it failed. serving goes on without it.
+fn start_mdns(auth_config: &auth::ServeConfig, listeners: &[(SocketAddr, Served)]) -> Option<mdns::Advertiser> {
+    let host = mdns::host_label(&std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).unwrap_or_default());
+    let mut services: Vec<mdns::Service> = Vec::new();
+    for (address, served) in listeners {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) if ip.is_loopback() => continue,
+            IpAddr::V4(ip) if ip.is_unspecified() => match mdns::local_address() {
+                Ok(ip) => ip,
+                Err(msg) => {
+                    println!("{} Not advertised with mDNS: {}", EXCLAMATION, theme().warning(msg));
+                    return None;
+                }
+            },
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => continue,
+        };
+        let mut txt = vec![format!("version={}", env!("CARGO_PKG_VERSION")), format!("auth={}", auth::schemes(auth_config))];
+        if auth_config.is_tls() {
+            txt.push("tls=1".to_string());
+        }
+        if served.api {
+            txt.push("api=/devices".to_string());
+        }
+        if served.metrics {
+            txt.push("metrics=/metrics".to_string());
+        }
+        if served.web {
+            txt.push("dashboard=/".to_string());
+        }
+        // instance names are unique on the network. the port tells listeners of this computer apart.
+        let instance = if listeners.len() > 1 { format!("xiaomi on {} ({})", host, address.port()) } else { format!("xiaomi on {}", host) };
+        services.push(mdns::Service { instance: instance, host: host.clone(), address: ip, port: address.port(), txt: txt });
+    }
+    if services.is_empty() {
+        return None;
+    }
+    return match mdns::Advertiser::start(services) {
+        Ok(advertiser) => {
+            println!("Advertising as {} on the local network {}", mdns::SERVICE_TYPE, CHECKBOX);
+            Some(advertiser)
+        },
+        Err(msg) => {
+            println!("{} Not advertised with mDNS: {}", EXCLAMATION, theme().warning(msg));
+            None
+        }
+    };
+}
 
-    if !toml_name.exists() {
-        eprintln!("{} Cannot find toml at {}", style("ERROR:").red(), toml_name.to_str().unwrap());
-        eprintln!("Exe path: {:?}", exe_path);
-        return;
+// 'serve' command handler. scans until Ctrl-C, while requests are answered from other threads.
+// returns false when the watcher stopped by itself.
+fn serve(ctx: &AppContext, endpoints: &ServeEndpoints, advertise: bool, gateway: bool, groups: &[String]) -> bool {
+    let loaded = config_or_exit(ctx);
+    let selection = DeviceSelection { names: &[], groups: groups, exclude: &[] };
+    let filter = ctx.device_filter(&loaded, &selection, loaded.general.strict_scan.unwrap_or(false));
+    let rssi_min = loaded.general.rssi_min;
+    if let Err(msg) = auth::check_config(&loaded.serve) {
+        eprintln!("{} {}", theme().error("ERROR:"), msg);
+        std::process::exit(1);
     }
-    println!("toml path: {}", style(toml_name.to_str().unwrap()).green());
+    // a certificate which can't be read stops serving before listening, rather than falling back to http.
+    let config_dir = Config::default_path().ok().and_then(|path| path.parent().map(|dir| dir.to_path_buf())).unwrap_or_default();
+    let tls = loaded.serve.tls_paths(&config_dir).map(|(cert, key)| {
+        http::load_tls_config(&cert, &key).unwrap_or_else(|msg| {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            std::process::exit(1);
+        })
+    });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut sinks = sinks_or_exit(ctx, &loaded, "serve");
+    let mut perf_counters = endpoints.perf_counters.then(start_perf_counters);
+    if perf_counters.is_some() {
+        println!("Setting performance counters {}", CHECKBOX);
+    }
+    // a broker which can't be reached fails serving before listening, like `scan --mqtt`.
+    let subscriber = gateway.then(|| {
+        mqtt::Subscriber::open(&loaded.mqtt, sinks::MQTT_TIMEOUT).unwrap_or_else(|msg| {
+            eprintln!("{} Subscribing to MQTT broker failed: {}", theme().error("ERROR:"), msg);
+            std::process::exit(1);
+        })
+    });
+    let state = Arc::new(ServeState {
+        ctx: ctx.with_format(ctx.format),
+        auth: loaded.serve.clone(),
+        config: loaded.into_device_map(),
+        devices: Mutex::new(HashMap::new()),
+        history: Mutex::new(HashMap::new()),
+        syncs: Mutex::new(api::SyncJobs::default()),
+        heard: Mutex::new(None),
+    });
 
-    let content = std::fs::read_to_string(toml_name).unwrap();
-    let config: Config = toml::from_str(&content).unwrap();
+    let host = state.auth.bind_host();
+    let mut listeners: Vec<(String, Served)> = Vec::new();
+    let mut add_listener = |address: Option<&str>, serve: fn(&mut Served)| {
+        if let Some(address) = address.map(|address| http::listen_address(address, &host)) {
+            match listeners.iter_mut().find(|(a, _)| *a == address) {
+                Some((_, served)) => serve(served),
+                None => {
+                    let mut served = Served::default();
+                    serve(&mut served);
+                    listeners.push((address, served));
+                }
+            }
+        }
+    };
+    add_listener(endpoints.prometheus, |served| served.metrics = true);
+    add_listener(endpoints.api, |served| served.api = true);
+    add_listener(endpoints.web, |served| served.web = true);
+    let mut started: Vec<(SocketAddr, Served)> = Vec::new();
+    for (address, served) in listeners {
+        // checked before listening, so nothing is served to other computers without asking who they are.
+        let resolved = address.to_socket_addrs().map_err(|e| format!("Listening on {} failed: {}", address, e));
+        if let Err(msg) = resolved.and_then(|mut addresses| addresses.try_for_each(|a| auth::check_listen(&state.auth, a.ip()))) {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            std::process::exit(1);
+        }
+        let shared = state.clone();
+        let handler = move |request: &http::Request| serve_request(&shared, request, served);
+        match http::start(&address, tls.clone(), Arc::new(handler)) {
+            Ok(local_address) => {
+                if served.metrics {
+                    println!("Serving Prometheus metrics at {}://{}/metrics {}", scheme, local_address, CHECKBOX);
+                }
+                if served.api {
+                    println!("Serving REST API at {}://{}/devices {}", scheme, local_address, CHECKBOX);
+                }
+                if served.web {
+                    println!("Serving dashboard at {}://{}/ {}", scheme, local_address, CHECKBOX);
+                }
+                started.push((local_address, served));
+            },
+            Err(msg) => {
+                eprintln!("{} {}", theme().error("ERROR:"), msg);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    {
-        if let Some(devices) = config.devices {
-            println!("Configuration:");
-            let mut table = Table::new();
-            table.add_row(row!["Address", "Name", "Omit", "Timezone", "Offset_Seconds"]);
-            for device in devices {
-                table.add_row(row![
-                    format_bluetooth_address(device.address),
-                    device.name.map_or("-".to_string(), |vv| vv.to_string()),
-                    device.omit.map_or("-".to_string(), |vv| vv.to_string()),
-                    device.timezone.map_or("-".to_string(), |vv| vv.to_string()),
-                    device.offset_seconds.map_or("-".to_string(), |vv| vv.to_string()),
-                ]);
+    if let Some(mut subscriber) = subscriber {
+        println!("Merging readings of other computers from MQTT broker {}", CHECKBOX);
+        let shared = state.clone();
+        let filter = filter.clone();
+        // blocks in receive for MQTT_TIMEOUT at most, then sees Ctrl-C.
+        thread::spawn(move || {
+            while !stop_requested() {
+                match subscriber.receive() {
+                    Ok(Some((remote, value))) => record_remote_reading(&shared, &filter, &remote, value),
+                    Ok(None) => {},
+                    Err(msg) => log::warn!("MQTT broker is gone: {}", msg),
+                }
             }
-            table.print_tty(true).ok();
+            subscriber.close();
+        });
+    }
+
+    let bus: EventBus<AdvertisementKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let readings = bus.subscribe();
+    let publisher = bus.publisher();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        if ble::is_weak_signal(&args, rssi_min) {
+            return Ok(());
         }
-        else {
-            println!("{} No {} defined in toml.", EXCLAMATION, style("[[device]]").yellow());
+        for value in ble::decode_advertisement(&args) {
+            publisher.publish(value);
+        }
+        Ok(())
+    };
+    let advertisement_watch = match ctx.watch_advertisements(on_received) {
+        Ok(advertisement_watch) => advertisement_watch,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            return false;
+        }
+    };
+    let advertiser = if advertise { start_mdns(&state.auth, &started) } else { None };
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+
+    let mut interrupted: Option<String> = None;
+    while !stop_requested() {
+        spinner.set_message(format!("Listening... {} devices. Ctrl-C to stop", state.devices.lock().unwrap().len()));
+        while let Ok(data) = readings.try_recv() {
+            if let (Some(heard), Some(address)) = (state.heard.lock().unwrap().as_ref(), ble::sensor_address(&data)) {
+                heard.send(address).ok();
+            }
+            let (metric, value) = match &data {
+                AdvertisementKind::Temperature(value) => (Metric::Temperature, value),
+                AdvertisementKind::Humidity(value) => (Metric::Humidity, value),
+                AdvertisementKind::Battery(value) => (Metric::Battery, value),
+                AdvertisementKind::Frame(_) => continue,
+            };
+            let address = resolve_device_address(&state.config, value.address);
+            if !filter.matches(address) {
+                continue;
+            }
+            let reading = humanize::round_value(value.value, precision());
+            let now = ctx.now();
+            state.devices.lock().unwrap().entry(address).or_default().record(metric, reading, now);
+            state.history.lock().unwrap().entry(address).or_default().record(metric, reading, now);
+            if let Some(perf_counters) = &mut perf_counters {
+                let name = state.config.get(&address).and_then(|d| d.name.clone()).unwrap_or_else(|| format_bluetooth_address(address));
+                perf_counters.set(address, &name, metric, reading);
+            }
+            if let Some(webhooks) = &mut sinks.webhooks {
+                let device = webhook::DeviceName {
+                    address: address,
+                    address_text: format_bluetooth_address(address),
+                    name: state.config.get(&address).and_then(|d| d.name.clone()),
+                };
+                webhooks.reading(&device, metric, reading, now);
+            }
         }
+        if let Some(msg) = advertisement_watch.stopped() {
+            interrupted = Some(msg);
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(250));
+    }
+    // a sync requested over the API stops listening on Ctrl-C too. its state is saved before ours.
+    while state.syncs.lock().unwrap().is_running() {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    spinner.finish_and_clear();
+    bus.shutdown();
+    advertisement_watch.stop();
+    if let Some(advertiser) = advertiser {
+        advertiser.stop();
     }
-}
 
-fn load_config() -> HashMap<u64, DeviceConfig> {
-    // get exe name of this process.
-    let exe_path = std::env::current_exe().unwrap();
-    let toml_name = std::path::Path::new(&exe_path).with_extension("toml");
+    let mut state_file = ctx.load_state();
+    for (address, readings) in state.devices.lock().unwrap().iter() {
+        if let Some(last_seen) = readings.last_seen() {
+            state_file.record_seen(*address, last_seen);
+        }
+    }
+    ctx.save_state(&state_file);
 
-    if !toml_name.exists() {
-        return HashMap::new();
+    if let Some(msg) = &interrupted {
+        println!("{} Serving was interrupted: {}", EXCLAMATION, theme().error(msg));
+        return false;
     }
+    return true;
+}
 
-    let content = std::fs::read_to_string(toml_name).unwrap();
-    let decoded: Config = toml::from_str(&content).unwrap();
-    let mut config: HashMap<u64, DeviceConfig> = HashMap::new();
-    for c in decoded.devices.unwrap() {
-        config.insert(c.address, c);
+// a reading of `serve --gateway`, published by another computer. it's merged like one heard here.
+// devices are known by the name in toml, or the address in the topic.
+fn record_remote_reading(state: &ServeState, filter: &DeviceFilter, remote: &mqtt::RemoteTopic, value: f32) {
+    let address = match state.ctx.load_config().and_then(|loaded| loaded.resolve_device(&remote.device)) {
+        Ok(address) => address,
+        Err(msg) => {
+            log::debug!("Skipping reading of {}: {}", remote.device, msg);
+            return;
+        }
+    };
+    let metric = match Metric::from_name(&remote.metric) {
+        Some(metric) => metric,
+        None => return,
+    };
+    if !filter.matches(address) {
+        return;
     }
+    let reading = humanize::round_value(value, precision());
+    let now = state.ctx.now();
+    state.devices.lock().unwrap().entry(address).or_default().record(metric, reading, now);
+    state.history.lock().unwrap().entry(address).or_default().record(metric, reading, now);
+}
 
-    return config;
+// 'soak' command handler. returns false when a check failed.
+fn soak(ctx: &AppContext, hours: f64, memory_mb: u64) -> bool {
+    let limits = soak::SoakLimits {
+        memory_ceiling_bytes: memory_mb * 1024 * 1024,
+        // the queue is bounded. reaching the capacity means BLE handlers were kept waiting.
+        max_queue_depth: EVENT_QUEUE_CAPACITY / 2,
+        stuck_seconds: 300,
+    };
+    let mut monitor = soak::SoakMonitor::new(limits);
+    let duration = time::Duration::from_secs_f64(hours.max(0.0) * 3600.0);
+
+    // panics of any thread are recorded. they're still printed by the default hook.
+    let panics: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let panics_clone = panics.clone();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Ok(mut panics) = panics_clone.lock() {
+            panics.push(info.to_string());
+        }
+        default_hook(info);
+    }));
+
+    let bus: EventBus<AdvertisementKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let readings = bus.subscribe();
+    // the watcher is started again when it stops by itself, so the rest of the run is still checked.
+    let start_watch = || {
+        let publisher = bus.publisher();
+        return ctx.watch_advertisements(move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+            for value in ble::decode_advertisement(&args) {
+                publisher.publish(value);
+            }
+            Ok(())
+        });
+    };
+    let mut advertisement_watch = match start_watch() {
+        Ok(advertisement_watch) => Some(advertisement_watch),
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            return false;
+        }
+    };
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+    spinner.print_above(format!("Soak test for {}... {}", humanize::format_duration(duration.as_secs()), CHECKBOX));
+    let start_time = time::Instant::now();
+    let mut last_advertisement = time::Instant::now();
+    let mut last_sample = time::Instant::now();
+    let mut count: u64 = 0;
+    while start_time.elapsed() < duration && !stop_requested() {
+        if readings.recv_timeout(time::Duration::from_millis(100)).is_ok() {
+            count += 1;
+            last_advertisement = time::Instant::now();
+        }
+        if last_sample.elapsed() < time::Duration::from_secs(1) {
+            continue;
+        }
+        last_sample = time::Instant::now();
+
+        if let Some(msg) = advertisement_watch.as_ref().and_then(|w| w.stopped()) {
+            spinner.print_above(format!("{} Watcher stopped: {}", EXCLAMATION, theme().error(&msg)));
+            monitor.record_watcher_stop(&msg);
+            advertisement_watch.take().map(|w| w.stop());
+        }
+        if advertisement_watch.is_none() {
+            advertisement_watch = start_watch().ok();
+        }
+        let sample = soak::SoakSample {
+            memory_bytes: working_set_bytes(),
+            queue_depth: readings.pending(),
+            seconds_since_advertisement: last_advertisement.elapsed().as_secs(),
+        };
+        monitor.record(&sample);
+        spinner.set_message(format!("{} of {}, {} advertisements, memory {}, queue {}",
+            humanize::format_duration(start_time.elapsed().as_secs()),
+            humanize::format_duration(duration.as_secs()),
+            count,
+            sample.memory_bytes.map_or("-".to_string(), |bytes| format!("{} MB", bytes / 1024 / 1024)),
+            sample.queue_depth));
+    }
+    bus.shutdown();
+    if let Some(advertisement_watch) = advertisement_watch {
+        advertisement_watch.stop();
+    }
+    spinner.finish_and_clear();
+
+    for panic in panics.lock().unwrap().iter() {
+        monitor.record_panic(panic);
+    }
+    let mut table = theme().table(&["Check", "Result", "Detail"]);
+    for check in monitor.checks() {
+        let result = if check.passed { theme().ok("pass").to_string() } else { theme().error("FAIL").to_string() };
+        table.add_row(row![check.name, result, check.detail]);
+    }
+    table.print_tty(true).ok();
+    if start_time.elapsed() < duration {
+        println!("{} Stopped after {}, the report is partial.", EXCLAMATION, humanize::format_duration(start_time.elapsed().as_secs()));
+    }
+    if !monitor.passed() {
+        println!("{} {}", EXCLAMATION, theme().error("Soak test failed"));
+        return false;
+    }
+    println!("Soak test passed {}", CHECKBOX);
+    return true;
+}
+
+// working set of this process. what Task Manager shows as memory.
+fn working_set_bytes() -> Option<u64> {
+    let mut counters = PROCESS_MEMORY_COUNTERS::default();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    let result = unsafe { GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) };
+    if !result.as_bool() {
+        return None;
+    }
+    return Some(counters.WorkingSetSize as u64);
+}
+
+// devices of the scan summary. Sorted by name, so the same device is found at the same place.
+fn scan_devices(config: &HashMap<u64, DeviceConfig>, sensors: &HashMap<u64, SensorData>, product_ids: &HashMap<u64, u16>,
+    intervals: &HashMap<u64, IntervalEstimator>, formula: climate::ApparentFormula) -> Vec<output::ScanDevice> {
+    let mut devices: Vec<(String, output::ScanDevice)> = sensors.iter()
+        .map(|(k, v)| (get_device_name(config, *k),
+            v.to_scan_device(*k, config, product_ids.get(k).copied(), intervals.get(k).and_then(|i| i.estimate()), formula)))
+        .collect();
+    devices.sort_by(|a, b| a.0.cmp(&b.0));
+    return devices.into_iter().map(|(_, device)| device).collect();
+}
+
+// row of the scan summary. with `now`, how long ago the device was heard is added.
+fn scan_row(device: &output::ScanDevice, now: Option<u64>) -> prettytable::Row {
+    let known_model = device.model.as_deref().and_then(model::find_model_by_name);
+    let mut row = row![
+        device.name.clone().unwrap_or(device.address.clone()),
+        device.model.clone().unwrap_or("-".to_string()),
+        model::format_metric(device.temperature, known_model, Metric::Temperature, precision()),
+        model::format_metric(device.humidity, known_model, Metric::Humidity, precision()),
+        model::format_metric(device.battery, known_model, Metric::Battery, precision()),
+        device.interval_milliseconds.map_or("-".to_string(), interval::format_interval),
+        device.rssi.map_or("-".to_string(), |rssi| rssi.to_string())];
+    if let Some(now) = now {
+        row.add_cell(prettytable::Cell::new(&device.last_seen.map_or("-".to_string(), |t| humanize::format_age(now.saturating_sub(t)))));
+    }
+    return row;
+}
+
+// "21.5 / 22.1 / 23" over a scan. "-" when nothing was heard.
+fn format_range(min: Option<f32>, average: Option<f32>, max: Option<f32>) -> String {
+    return match (min, average, max) {
+        (Some(min), Some(average), Some(max)) => format!("{} / {} / {}",
+            humanize::format_value(min, precision()), format_computed(Some(average)), humanize::format_value(max, precision())),
+        _ => "-".to_string(),
+    };
+}
+
+// averages and values computed from readings have many digits. one is shown without precision set.
+fn format_computed(value: Option<f32>) -> String {
+    return value.map_or("-".to_string(), |v| humanize::format_value(v, precision().or(Some(1))));
+}
+
+struct SensorData {
+    temperature: Option<f32>,
+    humidity: Option<f32>,
+    battery: Option<f32>,
+    // unix epoch of the last reading.
+    last_seen: Option<u64>,
+    // of every reading since the device was first heard.
+    temperature_summary: Option<store::Summary>,
+    humidity_summary: Option<store::Summary>,
+    // signal strength of the last advertisement, in dBm.
+    rssi: Option<i16>,
+}
+
+impl SensorData {
+    pub fn new() -> Self {
+        SensorData {humidity: None, temperature: None, battery: None, last_seen: None, temperature_summary: None, humidity_summary: None, rssi: None}
+    }
+
+    pub fn set_seen(&mut self, time: u64) {
+        self.last_seen = Some(time);
+    }
+
+    pub fn set_rssi(&mut self, rssi: i16) {
+        self.rssi = Some(rssi);
+    }
+
+    pub fn set_temperature(&mut self, value: f32) {
+        self.temperature = Some(value);
+        self.temperature_summary = Some(store::Summary::add(self.temperature_summary.take(), value));
+    }
+
+    pub fn set_humidity(&mut self, value: f32) {
+        self.humidity = Some(value);
+        self.humidity_summary = Some(store::Summary::add(self.humidity_summary.take(), value));
+    }
+
+    // None until both temperature and humidity are heard.
+    pub fn dew_point(&self) -> Option<f32> {
+        return climate::dew_point(self.temperature?, self.humidity?);
+    }
+
+    pub fn absolute_humidity(&self) -> Option<f32> {
+        return Some(climate::absolute_humidity(self.temperature?, self.humidity?));
+    }
+
+    // the same device as `scan` prints it.
+    pub fn to_scan_device(&self, address: u64, config: &HashMap<u64, DeviceConfig>, product_id: Option<u16>,
+        interval_milliseconds: Option<u64>, formula: climate::ApparentFormula) -> output::ScanDevice {
+        let temperature = self.temperature_summary.as_ref();
+        let humidity = self.humidity_summary.as_ref();
+        // JSON and CSV have as many digits as the table.
+        let round = |value: Option<f32>| value.map(|v| humanize::round_value(v, precision()));
+        return output::ScanDevice {
+            address: format_bluetooth_address(address),
+            name: config.get(&address).and_then(|d| d.name.clone()),
+            model: product_id.map(model::format_model),
+            temperature: round(self.temperature),
+            humidity: round(self.humidity),
+            battery: round(self.battery),
+            interval_milliseconds: interval_milliseconds,
+            last_seen: self.last_seen,
+            rssi: self.rssi,
+            temperature_min: round(temperature.map(|s| s.min)),
+            temperature_max: round(temperature.map(|s| s.max)),
+            temperature_average: round(temperature.map(|s| s.average)),
+            humidity_min: round(humidity.map(|s| s.min)),
+            humidity_max: round(humidity.map(|s| s.max)),
+            humidity_average: round(humidity.map(|s| s.average)),
+            dew_point: round(self.dew_point()),
+            absolute_humidity: round(self.absolute_humidity()),
+            apparent_temperature: round(self.temperature.zip(self.humidity).map(|(t, h)| climate::apparent_temperature(formula, t, h))),
+            comfort: self.humidity.map(|h| climate::comfort(h).name()),
+        };
+    }
+
+    pub fn set_battery(&mut self, value: f32) {
+        self.battery = Some(value);
+    }
+}
+
+// the device might be using one of its aliases now.
+fn read_device_time(device: &DeviceConfig, timeout: time::Duration) -> Result<ClockTime, String> {
+    let mut result: Result<ClockTime, String> = Err("No address".to_string());
+    for address in std::iter::once(device.address).chain(device.addresses.iter().copied()) {
+        result = ble::read_device_time(address, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+    return result;
+}
+
+// 'get-time' command handler. prints the time characteristic as it is.
+fn get_time(ctx: &AppContext, device: &Option<String>) {
+    let loaded = config_or_exit(ctx);
+    let timeout = loaded.general.gatt_timeout();
+    let devices: Vec<DeviceConfig> = match device {
+        Some(name) => match loaded.resolve_device(name) {
+            Ok(address) => {
+                let configured = loaded.devices.iter().flatten().find(|d| d.address == address).cloned();
+                vec![configured.unwrap_or(DeviceConfig { address: address, ..Default::default() })]
+            },
+            Err(msg) => {
+                println!("{} {}", EXCLAMATION, theme().warning(msg));
+                return;
+            }
+        },
+        None => loaded.devices.unwrap_or_default(),
+    };
+    if devices.is_empty() {
+        println!("{} No {} defined in toml.", EXCLAMATION, theme().warning("[[device]]"));
+        return;
+    }
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{msg}")));
+
+    let mut table = theme().table(&["Device ID", "Epoch", "Local time", "Timezone byte"]);
+    for device in &devices {
+        let name = device.name.clone().unwrap_or(format_bluetooth_address(device.address));
+        spinner.set_message(format!("Reading {}...", name));
+
+        match read_device_time(device, timeout) {
+            Ok(device_time) => {
+                table.add_row(row![
+                    name,
+                    device_time.epoch,
+                    device_time.format_local(),
+                    format!("0x{:02X} ({:+})", device_time.timezone as u8, device_time.timezone),
+                ]);
+            },
+            Err(msg) => {
+                table.add_row(row![name, theme().error(msg), "-", "-"]);
+            }
+        }
+    }
+    spinner.finish_and_clear();
+    table.print_tty(true).ok();
+}
+
+// 'drift' command handler. Nothing is written to devices.
+fn drift(ctx: &AppContext) {
+    let loaded = config_or_exit(ctx);
+    let timeout = loaded.general.gatt_timeout();
+    let timezone_policy = loaded.general.timezone_policy.unwrap_or_default();
+    let clock = get_time_provider(&loaded.general);
+    let devices = loaded.devices.unwrap_or_default();
+    if devices.is_empty() {
+        println!("{} No {} defined in toml.", EXCLAMATION, theme().warning("[[device]]"));
+        return;
+    }
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{msg}")));
+
+    let mut table = theme().table(&["Device ID", "Device time", "Timezone", "Drift"]);
+    for device in &devices {
+        let name = device.name.clone().unwrap_or(format_bluetooth_address(device.address));
+        spinner.set_message(format!("Reading {}...", name));
+
+        let timezone_hour = match device.get_timezone_hour(timezone_policy) {
+            Ok(hour) => hour,
+            Err(msg) => {
+                table.add_row(row![name, theme().error(msg), "-", "-"]);
+                continue;
+            }
+        };
+
+        match read_device_time(device, timeout) {
+            Ok(device_time) => {
+                let target = target_clock_time(clock.now(), timezone_hour, device.offset_seconds);
+                let mut timezone = format!("{:+}", device_time.timezone);
+                if device_time.timezone != target.timezone {
+                    timezone = format!("{} (expected {:+})", timezone, target.timezone);
+                }
+
+                table.add_row(row![
+                    name,
+                    device_time.format_local(),
+                    timezone,
+                    humanize::format_signed_duration(device_time.drift_seconds(&target)),
+                ]);
+            },
+            Err(msg) => {
+                table.add_row(row![name, theme().error(msg), "-", "-"]);
+            }
+        }
+    }
+    spinner.finish_and_clear();
+    table.print_tty(true).ok();
+}
+
+// time provider by `time_source`. NTP falls back to the system clock with a warning.
+fn get_time_provider(general: &GeneralConfig) -> Arc<dyn TimeProvider> {
+    let (clock, error) = timesource::from_config(general, time::Duration::from_secs(3));
+    if let Some(msg) = error {
+        println!("{} NTP failed, using system clock: {}", EXCLAMATION, theme().warning(msg));
+    }
+    else if general.time_source.is_some() {
+        println!("Time source: {} {}", clock.describe(), CHECKBOX);
+    }
+    return clock;
+}
+
+// 'set-time' command handler. returns false when writing failed.
+fn set_time(ctx: &AppContext, device: &str, epoch: u32, timezone_hour: Option<i8>) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timezone_policy = loaded.general.timezone_policy.unwrap_or_default();
+    let timeout = loaded.general.gatt_timeout();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    let mut timezone = clock::DEFAULT_TIMEZONE;
+    if let Some(hour) = timezone_hour {
+        timezone = hour;
+    }
+    else if let Some(device) = config.get(&address) {
+        match device.get_timezone_hour(timezone_policy) {
+            Ok(hour) => timezone = hour.unwrap_or(timezone),
+            Err(msg) => {
+                println!("{} {}: {}", EXCLAMATION, name, theme().warning(msg));
+                return false;
+            }
+        }
+    }
+    let clock_time = ClockTime { epoch: epoch, timezone: timezone };
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{msg}")));
+    spinner.set_message(format!("Writing {} [timezone:{:+}] to {}...", clock_time.format_local(), timezone, name));
+
+    // the device might be using one of its aliases now.
+    let mut addresses: Vec<u64> = vec![address];
+    addresses.extend(config.get(&address).map_or(Vec::new(), |d| d.addresses.clone()));
+    let mut result: Result<ClockTime, String> = Err("No address".to_string());
+    for address in addresses {
+        result = ble::write_device_time(address, &clock_time, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    return match result {
+        Ok(device_time) => {
+            println!("{}: wrote {} [timezone:{:+}], device shows {} {}", name, clock_time.epoch, clock_time.timezone, device_time.format_local(), CHECKBOX);
+            true
+        },
+        Err(msg) => {
+            println!("{}: {}", name, theme().error(msg));
+            false
+        }
+    };
+}
+
+// 'set-unit' command handler. returns false when writing failed.
+fn set_unit(ctx: &AppContext, device: &str, unit: TemperatureUnit) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timeout = loaded.general.gatt_timeout();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{msg}")));
+    spinner.set_message(format!("Setting unit {} on {}...", unit.symbol(), name));
+
+    // the device might be using one of its aliases now.
+    let mut addresses: Vec<u64> = vec![address];
+    addresses.extend(config.get(&address).map_or(Vec::new(), |d| d.addresses.clone()));
+    let mut result: Result<TemperatureUnit, String> = Err("No address".to_string());
+    for address in addresses {
+        result = ble::set_device_unit(address, unit, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    return match result {
+        Ok(device_unit) if device_unit == unit => {
+            println!("{}: unit {} {}", name, device_unit.symbol(), CHECKBOX);
+            true
+        },
+        Ok(device_unit) => {
+            println!("{}: {}", name, theme().error(format!("Device still shows {}", device_unit.symbol())));
+            false
+        },
+        Err(msg) => {
+            println!("{}: {}", name, theme().error(msg));
+            false
+        }
+    };
+}
+
+// 'history pull' command handler. returns false when downloading or writing failed.
+// 'firmware check' command handler. a known time sync bug is a warning, not a failure.
+fn firmware_check(ctx: &AppContext, device: &str) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timeout = loaded.general.gatt_timeout();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{prefix}: {msg}")));
+    spinner.set_prefix(name.clone());
+
+    // the device might be using one of its aliases now.
+    let mut addresses: Vec<u64> = vec![address];
+    addresses.extend(config.get(&address).map_or(Vec::new(), |d| d.addresses.clone()));
+    let mut result: Result<String, String> = Err("No address".to_string());
+    for address in addresses {
+        result = ble::read_firmware_revision(address, timeout, &|msg| spinner.set_message(msg.to_string()));
+        if result.is_ok() {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    let version = match result {
+        Ok(version) => version,
+        Err(msg) => {
+            println!("{}: {}", name, theme().error(msg));
+            return false;
+        }
+    };
+
+    match firmware::find_firmware(firmware::KNOWN_FIRMWARE, &version) {
+        Some(known) if known.time_sync_bug => {
+            println!("{}: {} firmware {} {}", name, known.model, version, EXCLAMATION);
+            println!("{} {}", EXCLAMATION, theme().warning(format!("Known time sync bug: {}", known.note)));
+        },
+        Some(known) => {
+            println!("{}: {} firmware {} {}", name, known.model, version, CHECKBOX);
+            if !known.note.is_empty() {
+                println!("{}", known.note);
+            }
+        },
+        None => {
+            println!("{}: firmware {}", name, version);
+            println!("Not a known version. Please report it along with how the clock keeps time.");
+        }
+    }
+    return true;
+}
+
+// 'info' command handler. returns false when the device couldn't be read.
+fn info(ctx: &AppContext, device: &str) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timeout = loaded.general.gatt_timeout();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{prefix}: {msg}")));
+    spinner.set_prefix(name.clone());
+
+    // the device might be using one of its aliases now.
+    let mut addresses: Vec<u64> = vec![address];
+    addresses.extend(config.get(&address).map_or(Vec::new(), |d| d.addresses.clone()));
+    let mut result: Result<(u64, ble::DeviceInformation), String> = Err("No address".to_string());
+    for address in addresses {
+        result = ble::read_device_information(address, timeout, &|msg| spinner.set_message(msg.to_string())).map(|info| (address, info));
+        if result.is_ok() {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    let (connected, information) = match result {
+        Ok(result) => result,
+        Err(msg) => {
+            println!("{}: {}", name, theme().error(msg));
+            return false;
+        }
+    };
+
+    let mut table = theme().table(&["Device ID", &name]);
+    table.add_row(row!["Address", format_bluetooth_address(connected)]);
+    for (field, value) in &information.fields {
+        table.add_row(row![field, value.clone().unwrap_or("-".to_string())]);
+    }
+    table.print_tty(true).ok();
+
+    let mut table = theme().table(&["Service", "Name"]);
+    for uuid in &information.services {
+        table.add_row(row![gatt::format_uuid(*uuid), gatt::service_name(*uuid).unwrap_or("-")]);
+    }
+    table.print_tty(true).ok();
+    return true;
+}
+
+// 'gatt' command handler. returns false when it failed.
+fn gatt_command(ctx: &AppContext, command: &GattCommands) -> bool {
+    let target = match command {
+        GattCommands::Read { target } | GattCommands::Write { target, .. } | GattCommands::Subscribe { target } => target,
+    };
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(&target.device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timeout = loaded.general.gatt_timeout();
+    let name = get_device_name(&loaded.into_device_map(), address);
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{prefix}: {msg}")));
+    spinner.set_prefix(name.clone());
+    let log = |msg: &str| spinner.set_message(msg.to_string());
+
+    let result = match command {
+        GattCommands::Read { .. } => {
+            ble::gatt_read(address, target.service, target.characteristic, timeout, &log).map(|data| {
+                spinner.finish_and_clear();
+                match gatt::decode_string(&data).filter(|text| !text.chars().any(char::is_control)) {
+                    Some(text) => println!("{} \"{}\"", capture::encode_hex(&data), text),
+                    None => println!("{}", capture::encode_hex(&data)),
+                }
+            })
+        },
+        GattCommands::Write { value, .. } => {
+            capture::decode_hex(value).and_then(|data| ble::gatt_write(address, target.service, target.characteristic, &data, timeout, &log)).map(|_| {
+                spinner.finish_and_clear();
+                println!("{}: written {}", name, CHECKBOX);
+            })
+        },
+        GattCommands::Subscribe { .. } => {
+            let bus: EventBus<Vec<u8>> = EventBus::new(EVENT_QUEUE_CAPACITY);
+            let values = bus.subscribe();
+            ble::gatt_subscribe(address, target.service, target.characteristic, bus.publisher(), timeout, &log).map(|subscription| {
+                spinner.set_message("Waiting for notifications... Ctrl-C to stop");
+                while !stop_requested() {
+                    if let Ok(data) = values.recv_timeout(time::Duration::from_millis(300)) {
+                        spinner.print_above(format!("{} {}", chrono::Local::now().format("%H:%M:%S%.3f"), capture::encode_hex(&data)));
+                    }
+                }
+                bus.shutdown();
+                subscription.stop();
+                spinner.finish_and_clear();
+            })
+        },
+    };
+    if let Err(msg) = result {
+        spinner.finish_and_clear();
+        println!("{}: {}", name, theme().error(msg));
+        return false;
+    }
+    return true;
+}
+
+fn history_pull(ctx: &AppContext, device: &str, csv: &Option<PathBuf>) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let timezone_policy = loaded.general.timezone_policy.unwrap_or_default();
+    let timeout = loaded.general.gatt_timeout();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    // times are shown in the timezone the clock uses.
+    let timezone = config.get(&address)
+        .and_then(|d| d.get_timezone_hour(timezone_policy).ok().flatten())
+        .unwrap_or(clock::DEFAULT_TIMEZONE);
+
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().warning, "{prefix}: {msg}")));
+    spinner.set_prefix(name.clone());
+
+    // the device might be using one of its aliases now.
+    let mut addresses: Vec<u64> = vec![address];
+    addresses.extend(config.get(&address).map_or(Vec::new(), |d| d.addresses.clone()));
+    let mut result: Result<Vec<HistoryRecord>, String> = Err("No address".to_string());
+    for address in addresses {
+        result = ble::read_device_history(address, timeout, &|msg| spinner.set_message(msg.to_string()));
+        if result.is_ok() {
+            break;
+        }
+    }
+    spinner.finish_and_clear();
+
+    let records = match result {
+        Ok(records) => records,
+        Err(msg) => {
+            println!("{}: {}", name, theme().error(msg));
+            return false;
+        }
+    };
+
+    if let Some(path) = csv {
+        let mut content = format!("{}\n", history::CSV_HEADER);
+        for record in &records {
+            content.push_str(&history::format_csv(record));
+            content.push('\n');
+        }
+        if let Err(e) = std::fs::write(path, content) {
+            println!("{}: {}", path.display(), theme().error(e));
+            return false;
+        }
+        println!("{}: {} records written to {} {}", name, records.len(), path.display(), CHECKBOX);
+        return true;
+    }
+
+    let mut table = theme().table(&["Index", "Hour", "Temp. min", "Temp. max", "Humidity % min", "Humidity % max"]);
+    for record in &records {
+        table.add_row(row![
+            record.index,
+            ClockTime { epoch: record.time, timezone: timezone }.format_local(),
+            format!("{:.1}", record.min_temperature),
+            format!("{:.1}", record.max_temperature),
+            record.min_humidity,
+            record.max_humidity,
+        ]);
+    }
+    table.printstd();
+    println!("{}: {} records", name, records.len());
+    return true;
+}
+
+// 'history query' command handler. returns false when the store can't be read.
+// no readings in the range is not an error, the device may just be out of range.
+fn history_query(ctx: &AppContext, device: &str, store_path: &PathBuf, since: u64, metric: Metric) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().warning(msg));
+            return false;
+        }
+    };
+    let name = get_device_name(&loaded.into_device_map(), address);
+    // opening creates an empty database. a typo shouldn't leave one behind.
+    if !store_path.exists() {
+        eprintln!("{} {}: not found", theme().error("ERROR:"), store_path.display());
+        return false;
+    }
+    let from = ctx.now().saturating_sub(since);
+    let readings = match Store::open(store_path).and_then(|store| store.query(address, metric, from)) {
+        Ok(readings) => readings,
+        Err(msg) => {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            return false;
+        }
+    };
+    let summary = match store::Summary::of(&readings) {
+        Some(summary) => summary,
+        None => {
+            println!("{} No {} of {} stored in last {}", EXCLAMATION, metric.name(), name, humanize::format_duration(since));
+            return true;
+        }
+    };
+
+    let mut table = theme().table(&["Time", metric.name()]);
+    for reading in &readings {
+        let time = chrono::DateTime::from_timestamp(reading.time as i64, 0).unwrap_or_default().with_timezone(&chrono::Local);
+        table.add_row(row![time.format("%Y-%m-%d %H:%M:%S"), humanize::format_value(reading.value, precision())]);
+    }
+    table.printstd();
+    println!("{}: {} of last {}, {} samples", name, metric.name(), humanize::format_duration(since), summary.count);
+    println!("min {}, max {}, avg {}",
+        humanize::format_value(summary.min, precision()), humanize::format_value(summary.max, precision()), format_computed(Some(summary.average)));
+    return true;
+}
+
+fn spinner_style(template: &str) -> ProgressStyle {
+    return ProgressStyle::with_template(template)
+        .unwrap()
+        .tick_strings(theme().spinner_ticks());
+}
+
+// lines printed above spinners. a hidden spinner, when stderr isn't a terminal or the output is JSON or CSV,
+// would drop them, so they go to stderr as they are.
+trait PrintAbove {
+    fn print_above<I: AsRef<str>>(&self, line: I);
+}
+
+impl PrintAbove for ProgressBar {
+    fn print_above<I: AsRef<str>>(&self, line: I) {
+        if self.is_hidden() {
+            eprintln!("{}", line.as_ref());
+        } else {
+            self.println(line);
+        }
+    }
+}
+
+impl PrintAbove for MultiProgress {
+    fn print_above<I: AsRef<str>>(&self, line: I) {
+        if self.is_hidden() {
+            eprintln!("{}", line.as_ref());
+        } else {
+            self.println(line).ok();
+        }
+    }
+}
+
+// spinner of a command. log messages go above it.
+// devices are synced at the same time, as many as there are workers. the rest wait for one.
+fn listening_message(bars: &HashMap<u64, ProgressBar>, waiting: &HashSet<u64>) -> String {
+    let syncing = bars.values().filter(|bar| !bar.is_finished()).count().saturating_sub(waiting.len());
+    return match (syncing, waiting.len()) {
+        (0, 0) => "Listening...".to_string(),
+        (syncing, 0) => format!("Listening... {} syncing", syncing),
+        (syncing, waiting) => format!("Listening... {} syncing, {} waiting", syncing, waiting),
+    };
+}
+
+fn new_spinner() -> ProgressBar {
+    let spinner = ProgressBar::new_spinner();
+    *LOG_SPINNER.lock().unwrap() = Some(spinner.clone());
+    return spinner;
+}
+
+// messages of `log` macros. warnings and errors look like the other ones.
+fn write_log(level: log::Level, message: &str) {
+    let line = match level {
+        log::Level::Error => format!("{} {}", EXCLAMATION, theme().error(message)),
+        log::Level::Warn => format!("{} {}", EXCLAMATION, theme().warning(message)),
+        _ => message.to_string(),
+    };
+    match LOG_SPINNER.lock().unwrap().as_ref() {
+        Some(spinner) if !spinner.is_finished() => spinner.print_above(line),
+        _ => eprintln!("{}", line),
+    }
+}
+
+// latest readings of devices heard, for metrics. ordered by address, so scrapes and pushes look the same every time.
+fn device_metrics(config: &HashMap<u64, DeviceConfig>, sensors: &HashMap<u64, SensorData>) -> Vec<prometheus::DeviceMetrics> {
+    let mut metrics: Vec<prometheus::DeviceMetrics> = sensors.iter().map(|(address, sensor)| prometheus::DeviceMetrics {
+        address: format_bluetooth_address(*address),
+        name: config.get(address).and_then(|d| d.name.clone()),
+        group: config.get(address).and_then(|d| d.group.clone()),
+        temperature: sensor.temperature.map(|v| humanize::round_value(v, precision())),
+        humidity: sensor.humidity.map(|v| humanize::round_value(v, precision())),
+        battery: sensor.battery.map(|v| humanize::round_value(v, precision())),
+        last_seen: sensor.last_seen,
+    }).collect();
+    metrics.sort_by(|a, b| a.address.cmp(&b.address));
+    return metrics;
+}
+
+// notifications of [[webhook]] for readings. None without any. a broken one fails the command, like other outputs.
+// broken config stops a one-shot command. running with wrong timezones is worse.
+fn config_or_exit(ctx: &AppContext) -> Config {
+    return ctx.load_config().unwrap_or_else(|msg| {
+        eprintln!("{} {}", theme().error("ERROR:"), msg);
+        std::process::exit(1);
+    });
+}
+
+// a sink which isn't configured, or can't be reached, stops the command before listening.
+fn sinks_or_exit(ctx: &AppContext, loaded: &Config, command: &str) -> Sinks {
+    return ctx.open_sinks(loaded, command).unwrap_or_else(|msg| {
+        eprintln!("{} {}", theme().error("ERROR:"), msg);
+        std::process::exit(1);
+    });
+}
+
+// configured name of the device, or its address.
+fn get_device_name(config: &HashMap<u64, DeviceConfig>, address: u64) -> String {
+    if let Some(device) = config.get(&address) {
+        if let Some(name) = &device.name {
+            return name.clone();
+        }
+    }
+    return format_bluetooth_address(address);
+}
+
+fn check_config(ctx: &AppContext) {
+    let (path, config) = match Config::default_path().and_then(|path| Config::load(&path).map(|config| (path, config))) {
+        Ok((path, config)) => {
+            set_display(&config);
+            (path, config)
+        },
+        Err(e) => {
+            eprintln!("{} {}", theme().error("ERROR:"), e);
+            return;
+        }
+    };
+    let lints = lint::lint_config(&config);
+
+    if ctx.format.is_machine_readable() {
+        let devices: Vec<output::ConfigDevice> = config.devices.iter().flatten().map(|d| output::ConfigDevice {
+            address: format_bluetooth_address(d.address),
+            addresses: d.addresses.iter().map(|a| format_bluetooth_address(*a)).collect(),
+            name: d.name.clone(),
+            group: d.group.clone(),
+            omit: d.omit,
+            timezone: d.timezone.clone(),
+            offset_seconds: d.offset_seconds,
+            unit: d.unit,
+            purchased: d.purchased.map(|date| date.to_string()),
+            battery_changed: d.battery_changed.map(|date| date.to_string()),
+            notes: d.notes.clone(),
+        }).collect();
+        if ctx.format == OutputFormat::Csv {
+            println!("{}", output::CONFIG_CSV_HEADER);
+            for device in &devices {
+                println!("{}", device.to_csv());
+            }
+            return;
+        }
+        let report = output::ConfigReport {
+            path: path.display().to_string(),
+            devices: devices,
+            lints: lints.iter().map(output::ConfigLint::from_lint).collect(),
+        };
+        println!("{}", serde_json::to_string(&report).unwrap());
+        return;
+    }
+
+    println!("toml path: {}", theme().ok(path.display()));
+
+    {
+        if let Some(devices) = config.devices {
+            println!("Configuration:");
+            let mut table = theme().table(&["Address", "Name", "Omit", "Timezone", "Offset_Seconds", "Purchased", "Battery_Changed", "Notes"]);
+            for device in devices {
+                // aliases are printed below the primary address.
+                let mut addresses: Vec<String> = vec![format_bluetooth_address(device.address)];
+                addresses.extend(device.addresses.iter().map(|a| format_bluetooth_address(*a)));
+
+                table.add_row(row![
+                    addresses.join("\n"),
+                    device.name.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.omit.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.timezone.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.offset_seconds.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.purchased.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.battery_changed.map_or("-".to_string(), |vv| vv.to_string()),
+                    device.notes.map_or("-".to_string(), |vv| vv.to_string()),
+                ]);
+            }
+            table.print_tty(true).ok();
+        }
+        else {
+            println!("{} No {} defined in toml.", EXCLAMATION, theme().warning("[[device]]"));
+        }
+    }
+
+    for l in lints {
+        let device = l.device.map_or("[general]".to_string(), |d| d);
+        println!("{} {}: {}", EXCLAMATION, theme().warning(device), l.message);
+        println!("    {}", l.suggestion);
+    }
+}
+
+// 'schedule' command handler. returns false when schtasks failed.
+fn schedule_task(every: Option<schedule::Every>, remove: bool, names: &[String], pushgateway: bool) -> bool {
+    let args = match every {
+        Some(every) if !remove => {
+            let exe = std::env::current_exe().unwrap();
+            schedule::create_args(&exe.display().to_string(), every, names, pushgateway)
+        },
+        _ => schedule::delete_args(),
+    };
+    // schtasks prints its own result.
+    return match std::process::Command::new("schtasks").args(&args).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            println!("{} {}", EXCLAMATION, theme().error(format!("schtasks failed: {}", status)));
+            false
+        },
+        Err(e) => {
+            println!("{} {}", EXCLAMATION, theme().error(format!("Running schtasks failed: {}", e)));
+            false
+        }
+    };
+}
+
+// 'gen-fixtures' command handler. returns false when the capture file can't be read.
+fn gen_fixtures(path: &PathBuf, name: &Option<String>) -> bool {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{} {}: {}", theme().error("ERROR:"), path.display(), e);
+            return false;
+        }
+    };
+
+    let mut frames: Vec<CapturedFrame> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match capture::parse_capture_line(line) {
+            Ok(frame) => frames.push(frame),
+            Err(msg) => {
+                eprintln!("{} {}:{}: {}", theme().error("ERROR:"), path.display(), number + 1, msg);
+                return false;
+            }
+        }
+    }
+    if frames.is_empty() {
+        eprintln!("{} {}: no frames", theme().error("ERROR:"), path.display());
+        return false;
+    }
+
+    let name = name.clone().unwrap_or_else(|| path.file_stem().map_or("capture".to_string(), |s| s.to_string_lossy().to_string()));
+    let source = path.file_name().map_or(path.display().to_string(), |s| s.to_string_lossy().to_string());
+    print!("{}", fixtures::generate_test(&name, &source, &frames));
+    return true;
+}
+
+// 'export' command handler. returns false when reading or writing failed.
+// readings are streamed one by one, so months of readings don't have to fit in memory.
+fn export(ctx: &AppContext, store_path: &PathBuf, since: Option<u64>, until: Option<u64>, out: &Option<PathBuf>, parquet: bool) -> bool {
+    // opening creates an empty database. a typo shouldn't leave one behind.
+    if !store_path.exists() {
+        eprintln!("{} {}: not found", theme().error("ERROR:"), store_path.display());
+        return false;
+    }
+    // both are times ago. readings written later than now are kept without --until.
+    let now = ctx.now();
+    let from = since.map_or(0, |since| now.saturating_sub(since));
+    let to = until.map_or(u64::MAX, |until| now.saturating_sub(until));
+    if from > to {
+        eprintln!("{} --since is later than --until", theme().error("ERROR:"));
+        return false;
+    }
+    let result = Store::open(store_path).and_then(|store| write_readings(ctx, &store, from, to, out, parquet));
+    return print_export_result(result, out);
+}
+
+// 'export --scan' command handler. readings are kept in memory, so repeated frames are written once, same as a store.
+fn export_scan(ctx: &AppContext, seconds: u64, out: &Option<PathBuf>, parquet: bool) -> bool {
+    let config = config_or_exit(ctx).into_device_map();
+    let result = Store::open_in_memory().and_then(|store| {
+        let bus: EventBus<AdvertisementKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+        let readings = bus.subscribe();
+        let publisher = bus.publisher();
+        let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+            for value in ble::decode_advertisement(&args) {
+                publisher.publish(value);
+            }
+            Ok(())
+        };
+        let advertisement_watch = ctx.watch_advertisements(on_received)?;
+
+        let spinner = new_spinner();
+        spinner.enable_steady_tick(time::Duration::from_millis(120));
+        spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+        // frame counter of the last frame from each address, for deduplication.
+        let mut frame_counters: HashMap<u64, u8> = HashMap::new();
+        let mut stopped: Option<String> = None;
+        let start_time = time::Instant::now();
+        loop {
+            if stopped.is_none() {
+                stopped = advertisement_watch.stopped();
+            }
+            // readings queued until then are still written.
+            if stopped.is_some() || start_time.elapsed() >= time::Duration::from_secs(seconds) || stop_requested() {
+                bus.shutdown();
+            }
+            let data = match readings.recv_timeout(time::Duration::from_millis(300)) {
+                Ok(data) => data,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                // shut down, and everything queued is handled.
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            let (value, metric) = match &data {
+                AdvertisementKind::Temperature(value) => (value, Metric::Temperature),
+                AdvertisementKind::Humidity(value) => (value, Metric::Humidity),
+                AdvertisementKind::Battery(value) => (value, Metric::Battery),
+                AdvertisementKind::Frame(frame) => {
+                    match decoder::decode_frame_counter(&frame.data) {
+                        Some(counter) => frame_counters.insert(frame.address, counter),
+                        None => frame_counters.remove(&frame.address),
+                    };
+                    continue;
+                },
+            };
+            let reading = StoredReading {
+                time: ctx.now(),
+                address: resolve_device_address(&config, value.address),
+                metric: metric,
+                value: value.value,
+                frame_counter: frame_counters.get(&value.address).copied(),
+            };
+            store.insert(&reading)?;
+            spinner.set_message(format!("Listening... {} readings", store.count()?));
+        }
+        advertisement_watch.stop();
+        spinner.finish_and_clear();
+        if let Some(msg) = stopped {
+            export_note(out, &format!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg)));
+        }
+        return write_readings(ctx, &store, 0, u64::MAX, out, parquet);
+    });
+    return print_export_result(result, out);
+}
+
+// returns the number of readings written. Parquet with --parquet, JSON with --format json, CSV otherwise.
+// to stdout without a file.
+fn write_readings(ctx: &AppContext, store: &Store, from: u64, to: u64, out: &Option<PathBuf>, parquet: bool) -> Result<u64, String> {
+    if let (true, Some(path)) = (parquet, out) {
+        return write_parquet(store, from, to, path);
+    }
+    let target = out.as_ref().map_or("stdout".to_string(), |path| path.display().to_string());
+    let file: Box<dyn Write> = match out {
+        Some(path) => Box::new(std::fs::File::create(path).map_err(|e| format!("{}: {}", target, e))?),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut writer = std::io::BufWriter::new(file);
+    let write_error = |e: std::io::Error| format!("{}: {}", target, e);
+    let json = ctx.format == OutputFormat::Json;
+
+    // a bar would be mixed into readings on a terminal.
+    let bar = if out.is_some() { ProgressBar::new(store.count_between(from, to)?) } else { ProgressBar::hidden() };
+    bar.set_style(ProgressStyle::with_template("{bar:40} {pos}/{len} readings").unwrap());
+    // a JSON array, written one reading at a time.
+    if json {
+        write!(writer, "[").map_err(write_error)?;
+    } else {
+        writeln!(writer, "{}", xiaomi::store::CSV_HEADER).map_err(write_error)?;
+    }
+    let mut count: u64 = 0;
+    store.for_each_reading(from, to, |reading| {
+        let reading = StoredReading { value: humanize::round_value(reading.value, precision()), ..reading };
+        bar.inc(1);
+        count += 1;
+        if json {
+            let separator = if count == 1 { "" } else { "," };
+            let line = serde_json::to_string(&output::ExportReading::of(&reading)).unwrap();
+            return write!(writer, "{}{}", separator, line).map_err(write_error);
+        }
+        return writeln!(writer, "{}", xiaomi::store::format_csv(&reading)).map_err(write_error);
+    })?;
+    if json {
+        writeln!(writer, "]").map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)?;
+    bar.finish_and_clear();
+    return Ok(count);
+}
+
+// a row group is kept in memory at a time, see `xiaomi::parquet_file`.
+#[cfg(feature = "parquet")]
+fn write_parquet(store: &Store, from: u64, to: u64, path: &PathBuf) -> Result<u64, String> {
+    let target = path.display().to_string();
+    let file = std::fs::File::create(path).map_err(|e| format!("{}: {}", target, e))?;
+    let mut writer = xiaomi::parquet_file::ParquetWriter::new(std::io::BufWriter::new(file)).map_err(|e| format!("{}: {}", target, e))?;
+    let bar = ProgressBar::new(store.count_between(from, to)?);
+    bar.set_style(ProgressStyle::with_template("{bar:40} {pos}/{len} readings").unwrap());
+    let mut count: u64 = 0;
+    store.for_each_reading(from, to, |reading| {
+        let reading = StoredReading { value: humanize::round_value(reading.value, precision()), ..reading };
+        bar.inc(1);
+        count += 1;
+        return writer.write(&reading).map_err(|e| format!("{}: {}", target, e));
+    })?;
+    let mut file = writer.finish().map_err(|e| format!("{}: {}", target, e))?;
+    file.flush().map_err(|e| format!("{}: {}", target, e))?;
+    bar.finish_and_clear();
+    return Ok(count);
+}
+
+// --parquet is refused before getting here.
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_store: &Store, _from: u64, _to: u64, _path: &PathBuf) -> Result<u64, String> {
+    return Err("Parquet isn't built in".to_string());
+}
+
+// readings on stdout are piped to other tools, so messages go to stderr then.
+fn export_note(out: &Option<PathBuf>, text: &str) {
+    if out.is_none() {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+fn print_export_result(result: Result<u64, String>, out: &Option<PathBuf>) -> bool {
+    return match result {
+        Ok(count) => {
+            let target = out.as_ref().map_or("stdout".to_string(), |path| path.display().to_string());
+            export_note(out, &format!("{} readings written to {} {}", count, target, CHECKBOX));
+            true
+        },
+        Err(msg) => {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            false
+        }
+    };
+}
+
+// 'completions' command handler. names are baked into the script, so it's generated again after editing toml.
+fn completions(ctx: &AppContext, shell: Shell) {
+    let loaded = config_or_exit(ctx);
+    let devices: Vec<String> = loaded.devices.iter().flatten().filter_map(|d| d.name.clone()).collect();
+    let mut groups: Vec<String> = loaded.devices.iter().flatten().filter_map(|d| d.group.clone()).collect();
+    groups.sort();
+    groups.dedup();
+    let mut command = with_completed_values(Cli::command(), &devices, &groups);
+    clap_complete::generate(shell, &mut command, "xiaomi", &mut std::io::stdout());
+}
+
+// device arguments take any name or address, but only names are worth offering.
+// the command is only used for completion, so limiting values doesn't reject anything.
+fn with_completed_values(command: clap::Command, devices: &[String], groups: &[String]) -> clap::Command {
+    return command
+        .mut_args(|arg| {
+            let values = match arg.get_id().as_str() {
+                "device" | "names" | "exclude" => devices,
+                "group" => groups,
+                _ => return arg,
+            };
+            if values.is_empty() {
+                return arg;
+            }
+            return arg.value_parser(clap::builder::PossibleValuesParser::new(values.to_vec()));
+        })
+        .mut_subcommands(|subcommand| with_completed_values(subcommand, devices, groups));
+}
+
+// 'setup' command handler. devices already in toml are left out. nothing is written until the last question.
+fn setup(ctx: &AppContext, duration: Option<u64>) -> bool {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("{} setup asks questions. Run it in a terminal, or use `config add`.", theme().error("ERROR:"));
+        return false;
+    }
+    let path = match Config::default_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{} {}", theme().error("ERROR:"), e);
+            return false;
+        }
+    };
+    // a new file gets the starter toml, so every setting is explained in it.
+    let mut text = if path.exists() {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("{} Reading {} failed: {}", theme().error("ERROR:"), path.display(), e);
+                return false;
+            }
+        }
+    } else {
+        template::CONFIG.to_string()
+    };
+    let loaded = config_or_exit(ctx);
+    let seconds = duration.or(loaded.general.scan_duration_seconds).unwrap_or(10);
+    let formula = loaded.general.apparent_temperature.unwrap_or_default();
+    let config = loaded.into_device_map();
+
+    // the strongest signal is likely the device in hand.
+    let bus: EventBus<(AdvertisementKind, Option<i16>)> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let events = bus.subscribe();
+    let publisher = bus.publisher();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        let rssi = ble::signal_strength(&args);
+        for value in ble::decode_advertisement(&args) {
+            publisher.publish((value, rssi));
+        }
+        Ok(())
+    };
+    let advertisement_watch = match ctx.watch_advertisements(on_received) {
+        Ok(watch) => watch,
+        Err(msg) => {
+            println!("{} {}", EXCLAMATION, theme().error(msg));
+            println!("Run `xiaomi doctor` to check Bluetooth.");
+            return false;
+        }
+    };
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+    spinner.set_message(format!("Looking for devices for {}...", humanize::format_duration(seconds)));
+    let mut sensors: HashMap<u64, SensorData> = HashMap::new();
+    let mut product_ids: HashMap<u64, u16> = HashMap::new();
+    let mut intervals: HashMap<u64, IntervalEstimator> = HashMap::new();
+    let mut stopped: Option<String> = None;
+    let start_time = time::Instant::now();
+    while start_time.elapsed() < time::Duration::from_secs(seconds) && stopped.is_none() && !stop_requested() {
+        stopped = advertisement_watch.stopped();
+        let (data, rssi) = match events.recv_timeout(time::Duration::from_millis(300)) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        let address = match &data {
+            AdvertisementKind::Temperature(value) | AdvertisementKind::Humidity(value) | AdvertisementKind::Battery(value) => value.address,
+            AdvertisementKind::Frame(frame) => frame.address,
+        };
+        if config.contains_key(&address) {
+            continue;
+        }
+        if !sensors.contains_key(&address) {
+            spinner.print_above(format!("Found {}", format_bluetooth_address(address)));
+        }
+        let sensor = sensors.entry(address).or_insert_with(SensorData::new);
+        sensor.set_seen(ctx.now());
+        if let Some(rssi) = rssi {
+            sensor.set_rssi(rssi);
+        }
+        match &data {
+            AdvertisementKind::Temperature(value) => sensor.set_temperature(value.value),
+            AdvertisementKind::Humidity(value) => sensor.set_humidity(value.value),
+            AdvertisementKind::Battery(value) => sensor.set_battery(value.value),
+            AdvertisementKind::Frame(frame) => {
+                if let Some(product_id) = decoder::decode_product_id(&frame.data) {
+                    product_ids.insert(address, product_id);
+                }
+                intervals.entry(address).or_default().record(start_time.elapsed().as_millis() as u64);
+            },
+        }
+        spinner.set_message(format!("Looking for devices... {} new", sensors.len()));
+    }
+    bus.shutdown();
+    advertisement_watch.stop();
+    spinner.finish_and_clear();
+    if let Some(msg) = stopped {
+        println!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg));
+    }
+    if stop_requested() {
+        return false;
+    }
+    if sensors.is_empty() {
+        println!("{} No device found that isn't in toml. Bring it closer, or scan longer with --duration.", EXCLAMATION);
+        // devices in toml are set up already.
+        return !config.is_empty();
+    }
+
+    let devices = scan_devices(&config, &sensors, &product_ids, &intervals, formula);
+    let mut table = theme().table(&["#", "Device ID", "Model", "Temp.", "Humidity %", "Battery %", "Interval", "RSSI"]);
+    for (i, device) in devices.iter().enumerate() {
+        let mut row = scan_row(device, None);
+        row.insert_cell(0, prettytable::Cell::new(&(i + 1).to_string()));
+        table.add_row(row);
+    }
+    table.printstd();
+    println!("Give each device a name. Enter skips a device, Ctrl-C quits without writing.");
+
+    let mut added = 0;
+    // the timezone given last is offered for the next device. most devices are in the same house.
+    let mut last_timezone: Option<String> = None;
+    for (i, device) in devices.iter().enumerate() {
+        // decoded back, the summary has it formatted.
+        let address = decode_bluetooth_adddress(&device.address).unwrap();
+        // asked again until toml takes it, or it's skipped.
+        loop {
+            let name = match prompt(&format!("[{}/{}] Name of {}: ", i + 1, devices.len(), device.address)) {
+                Some(name) => name,
+                None => return false,
+            };
+            if name.is_empty() {
+                break;
+            }
+            let question = match &last_timezone {
+                Some(timezone) => format!("    Timezone [{}]: ", timezone),
+                None => "    Timezone, like Asia/Seoul. Enter for none: ".to_string(),
+            };
+            let answer = match prompt(&question) {
+                Some(answer) => answer,
+                None => return false,
+            };
+            let timezone = if answer.is_empty() { last_timezone.clone() } else { Some(answer) };
+            match template::append_device(&text, address, Some(&name), timezone.as_deref()) {
+                Ok(appended) => {
+                    text = appended;
+                    last_timezone = timezone;
+                    added += 1;
+                    break;
+                },
+                Err(msg) => println!("{} {}", EXCLAMATION, theme().warning(msg)),
+            }
+        }
+    }
+    if added == 0 {
+        println!("Nothing to write.");
+        return true;
+    }
+    let answer = match prompt(&format!("Write {} devices to {}? [Y/n] ", added, path.display())) {
+        Some(answer) => answer,
+        None => return false,
+    };
+    if !answer.is_empty() && !answer.eq_ignore_ascii_case("y") {
+        println!("Nothing written.");
+        return true;
+    }
+    if let Err(e) = std::fs::write(&path, text) {
+        eprintln!("{} Writing {} failed: {}", theme().error("ERROR:"), path.display(), e);
+        return false;
+    }
+    println!("{} devices written to {} {}", added, path.display(), CHECKBOX);
+    println!("Run `xiaomi sync` to set their clocks.");
+    return true;
+}
+
+// trimmed answer. None when input ended, or Ctrl-C was pressed while waiting.
+fn prompt(question: &str) -> Option<String> {
+    print!("{}", question);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    let read = std::io::stdin().read_line(&mut answer).ok()?;
+    if read == 0 || stop_requested() {
+        return None;
+    }
+    return Some(answer.trim().to_string());
+}
+
+// 'config init' command handler. an existing config is never replaced without --force.
+fn config_init(path: &Option<PathBuf>, force: bool) -> bool {
+    let default_path = match Config::default_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{} {}", theme().error("ERROR:"), e);
+            return false;
+        }
+    };
+    let path = path.clone().unwrap_or(default_path.clone());
+    if path.exists() && !force {
+        eprintln!("{} {} already exists. Use --force to overwrite it", theme().error("ERROR:"), path.display());
+        return false;
+    }
+    if let Err(e) = std::fs::write(&path, template::CONFIG) {
+        eprintln!("{} Writing {} failed: {}", theme().error("ERROR:"), path.display(), e);
+        return false;
+    }
+    println!("Config written to {} {}", path.display(), CHECKBOX);
+    if path != default_path {
+        println!("{} xiaomi reads {}. Move it there when it's ready.", EXCLAMATION, default_path.display());
+    }
+    println!("Uncomment [[device]] and set the address of each device. `xiaomi scan` shows addresses around.");
+    return true;
+}
+
+// 'config check' command handler. unknown timezones, duplicate addresses and offsets of hours are errors.
+// a typo in a key is ignored when loading, so it's only a warning unless `strict`.
+fn config_check(strict: bool) -> bool {
+    let path = match Config::default_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{} {}", theme().error("ERROR:"), e);
+            return false;
+        }
+    };
+    // no config is a valid config.
+    if !path.exists() {
+        println!("{} No toml at {}. Devices are shown by address.", EXCLAMATION, path.display());
+        return true;
+    }
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{} Reading {} failed: {}", theme().error("ERROR:"), path.display(), e);
+            return false;
+        }
+    };
+    let config: Config = match toml::from_str(&text) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{} Parsing {} failed: {}", theme().error("ERROR:"), path.display(), e);
+            return false;
+        }
+    };
+    set_display(&config);
+    println!("toml path: {}", theme().ok(path.display()));
+
+    let mut errors = 0;
+    let mut warnings = 0;
+    let mut report = |error: bool, place: &str, message: String, suggestion: &str| {
+        if error {
+            println!("{} {}: {}", EXCLAMATION, theme().error(place), message);
+            errors += 1;
+        } else {
+            println!("{} {}: {}", EXCLAMATION, theme().warning(place), message);
+            warnings += 1;
+        }
+        println!("    {}", suggestion);
+    };
+    // it was parsed above, so finding keys can't fail.
+    for key in lint::unknown_keys(&text).unwrap_or_default() {
+        report(strict, &key, "no setting has this name, it's ignored".to_string(),
+            "Check the spelling. `xiaomi config init --path example.toml` writes every setting, with what it does.");
+    }
+    for l in lint::lint_config(&config) {
+        report(l.error, &l.device.unwrap_or("[general]".to_string()), l.message, &l.suggestion);
+    }
+
+    if errors + warnings == 0 {
+        println!("No problems found {}", CHECKBOX);
+    } else {
+        println!("{} errors, {} warnings", errors, warnings);
+    }
+    return errors == 0;
+}
+
+// 'config add' command handler. the file is appended as text, so comments in it are kept.
+fn config_add(address: &str, name: &Option<String>, timezone: &Option<String>) -> bool {
+    // short hex is taken as an address by the decoder. a name given by mistake shouldn't be.
+    let decoded = if address.len() == 12 || address.contains(':') { decode_bluetooth_adddress(address) } else { Err("given address is not 6 byte form") };
+    let address = match decoded {
+        Ok(address) => address,
+        Err(msg) => {
+            eprintln!("{} \"{}\": {}", theme().error("ERROR:"), address, msg);
+            return false;
+        }
+    };
+    let path = match Config::default_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{} {}", theme().error("ERROR:"), e);
+            return false;
+        }
+    };
+    let existing = if path.exists() {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("{} Reading {} failed: {}", theme().error("ERROR:"), path.display(), e);
+                return false;
+            }
+        }
+    } else {
+        String::new()
+    };
+    let text = match template::append_device(&existing, address, name.as_deref(), timezone.as_deref()) {
+        Ok(text) => text,
+        Err(msg) => {
+            eprintln!("{} {}", theme().error("ERROR:"), msg);
+            return false;
+        }
+    };
+    if let Err(e) = std::fs::write(&path, text) {
+        eprintln!("{} Writing {} failed: {}", theme().error("ERROR:"), path.display(), e);
+        return false;
+    }
+    let added = name.clone().unwrap_or(format_bluetooth_address(address));
+    println!("{} added to {} {}", added, path.display(), CHECKBOX);
+    return true;
+}
+
+// 'manpage' command handler. pages don't depend on toml, so a release can ship them.
+fn manpage(out_dir: &Option<PathBuf>) -> bool {
+    let result = match out_dir {
+        Some(dir) => std::fs::create_dir_all(dir).and_then(|_| clap_mangen::generate_to(Cli::command(), dir)),
+        None => clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout()),
+    };
+    if let Err(e) = result {
+        eprintln!("{} Writing man page failed: {}", theme().error("ERROR:"), e);
+        return false;
+    }
+    if let Some(dir) = out_dir {
+        println!("Man pages written to {} {}", dir.display(), CHECKBOX);
+    }
+    return true;
+}
+
+// 'doctor' command handler. returns false when something needs fixing. warnings don't fail it.
+fn doctor(ctx: &AppContext) -> bool {
+    let mut healthy = true;
+    let mut problem = |error: bool, message: String, suggestion: &str| {
+        if error {
+            println!("{} {}", EXCLAMATION, theme().error(message));
+            healthy = false;
+        } else {
+            println!("{} {}", EXCLAMATION, theme().warning(message));
+        }
+        println!("    {}", suggestion);
+    };
+
+    // config is checked first. a broken one stops every other command.
+    let mut timeout = GeneralConfig::default().gatt_timeout();
+    match Config::default_path().and_then(|path| Config::load(&path)) {
+        Ok(config) => {
+            set_display(&config);
+            timeout = config.general.gatt_timeout();
+            println!("Config has {} devices {}", config.devices.iter().flatten().count(), CHECKBOX);
+            let lints = lint::lint_config(&config);
+            if !lints.is_empty() {
+                problem(false, format!("Config has {} likely mistakes", lints.len()), "See `xiaomi toml` for each, with how to fix it.");
+            }
+            let unknown: Vec<&String> = config.devices.iter().flatten().filter_map(|d| d.timezone.as_ref())
+                .filter(|tz| tz.parse::<chrono_tz::Tz>().is_err()).collect();
+            for timezone in unknown {
+                problem(true, format!("Timezone \"{}\" is not in the timezone database", timezone), "Use a name from the tz database, like \"Asia/Seoul\".");
+            }
+        },
+        Err(ConfigError::NotFound(path)) => {
+            println!("No config at {}. Defaults are used {}", path.display(), CHECKBOX);
+        },
+        Err(e) => {
+            problem(true, e.to_string(), "Fix the file, or move it away to run with defaults. `xiaomi toml` shows it once it's read.");
+        },
+    }
+    println!("Local time is UTC{} {}", chrono::Local::now().format("%:z"), CHECKBOX);
+
+    let adapter = match ble::read_adapter(timeout) {
+        Ok(adapter) => adapter,
+        Err(_) => {
+            problem(true, "No Bluetooth adapter found".to_string(), "Plug in a Bluetooth adapter, or check it's enabled in Device Manager.");
+            return false;
+        }
+    };
+    println!("Bluetooth adapter {} {}", format_bluetooth_address(adapter.address), CHECKBOX);
+    match adapter.radio_state {
+        Some(RadioState::On) => println!("Bluetooth is on {}", CHECKBOX),
+        Some(RadioState::Off) => problem(true, "Bluetooth is turned off".to_string(), "Turn it on in Settings > Bluetooth & devices."),
+        Some(RadioState::Disabled) => problem(true, "Bluetooth radio is disabled".to_string(), "Turn off airplane mode, or enable the adapter in Device Manager."),
+        _ => problem(false, "Bluetooth power state is unknown".to_string(), "Allow apps to control device radios in Settings > Privacy & security > Radios."),
+    }
+    if adapter.low_energy {
+        println!("Bluetooth LE is supported {}", CHECKBOX);
+    } else {
+        problem(true, "The adapter doesn't support Bluetooth LE".to_string(), "Use an adapter with Bluetooth 4.0 or later.");
+    }
+    if !adapter.central_role {
+        problem(false, "The adapter can't connect to devices".to_string(), "scan works, but sync, get-time and history need to connect. Try another adapter.");
+    }
+    if adapter.radio_state != Some(RadioState::On) || !adapter.low_energy {
+        return false;
+    }
+
+    // passive scan is what scan, sync and daemon do.
+    let heard = Arc::new(AtomicU32::new(0));
+    let heard_clone = heard.clone();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, _args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        heard_clone.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    };
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+    spinner.set_message("Listening to advertisements...");
+    let stopped = match ctx.watch_advertisements(on_received) {
+        Ok(watch) => {
+            thread::sleep(time::Duration::from_secs(5));
+            let stopped = watch.stopped();
+            watch.stop();
+            stopped
+        },
+        Err(msg) => Some(msg),
+    };
+    spinner.finish_and_clear();
+    match stopped {
+        Some(msg) => problem(true, format!("Passive scan failed: {}", msg), "Check Settings > Privacy & security > Bluetooth allows apps, and no other program holds the adapter."),
+        None if heard.load(Ordering::SeqCst) == 0 => {
+            problem(false, "Passive scan heard nothing in 5 s".to_string(), "Move closer to a device, or check its battery. Other BLE devices would be heard too, if there are any.");
+        },
+        None => println!("Passive scan heard {} advertisements in 5 s {}", heard.load(Ordering::SeqCst), CHECKBOX),
+    }
+    return healthy;
+}
+
+// 'models' command handler. prints the built-in model registry.
+fn models() {
+    let mut header = vec!["Model", "Product IDs", "Description"];
+    header.extend(model::ALL_CAPABILITIES.iter().map(|c| c.name()));
+    header.push("Metrics");
+    let mut table = theme().table(&header);
+    for m in model::MODELS {
+        let mut row = prettytable::Row::new(vec![
+            prettytable::Cell::new(m.name),
+            prettytable::Cell::new(&m.product_ids.iter().map(|id| format!("0x{:04X}", id)).collect::<Vec<String>>().join(", ")),
+            prettytable::Cell::new(m.description),
+        ]);
+        for capability in model::ALL_CAPABILITIES {
+            row.add_cell(prettytable::Cell::new(if m.supports(capability) { "yes" } else { "-" }));
+        }
+        let metrics: Vec<&str> = m.metrics.iter().map(|metric| metric.name()).collect();
+        row.add_cell(prettytable::Cell::new(if metrics.is_empty() { "-".to_string() } else { metrics.join(", ") }.as_str()));
+        table.add_row(row);
+    }
+    table.print_tty(true).ok();
+    println!("Devices of other models are shown by `scan` with their product ID. Please report them with `scan --capture`.");
+}
+
+// 'read' command handler. returns false when the reading wasn't heard in time.
+// readings are advertised every few seconds, battery every few minutes. LYWSD02 battery is read over GATT then.
+fn read(ctx: &AppContext, device: &str, metric: Option<Metric>, duration: Option<u64>) -> bool {
+    let loaded = config_or_exit(ctx);
+    let address = match loaded.resolve_device(device) {
+        Ok(address) => address,
+        Err(msg) => {
+            ctx.note(&format!("{} {}", EXCLAMATION, theme().warning(msg)));
+            return false;
+        }
+    };
+    let timeout = loaded.general.gatt_timeout();
+    let formula = loaded.general.apparent_temperature.unwrap_or_default();
+    let config = loaded.into_device_map();
+    let name = get_device_name(&config, address);
+
+    let bus: EventBus<AdvertisementKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let events = bus.subscribe();
+    let publisher = bus.publisher();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        for value in ble::decode_advertisement(&args) {
+            publisher.publish(value);
+        }
+        Ok(())
+    };
+    let advertisement_watch = match ctx.watch_advertisements(on_received) {
+        Ok(watch) => watch,
+        Err(msg) => {
+            ctx.note(&format!("{} {}", EXCLAMATION, theme().error(msg)));
+            return false;
+        }
+    };
+    // spinner goes to stderr, stdout has the value only.
+    let spinner = new_spinner();
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+    spinner.set_message(format!("Waiting for {}...", name));
+    let mut sensor = SensorData::new();
+    let mut product_id: Option<u16> = None;
+    // metrics the model doesn't report are not waited for, once the model is known.
+    let wanted = |product_id: Option<u16>| -> Vec<Metric> {
+        let metrics = match metric {
+            Some(metric) => vec![metric],
+            None => vec![Metric::Temperature, Metric::Humidity],
+        };
+        let model = product_id.and_then(model::find_model);
+        return metrics.into_iter().filter(|m| model.is_none_or(|model| model.reports(*m))).collect();
+    };
+    let heard = |sensor: &SensorData, metric: &Metric| match metric {
+        Metric::Temperature => sensor.temperature.is_some(),
+        Metric::Humidity => sensor.humidity.is_some(),
+        Metric::Battery => sensor.battery.is_some(),
+    };
+    let mut stopped: Option<String> = None;
+    let start_time = time::Instant::now();
+    let seconds = duration.unwrap_or(60);
+    while !wanted(product_id).iter().all(|m| heard(&sensor, m)) && start_time.elapsed() < time::Duration::from_secs(seconds)
+        && stopped.is_none() && !stop_requested() {
+        stopped = advertisement_watch.stopped();
+        let data = match events.recv_timeout(time::Duration::from_millis(300)) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        match &data {
+            AdvertisementKind::Temperature(value) if resolve_device_address(&config, value.address) == address => sensor.set_temperature(value.value),
+            AdvertisementKind::Humidity(value) if resolve_device_address(&config, value.address) == address => sensor.set_humidity(value.value),
+            AdvertisementKind::Battery(value) if resolve_device_address(&config, value.address) == address => sensor.set_battery(value.value),
+            AdvertisementKind::Frame(frame) if resolve_device_address(&config, frame.address) == address => {
+                product_id = product_id.or(decoder::decode_product_id(&frame.data));
+                sensor.set_seen(ctx.now());
+            },
+            _ => {},
+        }
+    }
+    bus.shutdown();
+    advertisement_watch.stop();
+    if let Some(msg) = stopped {
+        spinner.finish_and_clear();
+        ctx.note(&format!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg)));
+        return false;
+    }
+    if metric == Some(Metric::Battery) && sensor.battery.is_none() && !stop_requested() {
+        spinner.set_message(format!("Reading battery of {}...", name));
+        match ble::read_device_battery(address, timeout) {
+            Ok(percent) => sensor.set_battery(percent as f32),
+            Err(msg) => ctx.note(&format!("{} Reading battery failed: {}", EXCLAMATION, theme().warning(msg))),
+        }
+    }
+    spinner.finish_and_clear();
+    let missing: Vec<&str> = wanted(product_id).iter().filter(|m| !heard(&sensor, m)).map(|m| m.name()).collect();
+    if !missing.is_empty() {
+        ctx.note(&format!("{} No {} from {} in {}", EXCLAMATION, missing.join(" or "), name, humanize::format_duration(seconds)));
+        return false;
+    }
+
+    let reading = sensor.to_scan_device(address, &config, product_id, None, formula);
+    match (ctx.format, metric) {
+        // the value only, for `$(xiaomi read kitchen --metric temperature)`.
+        (OutputFormat::Table, Some(metric)) => {
+            let value = match metric {
+                Metric::Temperature => reading.temperature,
+                Metric::Humidity => reading.humidity,
+                Metric::Battery => reading.battery,
+            };
+            println!("{}", value.unwrap_or_default());
+        },
+        (OutputFormat::Table, None) => {
+            for (metric, value) in [(Metric::Temperature, reading.temperature), (Metric::Humidity, reading.humidity), (Metric::Battery, reading.battery)] {
+                if let Some(value) = value {
+                    println!("{} {}", metric.name(), value);
+                }
+            }
+        },
+        (OutputFormat::Json, _) => println!("{}", serde_json::to_string(&reading).unwrap()),
+        (OutputFormat::Csv, _) => {
+            println!("{}", output::SCAN_CSV_HEADER);
+            println!("{}", reading.to_csv());
+        },
+    }
+    return true;
+}
+
+// `battery --store` looks this far back for devices without battery_changed.
+const BATTERY_HISTORY_SECONDS: u64 = 365 * 24 * 3600;
+// days of the curve `battery --store` shows.
+const BATTERY_CURVE_DAYS: usize = 30;
+
+// 'battery' command handler. returns false when a battery is below the threshold.
+// advertisements carry the level of most models. LYWSD02 advertises it rarely, so it can be read over GATT.
+fn battery(ctx: &AppContext, groups: &[String], duration: Option<u64>, read: bool, below: Option<u8>, store: &Option<PathBuf>) -> bool {
+    let loaded = config_or_exit(ctx);
+    let selection = DeviceSelection { names: &[], groups: groups, exclude: &[] };
+    let filter = ctx.device_filter(&loaded, &selection, true);
+    let threshold = below.or(loaded.general.low_battery_percent).unwrap_or(20);
+    let timeout = loaded.general.gatt_timeout();
+    let devices: Vec<DeviceConfig> = loaded.devices.clone().unwrap_or_default().into_iter().filter(|d| filter.matches(d.address)).collect();
+    if devices.is_empty() {
+        ctx.note(&format!("{} No device is configured. Add them to toml, see `xiaomi setup`.", EXCLAMATION));
+        return true;
+    }
+    let config = loaded.into_device_map();
+
+    let bus: EventBus<AdvertisementKind> = EventBus::new(EVENT_QUEUE_CAPACITY);
+    let events = bus.subscribe();
+    let publisher = bus.publisher();
+    let on_received = move |_sender: &Option<BluetoothLEAdvertisementWatcher>, args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
+        for value in ble::decode_advertisement(&args) {
+            publisher.publish(value);
+        }
+        Ok(())
+    };
+    let advertisement_watch = match ctx.watch_advertisements(on_received) {
+        Ok(watch) => watch,
+        Err(msg) => {
+            ctx.note(&format!("{} {}", EXCLAMATION, theme().error(msg)));
+            return false;
+        }
+    };
+    let spinner = if ctx.format.is_machine_readable() { ProgressBar::hidden() } else { new_spinner() };
+    spinner.enable_steady_tick(time::Duration::from_millis(120));
+    spinner.set_style(spinner_style(&theme().spinner_template(theme().ok, "{msg}")));
+    let seconds = duration.unwrap_or(60);
+    let mut levels: HashMap<u64, (u8, &'static str)> = HashMap::new();
+    let mut models: HashMap<u64, u16> = HashMap::new();
+    let mut stopped: Option<String> = None;
+    let start_time = time::Instant::now();
+    while levels.len() < devices.len() && start_time.elapsed() < time::Duration::from_secs(seconds) && stopped.is_none() && !stop_requested() {
+        spinner.set_message(format!("Listening... {} of {} devices", levels.len(), devices.len()));
+        stopped = advertisement_watch.stopped();
+        match events.recv_timeout(time::Duration::from_millis(300)) {
+            Ok(AdvertisementKind::Battery(value)) => {
+                let address = resolve_device_address(&config, value.address);
+                if filter.matches(address) && config.contains_key(&address) {
+                    levels.insert(address, (value.value.round().clamp(0.0, 100.0) as u8, "advertisement"));
+                }
+            },
+            Ok(AdvertisementKind::Frame(frame)) => {
+                if let Some(product_id) = decoder::decode_product_id(&frame.data) {
+                    models.insert(resolve_device_address(&config, frame.address), product_id);
+                }
+            },
+            _ => {},
+        }
+    }
+    bus.shutdown();
+    advertisement_watch.stop();
+    if let Some(msg) = stopped {
+        ctx.note(&format!("{} Scan was interrupted: {}", EXCLAMATION, theme().error(msg)));
+    }
+
+    if read {
+        for device in &devices {
+            // models known to have no battery characteristic aren't worth a connection.
+            let readable = models.get(&device.address).and_then(|id| model::find_model(*id)).is_none_or(|m| m.supports(model::Capability::Sync));
+            if levels.contains_key(&device.address) || !readable || stop_requested() {
+                continue;
+            }
+            let name = get_device_name(&config, device.address);
+            spinner.set_message(format!("Reading battery of {}...", name));
+            match ble::read_device_battery(device.address, timeout) {
+                Ok(percent) => {
+                    levels.insert(device.address, (percent, "gatt"));
+                },
+                Err(msg) => spinner.print_above(format!("{} {}: {}", EXCLAMATION, name, theme().warning(msg))),
+            }
+        }
+    }
+    spinner.finish_and_clear();
+
+    // levels are stored along with ones of `scan --store`, then the history of each battery is read back.
+    let mut discharges: HashMap<u64, discharge::Discharge> = HashMap::new();
+    if let Some(path) = store {
+        let now = ctx.now();
+        let result = Store::open(path).and_then(|store| {
+            for (address, (percent, _)) in &levels {
+                store.insert(&StoredReading { time: now, address: *address, metric: Metric::Battery, value: *percent as f32, frame_counter: None })?;
+            }
+            for device in &devices {
+                // levels of the battery in now. the one before was lower, and would look like it charged.
+                let since = device.battery_changed
+                    .map_or(now.saturating_sub(BATTERY_HISTORY_SECONDS), |date| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp().max(0) as u64);
+                if let Some(discharge) = discharge::Discharge::of(&store.query(device.address, Metric::Battery, since)?) {
+                    discharges.insert(device.address, discharge);
+                }
+            }
+            return Ok(());
+        });
+        if let Err(msg) = result {
+            ctx.note(&format!("{} {}: {}", EXCLAMATION, path.display(), theme().error(msg)));
+            return false;
+        }
+    }
+
+    let today = chrono::Local::now().date_naive();
+    let mut rows: Vec<output::BatteryDevice> = devices.iter().map(|d| output::BatteryDevice {
+        address: format_bluetooth_address(d.address),
+        name: d.name.clone(),
+        battery_percent: levels.get(&d.address).map(|(percent, _)| *percent),
+        source: levels.get(&d.address).map(|(_, source)| *source),
+        low: levels.get(&d.address).is_some_and(|(percent, _)| *percent < threshold),
+        battery_age_days: d.battery_age_days(today),
+        percent_per_day: discharges.get(&d.address).map(|discharge| discharge.percent_per_day),
+        days_left: discharges.get(&d.address).and_then(|discharge| discharge.days_left()),
+        discharge: discharges.get(&d.address).map(|discharge| discharge.verdict),
+        daily_levels: discharges.get(&d.address).map_or(vec![], |discharge| discharge.days.iter()
+            .map(|(day, level)| output::DailyLevel { date: discharge::format_day(*day), percent: *level })
+            .collect()),
+    }).collect();
+    output::sort_by_battery(&mut rows);
+    let low = rows.iter().filter(|r| r.low).count();
+
+    match ctx.format {
+        OutputFormat::Table if store.is_some() => {
+            let curves: HashMap<String, String> = discharges.iter()
+                .map(|(address, discharge)| (format_bluetooth_address(*address), discharge.curve(BATTERY_CURVE_DAYS, theme().ascii)))
+                .collect();
+            let mut table = theme().table(&["Device ID", "Battery %", "Source", "Battery age", "Per month", "Empty in", "Last 30 days", "Note"]);
+            for row in &rows {
+                let level = row.battery_percent.map_or("-".to_string(), |p| p.to_string());
+                let note = match row.discharge {
+                    Some(discharge::Verdict::Failing) => theme().error("failing").to_string(),
+                    Some(discharge::Verdict::Misreporting) => theme().warning("misreporting").to_string(),
+                    _ => String::new(),
+                };
+                table.add_row(row![
+                    row.name.clone().unwrap_or(row.address.clone()),
+                    if row.low { theme().error(level).to_string() } else { level },
+                    row.source.unwrap_or("-"),
+                    row.battery_age_days.map_or("-".to_string(), |days| format!("{}d", days)),
+                    row.percent_per_day.map_or("-".to_string(), |p| format!("{:+.1}%", -p * 30.0)),
+                    row.days_left.map_or("-".to_string(), |days| format!("{}d", days)),
+                    curves.get(&row.address).map_or("-", |curve| curve.as_str()),
+                    note,
+                ]);
+            }
+            table.print_tty(true).ok();
+            if discharges.len() < rows.len() {
+                println!("Devices without a curve have levels of fewer than 3 days stored. Run `battery --store` daily, or `scan --store`.");
+            }
+            let failing = rows.iter().filter(|r| r.discharge == Some(discharge::Verdict::Failing)).count();
+            if failing > 0 {
+                println!("{} {} batteries run down fast. They're worn out, or the device draws too much.", EXCLAMATION, failing);
+            }
+            let misreporting = rows.iter().filter(|r| r.discharge == Some(discharge::Verdict::Misreporting)).count();
+            if misreporting > 0 {
+                println!("{} Levels of {} batteries went up. Set battery_changed when a battery is replaced, otherwise the firmware misreports it.", EXCLAMATION, misreporting);
+            }
+        },
+        OutputFormat::Table => {
+            let mut table = theme().table(&["Device ID", "Battery %", "Source", "Battery age"]);
+            for row in &rows {
+                let level = row.battery_percent.map_or("-".to_string(), |p| p.to_string());
+                table.add_row(row![
+                    row.name.clone().unwrap_or(row.address.clone()),
+                    if row.low { theme().error(level).to_string() } else { level },
+                    row.source.unwrap_or("-"),
+                    row.battery_age_days.map_or("-".to_string(), |days| format!("{}d", days)),
+                ]);
+            }
+            table.print_tty(true).ok();
+            if rows.iter().any(|r| r.battery_percent.is_none()) && !read {
+                println!("Devices without a level didn't advertise it. `--read` connects to LYWSD02 and reads it.");
+            }
+        },
+        OutputFormat::Json => println!("{}", serde_json::to_string(&rows).unwrap()),
+        OutputFormat::Csv => {
+            println!("{}", output::BATTERY_CSV_HEADER);
+            for row in &rows {
+                println!("{}", row.to_csv());
+            }
+        },
+    }
+    if low > 0 {
+        ctx.note(&format!("{} {} batteries below {}%, replace them soon", EXCLAMATION, low, threshold));
+    }
+    return low == 0;
+}
+
+// `status` shows a device not heard for longer than this as silent.
+const SILENT_AFTER_SECONDS: u64 = 24 * 3600;
+
+// 'status' command handler. only reads the state file, no bluetooth.
+fn status(ctx: &AppContext) {
+    let loaded = config_or_exit(ctx);
+    let state = ctx.load_state();
+    let now = ctx.now();
+
+    // configured devices first, in toml order. then devices seen before but not in toml.
+    let devices = loaded.devices.unwrap_or_default();
+    let mut addresses: Vec<u64> = devices.iter().map(|d| d.address).collect();
+    for address in state.addresses() {
+        if !devices.iter().any(|d| d.address == address || d.addresses.contains(&address)) {
+            addresses.push(address);
+        }
+    }
+    // empty list or header only, for scripts.
+    if addresses.is_empty() && ctx.format == OutputFormat::Table {
+        println!("{} No device is configured nor seen yet.", EXCLAMATION);
+        return;
+    }
+
+    let config = Config { devices: Some(devices), ..Default::default() }.into_device_map();
+    let today = chrono::Local::now().date_naive();
+    let rows: Vec<output::StatusDevice> = addresses.into_iter().map(|address| {
+        let device = state.get(address).cloned().unwrap_or_default();
+        return output::StatusDevice {
+            address: format_bluetooth_address(address),
+            name: config.get(&address).and_then(|d| d.name.clone()),
+            last_seen: device.last_seen,
+            last_synced: device.last_synced,
+            last_drift_seconds: device.last_drift_seconds,
+            battery_age_days: config.get(&address).and_then(|d| d.battery_age_days(today)),
+        };
+    }).collect();
+
+    match ctx.format {
+        OutputFormat::Table => {
+            let age = |time: Option<u64>| time.map_or("never".to_string(), |t| humanize::format_age(now.saturating_sub(t)));
+            // silent devices stand out. they're likely out of battery, or out of range.
+            let seen = |time: Option<u64>| match time {
+                Some(t) if now.saturating_sub(t) <= SILENT_AFTER_SECONDS => age(time),
+                _ => theme().warning(age(time)).to_string(),
+            };
+            let mut table = theme().table(&["Device ID", "Last seen", "Last synced", "Drift", "Battery age"]);
+            for row in rows {
+                table.add_row(row![
+                    row.name.unwrap_or(row.address),
+                    seen(row.last_seen),
+                    age(row.last_synced),
+                    row.last_drift_seconds.map_or("-".to_string(), humanize::format_signed_duration),
+                    row.battery_age_days.map_or("-".to_string(), |days| format!("{}d", days)),
+                ]);
+            }
+            table.print_tty(true).ok();
+        },
+        OutputFormat::Json => println!("{}", serde_json::to_string(&rows).unwrap()),
+        OutputFormat::Csv => {
+            println!("{}", output::STATUS_CSV_HEADER);
+            for row in &rows {
+                println!("{}", row.to_csv());
+            }
+        },
+    }
 }
@@ -0,0 +1,433 @@
+// Advertises `serve` on the local network with mDNS and DNS-SD (RFC 6762, 6763) as _xiaomi-bridge._tcp, so
+// dashboards and Home Assistant find it without typing an address. A small responder of its own: it answers
+// questions about the service type, its instances and the host, announces them when started, and says goodbye
+// when stopped. Queries from ports other than 5353, one-shot queries of simple resolvers, aren't answered.
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const SERVICE_TYPE: &str = "_xiaomi-bridge._tcp.local";
+// browsing this lists every service type on the network.
+const SERVICES_TYPE: &str = "_services._dns-sd._udp.local";
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+// RFC 6762 10. records with a host name or address live 120 seconds, others 75 minutes.
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// RFC 6762 8.3. announced twice, a second apart.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// how often the responder thread sees it was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// on records only this computer answers for. caches replace what they have, instead of adding to it.
+const CACHE_FLUSH: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    // "xiaomi on TOKYO-PC"
+    pub instance: String,
+    // "TOKYO-PC", without .local
+    pub host: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+    // "key=value"
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    fn instance_name(&self) -> String {
+        return format!("{}.{}", self.instance, SERVICE_TYPE);
+    }
+
+    fn host_name(&self) -> String {
+        return format!("{}.local", self.host);
+    }
+}
+
+// letters, digits and '-' of a host name, short enough to leave room in an instance name. "Tokyo PC" is "Tokyo-PC".
+pub fn host_label(name: &str) -> String {
+    let label: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' }).take(48).collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        return "xiaomi".to_string();
+    }
+    return label.to_string();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+// without compression. labels have no dots, host_label makes sure of it.
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn name_data(name: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_name(&mut data, name);
+    return data;
+}
+
+fn ptr_record(service: &Service) -> Record {
+    return Record { name: SERVICE_TYPE.to_string(), rtype: TYPE_PTR, class: CLASS_IN, ttl: SERVICE_TTL, data: name_data(&service.instance_name()) };
+}
+
+fn srv_record(service: &Service) -> Record {
+    // priority and weight, then the port.
+    let mut data = vec![0, 0, 0, 0];
+    data.extend_from_slice(&service.port.to_be_bytes());
+    write_name(&mut data, &service.host_name());
+    return Record { name: service.instance_name(), rtype: TYPE_SRV, class: CLASS_IN | CACHE_FLUSH, ttl: HOST_TTL, data: data };
+}
+
+fn txt_record(service: &Service) -> Record {
+    let mut data = Vec::new();
+    for entry in &service.txt {
+        let entry = &entry.as_bytes()[..entry.len().min(255)];
+        data.push(entry.len() as u8);
+        data.extend_from_slice(entry);
+    }
+    // RFC 6763 6.1. a TXT record without entries has one empty string.
+    if data.is_empty() {
+        data.push(0);
+    }
+    return Record { name: service.instance_name(), rtype: TYPE_TXT, class: CLASS_IN | CACHE_FLUSH, ttl: SERVICE_TTL, data: data };
+}
+
+fn a_record(service: &Service) -> Record {
+    return Record { name: service.host_name(), rtype: TYPE_A, class: CLASS_IN | CACHE_FLUSH, ttl: HOST_TTL, data: service.address.octets().to_vec() };
+}
+
+fn services_record() -> Record {
+    return Record { name: SERVICES_TYPE.to_string(), rtype: TYPE_PTR, class: CLASS_IN, ttl: SERVICE_TTL, data: name_data(SERVICE_TYPE) };
+}
+
+// a response, without questions. ids of multicast responses are zero.
+fn message(answers: &[Record], additionals: &[Record]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // id, flags of an authoritative answer, then counts of questions, answers, authorities and additionals.
+    for value in [0, 0x8400, 0, answers.len() as u16, 0, additionals.len() as u16] {
+        out.extend_from_slice(&u16::to_be_bytes(value));
+    }
+    for record in answers.iter().chain(additionals) {
+        write_name(&mut out, &record.name);
+        out.extend_from_slice(&record.rtype.to_be_bytes());
+        out.extend_from_slice(&record.class.to_be_bytes());
+        out.extend_from_slice(&record.ttl.to_be_bytes());
+        out.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&record.data);
+    }
+    return out;
+}
+
+// every record of the services. a ttl of 0 says goodbye, and caches drop them.
+pub fn announcement(services: &[Service], ttl: Option<u32>) -> Vec<u8> {
+    let mut records = vec![services_record()];
+    for service in services {
+        records.extend([ptr_record(service), srv_record(service), txt_record(service), a_record(service)]);
+    }
+    if let Some(ttl) = ttl {
+        records.iter_mut().for_each(|r| r.ttl = ttl);
+    }
+    return message(&records, &[]);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    return packet.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+}
+
+// a name at `pos`, and the position after it. pointers of compressed names are followed.
+fn read_name(packet: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = pos;
+    let mut end: Option<usize> = None;
+    // a pointer loop ends here.
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            let pointer = (read_u16(packet, pos)? & 0x3FFF) as usize;
+            end.get_or_insert(pos + 2);
+            pos = pointer;
+            continue;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).to_string());
+        pos += 1 + len;
+    }
+    return None;
+}
+
+// questions of a query. None when it's broken. responses of others have no questions to answer.
+pub fn questions(packet: &[u8]) -> Option<Vec<Question>> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 != 0 {
+        return Some(vec![]);
+    }
+    let count = read_u16(packet, 4)?;
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, next) = read_name(packet, pos)?;
+        let qtype = read_u16(packet, next)?;
+        questions.push(Question { name: name, qtype: qtype });
+        // type, then class.
+        pos = next + 4;
+    }
+    return Some(questions);
+}
+
+// a response to a query. None when nothing asked is about these services.
+pub fn answer(services: &[Service], packet: &[u8]) -> Option<Vec<u8>> {
+    let mut answers: Vec<Record> = Vec::new();
+    let mut additionals: Vec<Record> = Vec::new();
+    for question in questions(packet)? {
+        let name = question.name.to_ascii_lowercase();
+        let wants = |rtype: u16| question.qtype == rtype || question.qtype == TYPE_ANY;
+        if name == SERVICES_TYPE && wants(TYPE_PTR) {
+            answers.push(services_record());
+        }
+        for service in services {
+            if name == SERVICE_TYPE && wants(TYPE_PTR) {
+                answers.push(ptr_record(service));
+                additionals.extend([srv_record(service), txt_record(service), a_record(service)]);
+            }
+            if name == service.instance_name().to_ascii_lowercase() {
+                if wants(TYPE_SRV) {
+                    answers.push(srv_record(service));
+                    additionals.push(a_record(service));
+                }
+                if wants(TYPE_TXT) {
+                    answers.push(txt_record(service));
+                }
+            }
+            if name == service.host_name().to_ascii_lowercase() && wants(TYPE_A) {
+                answers.push(a_record(service));
+            }
+        }
+    }
+    if answers.is_empty() {
+        return None;
+    }
+    let mut unique: Vec<Record> = Vec::new();
+    for record in additionals {
+        if !answers.contains(&record) && !unique.contains(&record) {
+            unique.push(record);
+        }
+    }
+    answers.dedup();
+    return Some(message(&answers, &unique));
+}
+
+// address of the interface multicast goes out of. nothing is sent.
+pub fn local_address() -> Result<Ipv4Addr, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|e| e.to_string())?;
+    socket.connect((GROUP, PORT)).map_err(|e| format!("no network for mDNS: {}", e))?;
+    return match socket.local_addr() {
+        Ok(SocketAddr::V4(address)) if !address.ip().is_unspecified() => Ok(*address.ip()),
+        _ => Err("no network for mDNS".to_string()),
+    };
+}
+
+// Windows listens on 5353 itself. the port is shared, and both receive every query.
+#[cfg(windows)]
+fn open_socket() -> std::io::Result<UdpSocket> {
+    use std::os::windows::io::FromRawSocket;
+    use windows::Win32::Networking::WinSock::{
+        bind, setsockopt, socket, WSAStartup, AF_INET, INVALID_SOCKET, IPPROTO_UDP, SOCKADDR, SOCKADDR_IN, SOCK_DGRAM, SOL_SOCKET, SO_REUSEADDR, WSADATA,
+    };
+    unsafe {
+        let mut data: WSADATA = std::mem::zeroed();
+        WSAStartup(0x202, &mut data);
+        let raw = socket(AF_INET.0 as i32, SOCK_DGRAM, IPPROTO_UDP.0);
+        if raw == INVALID_SOCKET {
+            return Err(std::io::Error::last_os_error());
+        }
+        // closed when dropped, from here on.
+        let udp = UdpSocket::from_raw_socket(raw.0 as u64);
+        if setsockopt(raw, SOL_SOCKET, SO_REUSEADDR, Some(&1u32.to_ne_bytes())) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut address: SOCKADDR_IN = std::mem::zeroed();
+        address.sin_family = AF_INET;
+        address.sin_port = PORT.to_be();
+        if bind(raw, &address as *const SOCKADDR_IN as *const SOCKADDR, std::mem::size_of::<SOCKADDR_IN>() as i32) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(udp);
+    }
+}
+
+#[cfg(not(windows))]
+fn open_socket() -> std::io::Result<UdpSocket> {
+    return UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT));
+}
+
+pub struct Advertiser {
+    socket: UdpSocket,
+    services: Vec<Service>,
+    stop: Arc<AtomicBool>,
+    responder: JoinHandle<()>,
+}
+
+impl Advertiser {
+    // queries are received on the interface of the address of the first service.
+    pub fn start(services: Vec<Service>) -> Result<Advertiser, String> {
+        let interface = services.first().map(|s| s.address).ok_or("no service to advertise")?;
+        let socket = open_socket().map_err(|e| format!("Listening on port {} failed: {}", PORT, e))?;
+        socket.join_multicast_v4(&GROUP, &interface).map_err(|e| format!("Joining {} on {} failed: {}", GROUP, interface, e))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(|e| e.to_string())?;
+        socket.send_to(&announcement(&services, None), (GROUP, PORT)).map_err(|e| format!("Announcing failed: {}", e))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = {
+            let socket = socket.try_clone().map_err(|e| e.to_string())?;
+            let services = services.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let announced = Instant::now();
+                let mut announce_again = true;
+                let mut buffer = [0u8; 9000];
+                while !stop.load(Ordering::SeqCst) {
+                    if announce_again && announced.elapsed() >= ANNOUNCE_INTERVAL {
+                        socket.send_to(&announcement(&services, None), (GROUP, PORT)).ok();
+                        announce_again = false;
+                    }
+                    let (len, from) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        // timed out. stop is checked again.
+                        Err(_) => continue,
+                    };
+                    if from.port() != PORT {
+                        continue;
+                    }
+                    if let Some(response) = answer(&services, &buffer[..len]) {
+                        if let Err(e) = socket.send_to(&response, (GROUP, PORT)) {
+                            log::debug!("Answering mDNS query failed: {}", e);
+                        }
+                    }
+                }
+            })
+        };
+        return Ok(Advertiser { socket: socket, services: services, stop: stop, responder: responder });
+    }
+
+    // says goodbye, so browsers drop the services right away instead of when they expire.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.responder.join().ok();
+        if let Err(e) = self.socket.send_to(&announcement(&self.services, Some(0)), (GROUP, PORT)) {
+            log::debug!("Sending mDNS goodbye failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> Service {
+        return Service {
+            instance: "xiaomi on TOKYO-PC".to_string(),
+            host: "TOKYO-PC".to_string(),
+            address: Ipv4Addr::new(192, 168, 1, 20),
+            port: 8080,
+            txt: vec!["api=/devices".to_string()],
+        };
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut packet, name);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        return packet;
+    }
+
+    #[test]
+    fn test_host_label() {
+        assert_eq!(host_label("TOKYO-PC"), "TOKYO-PC");
+        assert_eq!(host_label("Tokyo PC.lan"), "Tokyo-PC-lan");
+        assert_eq!(host_label("東京"), "xiaomi");
+    }
+
+    #[test]
+    fn test_questions() {
+        assert_eq!(questions(&query("_xiaomi-bridge._tcp.local", TYPE_PTR)),
+            Some(vec![Question { name: "_xiaomi-bridge._tcp.local".to_string(), qtype: TYPE_PTR }]));
+
+        // the second name points to "_tcp.local" of the first.
+        let mut packet = query("_xiaomi-bridge._tcp.local", TYPE_PTR);
+        packet[5] = 2;
+        packet.extend_from_slice(&[5, b'_', b'h', b't', b't', b'p', 0xC0, 27]);
+        packet.extend_from_slice(&[0, TYPE_PTR as u8, 0, 1]);
+        let names: Vec<String> = questions(&packet).unwrap().into_iter().map(|q| q.name).collect();
+        assert_eq!(names, vec!["_xiaomi-bridge._tcp.local", "_http._tcp.local"]);
+
+        assert_eq!(questions(&packet[..20]), None);
+        let mut response = query("_xiaomi-bridge._tcp.local", TYPE_PTR);
+        response[2] = 0x84;
+        assert_eq!(questions(&response), Some(vec![]));
+    }
+
+    #[test]
+    fn test_answer() {
+        let services = [service()];
+        let response = answer(&services, &query("_xiaomi-bridge._tcp.local", TYPE_PTR)).unwrap();
+        // one answer, the PTR. SRV, TXT and A are additional.
+        assert_eq!(&response[..12], &[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3]);
+        let mut expected = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+        write_name(&mut expected, "_xiaomi-bridge._tcp.local");
+        expected.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 46]);
+        write_name(&mut expected, "xiaomi on TOKYO-PC._xiaomi-bridge._tcp.local");
+        assert_eq!(&response[..expected.len()], &expected[..]);
+        assert!(response.ends_with(&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]));
+
+        let response = answer(&services, &query("TOKYO-PC.local", TYPE_A)).unwrap();
+        assert_eq!(&response[6..12], &[0, 1, 0, 0, 0, 0]);
+        assert!(answer(&services, &query("xiaomi on tokyo-pc._xiaomi-bridge._tcp.local", TYPE_ANY)).is_some());
+        assert!(answer(&services, &query("_services._dns-sd._udp.local", TYPE_PTR)).is_some());
+        assert!(answer(&services, &query("_http._tcp.local", TYPE_PTR)).is_none());
+    }
+
+    #[test]
+    fn test_announcement() {
+        let goodbye = announcement(&[service()], Some(0));
+        // services, then PTR, SRV, TXT and A of the service.
+        assert_eq!(&goodbye[6..8], &[0, 5]);
+        let txt = {
+            let mut txt = Vec::new();
+            write_name(&mut txt, "xiaomi on TOKYO-PC._xiaomi-bridge._tcp.local");
+            txt.extend_from_slice(&[0, 16, 0x80, 1, 0, 0, 0, 0, 0, 13, 12]);
+            txt.extend_from_slice(b"api=/devices");
+            txt
+        };
+        assert!(goodbye.windows(txt.len()).any(|w| w == &txt[..]));
+    }
+}
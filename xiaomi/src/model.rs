@@ -0,0 +1,149 @@
+// Registry of known xiaomi device models, identified by MiBeacon product id.
+
+use crate::store::Metric;
+
+// what this program can do with a model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    // readings are decoded by `scan`.
+    Scan,
+    // clock is written by `sync`.
+    Sync,
+    // `history pull`
+    History,
+    // `set-unit`
+    Unit,
+}
+
+pub const ALL_CAPABILITIES: [Capability; 4] = [Capability::Scan, Capability::Sync, Capability::History, Capability::Unit];
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Capability::Scan => "scan",
+            Capability::Sync => "sync",
+            Capability::History => "history",
+            Capability::Unit => "unit",
+        };
+    }
+}
+
+// readings only. clock, history and unit need the GATT service of LYWSD02.
+const SCAN: &[Capability] = &[Capability::Scan];
+const LYWSD02: &[Capability] = &[Capability::Scan, Capability::Sync, Capability::History, Capability::Unit];
+
+// metrics sent in advertisements.
+const CLIMATE: &[Metric] = &[Metric::Temperature, Metric::Humidity, Metric::Battery];
+// plant sensors send soil readings too, which aren't decoded. their battery is only read over GATT.
+const TEMPERATURE_ONLY: &[Metric] = &[Metric::Temperature];
+
+pub struct Model {
+    // model name printed on the device.
+    pub name: &'static str,
+    pub product_ids: &'static [u16],
+    pub description: &'static str,
+    pub capabilities: &'static [Capability],
+    // summaries show "n/a" for other metrics, instead of waiting for them.
+    pub metrics: &'static [Metric],
+}
+
+impl Model {
+    pub fn supports(&self, capability: Capability) -> bool {
+        return self.capabilities.contains(&capability);
+    }
+
+    pub fn reports(&self, metric: Metric) -> bool {
+        return self.metrics.contains(&metric);
+    }
+}
+
+pub static MODELS: &[Model] = &[
+    Model { name: "LYWSD02", product_ids: &[0x045B], description: "E-ink clock with temperature and humidity sensor", capabilities: LYWSD02, metrics: CLIMATE },
+    Model { name: "LYWSD02MMC", product_ids: &[0x16E4, 0x2542], description: "E-ink clock with temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "LYWSD03MMC", product_ids: &[0x055B], description: "Temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "LYWSDCGQ", product_ids: &[0x01AA], description: "Round temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "CGG1", product_ids: &[0x0347, 0x0B48], description: "Qingping temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "CGD1", product_ids: &[0x0576], description: "Qingping alarm clock", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "CGDK2", product_ids: &[0x066F], description: "Qingping temperature and humidity sensor lite", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "MHO-C401", product_ids: &[0x0387], description: "E-ink temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "MHO-C303", product_ids: &[0x06D3], description: "Alarm clock with temperature and humidity sensor", capabilities: SCAN, metrics: CLIMATE },
+    Model { name: "HHCCJCY01", product_ids: &[0x0098], description: "Flower care plant sensor", capabilities: SCAN, metrics: TEMPERATURE_ONLY },
+    Model { name: "GCLS002", product_ids: &[0x03BC], description: "Flower pot plant sensor", capabilities: SCAN, metrics: TEMPERATURE_ONLY },
+    Model { name: "MJYD02YL", product_ids: &[0x07F6], description: "Night light with motion sensor", capabilities: &[], metrics: &[] },
+    Model { name: "YLYK01YL", product_ids: &[0x0153], description: "Yeelight remote control", capabilities: &[], metrics: &[] },
+    Model { name: "WX08ZM", product_ids: &[0x040A], description: "Mosquito repellent", capabilities: &[], metrics: &[] },
+];
+
+pub fn find_model(product_id: u16) -> Option<&'static Model> {
+    return MODELS.iter().find(|m| m.product_ids.contains(&product_id));
+}
+
+pub fn find_model_by_name(name: &str) -> Option<&'static Model> {
+    return MODELS.iter().find(|m| m.name == name);
+}
+
+// "n/a" when the model never sends the metric, "-" when it's not received yet.
+pub fn format_metric(value: Option<f32>, model: Option<&Model>, metric: Metric, precision: Option<usize>) -> String {
+    return match value {
+        Some(value) => crate::humanize::format_value(value, precision),
+        None if model.is_some_and(|m| !m.reports(metric)) => "n/a".to_string(),
+        None => "-".to_string(),
+    };
+}
+
+// model name, or product id for unknown models.
+pub fn format_model(product_id: u16) -> String {
+    return match find_model(product_id) {
+        Some(model) => model.name.to_string(),
+        None => format!("0x{:04X}", product_id),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_model() {
+        assert_eq!(find_model(0x045B).unwrap().name, "LYWSD02");
+        assert_eq!(find_model(0x2542).unwrap().name, "LYWSD02MMC");
+        assert!(find_model(0xFFFF).is_none());
+
+        assert_eq!(format_model(0x055B), "LYWSD03MMC");
+        assert_eq!(format_model(0x0ABC), "0x0ABC");
+
+        assert!(find_model(0x045B).unwrap().supports(Capability::Sync));
+        assert!(!find_model(0x055B).unwrap().supports(Capability::Sync));
+        assert!(find_model(0x055B).unwrap().supports(Capability::Scan));
+        assert_eq!(find_model_by_name("HHCCJCY01").unwrap().product_ids, &[0x0098]);
+    }
+
+    #[test]
+    fn test_format_metric() {
+        let plant = find_model_by_name("HHCCJCY01");
+        assert_eq!(format_metric(Some(23.4), plant, Metric::Temperature, None), "23.4");
+        assert_eq!(format_metric(Some(23.4), plant, Metric::Temperature, Some(2)), "23.40");
+        assert_eq!(format_metric(None, plant, Metric::Temperature, None), "-");
+        assert_eq!(format_metric(None, plant, Metric::Humidity, None), "n/a");
+        assert_eq!(format_metric(None, find_model_by_name("LYWSD03MMC"), Metric::Humidity, None), "-");
+        // unknown models may send anything.
+        assert_eq!(format_metric(None, None, Metric::Humidity, None), "-");
+    }
+
+    #[test]
+    fn test_metrics() {
+        // a model decoded by scan reports something.
+        for m in MODELS {
+            assert_eq!(m.supports(Capability::Scan), !m.metrics.is_empty(), "{}", m.name);
+        }
+    }
+
+    #[test]
+    fn test_unique_product_ids() {
+        let mut ids: Vec<u16> = MODELS.iter().flat_map(|m| m.product_ids.iter().copied()).collect();
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+    }
+}
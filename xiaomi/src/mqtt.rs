@@ -0,0 +1,674 @@
+// Readings published to an MQTT broker, for home automation like Home Assistant, Node-RED or openHAB.
+// A small MQTT 3.1.1 client: connect, publish with QoS 0 or 1, ping and disconnect. `serve --gateway` subscribes
+// to the topic of readings too, to merge readings published by other computers.
+// mqtts:// connects with TLS, and the broker is verified with Mozilla's root certificates.
+
+use serde::Deserialize;
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_TOPIC: &str = "xiaomi/{name}/{metric}";
+
+// the broker drops a client silent for 1.5 times this. a ping is sent after half of it.
+const KEEP_ALIVE_SECONDS: u16 = 60;
+// a broker gone away isn't connected again on every reading.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// packet types, in the upper 4 bits of the first byte.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x80;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+// Declared as [mqtt] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct MqttConfig {
+    // "mqtt://broker.local" or "mqtts://broker.local". port is 1883, or 8883 with TLS, unless given.
+    pub url: Option<String>,
+    // {name}, {address} and {metric} are replaced. default is "xiaomi/{name}/{metric}".
+    pub topic: Option<String>,
+    // 0 or 1. default is 0.
+    pub qos: Option<u8>,
+    // broker keeps the last reading of each topic for clients subscribing later. default is false.
+    pub retain: Option<bool>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // default is "xiaomi-" and the process id.
+    pub client_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+pub fn parse_url(url: &str) -> Result<Endpoint, String> {
+    let invalid = |reason: &str| format!("invalid MQTT url \"{}\": {}. Use mqtt://host:1883, or mqtts:// for TLS", url, reason);
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("mqtt://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("mqtts://") {
+        (true, rest)
+    } else {
+        return Err(invalid("unknown scheme"));
+    };
+    let rest = rest.trim_end_matches('/');
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid("port isn't a number"))?),
+        None => (rest, if tls { 8883 } else { 1883 }),
+    };
+    if host.is_empty() || host.contains('/') {
+        return Err(invalid("no host"));
+    }
+    return Ok(Endpoint { tls: tls, host: host.to_string(), port: port });
+}
+
+// topic of a reading. characters with a meaning in topics are replaced in names, so a name is one level.
+pub fn topic(template: &str, name: Option<&str>, address: &str, metric: &str) -> String {
+    let name = match name {
+        Some(name) => name.replace(['/', '+', '#'], "_"),
+        None => address.to_string(),
+    };
+    return template.replace("{name}", &name).replace("{address}", address).replace("{metric}", metric);
+}
+
+// a reading of another computer, from the levels of its topic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteTopic {
+    // the address when the template has {address}, otherwise {name}. a name is the address for devices without one.
+    pub device: String,
+    pub metric: String,
+}
+
+// topic filter of every reading of the template. {name}, {address} and {metric} are a level each.
+pub fn subscription(template: &str) -> Result<String, String> {
+    let levels: Vec<&str> = template.split('/').map(|level| match level {
+        "{name}" | "{address}" | "{metric}" => Ok("+"),
+        level if level.contains('{') => Err(format!("topic \"{}\" can't be subscribed. Put {{name}}, {{address}} and {{metric}} in levels of their own", template)),
+        level => Ok(level),
+    }).collect::<Result<_, _>>()?;
+    if !template.contains("{metric}") || !(template.contains("{name}") || template.contains("{address}")) {
+        return Err(format!("topic \"{}\" can't be subscribed. It needs {{metric}}, and {{name}} or {{address}}", template));
+    }
+    return Ok(levels.join("/"));
+}
+
+// None when the topic isn't of the template.
+pub fn parse_topic(template: &str, topic: &str) -> Option<RemoteTopic> {
+    let levels: Vec<&str> = topic.split('/').collect();
+    let patterns: Vec<&str> = template.split('/').collect();
+    if levels.len() != patterns.len() {
+        return None;
+    }
+    let (mut name, mut address, mut metric) = (None, None, None);
+    for (pattern, level) in patterns.iter().zip(levels) {
+        match *pattern {
+            "{name}" => name = Some(level),
+            "{address}" => address = Some(level),
+            "{metric}" => metric = Some(level),
+            pattern if pattern != level => return None,
+            _ => {},
+        }
+    }
+    return Some(RemoteTopic { device: address.or(name)?.to_string(), metric: metric?.to_string() });
+}
+
+fn push_string(packet: &mut Vec<u8>, text: &str) {
+    packet.extend((text.len() as u16).to_be_bytes());
+    packet.extend(text.as_bytes());
+}
+
+// fixed header: type and flags, then length of the rest in 7 bit groups.
+fn with_header(first_byte: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    return packet;
+}
+
+pub fn encode_connect(client_id: &str, username: Option<&str>, password: Option<&str>, keep_alive_seconds: u16) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    push_string(&mut body, "MQTT");
+    // protocol level 4 is 3.1.1.
+    body.push(4);
+    // clean session. nothing is subscribed, there's nothing to resume.
+    let mut flags = 0x02;
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend(keep_alive_seconds.to_be_bytes());
+    push_string(&mut body, client_id);
+    if let Some(username) = username {
+        push_string(&mut body, username);
+    }
+    if let Some(password) = password {
+        push_string(&mut body, password);
+    }
+    return with_header(CONNECT, body);
+}
+
+// packet id is only sent with QoS 1.
+pub fn encode_publish(topic: &str, payload: &[u8], qos: u8, retain: bool, packet_id: u16) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    push_string(&mut body, topic);
+    if qos > 0 {
+        body.extend(packet_id.to_be_bytes());
+    }
+    body.extend(payload);
+    return with_header(PUBLISH | (qos << 1) | retain as u8, body);
+}
+
+// a single filter, at QoS 0. the broker sends readings at QoS 0 then, without acknowledging.
+pub fn encode_subscribe(filter: &str, packet_id: u16) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(packet_id.to_be_bytes());
+    push_string(&mut body, filter);
+    body.push(0);
+    // reserved flags of SUBSCRIBE are 0010.
+    return with_header(SUBSCRIBE | 0x02, body);
+}
+
+// body of SUBACK: packet id, then the granted QoS of each filter. 0x80 is refused.
+pub fn decode_suback(body: &[u8]) -> Result<(), String> {
+    return match body.get(2) {
+        Some(0x80) => Err("subscribing was refused by the broker".to_string()),
+        Some(_) => Ok(()),
+        None => Err(format!("unexpected SUBACK of {} bytes", body.len())),
+    };
+}
+
+// topic and payload. QoS of the message is in `flags`, the lower 4 bits of the first byte.
+// returns the packet id to acknowledge with QoS 1 too.
+pub fn decode_publish(flags: u8, body: &[u8]) -> Result<(String, Option<u16>, Vec<u8>), String> {
+    let invalid = || "invalid PUBLISH from MQTT broker".to_string();
+    let length = u16::from_be_bytes([*body.first().ok_or_else(invalid)?, *body.get(1).ok_or_else(invalid)?]) as usize;
+    let topic = body.get(2..2 + length).ok_or_else(invalid)?;
+    let topic = String::from_utf8(topic.to_vec()).map_err(|_| invalid())?;
+    let mut rest = &body[2 + length..];
+    let mut packet_id = None;
+    if flags & 0x06 != 0 {
+        let id = rest.get(..2).ok_or_else(invalid)?;
+        packet_id = Some(u16::from_be_bytes([id[0], id[1]]));
+        rest = &rest[2..];
+    }
+    return Ok((topic, packet_id, rest.to_vec()));
+}
+
+// body of CONNACK: session present flag, and return code.
+pub fn decode_connack(body: &[u8]) -> Result<(), String> {
+    if body.len() != 2 {
+        return Err(format!("unexpected CONNACK of {} bytes", body.len()));
+    }
+    return match body[1] {
+        0 => Ok(()),
+        1 => Err("broker doesn't support MQTT 3.1.1".to_string()),
+        2 => Err("client id was rejected".to_string()),
+        3 => Err("broker is unavailable".to_string()),
+        4 => Err("wrong username or password".to_string()),
+        5 => Err("not authorized".to_string()),
+        code => Err(format!("connection refused, code {}", code)),
+    };
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+pub struct Client {
+    stream: Box<dyn Stream>,
+    qos: u8,
+    retain: bool,
+    next_packet_id: u16,
+    last_sent: Instant,
+}
+
+impl Client {
+    pub fn connect(config: &MqttConfig, timeout: Duration) -> Result<Client, String> {
+        let url = config.url.as_deref().ok_or("no url in [mqtt]")?;
+        let endpoint = parse_url(url)?;
+        let qos = config.qos.unwrap_or(0);
+        if qos > 1 {
+            return Err(format!("qos {} isn't supported. Use 0 or 1", qos));
+        }
+
+        let addresses = (endpoint.host.as_str(), endpoint.port).to_socket_addrs().map_err(|e| format!("{}: {}", endpoint.host, e))?;
+        let mut tcp: Option<TcpStream> = None;
+        let mut error = format!("{}: no address", endpoint.host);
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                },
+                Err(e) => error = format!("{}: {}", address, e),
+            }
+        }
+        let tcp = tcp.ok_or(error)?;
+        tcp.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        tcp.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        tcp.set_nodelay(true).ok();
+
+        let stream: Box<dyn Stream> = if endpoint.tls {
+            Box::new(tls_stream(tcp, &endpoint.host)?)
+        } else {
+            Box::new(tcp)
+        };
+        let mut client = Client { stream: stream, qos: qos, retain: config.retain.unwrap_or(false), next_packet_id: 1, last_sent: Instant::now() };
+
+        let client_id = config.client_id.clone().unwrap_or(format!("xiaomi-{}", std::process::id()));
+        client.send(&encode_connect(&client_id, config.username.as_deref(), config.password.as_deref(), KEEP_ALIVE_SECONDS))?;
+        let body = client.expect(CONNACK)?;
+        decode_connack(&body).map_err(|e| format!("{}: {}", url, e))?;
+        return Ok(client);
+    }
+
+    // with QoS 1, returns once the broker has the message.
+    pub fn publish(&mut self, topic: &str, payload: &str) -> Result<(), String> {
+        let packet_id = self.next_packet_id;
+        // 0 isn't a valid packet id.
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.send(&encode_publish(topic, payload.as_bytes(), self.qos, self.retain, packet_id))?;
+        if self.qos == 1 {
+            let body = self.expect(PUBACK)?;
+            if body.get(..2) != Some(&packet_id.to_be_bytes()[..]) {
+                return Err("PUBACK is for another message".to_string());
+            }
+        }
+        return Ok(());
+    }
+
+    // pings the broker when nothing was sent for a while, so the connection isn't dropped.
+    pub fn keep_alive(&mut self) -> Result<(), String> {
+        if self.last_sent.elapsed() < Duration::from_secs(KEEP_ALIVE_SECONDS as u64 / 2) {
+            return Ok(());
+        }
+        self.send(&[PINGREQ, 0])?;
+        self.expect(PINGRESP)?;
+        return Ok(());
+    }
+
+    pub fn disconnect(mut self) {
+        self.send(&[DISCONNECT, 0]).ok();
+    }
+
+    pub fn subscribe(&mut self, filter: &str) -> Result<(), String> {
+        self.send(&encode_subscribe(filter, 1))?;
+        return decode_suback(&self.expect(SUBACK)?);
+    }
+
+    // topic and payload of the next message of a subscription. None when nothing came within the timeout,
+    // then the broker is pinged when it's time. its answer is skipped with anything else which isn't a message.
+    pub fn receive(&mut self) -> Result<Option<(String, Vec<u8>)>, String> {
+        if self.last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_SECONDS as u64 / 2) {
+            self.send(&[PINGREQ, 0])?;
+        }
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte) {
+            Ok(0) => return Err("MQTT broker closed the connection".to_string()),
+            Ok(_) => {},
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => return Ok(None),
+            Err(e) => return Err(format!("receiving from MQTT broker failed: {}", e)),
+        }
+        let body = self.read_body()?;
+        if byte[0] & 0xF0 != PUBLISH {
+            return Ok(None);
+        }
+        let (topic, packet_id, payload) = decode_publish(byte[0] & 0x0F, &body)?;
+        if let Some(packet_id) = packet_id {
+            let mut puback = vec![PUBACK, 2];
+            puback.extend(packet_id.to_be_bytes());
+            self.send(&puback)?;
+        }
+        return Ok(Some((topic, payload)));
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+        self.stream.write_all(packet).and_then(|_| self.stream.flush()).map_err(|e| format!("sending to MQTT broker failed: {}", e))?;
+        self.last_sent = Instant::now();
+        return Ok(());
+    }
+
+    // body of the next packet, which has to be of this type.
+    fn expect(&mut self, packet_type: u8) -> Result<Vec<u8>, String> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte).map_err(|e| format!("receiving from MQTT broker failed: {}", e))?;
+        let received_type = byte[0] & 0xF0;
+        let body = self.read_body()?;
+        if received_type != packet_type {
+            return Err(format!("unexpected packet 0x{:02X} from MQTT broker", received_type));
+        }
+        return Ok(body);
+    }
+
+    // length, then the rest of a packet after its first byte.
+    fn read_body(&mut self) -> Result<Vec<u8>, String> {
+        let error = |e: std::io::Error| format!("receiving from MQTT broker failed: {}", e);
+        let mut length: usize = 0;
+        for shift in [0, 7, 14, 21] {
+            let mut digit = [0u8; 1];
+            self.stream.read_exact(&mut digit).map_err(error)?;
+            length |= ((digit[0] & 0x7F) as usize) << shift;
+            if digit[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        self.stream.read_exact(&mut body).map_err(error)?;
+        return Ok(body);
+    }
+}
+
+fn tls_stream(tcp: TcpStream, host: &str) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, String> {
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string()).map_err(|e| format!("{}: {}", host, e))?;
+    let connection = rustls::ClientConnection::new(Arc::new(config), server_name).map_err(|e| format!("{}: {}", host, e))?;
+    return Ok(rustls::StreamOwned::new(connection, tcp));
+}
+
+// client which connects again when the broker goes away. readings until then are dropped.
+pub struct Connection {
+    config: MqttConfig,
+    timeout: Duration,
+    client: Option<Client>,
+    // connecting failed. it's tried again after this.
+    retry_at: Option<Instant>,
+}
+
+impl Connection {
+    // the first connection has to work, so a wrong url or password is told right away.
+    pub fn open(config: &MqttConfig, timeout: Duration) -> Result<Connection, String> {
+        let client = Client::connect(config, timeout)?;
+        return Ok(Connection { config: config.clone(), timeout: timeout, client: Some(client), retry_at: None });
+    }
+
+    pub fn topic(&self, name: Option<&str>, address: &str, metric: &str) -> String {
+        return topic(self.config.topic.as_deref().unwrap_or(DEFAULT_TOPIC), name, address, metric);
+    }
+
+    // Err when the connection is lost, or connecting again failed. Ok when it's dropped waiting to connect again.
+    pub fn publish(&mut self, topic: &str, payload: &str) -> Result<(), String> {
+        let client = match self.client() {
+            Some(client) => client?,
+            None => return Ok(()),
+        };
+        let result = client.publish(topic, payload);
+        if result.is_err() {
+            self.lost();
+        }
+        return result;
+    }
+
+    pub fn keep_alive(&mut self) -> Result<(), String> {
+        let result = match self.client.as_mut() {
+            Some(client) => client.keep_alive(),
+            None => return Ok(()),
+        };
+        if result.is_err() {
+            self.lost();
+        }
+        return result;
+    }
+
+    pub fn close(self) {
+        if let Some(client) = self.client {
+            client.disconnect();
+        }
+    }
+
+    fn lost(&mut self) {
+        self.client = None;
+        self.retry_at = Some(Instant::now());
+    }
+
+    // None while waiting to connect again.
+    fn client(&mut self) -> Option<Result<&mut Client, String>> {
+        if self.client.is_none() {
+            if self.retry_at.is_some_and(|at| at > Instant::now()) {
+                return None;
+            }
+            match Client::connect(&self.config, self.timeout) {
+                Ok(client) => {
+                    self.client = Some(client);
+                    self.retry_at = None;
+                },
+                Err(msg) => {
+                    self.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                    return Some(Err(msg));
+                },
+            }
+        }
+        return self.client.as_mut().map(Ok);
+    }
+}
+
+// readings other computers publish to the topic of [mqtt], for `serve --gateway`. connects again when the broker
+// goes away. readings until then are missed.
+pub struct Subscriber {
+    config: MqttConfig,
+    timeout: Duration,
+    template: String,
+    filter: String,
+    client: Option<Client>,
+    // connecting failed. it's tried again after this.
+    retry_at: Option<Instant>,
+}
+
+impl Subscriber {
+    // the first connection has to work, like `scan --mqtt`. its client id is of [mqtt] with "-gateway", so
+    // `scan --mqtt` of the same toml can run at the same time.
+    pub fn open(config: &MqttConfig, timeout: Duration) -> Result<Subscriber, String> {
+        let template = config.topic.clone().unwrap_or(DEFAULT_TOPIC.to_string());
+        let filter = subscription(&template)?;
+        let mut config = config.clone();
+        config.client_id = Some(format!("{}-gateway", config.client_id.unwrap_or(format!("xiaomi-{}", std::process::id()))));
+        let mut subscriber = Subscriber { config: config, timeout: timeout, template: template, filter: filter, client: None, retry_at: None };
+        subscriber.connect()?;
+        return Ok(subscriber);
+    }
+
+    // the next reading, and its device from the topic. None when nothing came within the timeout, or the payload
+    // isn't a number. Err when the connection is lost, or connecting again failed.
+    pub fn receive(&mut self) -> Result<Option<(RemoteTopic, f32)>, String> {
+        if self.client.is_none() {
+            if let Some(wait) = self.retry_at.and_then(|at| at.checked_duration_since(Instant::now())) {
+                std::thread::sleep(wait.min(self.timeout));
+                return Ok(None);
+            }
+            if let Err(msg) = self.connect() {
+                self.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                return Err(msg);
+            }
+        }
+        let received = match self.client.as_mut().map(|client| client.receive()) {
+            Some(Ok(received)) => received,
+            Some(Err(msg)) => {
+                self.client = None;
+                return Err(msg);
+            },
+            None => return Ok(None),
+        };
+        let (topic, payload) = match received {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+        let remote = parse_topic(&self.template, &topic);
+        let value = std::str::from_utf8(&payload).ok().and_then(|text| text.trim().parse::<f32>().ok());
+        return match (remote, value) {
+            (Some(remote), Some(value)) => Ok(Some((remote, value))),
+            _ => {
+                log::debug!("Skipping MQTT message of {}", topic);
+                Ok(None)
+            }
+        };
+    }
+
+    pub fn close(self) {
+        if let Some(client) = self.client {
+            client.disconnect();
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        let mut client = Client::connect(&self.config, self.timeout)?;
+        client.subscribe(&self.filter)?;
+        self.client = Some(client);
+        self.retry_at = None;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(parse_url("mqtt://broker.local"), Ok(Endpoint { tls: false, host: "broker.local".to_string(), port: 1883 }));
+        assert_eq!(parse_url("mqtts://broker.local/"), Ok(Endpoint { tls: true, host: "broker.local".to_string(), port: 8883 }));
+        assert_eq!(parse_url("mqtt://192.168.0.10:1884"), Ok(Endpoint { tls: false, host: "192.168.0.10".to_string(), port: 1884 }));
+        assert!(parse_url("http://broker.local").is_err());
+        assert!(parse_url("mqtt://broker.local:port").is_err());
+        assert!(parse_url("mqtt://").is_err());
+    }
+
+    #[test]
+    fn test_topic() {
+        assert_eq!(topic(DEFAULT_TOPIC, Some("Tokyo"), "AA:BB:CC:DD:EE:FF", "temperature"), "xiaomi/Tokyo/temperature");
+        assert_eq!(topic(DEFAULT_TOPIC, None, "AA:BB:CC:DD:EE:FF", "humidity"), "xiaomi/AA:BB:CC:DD:EE:FF/humidity");
+        assert_eq!(topic("home/{name}/{address}/{metric}", Some("kitchen/fridge #2"), "AA:BB:CC:DD:EE:FF", "battery"),
+            "home/kitchen_fridge _2/AA:BB:CC:DD:EE:FF/battery");
+    }
+
+    #[test]
+    fn test_subscription() {
+        assert_eq!(subscription(DEFAULT_TOPIC).unwrap(), "xiaomi/+/+");
+        assert_eq!(subscription("home/{name}/{address}/{metric}").unwrap(), "home/+/+/+");
+        assert!(subscription("xiaomi/{name}-{metric}").is_err());
+        assert!(subscription("xiaomi/{name}").is_err());
+    }
+
+    #[test]
+    fn test_parse_topic() {
+        assert_eq!(parse_topic(DEFAULT_TOPIC, "xiaomi/Tokyo/temperature"),
+            Some(RemoteTopic { device: "Tokyo".to_string(), metric: "temperature".to_string() }));
+        assert_eq!(parse_topic("home/{name}/{address}/{metric}", "home/Tokyo/AA:BB:CC:DD:EE:FF/battery"),
+            Some(RemoteTopic { device: "AA:BB:CC:DD:EE:FF".to_string(), metric: "battery".to_string() }));
+        assert_eq!(parse_topic(DEFAULT_TOPIC, "zigbee/Tokyo/temperature"), None);
+        assert_eq!(parse_topic(DEFAULT_TOPIC, "xiaomi/Tokyo/temperature/raw"), None);
+    }
+
+    #[test]
+    fn test_encode_subscribe() {
+        assert_eq!(encode_subscribe("x/+", 1), [0x82, 8, 0, 1, 0, 3, b'x', b'/', b'+', 0]);
+        assert_eq!(decode_suback(&[0, 1, 0]), Ok(()));
+        assert!(decode_suback(&[0, 1, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_decode_publish() {
+        let packet = encode_publish("a/b", b"23.4", 0, false, 0);
+        assert_eq!(decode_publish(packet[0] & 0x0F, &packet[2..]), Ok(("a/b".to_string(), None, b"23.4".to_vec())));
+        let packet = encode_publish("a/b", b"68", 1, true, 7);
+        assert_eq!(decode_publish(packet[0] & 0x0F, &packet[2..]), Ok(("a/b".to_string(), Some(7), b"68".to_vec())));
+        assert!(decode_publish(0, &[0, 9, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_encode_connect() {
+        let packet = encode_connect("xiaomi", None, None, 60);
+        assert_eq!(packet, [
+            0x10, 18,
+            0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60,
+            0, 6, b'x', b'i', b'a', b'o', b'm', b'i',
+        ]);
+
+        let packet = encode_connect("x", Some("user"), Some("pw"), 60);
+        assert_eq!(packet[9], 0xC2);
+        assert!(packet.ends_with(&[0, 4, b'u', b's', b'e', b'r', 0, 2, b'p', b'w']));
+    }
+
+    #[test]
+    fn test_encode_publish() {
+        assert_eq!(encode_publish("a/b", b"23.4", 0, false, 7), [0x30, 9, 0, 3, b'a', b'/', b'b', b'2', b'3', b'.', b'4']);
+        assert_eq!(encode_publish("a/b", b"68", 1, true, 7), [0x33, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'6', b'8']);
+
+        // remaining length over 127 takes 2 bytes.
+        let packet = encode_publish("t", &[0u8; 200], 0, false, 0);
+        assert_eq!(packet[..3], [0x30, 0xCB, 0x01]);
+        assert_eq!(packet.len(), 3 + 203);
+    }
+
+    #[test]
+    fn test_decode_connack() {
+        assert_eq!(decode_connack(&[0, 0]), Ok(()));
+        assert!(decode_connack(&[0, 4]).unwrap_err().contains("password"));
+        assert!(decode_connack(&[0]).is_err());
+    }
+
+    #[test]
+    fn test_subscriber() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        // a broker answering CONNECT and SUBSCRIBE, then sending readings of another computer.
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let read_packet = |stream: &mut TcpStream| {
+                let mut header = [0u8; 2];
+                stream.read_exact(&mut header).unwrap();
+                let mut body = vec![0u8; header[1] as usize];
+                stream.read_exact(&mut body).unwrap();
+                (header[0], body)
+            };
+            let (first_byte, body) = read_packet(&mut stream);
+            assert_eq!(first_byte, CONNECT);
+            assert!(body.ends_with(b"xiaomi-gateway"));
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let (first_byte, body) = read_packet(&mut stream);
+            assert_eq!(first_byte, 0x82);
+            assert!(body.ends_with(b"xiaomi/+/+\0"));
+            stream.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+            stream.write_all(&encode_publish("xiaomi/Tokyo/temperature", b"23.4", 0, false, 0)).unwrap();
+            stream.write_all(&encode_publish("xiaomi/Tokyo/temperature", b"hot", 0, false, 0)).unwrap();
+            stream.write_all(&encode_publish("xiaomi/AA:BB:CC:DD:EE:FF/humidity", b"68", 1, false, 5)).unwrap();
+            // acknowledged with QoS 1.
+            let (first_byte, body) = read_packet(&mut stream);
+            assert_eq!((first_byte, body), (PUBACK, vec![0, 5]));
+        });
+
+        let config = MqttConfig { url: Some(url), client_id: Some("xiaomi".to_string()), ..Default::default() };
+        let mut subscriber = Subscriber::open(&config, Duration::from_secs(5)).unwrap();
+        assert_eq!(subscriber.receive(), Ok(Some((RemoteTopic { device: "Tokyo".to_string(), metric: "temperature".to_string() }, 23.4))));
+        assert_eq!(subscriber.receive(), Ok(None));
+        assert_eq!(subscriber.receive(), Ok(Some((RemoteTopic { device: "AA:BB:CC:DD:EE:FF".to_string(), metric: "humidity".to_string() }, 68.0))));
+        broker.join().unwrap();
+    }
+}
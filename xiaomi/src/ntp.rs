@@ -0,0 +1,139 @@
+// Simple SNTP client (RFC 4330). Windows clock can be minutes off, and then every synced clock is off too.
+// Only the offset between NTP time and system clock is measured. It's applied to the system clock.
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// NTP counts seconds from 1900-01-01.
+const NTP_UNIX_OFFSET: f64 = 2208988800.0;
+const PACKET_SIZE: usize = 48;
+
+pub const DEFAULT_SERVERS: &[&str] = &["time.windows.com", "pool.ntp.org"];
+
+// unix epoch as NTP 64-bit timestamp. 32-bit seconds | 32-bit fraction
+pub fn encode_timestamp(unix_seconds: f64) -> [u8; 8] {
+    let ntp = unix_seconds + NTP_UNIX_OFFSET;
+    let seconds = (ntp.trunc() as u64 as u32).to_be_bytes();
+    let fraction = ((ntp.fract() * 4294967296.0) as u32).to_be_bytes();
+    return [seconds[0], seconds[1], seconds[2], seconds[3], fraction[0], fraction[1], fraction[2], fraction[3]];
+}
+
+pub fn decode_timestamp(data: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as f64;
+    let fraction = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as f64 / 4294967296.0;
+    return seconds + fraction - NTP_UNIX_OFFSET;
+}
+
+// client request. version 4, mode 3 (client). transmit time is echoed back as originate time.
+pub fn encode_request(transmit_unix_seconds: f64) -> [u8; PACKET_SIZE] {
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0] = (4 << 3) | 3;
+    packet[40..48].copy_from_slice(&encode_timestamp(transmit_unix_seconds));
+    return packet;
+}
+
+// returns receive and transmit time of the server, as unix epoch.
+pub fn decode_response(data: &[u8], request: &[u8; PACKET_SIZE]) -> Result<(f64, f64), String> {
+    if data.len() < PACKET_SIZE {
+        return Err(format!("NTP response is too short: {} bytes", data.len()));
+    }
+    if data[0] & 0x07 != 4 {
+        return Err("NTP response is not from a server".to_string());
+    }
+    if data[1] == 0 {
+        // kiss-o'-death. reason is in reference id.
+        return Err(format!("NTP server refused: {}", String::from_utf8_lossy(&data[12..16])));
+    }
+    if data[24..32] != request[40..48] {
+        return Err("NTP response doesn't match the request".to_string());
+    }
+    return Ok((decode_timestamp(&data[32..40]), decode_timestamp(&data[40..48])));
+}
+
+// seconds system clock is behind the server. t1: request sent, t2: server received, t3: server sent, t4: response received.
+pub fn clock_offset(t1: f64, t2: f64, t3: f64, t4: f64) -> f64 {
+    return ((t2 - t1) + (t3 - t4)) / 2.0;
+}
+
+fn now_unix_seconds() -> f64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).expect("failed to get UNIX_EPOCH").as_secs_f64();
+}
+
+// seconds to add to the system clock to get NTP time.
+pub fn query_offset(server: &str, timeout: Duration) -> Result<f64, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    socket.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    socket.connect((server, 123)).map_err(|e| format!("{}: {}", server, e))?;
+
+    let t1 = now_unix_seconds();
+    let started = Instant::now();
+    let request = encode_request(t1);
+    socket.send(&request).map_err(|e| format!("{}: {}", server, e))?;
+
+    let mut buffer = [0u8; 128];
+    let size = socket.recv(&mut buffer).map_err(|e| format!("{}: {}", server, e))?;
+    // measure the round trip with a monotonic clock, in case system clock jumps meanwhile.
+    let t4 = t1 + started.elapsed().as_secs_f64();
+
+    let (t2, t3) = decode_response(&buffer[..size], &request).map_err(|e| format!("{}: {}", server, e))?;
+    return Ok(clock_offset(t1, t2, t3, t4));
+}
+
+// tries servers in order. returns the server answered, and its offset.
+pub fn query_servers(servers: &[String], timeout: Duration) -> Result<(String, f64), String> {
+    let mut errors: Vec<String> = Vec::new();
+    for server in servers {
+        match query_offset(server, timeout) {
+            Ok(offset) => return Ok((server.clone(), offset)),
+            Err(msg) => errors.push(msg),
+        }
+    }
+    if errors.is_empty() {
+        return Err("No NTP server configured".to_string());
+    }
+    return Err(errors.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let encoded = encode_timestamp(1696891938.5);
+        // 1696891938 + 2208988800 = 0xE8CF02A2
+        assert_eq!(encoded, [0xE8, 0xCF, 0x02, 0xA2, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(decode_timestamp(&encoded), 1696891938.5);
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let request = encode_request(1000.0);
+        assert_eq!(request[0], 0x23);
+
+        let mut response = [0u8; PACKET_SIZE];
+        response[0] = 0x24;
+        response[1] = 2;
+        response[24..32].copy_from_slice(&request[40..48]);
+        response[32..40].copy_from_slice(&encode_timestamp(1010.25));
+        response[40..48].copy_from_slice(&encode_timestamp(1010.5));
+        assert_eq!(decode_response(&response, &request), Ok((1010.25, 1010.5)));
+
+        assert!(decode_response(&response[..40], &request).is_err());
+
+        let other = encode_request(2000.0);
+        assert!(decode_response(&response, &other).is_err());
+
+        let mut kiss = response;
+        kiss[1] = 0;
+        kiss[12..16].copy_from_slice(b"RATE");
+        assert!(decode_response(&kiss, &request).unwrap_err().contains("RATE"));
+    }
+
+    #[test]
+    fn test_clock_offset() {
+        // system clock is 10 seconds behind, and network takes 1 second each way.
+        assert_eq!(clock_offset(1000.0, 1011.0, 1011.0, 1002.0), 10.0);
+        assert_eq!(clock_offset(1000.0, 991.0, 991.0, 1002.0), -10.0);
+    }
+}
@@ -0,0 +1,417 @@
+// Metrics and a span of each run, exported to an OpenTelemetry collector with OTLP/HTTP in JSON.
+// POSTed to /v1/metrics and /v1/traces of `endpoint`, like the collector's default http://localhost:4318.
+//  xiaomi.temperature, xiaomi.humidity, xiaomi.battery  gauges of the latest readings of each device
+//  xiaomi.sync.duration                                 histogram of seconds syncing each device took
+//  xiaomi.sync.failures                                 count of devices failed or not seen, by device
+
+use crate::prometheus::DeviceMetrics;
+use crate::webhook::SyncReport;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_SERVICE_NAME: &str = "xiaomi";
+const DEFAULT_EXPORT_SECONDS: u64 = 60;
+// seconds a device sync can take, as histogram buckets. a retried sync takes tens of seconds.
+const SYNC_DURATION_BOUNDS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+// temporality of sums and histograms. each run exports what happened in it.
+const DELTA: u8 = 1;
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+// Declared as [otlp] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct OtlpConfig {
+    // "http://localhost:4318"
+    pub endpoint: Option<String>,
+    // default is "xiaomi".
+    pub service_name: Option<String>,
+    // sent with every request, like an API key of a hosted collector.
+    pub headers: Option<BTreeMap<String, String>>,
+    // readings of `scan --watch` and `--stream` are exported this often, in seconds. default is 60.
+    pub export_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Metric {
+    name: &'static str,
+    unit: &'static str,
+    description: &'static str,
+    #[serde(flatten)]
+    data: MetricData,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum MetricData {
+    #[serde(rename_all = "camelCase")]
+    Gauge { data_points: Vec<NumberDataPoint> },
+    #[serde(rename_all = "camelCase")]
+    Sum { data_points: Vec<NumberDataPoint>, aggregation_temporality: u8, is_monotonic: bool },
+    #[serde(rename_all = "camelCase")]
+    Histogram { data_points: Vec<HistogramDataPoint>, aggregation_temporality: u8 },
+}
+
+// 64 bit integers are strings in OTLP JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NumberDataPoint {
+    attributes: Vec<KeyValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_time_unix_nano: Option<String>,
+    time_unix_nano: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_double: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_int: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistogramDataPoint {
+    attributes: Vec<KeyValue>,
+    start_time_unix_nano: String,
+    time_unix_nano: String,
+    count: String,
+    sum: f64,
+    bucket_counts: Vec<String>,
+    explicit_bounds: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Span {
+    trace_id: String,
+    span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Status {
+    code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMetricsRequest {
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceMetrics {
+    resource: Resource,
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Serialize)]
+struct ScopeMetrics {
+    scope: Scope,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportTraceRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<Span>,
+}
+
+const SCOPE: Scope = Scope { name: "xiaomi", version: env!("CARGO_PKG_VERSION") };
+
+// attributes without a value are left out.
+pub fn attributes(pairs: &[(&str, Option<&str>)]) -> Vec<KeyValue> {
+    return pairs.iter()
+        .filter_map(|(key, value)| value.filter(|v| !v.is_empty()).map(|v| KeyValue { key: key.to_string(), value: AnyValue { string_value: v.to_string() } }))
+        .collect();
+}
+
+fn nanos(time: u64) -> String {
+    return (time as u128 * 1_000_000_000).to_string();
+}
+
+// readings are f32. through text, 23.4 stays 23.4 instead of 23.399999618530273.
+fn to_f64(value: f32) -> f64 {
+    return value.to_string().parse().unwrap_or(value as f64);
+}
+
+fn device_attributes(device: &DeviceMetrics) -> Vec<KeyValue> {
+    return attributes(&[("device.address", Some(&device.address)), ("device.name", device.name.as_deref()), ("device.group", device.group.as_deref())]);
+}
+
+// a gauge for each metric, with a data point for each device which sent it, at the time it was heard.
+pub fn reading_metrics(devices: &[DeviceMetrics]) -> Vec<Metric> {
+    let gauge = |name: &'static str, unit: &'static str, description: &'static str, value: &dyn Fn(&DeviceMetrics) -> Option<f32>| -> Metric {
+        let data_points = devices.iter()
+            .filter_map(|device| value(device).zip(device.last_seen).map(|(value, time)| NumberDataPoint {
+                attributes: device_attributes(device),
+                start_time_unix_nano: None,
+                time_unix_nano: nanos(time),
+                as_double: Some(to_f64(value)),
+                as_int: None,
+            }))
+            .collect();
+        return Metric { name: name, unit: unit, description: description, data: MetricData::Gauge { data_points: data_points } };
+    };
+    return vec![
+        gauge("xiaomi.temperature", "Cel", "Temperature of the last reading.", &|d| d.temperature),
+        gauge("xiaomi.humidity", "%", "Relative humidity of the last reading.", &|d| d.humidity),
+        gauge("xiaomi.battery", "%", "Battery level of the last reading.", &|d| d.battery),
+    ];
+}
+
+// what happened in a sync run from `start`. devices not seen have no duration, but count as failures.
+pub fn sync_metrics(report: &SyncReport, start: u64) -> Vec<Metric> {
+    let seconds: Vec<u64> = report.devices.iter().filter_map(|d| d.seconds).collect();
+    let mut bucket_counts = vec![0u64; SYNC_DURATION_BOUNDS.len() + 1];
+    for value in &seconds {
+        let bucket = SYNC_DURATION_BOUNDS.iter().position(|bound| *value as f64 <= *bound).unwrap_or(SYNC_DURATION_BOUNDS.len());
+        bucket_counts[bucket] += 1;
+    }
+    let duration = HistogramDataPoint {
+        attributes: Vec::new(),
+        start_time_unix_nano: nanos(start),
+        time_unix_nano: nanos(report.time),
+        count: seconds.len().to_string(),
+        sum: seconds.iter().sum::<u64>() as f64,
+        bucket_counts: bucket_counts.iter().map(|c| c.to_string()).collect(),
+        explicit_bounds: SYNC_DURATION_BOUNDS.to_vec(),
+    };
+    let failures = report.devices.iter()
+        .filter(|device| device.failed())
+        .map(|device| NumberDataPoint {
+            attributes: attributes(&[("device.address", Some(&device.address)), ("device.name", device.name.as_deref()), ("sync.result", Some(&device.result))]),
+            start_time_unix_nano: Some(nanos(start)),
+            time_unix_nano: nanos(report.time),
+            as_double: None,
+            as_int: Some("1".to_string()),
+        })
+        .collect();
+    return vec![
+        Metric {
+            name: "xiaomi.sync.duration",
+            unit: "s",
+            description: "Seconds syncing a device took.",
+            data: MetricData::Histogram { data_points: vec![duration], aggregation_temporality: DELTA },
+        },
+        Metric {
+            name: "xiaomi.sync.failures",
+            unit: "{device}",
+            description: "Devices which failed, were unfinished or were not seen.",
+            data: MetricData::Sum { data_points: failures, aggregation_temporality: DELTA, is_monotonic: true },
+        },
+    ];
+}
+
+// hex of random bytes. a trace of one span needs no more than unique ids.
+fn random_id(bytes: usize) -> String {
+    let mut id = String::new();
+    while id.len() < bytes * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(id.len());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(bytes * 2);
+    return id;
+}
+
+// a run of `scan` or `sync`, as a trace of its own. `error` is why it failed.
+pub fn run_span(name: &str, start: u64, end: u64, error: Option<&str>, attributes: Vec<KeyValue>) -> Span {
+    return Span {
+        trace_id: random_id(16),
+        span_id: random_id(8),
+        name: name.to_string(),
+        kind: SPAN_KIND_INTERNAL,
+        start_time_unix_nano: nanos(start),
+        end_time_unix_nano: nanos(end),
+        attributes: attributes,
+        status: Status { code: if error.is_some() { STATUS_ERROR } else { STATUS_OK }, message: error.map(|e| e.to_string()) },
+    };
+}
+
+fn endpoint(config: &OtlpConfig) -> Result<String, String> {
+    let endpoint = config.endpoint.as_deref().ok_or("no endpoint in [otlp]")?;
+    if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+        return Err(format!("invalid OTLP endpoint \"{}\". Use http://host:4318", endpoint));
+    }
+    return Ok(endpoint.trim_end_matches('/').to_string());
+}
+
+// where metrics and spans are sent. checks the config, without connecting.
+pub struct Exporter {
+    endpoint: String,
+    resource: Resource,
+    headers: BTreeMap<String, String>,
+    export_interval: Duration,
+    timeout: Duration,
+    last_export: Instant,
+}
+
+impl Exporter {
+    pub fn new(config: &OtlpConfig, timeout: Duration) -> Result<Exporter, String> {
+        let service_name = config.service_name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME);
+        return Ok(Exporter {
+            endpoint: endpoint(config)?,
+            resource: Resource { attributes: attributes(&[("service.name", Some(service_name))]) },
+            headers: config.headers.clone().unwrap_or_default(),
+            export_interval: Duration::from_secs(config.export_seconds.unwrap_or(DEFAULT_EXPORT_SECONDS)),
+            timeout: timeout,
+            last_export: Instant::now(),
+        });
+    }
+
+    // for readings exported periodically. true once in every interval.
+    pub fn is_due(&mut self) -> bool {
+        if self.last_export.elapsed() < self.export_interval {
+            return false;
+        }
+        self.last_export = Instant::now();
+        return true;
+    }
+
+    fn post(&self, path: &str, body: &str) -> Result<(), String> {
+        let url = format!("{}{}", self.endpoint, path);
+        let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(self.timeout)).build().into();
+        let mut request = agent.post(&url).header("Content-Type", "application/json");
+        for (key, value) in &self.headers {
+            request = request.header(key, value);
+        }
+        request.send(body).map_err(|e| format!("{}: {}", url, e))?;
+        return Ok(());
+    }
+
+    pub fn export_metrics(&self, metrics: Vec<Metric>) -> Result<(), String> {
+        let request = ExportMetricsRequest {
+            resource_metrics: vec![ResourceMetrics { resource: self.resource.clone(), scope_metrics: vec![ScopeMetrics { scope: SCOPE, metrics: metrics }] }],
+        };
+        return self.post("/v1/metrics", &serde_json::to_string(&request).map_err(|e| e.to_string())?);
+    }
+
+    pub fn export_span(&self, span: Span) -> Result<(), String> {
+        let request = ExportTraceRequest {
+            resource_spans: vec![ResourceSpans { resource: self.resource.clone(), scope_spans: vec![ScopeSpans { scope: SCOPE, spans: vec![span] }] }],
+        };
+        return self.post("/v1/traces", &serde_json::to_string(&request).map_err(|e| e.to_string())?);
+    }
+}
+
+// checks [otlp] the way `scan --otlp` and `sync --otlp` do.
+pub fn check_config(config: &OtlpConfig) -> Result<(), String> {
+    return endpoint(config).map(|_| ());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::DeviceReport;
+
+    #[test]
+    fn test_reading_metrics() {
+        let devices = [DeviceMetrics {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: Some("Tokyo".to_string()),
+            temperature: Some(23.4),
+            last_seen: Some(1696891938),
+            ..Default::default()
+        }];
+        let metrics = reading_metrics(&devices);
+        assert_eq!(serde_json::to_string(&metrics[0]).unwrap(), concat!(
+            r#"{"name":"xiaomi.temperature","unit":"Cel","description":"Temperature of the last reading.","gauge":{"dataPoints":[{"attributes":["#,
+            r#"{"key":"device.address","value":{"stringValue":"AA:BB:CC:DD:EE:FF"}},{"key":"device.name","value":{"stringValue":"Tokyo"}}],"#,
+            r#""timeUnixNano":"1696891938000000000","asDouble":23.4}]}}"#));
+        assert_eq!(metrics[1].data, MetricData::Gauge { data_points: Vec::new() });
+    }
+
+    #[test]
+    fn test_sync_metrics() {
+        let device = |address: &str, result: &str, seconds: Option<u64>| DeviceReport {
+            address: address.to_string(),
+            name: None,
+            result: result.to_string(),
+            error: None,
+            seconds: seconds,
+            drift_seconds: None,
+            battery_percent: None,
+        };
+        let report = SyncReport {
+            time: 1696891938,
+            success: false,
+            devices: vec![device("11:22:33:44:55:66", "synced", Some(4)), device("22:33:44:55:66:77", "failed", Some(45)), device("AA:BB:CC:DD:EE:FF", "not-seen", None)],
+        };
+        let metrics = sync_metrics(&report, 1696891900);
+        assert_eq!(serde_json::to_string(&metrics[0]).unwrap(), concat!(
+            r#"{"name":"xiaomi.sync.duration","unit":"s","description":"Seconds syncing a device took.","histogram":{"dataPoints":[{"attributes":[],"#,
+            r#""startTimeUnixNano":"1696891900000000000","timeUnixNano":"1696891938000000000","count":"2","sum":49.0,"#,
+            r#""bucketCounts":["0","0","1","0","0","0","1","0","0"],"explicitBounds":[1.0,2.0,5.0,10.0,20.0,30.0,60.0,120.0]}],"aggregationTemporality":1}}"#));
+        let json = serde_json::to_string(&metrics[1]).unwrap();
+        assert!(json.contains(r#""sum":{"dataPoints":[{"attributes":[{"key":"device.address","value":{"stringValue":"22:33:44:55:66:77"}}"#));
+        assert!(json.contains(r#"{"key":"sync.result","value":{"stringValue":"not-seen"}}"#));
+        assert!(json.ends_with(r#""aggregationTemporality":1,"isMonotonic":true}}"#));
+    }
+
+    #[test]
+    fn test_run_span() {
+        let span = run_span("sync", 1696891900, 1696891938, Some("2 devices failed"), attributes(&[("sync.devices", Some("3"))]));
+        assert_eq!(span.trace_id.len(), 32);
+        assert_eq!(span.span_id.len(), 16);
+        assert_ne!(span.trace_id, run_span("sync", 0, 0, None, Vec::new()).trace_id);
+        assert_eq!(span.status, Status { code: STATUS_ERROR, message: Some("2 devices failed".to_string()) });
+        assert_eq!(span.end_time_unix_nano, "1696891938000000000");
+    }
+
+    #[test]
+    fn test_check_config() {
+        assert!(check_config(&toml::from_str("endpoint = \"http://localhost:4318/\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("endpoint = \"localhost:4317\"").unwrap()).is_err());
+        assert!(check_config(&OtlpConfig::default()).is_err());
+
+        let config: OtlpConfig = toml::from_str("endpoint = \"https://otlp.example.com\"\n[headers]\napi-key = \"secret\"").unwrap();
+        assert_eq!(config.headers.unwrap()["api-key"], "secret");
+    }
+}
@@ -0,0 +1,474 @@
+// Machine readable output of `scan`, `sync`, `status`, `battery`, `toml` and `export`, chosen by --format, and of `scan --stream`.
+// JSON is one document printed when the command ends. CSV has a header line, then a line per device.
+// Values are raw, not humanized: addresses as AA:BB:CC:DD:EE:FF, times as unix epoch.
+
+use serde::Serialize;
+
+use crate::discharge;
+use crate::lint::Lint;
+use crate::store::StoredReading;
+use crate::unit::TemperatureUnit;
+use crate::webhook::DeviceReport;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+impl OutputFormat {
+    // progress and notes go to stderr then, so stdout has the output only.
+    pub fn is_machine_readable(&self) -> bool {
+        return *self != OutputFormat::Table;
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown format \"{}\". Use table, json or csv", s)),
+        };
+    }
+}
+
+// `scan --stream`. only ndjson for now, a JSON object per line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    Ndjson,
+}
+
+impl std::str::FromStr for StreamFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_lowercase().as_str() {
+            "ndjson" => Ok(StreamFormat::Ndjson),
+            _ => Err(format!("unknown stream format \"{}\". Use ndjson", s)),
+        };
+    }
+}
+
+// a reading printed by `scan --stream ndjson` as it arrives.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamReading {
+    // unix epoch when it was received.
+    pub time: u64,
+    pub address: String,
+    pub name: Option<String>,
+    // "temperature", "humidity" or "battery". with `scan --dew-point`, "dew_point" and "absolute_humidity" too.
+    pub metric: &'static str,
+    pub value: f32,
+}
+
+// a reading written by `export --format json`. CSV of it is store::format_csv.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportReading {
+    pub time: u64,
+    pub address: String,
+    pub metric: &'static str,
+    pub value: f32,
+    pub frame_counter: Option<u8>,
+}
+
+impl ExportReading {
+    pub fn of(reading: &StoredReading) -> ExportReading {
+        return ExportReading {
+            time: reading.time,
+            address: crate::format_bluetooth_address(reading.address),
+            metric: reading.metric.name(),
+            value: reading.value,
+            frame_counter: reading.frame_counter,
+        };
+    }
+}
+
+// one line, without the newline. fields with commas, quotes or newlines are quoted.
+pub fn csv_line(fields: &[String]) -> String {
+    return fields.iter().map(|field| {
+        if field.contains([',', '"', '\n', '\r']) {
+            return format!("\"{}\"", field.replace('"', "\"\""));
+        }
+        return field.clone();
+    }).collect::<Vec<String>>().join(",");
+}
+
+// missing values are empty.
+fn csv_option<T: ToString>(value: &Option<T>) -> String {
+    return value.as_ref().map_or(String::new(), |v| v.to_string());
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanReport {
+    // unix epoch when the scan ended.
+    pub time: u64,
+    // why the scan stopped early.
+    pub interrupted: Option<String>,
+    pub devices: Vec<ScanDevice>,
+}
+
+// latest readings of a device.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<f32>,
+    // advertising interval, estimated.
+    pub interval_milliseconds: Option<u64>,
+    // unix epoch of the last reading.
+    pub last_seen: Option<u64>,
+    // signal strength of the last advertisement with a reading, in dBm.
+    pub rssi: Option<i16>,
+    // over readings heard during the scan. temperature and humidity above are the last ones.
+    pub temperature_min: Option<f32>,
+    pub temperature_max: Option<f32>,
+    pub temperature_average: Option<f32>,
+    pub humidity_min: Option<f32>,
+    pub humidity_max: Option<f32>,
+    pub humidity_average: Option<f32>,
+    // from the last temperature and humidity, by climate::dew_point and climate::absolute_humidity.
+    pub dew_point: Option<f32>,
+    pub absolute_humidity: Option<f32>,
+    // "feels like" temperature, by apparent_temperature of [general].
+    pub apparent_temperature: Option<f32>,
+    // "dry", "comfortable" or "humid".
+    pub comfort: Option<&'static str>,
+}
+
+// columns added later are at the end, so scripts reading columns by position keep working.
+pub const SCAN_CSV_HEADER: &str = "address,name,model,temperature,humidity,battery,interval_milliseconds,last_seen,\
+temperature_min,temperature_max,temperature_average,humidity_min,humidity_max,humidity_average,rssi,\
+dew_point,absolute_humidity,apparent_temperature,comfort";
+
+impl ScanDevice {
+    pub fn to_csv(&self) -> String {
+        return csv_line(&[
+            self.address.clone(), csv_option(&self.name), csv_option(&self.model),
+            csv_option(&self.temperature), csv_option(&self.humidity), csv_option(&self.battery),
+            csv_option(&self.interval_milliseconds), csv_option(&self.last_seen),
+            csv_option(&self.temperature_min), csv_option(&self.temperature_max), csv_option(&self.temperature_average),
+            csv_option(&self.humidity_min), csv_option(&self.humidity_max), csv_option(&self.humidity_average),
+            csv_option(&self.rssi), csv_option(&self.dew_point), csv_option(&self.absolute_humidity),
+            csv_option(&self.apparent_temperature), csv_option(&self.comfort),
+        ]);
+    }
+}
+
+// order of the scan summary, by `scan --sort`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ScanSort {
+    #[default]
+    Name,
+    Temperature,
+    Humidity,
+    Battery,
+    Rssi,
+}
+
+impl std::str::FromStr for ScanSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_lowercase().as_str() {
+            "name" => Ok(ScanSort::Name),
+            "temperature" => Ok(ScanSort::Temperature),
+            "humidity" => Ok(ScanSort::Humidity),
+            "battery" => Ok(ScanSort::Battery),
+            "rssi" => Ok(ScanSort::Rssi),
+            _ => Err(format!("unknown sort \"{}\". Use name, temperature, humidity, battery or rssi", s)),
+        };
+    }
+}
+
+// devices without the value go last either way, they're not the ones looked for. ties keep the order given.
+pub fn sort_scan_devices(devices: &mut [ScanDevice], by: ScanSort, descending: bool) {
+    let value = |d: &ScanDevice| match by {
+        ScanSort::Name => None,
+        ScanSort::Temperature => d.temperature,
+        ScanSort::Humidity => d.humidity,
+        ScanSort::Battery => d.battery,
+        ScanSort::Rssi => d.rssi.map(|v| v as f32),
+    };
+    devices.sort_by(|a, b| {
+        if by == ScanSort::Name {
+            let name = |d: &ScanDevice| d.name.clone().unwrap_or(d.address.clone()).to_lowercase();
+            let order = name(a).cmp(&name(b));
+            return if descending { order.reverse() } else { order };
+        }
+        return match (value(a), value(b)) {
+            (Some(x), Some(y)) => {
+                let order = x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal);
+                if descending { order.reverse() } else { order }
+            },
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+    });
+}
+
+// JSON of `sync` is the same as the webhook.
+pub const SYNC_CSV_HEADER: &str = "address,name,result,error,seconds,drift_seconds,battery_percent";
+
+pub fn sync_csv(report: &DeviceReport) -> String {
+    return csv_line(&[
+        report.address.clone(), csv_option(&report.name), report.result.clone(), csv_option(&report.error),
+        csv_option(&report.seconds), csv_option(&report.drift_seconds), csv_option(&report.battery_percent),
+    ]);
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusDevice {
+    pub address: String,
+    pub name: Option<String>,
+    // unix epoch.
+    pub last_seen: Option<u64>,
+    pub last_synced: Option<u64>,
+    pub last_drift_seconds: Option<i64>,
+    pub battery_age_days: Option<i64>,
+}
+
+pub const STATUS_CSV_HEADER: &str = "address,name,last_seen,last_synced,last_drift_seconds,battery_age_days";
+
+impl StatusDevice {
+    pub fn to_csv(&self) -> String {
+        return csv_line(&[
+            self.address.clone(), csv_option(&self.name), csv_option(&self.last_seen), csv_option(&self.last_synced),
+            csv_option(&self.last_drift_seconds), csv_option(&self.battery_age_days),
+        ]);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatteryDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub battery_percent: Option<u8>,
+    // "advertisement" or "gatt". None when it's not known.
+    pub source: Option<&'static str>,
+    // below the threshold.
+    pub low: bool,
+    pub battery_age_days: Option<i64>,
+    // from levels stored with --store. None without it, or with too few days stored.
+    pub percent_per_day: Option<f32>,
+    pub days_left: Option<u32>,
+    pub discharge: Option<discharge::Verdict>,
+    // average level of each day stored, oldest first. not in CSV.
+    pub daily_levels: Vec<DailyLevel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyLevel {
+    // YYYY-MM-DD, UTC.
+    pub date: String,
+    pub percent: f32,
+}
+
+pub const BATTERY_CSV_HEADER: &str = "address,name,battery_percent,source,low,battery_age_days,percent_per_day,days_left,discharge";
+
+impl BatteryDevice {
+    pub fn to_csv(&self) -> String {
+        return csv_line(&[
+            self.address.clone(), csv_option(&self.name), csv_option(&self.battery_percent), csv_option(&self.source),
+            self.low.to_string(), csv_option(&self.battery_age_days),
+            csv_option(&self.percent_per_day.map(|p| format!("{:.2}", p))), csv_option(&self.days_left), csv_option(&self.discharge.map(|d| d.name())),
+        ]);
+    }
+}
+
+// lowest battery first, the ones to replace. devices without a level go last, by name.
+pub fn sort_by_battery(devices: &mut [BatteryDevice]) {
+    devices.sort_by(|a, b| {
+        let level = |d: &BatteryDevice| d.battery_percent.map_or(u16::MAX, |p| p as u16);
+        let name = |d: &BatteryDevice| d.name.clone().unwrap_or(d.address.clone()).to_lowercase();
+        return level(a).cmp(&level(b)).then(name(a).cmp(&name(b)));
+    });
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigReport {
+    pub path: String,
+    pub devices: Vec<ConfigDevice>,
+    pub lints: Vec<ConfigLint>,
+}
+
+// a [[device]] of toml, as it's read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigDevice {
+    pub address: String,
+    pub addresses: Vec<String>,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub omit: Option<bool>,
+    pub timezone: Option<String>,
+    pub offset_seconds: Option<i32>,
+    pub unit: Option<TemperatureUnit>,
+    // YYYY-MM-DD
+    pub purchased: Option<String>,
+    pub battery_changed: Option<String>,
+    pub notes: Option<String>,
+}
+
+// aliases are separated by spaces in CSV.
+pub const CONFIG_CSV_HEADER: &str = "address,addresses,name,group,omit,timezone,offset_seconds,unit,purchased,battery_changed,notes";
+
+impl ConfigDevice {
+    pub fn to_csv(&self) -> String {
+        return csv_line(&[
+            self.address.clone(), self.addresses.join(" "), csv_option(&self.name), csv_option(&self.group),
+            csv_option(&self.omit), csv_option(&self.timezone), csv_option(&self.offset_seconds),
+            self.unit.map_or(String::new(), |u| u.name().to_string()),
+            csv_option(&self.purchased), csv_option(&self.battery_changed), csv_option(&self.notes),
+        ]);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigLint {
+    // None for general settings.
+    pub device: Option<String>,
+    pub message: String,
+    pub suggestion: String,
+    pub error: bool,
+}
+
+impl ConfigLint {
+    pub fn from_lint(lint: &Lint) -> ConfigLint {
+        return ConfigLint { device: lint.device.clone(), message: lint.message.clone(), suggestion: lint.suggestion.clone(), error: lint.error };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!("json".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("CSV".parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+        assert!("xml".parse::<OutputFormat>().is_err());
+        assert!(!OutputFormat::default().is_machine_readable());
+        assert_eq!("NDJSON".parse::<StreamFormat>(), Ok(StreamFormat::Ndjson));
+        assert!("json".parse::<StreamFormat>().is_err());
+    }
+
+    #[test]
+    fn test_stream_reading() {
+        let reading = StreamReading { time: 1696891938, address: "11:22:33:44:55:66".to_string(), name: None, metric: "humidity", value: 45.5 };
+        assert_eq!(serde_json::to_string(&reading).unwrap(),
+            r#"{"time":1696891938,"address":"11:22:33:44:55:66","name":null,"metric":"humidity","value":45.5}"#);
+    }
+
+    #[test]
+    fn test_export_reading() {
+        let reading = StoredReading {
+            time: 1696891938, address: 0x112233445566, metric: crate::store::Metric::Temperature, value: 23.5, frame_counter: Some(8),
+        };
+        assert_eq!(serde_json::to_string(&ExportReading::of(&reading)).unwrap(),
+            r#"{"time":1696891938,"address":"11:22:33:44:55:66","metric":"temperature","value":23.5,"frame_counter":8}"#);
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(csv_line(&["a".to_string(), "b, c".to_string(), "say \"hi\"".to_string()]), "a,\"b, c\",\"say \"\"hi\"\"\"");
+
+        let device = ScanDevice {
+            address: "11:22:33:44:55:66".to_string(),
+            name: Some("Tokyo".to_string()),
+            model: Some("LYWSD02".to_string()),
+            temperature: Some(23.4),
+            humidity: None,
+            battery: Some(14.0),
+            interval_milliseconds: Some(1500),
+            last_seen: Some(1696891938),
+            rssi: Some(-67),
+            temperature_min: Some(22.5),
+            temperature_max: Some(23.4),
+            temperature_average: Some(23.0),
+            humidity_min: None,
+            humidity_max: None,
+            humidity_average: None,
+            dew_point: None,
+            absolute_humidity: None,
+            apparent_temperature: Some(23.1),
+            comfort: None,
+        };
+        assert_eq!(device.to_csv(), "11:22:33:44:55:66,Tokyo,LYWSD02,23.4,,14,1500,1696891938,22.5,23.4,23,,,,-67,,,23.1,");
+        assert_eq!(SCAN_CSV_HEADER.split(',').count(), device.to_csv().split(',').count());
+        assert_eq!(serde_json::to_string(&device).unwrap(), concat!(
+            r#"{"address":"11:22:33:44:55:66","name":"Tokyo","model":"LYWSD02","temperature":23.4,"humidity":null,"#,
+            r#""battery":14.0,"interval_milliseconds":1500,"last_seen":1696891938,"rssi":-67,"#,
+            r#""temperature_min":22.5,"temperature_max":23.4,"temperature_average":23.0,"#,
+            r#""humidity_min":null,"humidity_max":null,"humidity_average":null,"dew_point":null,"absolute_humidity":null,"#,
+            r#""apparent_temperature":23.1,"comfort":null}"#));
+    }
+
+    #[test]
+    fn test_sort_scan_devices() {
+        let device = |name: Option<&str>, address: &str, temperature: Option<f32>, rssi: Option<i16>| ScanDevice {
+            address: address.to_string(), name: name.map(|n| n.to_string()), model: None,
+            temperature: temperature, humidity: None, battery: None, interval_milliseconds: None, last_seen: None, rssi: rssi,
+            temperature_min: None, temperature_max: None, temperature_average: None,
+            humidity_min: None, humidity_max: None, humidity_average: None, dew_point: None, absolute_humidity: None,
+            apparent_temperature: None, comfort: None,
+        };
+        let mut devices = vec![
+            device(Some("kitchen"), "11:22:33:44:55:66", Some(24.5), Some(-80)),
+            device(None, "AA:BB:CC:DD:EE:FF", None, Some(-50)),
+            device(Some("Attic"), "11:22:33:44:55:00", Some(31.0), None),
+        ];
+        let order = |devices: &[ScanDevice]| devices.iter().map(|d| d.address.clone()).collect::<Vec<String>>();
+        let (kitchen, unnamed, attic) = ("11:22:33:44:55:66", "AA:BB:CC:DD:EE:FF", "11:22:33:44:55:00");
+
+        sort_scan_devices(&mut devices, ScanSort::Name, false);
+        assert_eq!(order(&devices), vec![unnamed, attic, kitchen]);
+        sort_scan_devices(&mut devices, ScanSort::Temperature, false);
+        assert_eq!(order(&devices), vec![kitchen, attic, unnamed]);
+        // hottest first, still without a reading last.
+        sort_scan_devices(&mut devices, ScanSort::Temperature, true);
+        assert_eq!(order(&devices), vec![attic, kitchen, unnamed]);
+        sort_scan_devices(&mut devices, ScanSort::Rssi, true);
+        assert_eq!(order(&devices), vec![unnamed, kitchen, attic]);
+        sort_scan_devices(&mut devices, ScanSort::Name, true);
+        assert_eq!(order(&devices), vec![kitchen, attic, unnamed]);
+
+        assert_eq!("RSSI".parse::<ScanSort>(), Ok(ScanSort::Rssi));
+        assert!("signal".parse::<ScanSort>().is_err());
+    }
+
+    #[test]
+    fn test_battery() {
+        let device = |name: &str, percent: Option<u8>| BatteryDevice {
+            address: "11:22:33:44:55:66".to_string(),
+            name: Some(name.to_string()),
+            battery_percent: percent,
+            source: percent.map(|_| "advertisement"),
+            low: percent.is_some_and(|p| p < 20),
+            battery_age_days: None,
+            percent_per_day: None,
+            days_left: None,
+            discharge: None,
+            daily_levels: vec![],
+        };
+        let mut devices = vec![device("Osaka", None), device("Tokyo", Some(87)), device("attic", None), device("Kyoto", Some(12))];
+        sort_by_battery(&mut devices);
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_deref().unwrap()).collect();
+        assert_eq!(names, vec!["Kyoto", "Tokyo", "attic", "Osaka"]);
+        assert_eq!(devices[0].to_csv(), "11:22:33:44:55:66,Kyoto,12,advertisement,true,,,,");
+        assert_eq!(devices[3].to_csv(), "11:22:33:44:55:66,Osaka,,,false,,,,");
+
+        devices[0].percent_per_day = Some(0.3333);
+        devices[0].days_left = Some(36);
+        devices[0].discharge = Some(discharge::Verdict::Normal);
+        assert_eq!(devices[0].to_csv(), "11:22:33:44:55:66,Kyoto,12,advertisement,true,,0.33,36,normal");
+    }
+}
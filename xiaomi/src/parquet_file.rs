@@ -0,0 +1,132 @@
+// Readings of `export --parquet`, in the columns of the CSV. Built with `--features parquet`.
+// Rows are kept until a row group is full, then written, so memory stays bounded however big the store is.
+// time is a timestamp in milliseconds, which tools show as a date.
+
+use crate::store::StoredReading;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::io::Write;
+use std::sync::Arc;
+
+const SCHEMA: &str = "
+message reading {
+    required int64 time (TIMESTAMP(MILLIS,true));
+    required binary address (STRING);
+    required binary metric (STRING);
+    required float value;
+    optional int32 frame_counter (INTEGER(8,false));
+}";
+// rows in memory at most.
+pub const ROW_GROUP_SIZE: usize = 65536;
+
+#[derive(Default)]
+struct Columns {
+    time: Vec<i64>,
+    address: Vec<ByteArray>,
+    metric: Vec<ByteArray>,
+    value: Vec<f32>,
+    frame_counter: Vec<i32>,
+    // 1 with a frame counter, 0 without.
+    frame_counter_levels: Vec<i16>,
+}
+
+pub struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    columns: Columns,
+    rows: usize,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(out: W) -> Result<ParquetWriter<W>, String> {
+        let schema = parse_message_type(SCHEMA).map_err(|e| e.to_string())?;
+        let properties = WriterProperties::builder().set_compression(Compression::UNCOMPRESSED).build();
+        let writer = SerializedFileWriter::new(out, Arc::new(schema), Arc::new(properties)).map_err(|e| e.to_string())?;
+        return Ok(ParquetWriter { writer: writer, columns: Columns::default(), rows: 0 });
+    }
+
+    pub fn write(&mut self, reading: &StoredReading) -> Result<(), String> {
+        let columns = &mut self.columns;
+        columns.time.push((reading.time.min(i64::MAX as u64 / 1000) * 1000) as i64);
+        columns.address.push(ByteArray::from(crate::format_bluetooth_address(reading.address).as_str()));
+        columns.metric.push(ByteArray::from(reading.metric.name()));
+        columns.value.push(reading.value);
+        match reading.frame_counter {
+            Some(counter) => {
+                columns.frame_counter.push(counter as i32);
+                columns.frame_counter_levels.push(1);
+            },
+            None => columns.frame_counter_levels.push(0),
+        }
+        self.rows += 1;
+        if self.rows == ROW_GROUP_SIZE {
+            self.flush()?;
+        }
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let columns = std::mem::take(&mut self.columns);
+        let mut row_group = self.writer.next_row_group().map_err(|e| e.to_string())?;
+        // in the order of SCHEMA.
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column().map_err(|e| e.to_string())? {
+            let written = match index {
+                0 => column.typed::<Int64Type>().write_batch(&columns.time, None, None),
+                1 => column.typed::<ByteArrayType>().write_batch(&columns.address, None, None),
+                2 => column.typed::<ByteArrayType>().write_batch(&columns.metric, None, None),
+                3 => column.typed::<FloatType>().write_batch(&columns.value, None, None),
+                _ => column.typed::<Int32Type>().write_batch(&columns.frame_counter, Some(&columns.frame_counter_levels), None),
+            };
+            written.map_err(|e| e.to_string())?;
+            column.close().map_err(|e| e.to_string())?;
+            index += 1;
+        }
+        row_group.close().map_err(|e| e.to_string())?;
+        self.rows = 0;
+        return Ok(());
+    }
+
+    // writes the rows left and the footer. the file can't be read without it.
+    pub fn finish(mut self) -> Result<W, String> {
+        self.flush()?;
+        return self.writer.into_inner().map_err(|e| e.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Metric;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_write() {
+        let path = std::env::temp_dir().join(format!("xiaomi-test-{}.parquet", std::process::id()));
+        let mut writer = ParquetWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        for i in 0..ROW_GROUP_SIZE + 2 {
+            let frame_counter = if i % 2 == 0 { Some((i % 256) as u8) } else { None };
+            writer.write(&StoredReading { time: 1696891800 + i as u64, address: 0x112233445566, metric: Metric::Humidity, value: 45.5, frame_counter: frame_counter }).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        // a full row group, and the rest.
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), ROW_GROUP_SIZE as i64 + 2);
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().take(2).map(|row| row.unwrap()).collect();
+        assert_eq!(rows[0].get_timestamp_millis(0).unwrap(), 1696891800000);
+        assert_eq!(rows[0].get_string(1).unwrap(), "11:22:33:44:55:66");
+        assert_eq!(rows[0].get_string(2).unwrap(), "humidity");
+        assert_eq!(rows[0].get_float(3).unwrap(), 45.5);
+        assert_eq!(rows[0].get_ubyte(4).unwrap(), 0);
+        assert!(rows[1].get_ubyte(4).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
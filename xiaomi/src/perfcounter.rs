@@ -0,0 +1,91 @@
+// Latest readings as Windows performance counters, set by `serve --perf-counters`, for PerfMon and SCOM.
+// A "Xiaomi Sensors" counter set with an instance for each device. The counter set is declared in a manifest
+// registered with `lodctr /m:` by `xiaomi counters install`. Counters are unsigned integers, so temperature
+// and humidity are kept in tenths, and a temperature below zero reads 0.
+
+use crate::store::Metric;
+
+// {5d6a7b3e-2f4c-4b8e-9a1d-7c3e8f0b6a21}
+pub const PROVIDER_GUID: u128 = 0x5d6a7b3e_2f4c_4b8e_9a1d_7c3e8f0b6a21;
+// {a4c1e9d2-6b3f-4e7a-8d5c-1f2b3a4c5d6e}
+pub const COUNTERSET_GUID: u128 = 0xa4c1e9d2_6b3f_4e7a_8d5c_1f2b3a4c5d6e;
+pub const MANIFEST_FILE: &str = "xiaomi.man";
+
+pub struct Counter {
+    pub id: u32,
+    pub metric: Metric,
+    pub name: &'static str,
+    pub description: &'static str,
+    // value is the reading times 10^-scale. PerfMon graphs it times 10^scale.
+    pub scale: i32,
+}
+
+pub const COUNTERS: [Counter; 3] = [
+    Counter { id: 1, metric: Metric::Temperature, name: "Temperature", description: "Temperature of the last reading, in tenths of a degree Celsius.", scale: -1 },
+    Counter { id: 2, metric: Metric::Humidity, name: "Humidity", description: "Relative humidity of the last reading, in tenths of a percent.", scale: -1 },
+    Counter { id: 3, metric: Metric::Battery, name: "Battery", description: "Battery level of the last reading, in percent.", scale: 0 },
+];
+
+pub fn counter(metric: Metric) -> &'static Counter {
+    return COUNTERS.iter().find(|c| c.metric == metric).unwrap();
+}
+
+// rounded, and 0 below zero.
+pub fn counter_value(counter: &Counter, value: f32) -> u32 {
+    return (value * 10f32.powi(-counter.scale)).round().max(0.0) as u32;
+}
+
+// registry form, without braces.
+pub fn guid_text(guid: u128) -> String {
+    return format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        (guid >> 96) as u32, (guid >> 80) as u16, (guid >> 64) as u16, (guid >> 48) as u16, guid & 0xffff_ffff_ffff);
+}
+
+fn escape(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+// instrumentation manifest for lodctr. exe is where the provider runs from, install again after moving it.
+pub fn manifest(exe: &str) -> String {
+    let mut counters = String::new();
+    for counter in COUNTERS.iter() {
+        counters.push_str(&format!(
+            "          <counter id=\"{}\" uri=\"Xiaomi.Sensors.{}\" name=\"{}\" description=\"{}\" type=\"perf_counter_rawcount\" detailLevel=\"standard\" defaultScale=\"{}\"/>\n",
+            counter.id, counter.name, counter.name, escape(counter.description), counter.scale));
+    }
+    return format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events" xmlns:win="http://manifests.microsoft.com/win/2004/08/windows/events" xmlns:xs="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <instrumentation>
+    <counters xmlns="http://schemas.microsoft.com/win/2005/12/counters" schemaVersion="2.0">
+      <provider providerName="xiaomi" providerGuid="{{{}}}" applicationIdentity="{}" providerType="userMode">
+        <counterSet guid="{{{}}}" uri="Xiaomi.Sensors" name="Xiaomi Sensors" description="Latest readings of Xiaomi devices, heard by xiaomi serve." instances="multiple">
+{}        </counterSet>
+      </provider>
+    </counters>
+  </instrumentation>
+</instrumentationManifest>
+"#, guid_text(PROVIDER_GUID), escape(exe), guid_text(COUNTERSET_GUID), counters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_value() {
+        assert_eq!(counter_value(counter(Metric::Temperature), 21.46), 215);
+        assert_eq!(counter_value(counter(Metric::Temperature), -3.2), 0);
+        assert_eq!(counter_value(counter(Metric::Humidity), 45.0), 450);
+        assert_eq!(counter_value(counter(Metric::Battery), 87.0), 87);
+    }
+
+    #[test]
+    fn test_manifest() {
+        assert_eq!(guid_text(COUNTERSET_GUID), "a4c1e9d2-6b3f-4e7a-8d5c-1f2b3a4c5d6e");
+        let manifest = manifest("C:\\Tools & Apps\\xiaomi.exe");
+        assert!(manifest.contains("providerGuid=\"{5d6a7b3e-2f4c-4b8e-9a1d-7c3e8f0b6a21}\" applicationIdentity=\"C:\\Tools &amp; Apps\\xiaomi.exe\""));
+        assert!(manifest.contains("<counter id=\"1\" uri=\"Xiaomi.Sensors.Temperature\" name=\"Temperature\""));
+        assert!(manifest.contains("defaultScale=\"-1\""));
+        assert_eq!(manifest.matches("<counter ").count(), 3);
+    }
+}
@@ -0,0 +1,217 @@
+// Latest readings in the Prometheus text format, for `/metrics` of `serve --prometheus`, and pushed to
+// a Pushgateway by `scan --pushgateway` and `sync --pushgateway`. A gauge for each metric, with a sample for each device:
+//  # HELP xiaomi_temperature_celsius Temperature of the last reading.
+//  # TYPE xiaomi_temperature_celsius gauge
+//  xiaomi_temperature_celsius{address="AA:BB:CC:DD:EE:FF",name="Tokyo",group="bedroom"} 23.4
+
+use crate::webhook::SyncReport;
+use serde::Deserialize;
+use std::time::Duration;
+
+// content type of the text format, for scrapers.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const DEFAULT_JOB: &str = "xiaomi";
+
+// Declared as [pushgateway] in toml.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct PushgatewayConfig {
+    // "http://localhost:9091"
+    pub url: Option<String>,
+    // default is "xiaomi".
+    pub job: Option<String>,
+}
+
+// what a device sent last. a metric not received yet has no sample.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceMetrics {
+    pub address: String,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<f32>,
+    // unix epoch of the last reading.
+    pub last_seen: Option<u64>,
+}
+
+// backslashes, double quotes and line feeds are escaped in label values.
+fn escape(text: &str) -> String {
+    return text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+// labels without a value are left out, same as an empty label to Prometheus.
+fn labels(pairs: &[(&str, Option<&str>)]) -> String {
+    let labels: Vec<String> = pairs.iter()
+        .filter_map(|(key, value)| value.filter(|v| !v.is_empty()).map(|v| format!("{}=\"{}\"", key, escape(v))))
+        .collect();
+    if labels.is_empty() {
+        return String::new();
+    }
+    return format!("{{{}}}", labels.join(","));
+}
+
+fn device_labels(device: &DeviceMetrics) -> String {
+    return labels(&[("address", Some(&device.address)), ("name", device.name.as_deref()), ("group", device.group.as_deref())]);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, samples: &[(String, String)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{} {}\n", name, labels, value));
+    }
+}
+
+// devices in the order given. last seen is the age at `now`, so a device which went quiet stands out in alerts.
+pub fn format_metrics(devices: &[DeviceMetrics], now: u64) -> String {
+    let samples = |value: &dyn Fn(&DeviceMetrics) -> Option<String>| -> Vec<(String, String)> {
+        return devices.iter().filter_map(|device| value(device).map(|v| (device_labels(device), v))).collect();
+    };
+    let mut out = String::new();
+    write_gauge(&mut out, "xiaomi_temperature_celsius", "Temperature of the last reading.", &samples(&|d| d.temperature.map(|v| v.to_string())));
+    write_gauge(&mut out, "xiaomi_humidity_percent", "Relative humidity of the last reading.", &samples(&|d| d.humidity.map(|v| v.to_string())));
+    write_gauge(&mut out, "xiaomi_battery_percent", "Battery level of the last reading.", &samples(&|d| d.battery.map(|v| v.to_string())));
+    write_gauge(&mut out, "xiaomi_last_seen_seconds", "Seconds since the last reading.",
+        &samples(&|d| d.last_seen.map(|time| now.saturating_sub(time).to_string())));
+    return out;
+}
+
+// summary of a sync run. a device succeeded unless it failed, was unfinished or not seen, same as the exit code.
+pub fn format_sync_report(report: &SyncReport) -> String {
+    let samples = |value: &dyn Fn(&crate::webhook::DeviceReport) -> Option<String>| -> Vec<(String, String)> {
+        return report.devices.iter()
+            .filter_map(|device| value(device).map(|v| (labels(&[("address", Some(&device.address)), ("name", device.name.as_deref())]), v)))
+            .collect();
+    };
+    let mut out = String::new();
+    write_gauge(&mut out, "xiaomi_sync_success", "1 when every device of the last sync run succeeded.",
+        &[(String::new(), (report.success as u8).to_string())]);
+    write_gauge(&mut out, "xiaomi_sync_last_run_timestamp_seconds", "Unix time the last sync run ended.",
+        &[(String::new(), report.time.to_string())]);
+    let mut results = Vec::new();
+    for device in &report.devices {
+        let succeeded = !device.failed();
+        let labels = labels(&[("address", Some(&device.address)), ("name", device.name.as_deref()), ("result", Some(&device.result))]);
+        results.push((labels, (succeeded as u8).to_string()));
+    }
+    write_gauge(&mut out, "xiaomi_sync_device_success", "1 when the device was synced or left alone on purpose.", &results);
+    write_gauge(&mut out, "xiaomi_sync_duration_seconds", "Seconds syncing the device took.", &samples(&|d| d.seconds.map(|v| v.to_string())));
+    write_gauge(&mut out, "xiaomi_sync_drift_seconds", "Seconds the clock was ahead before it was synced.",
+        &samples(&|d| d.drift_seconds.map(|v| v.to_string())));
+    write_gauge(&mut out, "xiaomi_sync_battery_percent", "Battery level read during sync.", &samples(&|d| d.battery_percent.map(|v| v.to_string())));
+    return out;
+}
+
+// metrics of a run replace the last run of the same command, and are kept apart from the other command.
+//  http://localhost:9091/metrics/job/xiaomi/command/sync
+pub fn push_url(config: &PushgatewayConfig, command: &str) -> Result<String, String> {
+    let url = config.url.as_deref().ok_or("no url in [pushgateway]")?;
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("invalid Pushgateway url \"{}\". Use http://host:9091", url));
+    }
+    let job = config.job.as_deref().unwrap_or(DEFAULT_JOB);
+    if job.is_empty() || job.contains('/') {
+        return Err(format!("invalid Pushgateway job \"{}\". Use a name without '/'", job));
+    }
+    return Ok(format!("{}/metrics/job/{}/command/{}", url.trim_end_matches('/'), job, command));
+}
+
+// a failed push doesn't fail the run. caller prints the error.
+pub fn push(url: &str, metrics: &str, timeout: Duration) -> Result<(), String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(timeout)).build().into();
+    agent.put(url)
+        .header("Content-Type", CONTENT_TYPE)
+        .send(metrics)
+        .map_err(|e| format!("{}: {}", url, e))?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_metrics() {
+        let devices = [
+            DeviceMetrics {
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                name: Some("Tokyo".to_string()),
+                group: Some("bedroom".to_string()),
+                temperature: Some(23.4),
+                humidity: Some(68.0),
+                battery: None,
+                last_seen: Some(1696891930),
+            },
+            DeviceMetrics {
+                address: "11:22:33:44:55:66".to_string(),
+                name: Some("Living room \"north\"".to_string()),
+                battery: Some(87.0),
+                last_seen: Some(1696891940),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(format_metrics(&devices, 1696891938), concat!(
+            "# HELP xiaomi_temperature_celsius Temperature of the last reading.\n",
+            "# TYPE xiaomi_temperature_celsius gauge\n",
+            "xiaomi_temperature_celsius{address=\"AA:BB:CC:DD:EE:FF\",name=\"Tokyo\",group=\"bedroom\"} 23.4\n",
+            "# HELP xiaomi_humidity_percent Relative humidity of the last reading.\n",
+            "# TYPE xiaomi_humidity_percent gauge\n",
+            "xiaomi_humidity_percent{address=\"AA:BB:CC:DD:EE:FF\",name=\"Tokyo\",group=\"bedroom\"} 68\n",
+            "# HELP xiaomi_battery_percent Battery level of the last reading.\n",
+            "# TYPE xiaomi_battery_percent gauge\n",
+            "xiaomi_battery_percent{address=\"11:22:33:44:55:66\",name=\"Living room \\\"north\\\"\"} 87\n",
+            "# HELP xiaomi_last_seen_seconds Seconds since the last reading.\n",
+            "# TYPE xiaomi_last_seen_seconds gauge\n",
+            "xiaomi_last_seen_seconds{address=\"AA:BB:CC:DD:EE:FF\",name=\"Tokyo\",group=\"bedroom\"} 8\n",
+            "xiaomi_last_seen_seconds{address=\"11:22:33:44:55:66\",name=\"Living room \\\"north\\\"\"} 0\n"));
+    }
+
+    #[test]
+    fn test_format_sync_report() {
+        let device = |address: &str, result: &str, seconds: Option<u64>| crate::webhook::DeviceReport {
+            address: address.to_string(),
+            name: None,
+            result: result.to_string(),
+            error: None,
+            seconds: seconds,
+            drift_seconds: None,
+            battery_percent: None,
+        };
+        let mut synced = device("11:22:33:44:55:66", "synced", Some(4));
+        synced.name = Some("Tokyo".to_string());
+        synced.drift_seconds = Some(-62);
+        synced.battery_percent = Some(87);
+        let report = SyncReport { time: 1696891938, success: false, devices: vec![synced, device("AA:BB:CC:DD:EE:FF", "not-seen", None)] };
+        assert_eq!(format_sync_report(&report), concat!(
+            "# HELP xiaomi_sync_success 1 when every device of the last sync run succeeded.\n",
+            "# TYPE xiaomi_sync_success gauge\n",
+            "xiaomi_sync_success 0\n",
+            "# HELP xiaomi_sync_last_run_timestamp_seconds Unix time the last sync run ended.\n",
+            "# TYPE xiaomi_sync_last_run_timestamp_seconds gauge\n",
+            "xiaomi_sync_last_run_timestamp_seconds 1696891938\n",
+            "# HELP xiaomi_sync_device_success 1 when the device was synced or left alone on purpose.\n",
+            "# TYPE xiaomi_sync_device_success gauge\n",
+            "xiaomi_sync_device_success{address=\"11:22:33:44:55:66\",name=\"Tokyo\",result=\"synced\"} 1\n",
+            "xiaomi_sync_device_success{address=\"AA:BB:CC:DD:EE:FF\",result=\"not-seen\"} 0\n",
+            "# HELP xiaomi_sync_duration_seconds Seconds syncing the device took.\n",
+            "# TYPE xiaomi_sync_duration_seconds gauge\n",
+            "xiaomi_sync_duration_seconds{address=\"11:22:33:44:55:66\",name=\"Tokyo\"} 4\n",
+            "# HELP xiaomi_sync_drift_seconds Seconds the clock was ahead before it was synced.\n",
+            "# TYPE xiaomi_sync_drift_seconds gauge\n",
+            "xiaomi_sync_drift_seconds{address=\"11:22:33:44:55:66\",name=\"Tokyo\"} -62\n",
+            "# HELP xiaomi_sync_battery_percent Battery level read during sync.\n",
+            "# TYPE xiaomi_sync_battery_percent gauge\n",
+            "xiaomi_sync_battery_percent{address=\"11:22:33:44:55:66\",name=\"Tokyo\"} 87\n"));
+    }
+
+    #[test]
+    fn test_push_url() {
+        let config: PushgatewayConfig = toml::from_str("url = \"http://localhost:9091/\"").unwrap();
+        assert_eq!(push_url(&config, "sync").unwrap(), "http://localhost:9091/metrics/job/xiaomi/command/sync");
+
+        let config: PushgatewayConfig = toml::from_str("url = \"https://push.local\"\njob = \"bedroom-pc\"").unwrap();
+        assert_eq!(push_url(&config, "scan").unwrap(), "https://push.local/metrics/job/bedroom-pc/command/scan");
+
+        assert!(push_url(&toml::from_str("url = \"localhost:9091\"").unwrap(), "scan").is_err());
+        assert!(push_url(&toml::from_str("url = \"http://localhost:9091\"\njob = \"a/b\"").unwrap(), "scan").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// Task Scheduler entry running `sync` periodically, made with schtasks.exe.
+// Lighter than the service: it runs as the user, only while the user is logged on.
+
+pub const TASK_NAME: &str = "xiaomi-sync";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Every {
+    Minutes(u32),
+    Hours(u32),
+    Days(u32),
+}
+
+// "30m", "12h" or "1d". ranges are what schtasks accepts.
+pub fn parse_every(text: &str) -> Result<Every, String> {
+    let invalid = || format!("invalid interval \"{}\". Use like 30m, 12h or 1d", text);
+    if text.len() < 2 || !text.is_ascii() {
+        return Err(invalid());
+    }
+    let (number, unit) = text.split_at(text.len() - 1);
+    let n: u32 = number.parse().map_err(|_| invalid())?;
+    let (every, max) = match unit {
+        "m" => (Every::Minutes(n), 1439),
+        "h" => (Every::Hours(n), 23),
+        "d" => (Every::Days(n), 365),
+        _ => return Err(invalid()),
+    };
+    if n == 0 || n > max {
+        return Err(format!("interval \"{}\" is out of range. {} is 1 to {}", text, unit, max));
+    }
+    return Ok(every);
+}
+
+// an argument in double quotes, read back as it is by the command line parser of Windows programs.
+// a '"' is escaped with a backslash, and so are backslashes before it, or before the closing quote.
+fn quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            },
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            },
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    return quoted;
+}
+
+// command line task runs. exe path and names are quoted, they often have spaces.
+pub fn task_command(exe: &str, names: &[String], pushgateway: bool) -> String {
+    let mut command = format!("\"{}\" sync", exe);
+    if pushgateway {
+        command.push_str(" --pushgateway");
+    }
+    for name in names {
+        command.push(' ');
+        command.push_str(&quote(name));
+    }
+    return command;
+}
+
+// arguments of `schtasks`. /F replaces the task made before.
+pub fn create_args(exe: &str, every: Every, names: &[String], pushgateway: bool) -> Vec<String> {
+    let (schedule, modifier) = match every {
+        Every::Minutes(n) => ("MINUTE", n),
+        Every::Hours(n) => ("HOURLY", n),
+        Every::Days(n) => ("DAILY", n),
+    };
+    return vec![
+        "/Create".to_string(),
+        "/TN".to_string(), TASK_NAME.to_string(),
+        "/TR".to_string(), task_command(exe, names, pushgateway),
+        "/SC".to_string(), schedule.to_string(),
+        "/MO".to_string(), modifier.to_string(),
+        "/F".to_string(),
+    ];
+}
+
+pub fn delete_args() -> Vec<String> {
+    return vec!["/Delete".to_string(), "/TN".to_string(), TASK_NAME.to_string(), "/F".to_string()];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_every() {
+        assert_eq!(parse_every("30m"), Ok(Every::Minutes(30)));
+        assert_eq!(parse_every("12h"), Ok(Every::Hours(12)));
+        assert_eq!(parse_every("1d"), Ok(Every::Days(1)));
+        assert!(parse_every("24h").is_err());
+        assert!(parse_every("0m").is_err());
+        assert!(parse_every("12").is_err());
+        assert!(parse_every("h").is_err());
+        assert!(parse_every("1w").is_err());
+    }
+
+    #[test]
+    fn test_create_args() {
+        let names = vec!["bedroom".to_string(), "kitchen".to_string()];
+        assert_eq!(create_args("C:\\Program Files\\xiaomi\\xiaomi.exe", Every::Hours(12), &names, false), vec![
+            "/Create", "/TN", "xiaomi-sync",
+            "/TR", "\"C:\\Program Files\\xiaomi\\xiaomi.exe\" sync \"bedroom\" \"kitchen\"",
+            "/SC", "HOURLY", "/MO", "12", "/F",
+        ]);
+        assert_eq!(task_command("xiaomi.exe", &[], true), "\"xiaomi.exe\" sync --pushgateway");
+        // one argument each, however they're spelled.
+        assert_eq!(task_command("xiaomi.exe", &["Living Room".to_string()], false), "\"xiaomi.exe\" sync \"Living Room\"");
+        assert_eq!(task_command("xiaomi.exe", &["Kid's \"den\"".to_string(), "a\\".to_string()], false),
+            "\"xiaomi.exe\" sync \"Kid's \\\"den\\\"\" \"a\\\\\"");
+    }
+}
@@ -0,0 +1,154 @@
+// Runs `daemon` as a Windows service, so syncing goes on without anyone logged in.
+// The service runs `xiaomi service run` as LocalSystem. Output goes to xiaomi.log next to xiaomi.exe,
+// as there's no console.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::OpenOptions,
+    os::windows::io::AsRawHandle,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use windows::Win32::{
+    Foundation::HANDLE,
+    System::Console::{SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE},
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+const SERVICE_NAME: &str = "xiaomi";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+// a sync run isn't interrupted. stopping waits until it ends.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(120);
+
+fn open_manager(access: ServiceManagerAccess) -> Result<ServiceManager, String> {
+    return ServiceManager::local_computer(None::<&str>, access).map_err(|e| format!("Opening service manager failed: {}", e));
+}
+
+fn open_service(access: ServiceAccess) -> Result<windows_service::service::Service, String> {
+    let manager = open_manager(ServiceManagerAccess::CONNECT)?;
+    return manager.open_service(SERVICE_NAME, access).map_err(|e| format!("Opening service failed: {}", e));
+}
+
+// registers this exe, as it is now. install again after moving it.
+pub fn install() -> Result<(), String> {
+    let manager = open_manager(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("Xiaomi clock sync"),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(|e| e.to_string())?,
+        launch_arguments: vec![OsString::from("service"), OsString::from("run")],
+        dependencies: vec![],
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG).map_err(|e| format!("Creating service failed: {}", e))?;
+    service.set_description("Syncs clocks of Xiaomi LYWSD02 devices periodically.").map_err(|e| e.to_string())?;
+    return Ok(());
+}
+
+// stops the service first, when it's running.
+pub fn uninstall() -> Result<(), String> {
+    let service = open_service(ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+    let status = service.query_status().map_err(|e| e.to_string())?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().map_err(|e| format!("Stopping service failed: {}", e))?;
+    }
+    // removed once it's stopped, and every handle is closed.
+    return service.delete().map_err(|e| format!("Deleting service failed: {}", e));
+}
+
+pub fn start() -> Result<(), String> {
+    let service = open_service(ServiceAccess::START)?;
+    return service.start(&[] as &[&OsStr]).map_err(|e| format!("Starting service failed: {}", e));
+}
+
+pub fn stop() -> Result<(), String> {
+    let service = open_service(ServiceAccess::STOP)?;
+    return service.stop().map(|_| ()).map_err(|e| format!("Stopping service failed: {}", e));
+}
+
+// called by the service manager, through `xiaomi service run`. blocks until the service stops.
+pub fn run() -> Result<(), String> {
+    return service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|e| format!("Not started by the service manager: {}", e));
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    // nowhere to tell when this fails. the service runs anyway.
+    redirect_output().ok();
+    if let Err(msg) = run_service() {
+        eprintln!("Service failed: {}", msg);
+    }
+}
+
+// println! writes to whatever the standard handles are, so they're pointed to the log file.
+fn redirect_output() -> Result<(), String> {
+    let path = std::env::current_exe().map_err(|e| e.to_string())?.with_extension("log");
+    let file = OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let handle = HANDLE(file.as_raw_handle() as isize);
+    unsafe {
+        SetStdHandle(STD_OUTPUT_HANDLE, handle).ok().map_err(|e| e.to_string())?;
+        SetStdHandle(STD_ERROR_HANDLE, handle).ok().map_err(|e| e.to_string())?;
+    }
+    // the handle is used until the process ends.
+    std::mem::forget(file);
+    return Ok(());
+}
+
+fn run_service() -> Result<(), String> {
+    let ctx = crate::context::AppContext::new(Default::default(), Default::default(), Arc::new(xiaomi::timesource::SystemClock))?;
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let handler = move |control| -> ServiceControlHandlerResult {
+        match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop_tx.send(()).ok();
+                return ServiceControlHandlerResult::NoError;
+            },
+            ServiceControl::Interrogate => {
+                return ServiceControlHandlerResult::NoError;
+            },
+            _ => {
+                return ServiceControlHandlerResult::NotImplemented;
+            }
+        }
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, handler).map_err(|e| e.to_string())?;
+    let set_status = |state: ServiceState, accept: ServiceControlAccept, wait_hint: Duration| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted: accept,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: wait_hint,
+            process_id: None,
+        }).map_err(|e| e.to_string())
+    };
+
+    set_status(ServiceState::Running, ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN, Duration::ZERO)?;
+    crate::daemon(&ctx, None, &|duration| {
+        // disconnected when the handler is gone. stop then, too.
+        let stopping = !matches!(stop_rx.recv_timeout(duration), Err(mpsc::RecvTimeoutError::Timeout));
+        if stopping {
+            set_status(ServiceState::StopPending, ServiceControlAccept::empty(), STOP_WAIT_HINT).ok();
+        }
+        stopping
+    });
+    set_status(ServiceState::Stopped, ServiceControlAccept::empty(), Duration::ZERO)?;
+    return Ok(());
+}
@@ -0,0 +1,124 @@
+// Where readings and runs go besides the screen. Which ones is asked on the command line, --store, --mqtt and so
+// on, and [[webhook]] in toml is always on. Opened by each run of a command from the shared context, so `daemon`
+// and `serve` get fresh connections, and a broken one is told before listening.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::store::Store;
+use crate::{graphite, influxdb, mqtt, otlp, prometheus, webhook, Config};
+
+// connecting and each message sent to an MQTT broker.
+pub const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+// connecting and each line sent to Graphite.
+pub const GRAPHITE_TIMEOUT: Duration = Duration::from_secs(10);
+// each request to InfluxDB and other HTTP outputs.
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// sinks asked for on the command line.
+#[derive(Debug, Default, Clone)]
+pub struct SinkSelection {
+    // readings are stored in this SQLite database.
+    pub store: Option<PathBuf>,
+    // readings are published to [mqtt].
+    pub mqtt: bool,
+    // readings are written to [influxdb].
+    pub influxdb: bool,
+    // readings are sent to [graphite].
+    pub graphite: bool,
+    // latest readings or the summary are pushed to [pushgateway] at the end.
+    pub pushgateway: bool,
+    // readings, or metrics of the run, and a span are exported to [otlp].
+    pub otlp: bool,
+}
+
+pub struct Sinks {
+    pub store: Option<Store>,
+    pub mqtt: Option<mqtt::Connection>,
+    pub influxdb: Option<influxdb::Batch>,
+    pub graphite: Option<graphite::Sink>,
+    // url of the job, like ".../metrics/job/xiaomi/command/scan".
+    pub push_url: Option<String>,
+    pub otlp: Option<otlp::Exporter>,
+    pub webhooks: Option<webhook::Notifier>,
+}
+
+impl Sinks {
+    // nothing but [[webhook]]s, for runs with nothing asked on the command line.
+    pub fn none() -> Sinks {
+        return Sinks { store: None, mqtt: None, influxdb: None, graphite: None, push_url: None, otlp: None, webhooks: None };
+    }
+
+    // `command` is the job pushed to Pushgateway. Err when a sink isn't configured, or it can't be reached.
+    // sinks opened until then are closed.
+    pub fn open(config: &Config, selection: &SinkSelection, command: &str) -> Result<Sinks, String> {
+        let mut sinks = Sinks::none();
+        if let Some(path) = &selection.store {
+            sinks.store = Some(Store::open(path).map_err(|msg| format!("Opening store failed: {}", msg))?);
+        }
+        if selection.pushgateway {
+            sinks.push_url = Some(prometheus::push_url(&config.pushgateway, command)?);
+        }
+        if selection.otlp {
+            sinks.otlp = Some(otlp::Exporter::new(&config.otlp, HTTP_TIMEOUT)?);
+        }
+        if selection.influxdb {
+            sinks.influxdb = Some(influxdb::Batch::new(&config.influxdb, HTTP_TIMEOUT)?);
+        }
+        let configs = config.webhooks();
+        if !configs.is_empty() {
+            let low_battery_percent = config.general.low_battery_percent.unwrap_or(20);
+            sinks.webhooks = Some(webhook::Notifier::new(&configs, low_battery_percent, HTTP_TIMEOUT)?);
+        }
+        // connections last, so a wrong setting above doesn't leave them open.
+        if selection.graphite {
+            sinks.graphite = Some(graphite::Sink::open(&config.graphite, GRAPHITE_TIMEOUT).map_err(|msg| format!("Connecting to Graphite failed: {}", msg))?);
+        }
+        if selection.mqtt {
+            sinks.mqtt = Some(mqtt::Connection::open(&config.mqtt, MQTT_TIMEOUT).map_err(|msg| format!("Connecting to MQTT broker failed: {}", msg))?);
+        }
+        return Ok(sinks);
+    }
+
+    // keeps connections alive, and writes batches which are due. called between readings.
+    pub fn tick(&mut self) {
+        if let Some(Err(msg)) = self.mqtt.as_mut().map(|mqtt| mqtt.keep_alive()) {
+            log::warn!("MQTT broker is gone: {}", msg);
+        }
+        if let Some(Err(msg)) = self.influxdb.as_mut().map(|influxdb| influxdb.flush_if_due()) {
+            log::warn!("Writing to InfluxDB failed: {}", msg);
+        }
+    }
+
+    // disconnects, and writes what's pending. Pushgateway, OpenTelemetry and webhooks are left to the caller,
+    // for the summary of the run.
+    pub fn close(&mut self) {
+        if let Some(mqtt) = self.mqtt.take() {
+            mqtt.close();
+        }
+        if let Some(influxdb) = &mut self.influxdb {
+            if let Err(msg) = influxdb.flush() {
+                log::warn!("Writing to InfluxDB failed, {} readings are lost: {}", influxdb.pending(), msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let config = Config::default();
+        let sinks = Sinks::open(&config, &SinkSelection::default(), "scan").unwrap();
+        assert!(sinks.store.is_none() && sinks.mqtt.is_none() && sinks.webhooks.is_none());
+
+        // not configured.
+        let selection = SinkSelection { pushgateway: true, ..Default::default() };
+        assert!(Sinks::open(&config, &selection, "scan").is_err());
+        let selection = SinkSelection { graphite: true, ..Default::default() };
+        let msg = Sinks::open(&config, &selection, "scan").err().unwrap();
+        assert!(msg.contains("Graphite"), "{}", msg);
+    }
+}
@@ -0,0 +1,146 @@
+// `soak` runs monitoring for hours, sampling the process every second, and checks it stayed healthy.
+// Each check is judged on the whole run, so a spike in the middle of the night fails it too.
+
+use crate::humanize::format_duration;
+
+pub struct SoakLimits {
+    // peak working set of the process.
+    pub memory_ceiling_bytes: u64,
+    // events published but not read yet.
+    pub max_queue_depth: usize,
+    // no advertisement for this long means the watcher is stuck.
+    pub stuck_seconds: u64,
+}
+
+// taken every second.
+pub struct SoakSample {
+    // None when the platform can't tell.
+    pub memory_bytes: Option<u64>,
+    pub queue_depth: usize,
+    pub seconds_since_advertisement: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+pub struct SoakMonitor {
+    limits: SoakLimits,
+    samples: u64,
+    peak_memory_bytes: Option<u64>,
+    peak_queue_depth: usize,
+    longest_silence_seconds: u64,
+    panics: Vec<String>,
+    // why the watcher stopped by itself, each time.
+    watcher_stops: Vec<String>,
+}
+
+impl SoakMonitor {
+    pub fn new(limits: SoakLimits) -> SoakMonitor {
+        return SoakMonitor {
+            limits: limits,
+            samples: 0,
+            peak_memory_bytes: None,
+            peak_queue_depth: 0,
+            longest_silence_seconds: 0,
+            panics: Vec::new(),
+            watcher_stops: Vec::new(),
+        };
+    }
+
+    pub fn record(&mut self, sample: &SoakSample) {
+        self.samples += 1;
+        if let Some(memory) = sample.memory_bytes {
+            self.peak_memory_bytes = Some(self.peak_memory_bytes.unwrap_or(0).max(memory));
+        }
+        self.peak_queue_depth = self.peak_queue_depth.max(sample.queue_depth);
+        self.longest_silence_seconds = self.longest_silence_seconds.max(sample.seconds_since_advertisement);
+    }
+
+    pub fn record_panic(&mut self, message: &str) {
+        self.panics.push(message.to_string());
+    }
+
+    pub fn record_watcher_stop(&mut self, reason: &str) {
+        self.watcher_stops.push(reason.to_string());
+    }
+
+    pub fn checks(&self) -> Vec<SoakCheck> {
+        let mut checks: Vec<SoakCheck> = Vec::new();
+        checks.push(SoakCheck {
+            name: "panics",
+            passed: self.panics.is_empty(),
+            detail: match self.panics.first() {
+                Some(first) => format!("{} panics. first: {}", self.panics.len(), first),
+                None => "none".to_string(),
+            },
+        });
+        let stuck = self.longest_silence_seconds >= self.limits.stuck_seconds;
+        checks.push(SoakCheck {
+            name: "watcher",
+            passed: self.watcher_stops.is_empty() && !stuck,
+            detail: match self.watcher_stops.first() {
+                Some(first) => format!("stopped {} times. first: {}", self.watcher_stops.len(), first),
+                None => format!("longest silence {}, limit {}",
+                    format_duration(self.longest_silence_seconds), format_duration(self.limits.stuck_seconds)),
+            },
+        });
+        checks.push(SoakCheck {
+            name: "memory",
+            passed: self.peak_memory_bytes.is_none_or(|peak| peak <= self.limits.memory_ceiling_bytes),
+            detail: match self.peak_memory_bytes {
+                Some(peak) => format!("peak {} MB, limit {} MB", peak / 1024 / 1024, self.limits.memory_ceiling_bytes / 1024 / 1024),
+                None => "not measured on this platform".to_string(),
+            },
+        });
+        checks.push(SoakCheck {
+            name: "queue depth",
+            passed: self.peak_queue_depth <= self.limits.max_queue_depth,
+            detail: format!("peak {}, limit {}", self.peak_queue_depth, self.limits.max_queue_depth),
+        });
+        return checks;
+    }
+
+    // a run without samples proves nothing.
+    pub fn passed(&self) -> bool {
+        return self.samples > 0 && self.checks().iter().all(|c| c.passed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> SoakMonitor {
+        return SoakMonitor::new(SoakLimits { memory_ceiling_bytes: 100 * 1024 * 1024, max_queue_depth: 10, stuck_seconds: 300 });
+    }
+
+    #[test]
+    fn test_passed() {
+        let mut soak = monitor();
+        assert!(!soak.passed());
+        soak.record(&SoakSample { memory_bytes: Some(20 * 1024 * 1024), queue_depth: 3, seconds_since_advertisement: 2 });
+        soak.record(&SoakSample { memory_bytes: None, queue_depth: 0, seconds_since_advertisement: 0 });
+        assert!(soak.passed());
+        assert_eq!(soak.checks()[2].detail, "peak 20 MB, limit 100 MB");
+    }
+
+    #[test]
+    fn test_failed() {
+        let mut soak = monitor();
+        soak.record(&SoakSample { memory_bytes: Some(120 * 1024 * 1024), queue_depth: 11, seconds_since_advertisement: 300 });
+        let failed: Vec<&str> = soak.checks().iter().filter(|c| !c.passed).map(|c| c.name).collect();
+        assert_eq!(failed, vec!["watcher", "memory", "queue depth"]);
+
+        let mut soak = monitor();
+        soak.record(&SoakSample { memory_bytes: None, queue_depth: 0, seconds_since_advertisement: 0 });
+        soak.record_panic("index out of bounds");
+        soak.record_watcher_stop("Radio is off");
+        assert!(!soak.passed());
+        assert_eq!(soak.checks()[0].detail, "1 panics. first: index out of bounds");
+        assert_eq!(soak.checks()[1].detail, "stopped 1 times. first: Radio is off");
+    }
+}
@@ -0,0 +1,124 @@
+// What happened to each device in previous runs. Saved as toml next to the config file.
+// Unlike the config, this file is written by the program. Deleting it is harmless.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::{decode_bluetooth_adddress, format_bluetooth_address};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceState {
+    // unix epoch, UTC.
+    pub last_seen: Option<u64>,
+    // last time the clock was written, or found close enough.
+    pub last_synced: Option<u64>,
+    // drift measured before the last sync. positive when the clock was ahead.
+    pub last_drift_seconds: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    // keyed by the address identifying the logical device. toml keys are strings.
+    #[serde(default)]
+    device: BTreeMap<String, DeviceState>,
+}
+
+impl State {
+    // missing file is an empty state.
+    pub fn load(path: &Path) -> Result<State, String> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = toml::to_string(self).map_err(|e| e.to_string())?;
+        return std::fs::write(path, content).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    pub fn get(&self, address: u64) -> Option<&DeviceState> {
+        return self.device.get(&format_bluetooth_address(address));
+    }
+
+    pub fn get_mut(&mut self, address: u64) -> &mut DeviceState {
+        return self.device.entry(format_bluetooth_address(address)).or_default();
+    }
+
+    // addresses of every device in the state.
+    pub fn addresses(&self) -> Vec<u64> {
+        return self.device.keys().filter_map(|k| decode_bluetooth_adddress(k).ok()).collect();
+    }
+
+    // devices synced less than `max_age_seconds` ago.
+    pub fn synced_within(&self, now: u64, max_age_seconds: u64) -> HashSet<u64> {
+        return self.addresses().into_iter()
+            .filter(|address| self.get(*address).and_then(|d| d.last_synced).is_some_and(|synced| now.saturating_sub(synced) < max_age_seconds))
+            .collect();
+    }
+
+    // devices synced less than `max_age_seconds` ago, whose drift was within `threshold_seconds` when read last.
+    // they only need a look at their drift.
+    pub fn drift_checks(&self, now: u64, max_age_seconds: u64, threshold_seconds: u64) -> HashSet<u64> {
+        return self.synced_within(now, max_age_seconds).into_iter()
+            .filter(|address| self.get(*address).and_then(|d| d.last_drift_seconds).is_none_or(|drift| drift.unsigned_abs() <= threshold_seconds))
+            .collect();
+    }
+
+    pub fn record_seen(&mut self, address: u64, now: u64) {
+        self.get_mut(address).last_seen = Some(now);
+    }
+
+    pub fn record_synced(&mut self, address: u64, now: u64) {
+        let device = self.get_mut(address);
+        device.last_seen = Some(now);
+        device.last_synced = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let mut state = State::default();
+        assert!(state.get(0x112233445566).is_none());
+
+        state.record_seen(0x112233445566, 1000);
+        state.record_synced(0xAABBCCDDEEFF, 2000);
+        state.get_mut(0xAABBCCDDEEFF).last_drift_seconds = Some(-62);
+        assert_eq!(state.get(0x112233445566).unwrap().last_synced, None);
+
+        let content = toml::to_string(&state).unwrap();
+        assert!(content.contains("[device.\"AA:BB:CC:DD:EE:FF\"]"));
+
+        let loaded: State = toml::from_str(&content).unwrap();
+        assert_eq!(loaded.addresses(), vec![0x112233445566, 0xAABBCCDDEEFF]);
+        assert_eq!(loaded.get(0xAABBCCDDEEFF), Some(&DeviceState { last_seen: Some(2000), last_synced: Some(2000), last_drift_seconds: Some(-62) }));
+    }
+
+    #[test]
+    fn test_synced_within() {
+        let mut state = State::default();
+        state.record_seen(0x112233445566, 1000);
+        state.record_synced(0xAABBCCDDEEFF, 2000);
+        state.record_synced(0x665544332211, 9000);
+
+        assert_eq!(state.synced_within(10000, 3600), HashSet::from([0x665544332211]));
+        assert_eq!(state.synced_within(10000, 9000), HashSet::from([0x665544332211, 0xAABBCCDDEEFF]));
+
+        // drifted too far since, when read.
+        state.get_mut(0xAABBCCDDEEFF).last_drift_seconds = Some(-62);
+        state.get_mut(0x665544332211).last_drift_seconds = Some(3);
+        assert_eq!(state.drift_checks(10000, 9000, 5), HashSet::from([0x665544332211]));
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let state = State::load(Path::new("does-not-exist.state.toml")).unwrap();
+        assert!(state.addresses().is_empty());
+    }
+}
@@ -0,0 +1,383 @@
+// Readings kept in a SQLite database, so they can be looked at after `scan` ends.
+// Devices repeat the same frame several times, and the same frame can be stored again by a replay,
+// a restart, or two computers scanning the same room. Those are stored once: a reading with the frame counter
+// of one stored within a short window around it is the same frame, wherever the window falls.
+
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Temperature,
+    Humidity,
+    Battery,
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Battery => "battery",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Metric> {
+        return match name {
+            "temperature" => Some(Metric::Temperature),
+            "humidity" => Some(Metric::Humidity),
+            "battery" => Some(Metric::Battery),
+            _ => None,
+        };
+    }
+}
+
+// case insensitive, for command line.
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return Metric::from_name(&s.to_lowercase()).ok_or(format!("unknown metric \"{}\". Use temperature, humidity or battery", s));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredReading {
+    // unix epoch, UTC.
+    pub time: u64,
+    // address identifying the logical device.
+    pub address: u64,
+    pub metric: Metric,
+    pub value: f32,
+    pub frame_counter: Option<u8>,
+}
+
+// times are stored as i64. u64::MAX is the end of a range without end, not a negative time.
+fn epoch(time: u64) -> i64 {
+    return time.min(i64::MAX as u64) as i64;
+}
+
+// frame counter wraps at 256, so a frame is the same only this close in time. two computers with clocks a minute
+// apart store it once, and it's well within the time the counter takes to wrap around.
+const COUNTER_WINDOW_SECONDS: u64 = 120;
+// without a counter, readings this close are the same.
+const TIME_WINDOW_SECONDS: u64 = 5;
+
+// readings stored within this many seconds of `time` with the same counter are the same frame.
+pub fn dedup_window(frame_counter: Option<u8>) -> u64 {
+    return match frame_counter {
+        Some(_) => COUNTER_WINDOW_SECONDS,
+        None => TIME_WINDOW_SECONDS,
+    };
+}
+
+pub const CSV_HEADER: &str = "time,address,metric,value,frame_counter";
+
+// one line, without the newline.
+pub fn format_csv(reading: &StoredReading) -> String {
+    return format!("{},{},{},{},{}",
+        reading.time,
+        crate::format_bluetooth_address(reading.address),
+        reading.metric.name(),
+        reading.value,
+        reading.frame_counter.map_or(String::new(), |c| c.to_string()));
+}
+
+// min, max and average of readings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub average: f32,
+}
+
+impl Summary {
+    // None when there's no reading.
+    pub fn of(readings: &[StoredReading]) -> Option<Summary> {
+        if readings.is_empty() {
+            return None;
+        }
+        let min = readings.iter().map(|r| r.value).fold(f32::INFINITY, f32::min);
+        let max = readings.iter().map(|r| r.value).fold(f32::NEG_INFINITY, f32::max);
+        // summed in f64, so months of readings don't lose precision.
+        let sum: f64 = readings.iter().map(|r| r.value as f64).sum();
+        return Some(Summary { count: readings.len(), min: min, max: max, average: (sum / readings.len() as f64) as f32 });
+    }
+
+    // `summary` with one more value, for readings summarized as they arrive, like `scan` does.
+    pub fn add(summary: Option<Summary>, value: f32) -> Summary {
+        return match summary {
+            None => Summary { count: 1, min: value, max: value, average: value },
+            Some(summary) => {
+                let count = summary.count + 1;
+                let average = summary.average as f64 + (value as f64 - summary.average as f64) / count as f64;
+                Summary { count: count, min: summary.min.min(value), max: summary.max.max(value), average: average as f32 }
+            }
+        };
+    }
+}
+
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Store, String> {
+        let connection = Connection::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Store::init(connection);
+    }
+
+    pub fn open_in_memory() -> Result<Store, String> {
+        return Store::init(Connection::open_in_memory().map_err(|e| e.to_string())?);
+    }
+
+    fn init(connection: Connection) -> Result<Store, String> {
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS readings (
+                time INTEGER NOT NULL,
+                address INTEGER NOT NULL,
+                metric TEXT NOT NULL,
+                value REAL NOT NULL,
+                frame_counter INTEGER
+            );
+        ").map_err(|e| e.to_string())?;
+        // stores made by older versions have a dedup_key, unique in 5 minute buckets. a constraint can't be
+        // dropped in SQLite, so the table is copied without it.
+        let has_dedup_key = connection.prepare("SELECT 1 FROM pragma_table_info('readings') WHERE name = 'dedup_key'")
+            .and_then(|mut statement| statement.exists([]))
+            .map_err(|e| e.to_string())?;
+        if has_dedup_key {
+            connection.execute_batch("
+                BEGIN;
+                CREATE TABLE readings_new (
+                    time INTEGER NOT NULL,
+                    address INTEGER NOT NULL,
+                    metric TEXT NOT NULL,
+                    value REAL NOT NULL,
+                    frame_counter INTEGER
+                );
+                INSERT INTO readings_new SELECT time, address, metric, value, frame_counter FROM readings;
+                DROP TABLE readings;
+                ALTER TABLE readings_new RENAME TO readings;
+                COMMIT;
+            ").map_err(|e| format!("Upgrading store failed: {}", e))?;
+        }
+        connection.execute_batch("
+            CREATE INDEX IF NOT EXISTS readings_time ON readings (time);
+            CREATE INDEX IF NOT EXISTS readings_frame ON readings (address, metric, frame_counter, time);
+        ").map_err(|e| e.to_string())?;
+        return Ok(Store { connection: connection });
+    }
+
+    // a reading already stored keeps its time, and takes the new value.
+    // each statement is atomic, so another process storing the same frame meanwhile doesn't make a duplicate.
+    pub fn insert(&self, reading: &StoredReading) -> Result<(), String> {
+        let window = dedup_window(reading.frame_counter);
+        let values = params![
+            reading.time as i64,
+            reading.address as i64,
+            reading.metric.name(),
+            reading.value as f64,
+            reading.frame_counter,
+            epoch(reading.time.saturating_sub(window)),
+            epoch(reading.time.saturating_add(window)),
+        ];
+        let updated = self.connection.execute("
+            UPDATE readings SET value = ?4 WHERE rowid = (
+                SELECT rowid FROM readings
+                WHERE address = ?2 AND metric = ?3 AND frame_counter IS ?5 AND time >= ?6 AND time <= ?7
+                ORDER BY ABS(time - ?1) LIMIT 1)",
+            values).map_err(|e| e.to_string())?;
+        if updated > 0 {
+            return Ok(());
+        }
+        self.connection.execute("
+            INSERT INTO readings (time, address, metric, value, frame_counter)
+            SELECT ?1, ?2, ?3, ?4, ?5 WHERE NOT EXISTS (
+                SELECT 1 FROM readings
+                WHERE address = ?2 AND metric = ?3 AND frame_counter IS ?5 AND time >= ?6 AND time <= ?7)",
+            values).map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    // readings in time order, one at a time. only the current row is in memory, however big the store is.
+    // stops at the first error from `f`.
+    // only readings from `from` to `until`, both included.
+    pub fn for_each_reading(&self, from: u64, until: u64, mut f: impl FnMut(StoredReading) -> Result<(), String>) -> Result<(), String> {
+        let mut statement = self.connection.prepare("
+            SELECT time, address, metric, value, frame_counter FROM readings WHERE time >= ?1 AND time <= ?2
+            ORDER BY time, address, metric")
+            .map_err(|e| e.to_string())?;
+        let mut rows = statement.query(params![epoch(from), epoch(until)]).map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let metric: String = row.get(2).map_err(|e| e.to_string())?;
+            f(StoredReading {
+                time: row.get::<_, i64>(0).map_err(|e| e.to_string())? as u64,
+                address: row.get::<_, i64>(1).map_err(|e| e.to_string())? as u64,
+                metric: Metric::from_name(&metric).ok_or(format!("Unknown metric: {}", metric))?,
+                value: row.get::<_, f64>(3).map_err(|e| e.to_string())? as f32,
+                frame_counter: row.get(4).map_err(|e| e.to_string())?,
+            })?;
+        }
+        return Ok(());
+    }
+
+    // readings of a device and metric at or after `since`, in time order.
+    pub fn query(&self, address: u64, metric: Metric, since: u64) -> Result<Vec<StoredReading>, String> {
+        let mut statement = self.connection.prepare("
+            SELECT time, value, frame_counter FROM readings WHERE address = ?1 AND metric = ?2 AND time >= ?3 ORDER BY time")
+            .map_err(|e| e.to_string())?;
+        let rows = statement.query_map(params![address as i64, metric.name(), since as i64], |row| {
+            return Ok(StoredReading {
+                time: row.get::<_, i64>(0)? as u64,
+                address: address,
+                metric: metric,
+                value: row.get::<_, f64>(1)? as f32,
+                frame_counter: row.get(2)?,
+            });
+        }).map_err(|e| e.to_string())?;
+        return rows.collect::<Result<Vec<StoredReading>, _>>().map_err(|e| e.to_string());
+    }
+
+    pub fn count(&self) -> Result<u64, String> {
+        return self.connection.query_row("SELECT COUNT(*) FROM readings", [], |row| row.get::<_, i64>(0))
+            .map(|count| count as u64)
+            .map_err(|e| e.to_string());
+    }
+
+    // readings `for_each_reading` goes through.
+    pub fn count_between(&self, from: u64, until: u64) -> Result<u64, String> {
+        return self.connection.query_row("SELECT COUNT(*) FROM readings WHERE time >= ?1 AND time <= ?2",
+            params![epoch(from), epoch(until)], |row| row.get::<_, i64>(0))
+            .map(|count| count as u64)
+            .map_err(|e| e.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(time: u64, metric: Metric, value: f32, frame_counter: Option<u8>) -> StoredReading {
+        return StoredReading { time: time, address: 0x112233445566, metric: metric, value: value, frame_counter: frame_counter };
+    }
+
+    #[test]
+    fn test_metric() {
+        assert_eq!("Humidity".parse::<Metric>(), Ok(Metric::Humidity));
+        assert_eq!(Metric::from_name(Metric::Battery.name()), Some(Metric::Battery));
+        assert!("pressure".parse::<Metric>().is_err());
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        // 1696891800 is on a 5 minute boundary. the same frame heard on both sides of it, or by two computers
+        // with clocks apart, is stored once.
+        let store = Store::open_in_memory().unwrap();
+        store.insert(&reading(1696891799, Metric::Temperature, 23.4, Some(7))).unwrap();
+        store.insert(&reading(1696891801, Metric::Temperature, 23.4, Some(7))).unwrap();
+        store.insert(&reading(1696891860, Metric::Temperature, 23.4, Some(7))).unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+
+        // counter wrapped around.
+        store.insert(&reading(1696892400, Metric::Temperature, 23.6, Some(7))).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+
+        // without a counter, only seconds apart.
+        store.insert(&reading(1696891798, Metric::Battery, 14.0, None)).unwrap();
+        store.insert(&reading(1696891802, Metric::Battery, 14.0, None)).unwrap();
+        store.insert(&reading(1696891810, Metric::Battery, 14.0, None)).unwrap();
+        assert_eq!(store.count().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE readings (
+                time INTEGER NOT NULL, address INTEGER NOT NULL, metric TEXT NOT NULL, value REAL NOT NULL,
+                frame_counter INTEGER, dedup_key INTEGER NOT NULL, UNIQUE (address, metric, dedup_key));
+            INSERT INTO readings VALUES (1696891799, 1, 'temperature', 23.4, 7, 1448014087);
+        ").unwrap();
+        let store = Store::init(connection).unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+        // same key in the old table. it's a different frame now, more than the window apart.
+        store.insert(&StoredReading { time: 1696891650, address: 1, metric: Metric::Temperature, value: 23.1, frame_counter: Some(7) }).unwrap();
+        store.insert(&StoredReading { time: 1696891800, address: 1, metric: Metric::Temperature, value: 23.5, frame_counter: Some(7) }).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.query(1, Metric::Temperature, 0).unwrap()[1].value, 23.5);
+    }
+
+    #[test]
+    fn test_insert() {
+        let store = Store::open_in_memory().unwrap();
+        store.insert(&reading(1696891938, Metric::Temperature, 23.4, Some(7))).unwrap();
+        // repeated frame.
+        store.insert(&reading(1696891940, Metric::Temperature, 23.4, Some(7))).unwrap();
+        assert_eq!(store.count().unwrap(), 1);
+
+        // other metric of the same frame.
+        store.insert(&reading(1696891938, Metric::Humidity, 68.0, Some(7))).unwrap();
+        // next frame.
+        store.insert(&reading(1696891948, Metric::Temperature, 23.5, Some(8))).unwrap();
+        assert_eq!(store.count().unwrap(), 3);
+
+        store.insert(&reading(1696891938, Metric::Battery, 14.0, None)).unwrap();
+        store.insert(&reading(1696891939, Metric::Battery, 14.0, None)).unwrap();
+        assert_eq!(store.count().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_for_each_reading() {
+        let store = Store::open_in_memory().unwrap();
+        store.insert(&reading(1696891948, Metric::Temperature, 23.5, Some(8))).unwrap();
+        store.insert(&reading(1696891938, Metric::Humidity, 68.0, None)).unwrap();
+
+        let mut lines: Vec<String> = Vec::new();
+        store.for_each_reading(0, u64::MAX, |r| {
+            lines.push(format_csv(&r));
+            Ok(())
+        }).unwrap();
+        assert_eq!(lines, vec![
+            "1696891938,11:22:33:44:55:66,humidity,68,",
+            "1696891948,11:22:33:44:55:66,temperature,23.5,8",
+        ]);
+        assert_eq!(store.count_between(0, u64::MAX).unwrap(), 2);
+
+        // both ends are included.
+        let mut times: Vec<u64> = Vec::new();
+        store.for_each_reading(1696891940, 1696891948, |r| {
+            times.push(r.time);
+            Ok(())
+        }).unwrap();
+        assert_eq!(times, vec![1696891948]);
+        assert_eq!(store.count_between(1696891938, 1696891938).unwrap(), 1);
+        assert_eq!(store.count_between(1696891949, u64::MAX).unwrap(), 0);
+
+        assert!(store.for_each_reading(0, u64::MAX, |_| Err("disk full".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let store = Store::open_in_memory().unwrap();
+        store.insert(&reading(1696891000, Metric::Temperature, 21.0, Some(1))).unwrap();
+        store.insert(&reading(1696892000, Metric::Temperature, 23.5, Some(2))).unwrap();
+        store.insert(&reading(1696893000, Metric::Temperature, 22.0, Some(3))).unwrap();
+        store.insert(&reading(1696893000, Metric::Humidity, 68.0, Some(3))).unwrap();
+        let other = StoredReading { address: 0xAABBCCDDEEFF, ..reading(1696893000, Metric::Temperature, 30.0, Some(3)) };
+        store.insert(&other).unwrap();
+
+        let readings = store.query(0x112233445566, Metric::Temperature, 1696892000).unwrap();
+        let values: Vec<f32> = readings.iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![23.5, 22.0]);
+        assert_eq!(readings[0], reading(1696892000, Metric::Temperature, 23.5, Some(2)));
+
+        let all = store.query(0x112233445566, Metric::Temperature, 0).unwrap();
+        assert_eq!(Summary::of(&all), Some(Summary { count: 3, min: 21.0, max: 23.5, average: 22.166666 }));
+        assert_eq!(Summary::of(&[]), None);
+
+        let running = all.iter().fold(None, |summary, r| Some(Summary::add(summary, r.value)));
+        assert_eq!(running, Summary::of(&all));
+    }
+}
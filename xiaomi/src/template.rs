@@ -0,0 +1,272 @@
+// Config text written by `config` commands. The file is edited as text, so comments and order people gave it are kept.
+
+use crate::{Config, format_bluetooth_address};
+
+// Starter config written by `config init`. Every setting is commented out, so the file changes nothing until edited.
+// `## ` lines explain, `# ` lines are settings to uncomment. Tests uncomment them all and read the result.
+
+pub const CONFIG: &str = r#"## config of xiaomi. keep it next to xiaomi.exe, with the same name.
+## `xiaomi toml` shows how it's read, and warns about likely mistakes.
+## `xiaomi scan` shows addresses of devices around.
+
+## a device. repeat [[device]] for every device.
+# [[device]]
+## address of the device. use : as a delimiter.
+# address = "AA:BB:CC:DD:EE:FF"
+## name used in output, and instead of the address on command line.
+# name = "Tokyo"
+## timezone of the clock, from the tz database. the clock only shows whole hours.
+# timezone = "Asia/Tokyo"
+## set the clock ahead, or behind when negative, by this many seconds.
+# offset_seconds = 300
+## temperature unit shown on the display, "c" or "f". set during sync when it's different.
+# unit = "c"
+## devices in a group are synced or scanned together. `xiaomi sync --group bedroom`
+# group = "bedroom"
+## other addresses of the same device. some firmware changes the address after battery swap.
+# addresses = ["AA:BB:CC:DD:EE:00"]
+## free text notes, shown by `toml` command.
+# notes = "bedroom, on the shelf"
+## dates as "YYYY-MM-DD". `status` shows battery age, counted from battery_changed, or purchased.
+# purchased = "2023-01-15"
+# battery_changed = "2023-10-01"
+
+## another device. omit = true scans it, but never writes its clock.
+# [[device]]
+# address = "11:22:33:44:55:66"
+# name = "Osaka"
+# omit = true
+
+## settings applied to all devices.
+# [general]
+## how long `sync` listens to BLE advertisements, in seconds. default is 30.
+# sync_duration_seconds = 30
+## how long `scan` listens to BLE advertisements, in seconds. default is 10.
+# scan_duration_seconds = 10
+## how many times a failed sync is retried. default is 2.
+# sync_retry_count = 2
+## delay before the first retry, in milliseconds. doubled on every retry. default is 500.
+# sync_retry_delay_milliseconds = 500
+## how long a single bluetooth operation (connect, query, write) can take, in seconds. default is 10.
+# gatt_timeout_seconds = 10
+## clock is not written when it's off by this or less, in seconds. default is 5.
+# sync_drift_threshold_seconds = 5
+## time is read back after sync. sync fails when it's off by more than this, in seconds. default is 2.
+# sync_verify_tolerance_seconds = 2
+## how many devices are synced at the same time. default is 2.
+# sync_workers = 2
+## "truncate", "round-nearest" or "error", for timezones like +5:30. default is "truncate".
+# timezone_policy = "truncate"
+## where the time written to clocks comes from. "system" or "ntp". default is "system".
+# time_source = "ntp"
+# ntp_servers = ["time.windows.com", "pool.ntp.org"]
+## `sync` warns when a device's battery is below this, in percent. default is 20.
+# low_battery_percent = 20
+## minutes between sync runs of `daemon`. default is 60.
+# daemon_interval_minutes = 60
+## `daemon` syncs devices not synced for this many hours, and only reads drift of others. default is 24.
+# daemon_sync_age_hours = 24
+## `sync` and `daemon` only touch devices in this file. default is false.
+# strict = true
+## `scan` only shows devices in this file. default is false.
+# strict_scan = true
+## `scan` and `sync` ignore advertisements weaker than this, in dBm. -80 leaves out most devices next door.
+# rssi_min = -80
+## "heat-index" or "humidex", for "feels like" temperature of `scan --comfort`. default is "heat-index".
+# apparent_temperature = "humidex"
+## digits after the point of readings in output and exports. 0 shows 23, 2 shows 23.40. readings are shown as received without it.
+# precision = 1
+
+## colors and table borders.
+# [theme]
+## "dark", "light" or "plain". default is "dark".
+# preset = "light"
+## "ascii", "box" or "none".
+# borders = "box"
+## ASCII instead of emoji, box borders and spinners. automatic when output isn't a UTF-8 terminal.
+# ascii = true
+
+## MQTT broker of `scan --mqtt` and `serve --gateway`.
+# [mqtt]
+## "mqtt://host", or "mqtts://host" for TLS. port is 1883, or 8883 with TLS.
+# url = "mqtt://homeassistant.local"
+## {name}, {address} and {metric} are replaced. default is "xiaomi/{name}/{metric}".
+# topic = "xiaomi/{name}/{metric}"
+## 0, or 1 to wait until the broker has each reading. default is 0.
+# qos = 1
+## the broker keeps the last reading of each topic. default is false.
+# retain = true
+# username = "xiaomi"
+# password = "secret"
+
+## InfluxDB of `scan --influxdb`. `database` for 1.x, or `org`, `bucket` and `token` for 2.x.
+# [influxdb]
+# url = "http://localhost:8086"
+# database = "home"
+## readings sent in one request, and at least this often in seconds. default is 100 and 10.
+# batch_size = 100
+# flush_seconds = 10
+
+## Pushgateway of `scan --pushgateway` and `sync --pushgateway`, for scheduled runs.
+# [pushgateway]
+# url = "http://localhost:9091"
+## each command replaces its own metrics of the last run under this job. default is "xiaomi".
+# job = "xiaomi"
+
+## Graphite of `scan --graphite`, with the plaintext protocol.
+# [graphite]
+# host = "graphite.local"
+## default is 2003.
+# port = 2003
+## paths are <prefix>.<name>.<metric>. default is "xiaomi".
+# prefix = "xiaomi"
+
+## notifications of `scan`, `serve`, `sync` and `daemon`. repeat the section for more.
+# [[webhook]]
+# url = "https://ntfy.sh/my-sensors"
+## "reading", "threshold", "sync-failure" and "sync". default is threshold and sync-failure.
+# events = ["threshold", "sync-failure"]
+## limits of threshold events. battery_below is low_battery_percent by default.
+# temperature_above = 28.0
+# temperature_below = 16.0
+# humidity_above = 70.0
+# humidity_below = 30.0
+# battery_below = 20
+## readings of a device are sent this often at most, in seconds. default is 300.
+# reading_interval_seconds = 300
+## body with {event}, {time}, {address}, {name}, {metric}, {value}, {threshold} and {message}. default is JSON.
+# template = "{message}"
+# [webhook.headers]
+# Content-Type = "text/plain"
+# Title = "Xiaomi sensors"
+
+## asked on every request of `serve`. a token, a username and password, or both.
+# [serve]
+# token = "change-me"
+# username = "family"
+# password = "change-me"
+## PEM files of a certificate and its private key, relative to this file. with both, `serve` answers https only.
+# cert = "xiaomi.crt"
+# key = "xiaomi.key"
+## where addresses like ":9184" listen. default is every interface with a token or a password, 127.0.0.1 without.
+# bind = "10.8.0.2"
+
+## OpenTelemetry collector of `scan --otlp` and `sync --otlp`, with OTLP/HTTP.
+# [otlp]
+# endpoint = "http://localhost:4318"
+## service.name of the resource. default is "xiaomi".
+# service_name = "xiaomi"
+## readings of `scan --watch` are exported this often, in seconds. default is 60.
+# export_seconds = 60
+## sent with every request, like an API key of a hosted collector.
+# [otlp.headers]
+# api-key = "secret"
+"#;
+
+// `existing` with a [[device]] appended, for `config add`. Err when the device or name is already there,
+// or the timezone is unknown. The result is read back before it's returned, so a broken file is never written.
+pub fn append_device(existing: &str, address: u64, name: Option<&str>, timezone: Option<&str>) -> Result<String, String> {
+    let config: Config = toml::from_str(existing).map_err(|e| format!("Parsing toml failed: {}", e))?;
+    for device in config.devices.iter().flatten() {
+        if device.address == address || device.addresses.contains(&address) {
+            let known_as = device.name.clone().unwrap_or(format_bluetooth_address(device.address));
+            return Err(format!("{} is already in toml, as {}", format_bluetooth_address(address), known_as));
+        }
+    }
+    if let Some(name) = name {
+        if config.get_device_by_name(name).is_some() {
+            return Err(format!("Name \"{}\" is already used. Names are case insensitive.", name));
+        }
+    }
+    if let Some(timezone) = timezone {
+        if timezone.parse::<chrono_tz::Tz>().is_err() {
+            return Err(format!("Unknown timezone \"{}\". Use a name from the tz database, for example \"Asia/Seoul\".", timezone));
+        }
+    }
+
+    let mut text = existing.to_string();
+    if !text.is_empty() {
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push('\n');
+    }
+    text.push_str("[[device]]\n");
+    text.push_str(&format!("address = \"{}\"\n", format_bluetooth_address(address)));
+    // quoted by toml, names can have any character.
+    if let Some(name) = name {
+        text.push_str(&format!("name = {}\n", toml::Value::String(name.to_string())));
+    }
+    if let Some(timezone) = timezone {
+        text.push_str(&format!("timezone = {}\n", toml::Value::String(timezone.to_string())));
+    }
+
+    let added: Config = toml::from_str(&text).map_err(|e| format!("Adding the device broke toml: {}", e))?;
+    if added.devices.iter().flatten().all(|d| d.address != address) {
+        return Err("Adding the device broke toml".to_string());
+    }
+    return Ok(text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the template with every setting uncommented.
+    fn uncommented() -> String {
+        let lines: Vec<&str> = CONFIG.lines().map(|l| l.strip_prefix("# ").unwrap_or(l)).collect();
+        return lines.join("\n");
+    }
+
+    #[test]
+    fn test_template_changes_nothing() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert!(config.devices.is_none());
+        assert!(config.general.sync_workers.is_none());
+    }
+
+    #[test]
+    fn test_template_uncommented() {
+        let text = uncommented();
+        let config: Config = toml::from_str(&text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        let devices = config.devices.as_ref().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name.as_deref(), Some("Tokyo"));
+        assert_eq!(devices[1].omit, Some(true));
+        assert_eq!(config.general.sync_workers, Some(2));
+        assert_eq!(config.mqtt.qos, Some(1));
+        assert!(crate::lint::lint_config(&config).is_empty());
+    }
+
+    #[test]
+    fn test_append_device() {
+        let existing = "# my clocks\n[[device]]\naddress = \"11:22:33:44:55:66\"\nname = \"Tokyo\"\n\n[general]\nsync_workers = 4";
+        let text = append_device(existing, 0xAABBCCDDEEFF, Some("Kitchen"), Some("Asia/Seoul")).unwrap();
+        assert!(text.starts_with(existing));
+        assert!(text.ends_with("\n\n[[device]]\naddress = \"AA:BB:CC:DD:EE:FF\"\nname = \"Kitchen\"\ntimezone = \"Asia/Seoul\"\n"));
+
+        // [[device]] after [general] is still a device, and [general] is kept.
+        let config: Config = toml::from_str(&text).unwrap();
+        let devices = config.devices.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].name.as_deref(), Some("Kitchen"));
+        assert_eq!(config.general.sync_workers, Some(4));
+
+        let text = append_device(existing, 0xAABBCCDDEEFF, Some("Kitchen \"A\" \\ 2"), None).unwrap();
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.devices.unwrap()[1].name.as_deref(), Some("Kitchen \"A\" \\ 2"));
+
+        assert_eq!(append_device("", 0xAABBCCDDEEFF, None, None).unwrap(), "[[device]]\naddress = \"AA:BB:CC:DD:EE:FF\"\n");
+        assert!(append_device(CONFIG, 0xAABBCCDDEEFF, Some("Tokyo"), None).is_ok());
+    }
+
+    #[test]
+    fn test_append_device_rejected() {
+        let existing = "[[device]]\naddress = \"11:22:33:44:55:66\"\naddresses = [\"11:22:33:44:55:00\"]\nname = \"Tokyo\"\n";
+        assert!(append_device(existing, 0x112233445566, None, None).unwrap_err().contains("as Tokyo"));
+        assert!(append_device(existing, 0x112233445500, None, None).is_err());
+        assert!(append_device(existing, 0xAABBCCDDEEFF, Some("tokyo"), None).unwrap_err().contains("already used"));
+        assert!(append_device(existing, 0xAABBCCDDEEFF, None, Some("Asia/Atlantis")).unwrap_err().contains("Unknown timezone"));
+        assert!(append_device("[[device]", 0xAABBCCDDEEFF, None, None).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+// Colors and table borders used in output. Default colors are made for dark terminal backgrounds,
+// yellow is hard to read on light ones.
+
+use console::{style, StyledObject};
+use prettytable::{format, Cell, Row, Table};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    #[default]
+    Dark,
+    Light,
+    // no colors, for logs and terminals without ANSI support.
+    Plain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Borders {
+    // +---+ and |
+    Ascii,
+    // ┌───┐ and │
+    Box,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Color {
+    // terminal's own text color.
+    Default,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    // name used in indicatif templates, like "{spinner:.yellow}". None for the default color.
+    pub fn name(&self) -> Option<&'static str> {
+        return match self {
+            Color::Default => None,
+            Color::Black => Some("black"),
+            Color::Red => Some("red"),
+            Color::Green => Some("green"),
+            Color::Yellow => Some("yellow"),
+            Color::Blue => Some("blue"),
+            Color::Magenta => Some("magenta"),
+            Color::Cyan => Some("cyan"),
+            Color::White => Some("white"),
+        };
+    }
+
+    // for `dashboard`.
+    pub fn ratatui_color(&self) -> ratatui::style::Color {
+        return match self {
+            Color::Default => ratatui::style::Color::Reset,
+            Color::Black => ratatui::style::Color::Black,
+            Color::Red => ratatui::style::Color::Red,
+            Color::Green => ratatui::style::Color::Green,
+            Color::Yellow => ratatui::style::Color::Yellow,
+            Color::Blue => ratatui::style::Color::Blue,
+            Color::Magenta => ratatui::style::Color::Magenta,
+            Color::Cyan => ratatui::style::Color::Cyan,
+            Color::White => ratatui::style::Color::White,
+        };
+    }
+
+    fn console_color(&self) -> Option<console::Color> {
+        return match self {
+            Color::Default => None,
+            Color::Black => Some(console::Color::Black),
+            Color::Red => Some(console::Color::Red),
+            Color::Green => Some(console::Color::Green),
+            Color::Yellow => Some(console::Color::Yellow),
+            Color::Blue => Some(console::Color::Blue),
+            Color::Magenta => Some(console::Color::Magenta),
+            Color::Cyan => Some(console::Color::Cyan),
+            Color::White => Some(console::Color::White),
+        };
+    }
+}
+
+// Declared as [theme] in toml. Every field overrides the one from `preset`.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ThemeConfig {
+    // "dark", "light" or "plain". Default is "dark".
+    pub preset: Option<ThemeName>,
+    pub borders: Option<Borders>,
+    // synced, and other good news.
+    pub ok: Option<Color>,
+    pub warning: Option<Color>,
+    pub error: Option<Color>,
+    // table header in bold.
+    pub bold_header: Option<bool>,
+    // ASCII instead of emoji, box borders and spinners. `--ascii` does the same for one run.
+    pub ascii: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub borders: Borders,
+    pub ok: Color,
+    pub warning: Color,
+    pub error: Color,
+    pub bold_header: bool,
+    // emoji, box borders and spinners are replaced, for log files and terminals without UTF-8.
+    pub ascii: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        return Theme::preset(ThemeName::Dark);
+    }
+}
+
+impl Theme {
+    pub fn preset(name: ThemeName) -> Theme {
+        return match name {
+            ThemeName::Dark => Theme { borders: Borders::Ascii, ok: Color::Green, warning: Color::Yellow, error: Color::Red, bold_header: false, ascii: false },
+            ThemeName::Light => Theme { borders: Borders::Ascii, ok: Color::Blue, warning: Color::Magenta, error: Color::Red, bold_header: true, ascii: false },
+            ThemeName::Plain => Theme { borders: Borders::Ascii, ok: Color::Default, warning: Color::Default, error: Color::Default, bold_header: false, ascii: false },
+        };
+    }
+
+    pub fn from_config(config: &ThemeConfig) -> Theme {
+        let preset = Theme::preset(config.preset.unwrap_or_default());
+        return Theme {
+            borders: config.borders.unwrap_or(preset.borders),
+            ok: config.ok.unwrap_or(preset.ok),
+            warning: config.warning.unwrap_or(preset.warning),
+            error: config.error.unwrap_or(preset.error),
+            bold_header: config.bold_header.unwrap_or(preset.bold_header),
+            ascii: config.ascii.unwrap_or(preset.ascii),
+        };
+    }
+
+    pub fn ok<D>(&self, text: D) -> StyledObject<D> {
+        return paint(text, self.ok);
+    }
+
+    pub fn warning<D>(&self, text: D) -> StyledObject<D> {
+        return paint(text, self.warning);
+    }
+
+    pub fn error<D>(&self, text: D) -> StyledObject<D> {
+        return paint(text, self.error);
+    }
+
+    // indicatif template with the spinner in `color`, like "{spinner:.yellow} {msg}".
+    pub fn spinner_template(&self, color: Color, rest: &str) -> String {
+        return match color.name() {
+            Some(name) => format!("{{spinner:.{}}} {}", name, rest),
+            None => format!("{{spinner}} {}", rest),
+        };
+    }
+
+    // frames of spinners, all the same width.
+    pub fn spinner_ticks(&self) -> &'static [&'static str] {
+        if self.ascii {
+            return &["-----", ">----", "->---", "-->--", "--->-", "---->", "====="];
+        }
+        // For more spinners check out the cli-spinners project:
+        // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+        return &["▹▹▹▹▹", "▸▹▹▹▹", "▹▸▹▹▹", "▹▹▸▹▹", "▹▹▹▸▹", "▹▹▹▹▸", "▪▪▪▪▪"];
+    }
+
+    // same borders and symbols, without colors or bold. for NO_COLOR, `--no-color` and output which isn't a terminal.
+    pub fn without_colors(&self) -> Theme {
+        let plain = Theme::preset(ThemeName::Plain);
+        return Theme { ok: plain.ok, warning: plain.warning, error: plain.error, bold_header: false, ..self.clone() };
+    }
+
+    // empty table with the header row.
+    pub fn table(&self, header: &[&str]) -> Table {
+        let mut table = Table::new();
+        table.set_format(match self.borders {
+            Borders::Ascii => *format::consts::FORMAT_DEFAULT,
+            Borders::Box if self.ascii => *format::consts::FORMAT_DEFAULT,
+            Borders::Box => *format::consts::FORMAT_BOX_CHARS,
+            Borders::None => *format::consts::FORMAT_CLEAN,
+        });
+        table.add_row(Row::new(header.iter().map(|title| {
+            if self.bold_header {
+                return Cell::new(&style(title).bold().to_string());
+            }
+            return Cell::new(title);
+        }).collect()));
+        return table;
+    }
+}
+
+fn paint<D>(text: D, color: Color) -> StyledObject<D> {
+    return match color.console_color() {
+        Some(color) => style(text).fg(color),
+        None => style(text),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_config() {
+        assert_eq!(Theme::from_config(&ThemeConfig::default()), Theme::default());
+
+        let config: ThemeConfig = toml::from_str("preset = \"light\"\nborders = \"box\"\nerror = \"magenta\"").unwrap();
+        let theme = Theme::from_config(&config);
+        assert_eq!(theme.borders, Borders::Box);
+        assert_eq!(theme.error, Color::Magenta);
+        assert_eq!(theme.warning, Theme::preset(ThemeName::Light).warning);
+        assert!(theme.bold_header);
+    }
+
+    #[test]
+    fn test_spinner_template() {
+        let theme = Theme::preset(ThemeName::Plain);
+        assert_eq!(theme.spinner_template(theme.warning, "{msg}"), "{spinner} {msg}");
+        assert_eq!(theme.spinner_template(Color::Yellow, "{prefix}: {msg}"), "{spinner:.yellow} {prefix}: {msg}");
+    }
+
+    #[test]
+    fn test_ascii() {
+        let config: ThemeConfig = toml::from_str("borders = \"box\"\nascii = true").unwrap();
+        let theme = Theme::from_config(&config);
+        assert!(theme.spinner_ticks().iter().all(|tick| tick.is_ascii() && tick.len() == 5));
+        let mut table = theme.table(&["Device ID", "Temp."]);
+        table.add_row(Row::new(vec![Cell::new("Tokyo"), Cell::new("23.4")]));
+        assert!(table.to_string().is_ascii());
+        assert!(table.to_string().starts_with("+-"));
+
+        assert!(!Theme::default().spinner_ticks()[0].is_ascii());
+    }
+
+    #[test]
+    fn test_without_colors() {
+        let config: ThemeConfig = toml::from_str("preset = \"light\"\nborders = \"box\"").unwrap();
+        let theme = Theme::from_config(&config).without_colors();
+        assert_eq!(theme.borders, Borders::Box);
+        assert_eq!(theme.error, Color::Default);
+        assert!(!theme.bold_header);
+        assert_eq!(theme.spinner_template(theme.ok, "{msg}"), "{spinner} {msg}");
+    }
+}
@@ -0,0 +1,100 @@
+// Where the time written to clocks comes from. Sync only asks a TimeProvider for "now",
+// so it doesn't care whether it's the system clock, NTP, or a fixed time in tests.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{GeneralConfig, TimeSource, get_unix_epoc, ntp};
+
+pub trait TimeProvider: Send + Sync {
+    // unix epoch, UTC.
+    fn now(&self) -> u64;
+    // human readable description, for logs.
+    fn describe(&self) -> String;
+}
+
+pub struct SystemClock;
+
+impl TimeProvider for SystemClock {
+    fn now(&self) -> u64 {
+        return get_unix_epoc();
+    }
+
+    fn describe(&self) -> String {
+        return "system clock".to_string();
+    }
+}
+
+// system clock, corrected by the offset measured from a NTP server once.
+pub struct NtpClock {
+    pub server: String,
+    pub offset_seconds: i64,
+}
+
+impl NtpClock {
+    pub fn query(servers: &[String], timeout: Duration) -> Result<NtpClock, String> {
+        let (server, offset) = ntp::query_servers(servers, timeout)?;
+        return Ok(NtpClock { server: server, offset_seconds: offset.round() as i64 });
+    }
+}
+
+impl TimeProvider for NtpClock {
+    fn now(&self) -> u64 {
+        return (get_unix_epoc() as i64 + self.offset_seconds).max(0) as u64;
+    }
+
+    fn describe(&self) -> String {
+        return format!("{} (system clock {:+}s)", self.server, self.offset_seconds);
+    }
+}
+
+// always the same time. for tests.
+pub struct FixedClock(pub u64);
+
+impl TimeProvider for FixedClock {
+    fn now(&self) -> u64 {
+        return self.0;
+    }
+
+    fn describe(&self) -> String {
+        return format!("fixed time {}", self.0);
+    }
+}
+
+// time provider configured by `time_source`. NTP falls back to the system clock, with the reason.
+pub fn from_config(general: &GeneralConfig, timeout: Duration) -> (Arc<dyn TimeProvider>, Option<String>) {
+    return match general.time_source.unwrap_or_default() {
+        TimeSource::System => (Arc::new(SystemClock), None),
+        TimeSource::Ntp => match NtpClock::query(&general.ntp_servers(), timeout) {
+            Ok(clock) => (Arc::new(clock), None),
+            Err(msg) => (Arc::new(SystemClock), Some(msg)),
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ClockTime, target_clock_time};
+
+    #[test]
+    fn test_fixed_clock() {
+        let clock: Box<dyn TimeProvider> = Box::new(FixedClock(1696891938));
+        assert_eq!(target_clock_time(clock.now(), Some(9), Some(60)), ClockTime { epoch: 1696891998, timezone: 9 });
+    }
+
+    #[test]
+    fn test_ntp_clock() {
+        let clock = NtpClock { server: "ntp.example.com".to_string(), offset_seconds: -30 };
+        let system = get_unix_epoc();
+        assert!(clock.now() + 30 >= system && clock.now() + 30 <= system + 1);
+        assert_eq!(clock.describe(), "ntp.example.com (system clock -30s)");
+    }
+
+    #[test]
+    fn test_from_config() {
+        let (clock, error) = from_config(&GeneralConfig::default(), Duration::from_secs(1));
+        assert_eq!(clock.describe(), "system clock");
+        assert!(error.is_none());
+    }
+}
@@ -0,0 +1,64 @@
+// Syncing on demand while `daemon` waits for the next run, without a restart. Creating xiaomi.sync next to
+// xiaomi.exe starts a run right away, then the file is removed. It works the same for the service, which has
+// no terminal to press a key in.
+//  empty file        every device, even ones synced within daemon_sync_age_hours
+//  a name per line   only these devices. names in toml or addresses, like `sync` takes. # starts a comment
+
+use std::path::{Path, PathBuf};
+
+pub struct Trigger {
+    path: PathBuf,
+}
+
+impl Trigger {
+    pub fn new(path: &Path) -> Trigger {
+        return Trigger { path: path.to_path_buf() };
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    // None without the file. names to sync otherwise, empty for every device. the file is removed, so a run
+    // is started once for it. a file which can't be removed is left, and taken again, rather than ignored.
+    pub fn take(&self) -> Option<Vec<String>> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Removing {} failed: {}", self.path.display(), e);
+        }
+        return Some(parse(&text));
+    }
+}
+
+pub fn parse(text: &str) -> Vec<String> {
+    return text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("").is_empty());
+        assert!(parse("\n  \n# every device\n").is_empty());
+        assert_eq!(parse("Tokyo\nA4:C1:38:5E:2B:11  # attic\n\nLiving room\n"), vec!["Tokyo", "A4:C1:38:5E:2B:11", "Living room"]);
+    }
+
+    #[test]
+    fn test_take() {
+        let path = std::env::temp_dir().join(format!("xiaomi-test-{}.sync", std::process::id()));
+        let trigger = Trigger::new(&path);
+        assert_eq!(trigger.take(), None);
+
+        std::fs::write(&path, "Tokyo\n").unwrap();
+        assert_eq!(trigger.take(), Some(vec!["Tokyo".to_string()]));
+        // taken once.
+        assert!(!path.exists());
+        assert_eq!(trigger.take(), None);
+    }
+}
@@ -0,0 +1,75 @@
+// LYWSD02 temperature unit characteristic. 1 byte, readable and writable.
+//  0xFF: Celsius, 0x01: Fahrenheit
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TemperatureUnit {
+    #[serde(rename = "c", alias = "C", alias = "celsius")]
+    Celsius,
+    #[serde(rename = "f", alias = "F", alias = "fahrenheit")]
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    pub fn encode(&self) -> u8 {
+        return match self {
+            TemperatureUnit::Celsius => 0xFF,
+            TemperatureUnit::Fahrenheit => 0x01,
+        };
+    }
+
+    // some firmware reports Celsius as 0x00.
+    pub fn decode(data: &[u8]) -> Option<TemperatureUnit> {
+        return match data.first() {
+            Some(0xFF) | Some(0x00) => Some(TemperatureUnit::Celsius),
+            Some(0x01) => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        };
+    }
+
+    // as written in toml.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            TemperatureUnit::Celsius => "c",
+            TemperatureUnit::Fahrenheit => "f",
+        };
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        return match self {
+            TemperatureUnit::Celsius => "'C",
+            TemperatureUnit::Fahrenheit => "'F",
+        };
+    }
+}
+
+impl std::str::FromStr for TemperatureUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_lowercase().as_str() {
+            "c" | "celsius" => Ok(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(format!("unknown unit \"{}\". Use c or f", s)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit() {
+        assert_eq!(TemperatureUnit::decode(&[TemperatureUnit::Celsius.encode()]), Some(TemperatureUnit::Celsius));
+        assert_eq!(TemperatureUnit::decode(&[TemperatureUnit::Fahrenheit.encode()]), Some(TemperatureUnit::Fahrenheit));
+        assert_eq!(TemperatureUnit::decode(&[0x00]), Some(TemperatureUnit::Celsius));
+        assert_eq!(TemperatureUnit::decode(&[0x02]), None);
+        assert_eq!(TemperatureUnit::decode(&[]), None);
+
+        assert_eq!("F".parse::<TemperatureUnit>(), Ok(TemperatureUnit::Fahrenheit));
+        assert_eq!("celsius".parse::<TemperatureUnit>(), Ok(TemperatureUnit::Celsius));
+        assert!("k".parse::<TemperatureUnit>().is_err());
+    }
+}
@@ -0,0 +1,19 @@
+// Dashboard page of `serve --web`, for people who don't use the command line. The page is built in the browser
+// from GET routes of the API served next to it, and refreshes itself.
+
+pub const CONTENT_TYPE: &str = "text/html; charset=utf-8";
+pub const PAGE: &str = include_str!("web.html");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{self, Route};
+
+    #[test]
+    fn test_page_routes() {
+        // paths are relative in the page, so it works behind a reverse proxy too.
+        assert!(PAGE.contains("fetch(\"devices\")"));
+        assert!(PAGE.contains("\"/history\""));
+        assert_eq!(api::route("/devices/AA%3ABB%3ACC%3ADD%3AEE%3AFF/history"), Some(Route::History("AA:BB:CC:DD:EE:FF".to_string())));
+    }
+}
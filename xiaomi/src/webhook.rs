@@ -0,0 +1,507 @@
+// Notifications of [[webhook]] in toml, for services like ntfy or IFTTT. Readings, thresholds crossed and devices
+// which failed to sync are POSTed as an event, or as the body of `template`, from a thread of their own.
+//  {"event":"threshold","time":1696891938,"address":"AA:BB:CC:DD:EE:FF","name":"Tokyo","metric":"temperature",
+//   "value":28.4,"threshold":28.0,"message":"Tokyo: temperature 28.4 is above 28"}
+//
+// With "sync" in events, the summary of every sync run is POSTed as JSON. Automation flows (Node-RED, n8n,
+// Home Assistant) can react to it, like notifying only on failures. `sync_webhook_url` of [general] is one of these.
+//  {"time":1696891938,"success":false,"devices":[{"address":"AA:BB:CC:DD:EE:FF","name":"Tokyo","result":"synced",...}]}
+
+use crate::store::Metric;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const DEFAULT_READING_INTERVAL_SECONDS: u64 = 300;
+// notifications waiting for a slow server. more are dropped with a warning.
+const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncReport {
+    // unix epoch when the run ended.
+    pub time: u64,
+    // false when any device failed, or a configured device was not seen. same as the exit code of `sync`.
+    pub success: bool,
+    pub devices: Vec<DeviceReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceReport {
+    pub address: String,
+    pub name: Option<String>,
+    // "synced", "skipped", "omitted", "dry-run", "checked", "failed", "unfinished" or "not-seen".
+    pub result: String,
+    // why it failed.
+    pub error: Option<String>,
+    pub seconds: Option<u64>,
+    // positive when the clock was ahead.
+    pub drift_seconds: Option<i64>,
+    pub battery_percent: Option<u8>,
+}
+
+impl DeviceReport {
+    // what makes a run unsuccessful. devices left alone on purpose aren't failures.
+    pub fn failed(&self) -> bool {
+        return matches!(self.result.as_str(), "failed" | "unfinished" | "not-seen");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    // a reading of a metric, at most every reading_interval_seconds.
+    Reading,
+    // a reading went over or under a limit. sent again only after it was back within.
+    Threshold,
+    // a device failed, was unfinished or not seen in a sync run.
+    SyncFailure,
+    // the summary of a sync run, as SyncReport. `template` isn't used for it.
+    Sync,
+}
+
+// Declared as [[webhook]] in toml. fired by `scan`, `serve`, `sync` and `daemon`.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct WebhookConfig {
+    // "https://ntfy.sh/my-sensors"
+    pub url: Option<String>,
+    // default is threshold and sync-failure.
+    pub events: Option<Vec<EventKind>>,
+    // Content-Type is application/json unless it's set here.
+    pub headers: Option<BTreeMap<String, String>>,
+    // body, with {event}, {time}, {address}, {name}, {metric}, {value}, {threshold} and {message} replaced.
+    // default is the event as JSON.
+    pub template: Option<String>,
+    pub temperature_above: Option<f32>,
+    pub temperature_below: Option<f32>,
+    pub humidity_above: Option<f32>,
+    pub humidity_below: Option<f32>,
+    // default is low_battery_percent of [general].
+    pub battery_below: Option<f32>,
+    // default is 300.
+    pub reading_interval_seconds: Option<u64>,
+}
+
+impl WebhookConfig {
+    fn events(&self) -> Vec<EventKind> {
+        return self.events.clone().unwrap_or(vec![EventKind::Threshold, EventKind::SyncFailure]);
+    }
+
+    fn content_type(&self) -> String {
+        let header = self.headers.iter().flatten().find(|(key, _)| key.eq_ignore_ascii_case("content-type"));
+        return header.map(|(_, value)| value.clone()).unwrap_or("application/json".to_string());
+    }
+}
+
+pub fn check_config(config: &WebhookConfig) -> Result<(), String> {
+    let url = config.url.as_deref().ok_or("no url in [[webhook]]")?;
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("invalid webhook url \"{}\". Use http:// or https://", url));
+    }
+    if config.events.as_ref().is_some_and(|events| events.is_empty()) {
+        return Err(format!("no events of webhook {}", url));
+    }
+    return Ok(());
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub time: u64,
+    pub address: String,
+    pub name: Option<String>,
+    pub metric: Option<&'static str>,
+    pub value: Option<f32>,
+    // the limit crossed, of a threshold event.
+    pub threshold: Option<f32>,
+    // for people, like "Tokyo: temperature 28.4 is above 28".
+    pub message: String,
+}
+
+// values go into JSON strings of a template escaped, so a name with a quote doesn't break the body.
+fn escape_json(text: &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or_default();
+    return quoted[1..quoted.len() - 1].to_string();
+}
+
+// {name} is the address of a device without a name. placeholders without a value are empty.
+pub fn render(template: &str, event: &Event, json: bool) -> String {
+    let text = |value: Option<String>| -> String {
+        let value = value.unwrap_or_default();
+        return if json { escape_json(&value) } else { value };
+    };
+    let event_name = serde_json::to_string(&event.event).unwrap_or_default().trim_matches('"').to_string();
+    return template
+        .replace("{event}", &event_name)
+        .replace("{time}", &event.time.to_string())
+        .replace("{address}", &text(Some(event.address.clone())))
+        .replace("{name}", &text(Some(event.name.clone().unwrap_or(event.address.clone()))))
+        .replace("{metric}", event.metric.unwrap_or(""))
+        .replace("{value}", &event.value.map(|v| v.to_string()).unwrap_or_default())
+        .replace("{threshold}", &event.threshold.map(|v| v.to_string()).unwrap_or_default())
+        .replace("{message}", &text(Some(event.message.clone())));
+}
+
+// a webhook, and what it already sent.
+struct Hook {
+    config: WebhookConfig,
+    events: Vec<EventKind>,
+    battery_below: Option<f32>,
+    // time a reading of (device, metric) was last sent.
+    last_sent: HashMap<(u64, &'static str), u64>,
+    // (device, metric, above) over or under its limit. nothing is sent again until it's back within.
+    breached: HashSet<(u64, &'static str, bool)>,
+}
+
+impl Hook {
+    fn new(config: &WebhookConfig, low_battery_percent: u8) -> Hook {
+        return Hook {
+            config: config.clone(),
+            events: config.events(),
+            battery_below: config.battery_below.or(Some(low_battery_percent as f32)),
+            last_sent: HashMap::new(),
+            breached: HashSet::new(),
+        };
+    }
+
+    // (limit, above) of the metric.
+    fn limits(&self, metric: Metric) -> Vec<(f32, bool)> {
+        let limits = match metric {
+            Metric::Temperature => [self.config.temperature_above.map(|l| (l, true)), self.config.temperature_below.map(|l| (l, false))],
+            Metric::Humidity => [self.config.humidity_above.map(|l| (l, true)), self.config.humidity_below.map(|l| (l, false))],
+            Metric::Battery => [None, self.battery_below.map(|l| (l, false))],
+        };
+        return limits.into_iter().flatten().collect();
+    }
+
+    fn reading_events(&mut self, device: &DeviceName, metric: Metric, value: f32, time: u64) -> Vec<Event> {
+        let mut events = Vec::new();
+        let event = |kind: EventKind, threshold: Option<f32>, message: String| Event {
+            event: kind,
+            time: time,
+            address: device.address_text.clone(),
+            name: device.name.clone(),
+            metric: Some(metric.name()),
+            value: Some(value),
+            threshold: threshold,
+            message: message,
+        };
+        let label = device.label();
+        if self.events.contains(&EventKind::Reading) {
+            let key = (device.address, metric.name());
+            let interval = self.config.reading_interval_seconds.unwrap_or(DEFAULT_READING_INTERVAL_SECONDS);
+            if self.last_sent.get(&key).is_none_or(|last| time >= last + interval) {
+                self.last_sent.insert(key, time);
+                events.push(event(EventKind::Reading, None, format!("{}: {} {}", label, metric.name(), value)));
+            }
+        }
+        if self.events.contains(&EventKind::Threshold) {
+            for (limit, above) in self.limits(metric) {
+                let key = (device.address, metric.name(), above);
+                let outside = if above { value > limit } else { value < limit };
+                if !outside {
+                    self.breached.remove(&key);
+                } else if self.breached.insert(key) {
+                    let direction = if above { "above" } else { "below" };
+                    events.push(event(EventKind::Threshold, Some(limit), format!("{}: {} {} is {} {}", label, metric.name(), value, direction, limit)));
+                }
+            }
+        }
+        return events;
+    }
+}
+
+// a device as events tell it.
+pub struct DeviceName {
+    // identifying the logical device.
+    pub address: u64,
+    pub address_text: String,
+    pub name: Option<String>,
+}
+
+impl DeviceName {
+    fn label(&self) -> &str {
+        return self.name.as_deref().unwrap_or(&self.address_text);
+    }
+}
+
+// what's POSTed. built on the caller's thread, sent on the sender's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+// `headers` of the webhook, with Content-Type.
+fn headers(config: &WebhookConfig) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = config.headers.iter().flatten().map(|(k, v)| (k.clone(), v.clone())).collect();
+    if !headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("content-type")) {
+        headers.push(("Content-Type".to_string(), config.content_type()));
+    }
+    return headers;
+}
+
+fn request(config: &WebhookConfig, event: &Event) -> Request {
+    let body = match &config.template {
+        Some(template) => render(template, event, config.content_type().contains("json")),
+        None => serde_json::to_string(event).unwrap_or_default(),
+    };
+    return Request { url: config.url.clone().unwrap_or_default(), headers: headers(config), body: body };
+}
+
+fn send(request: &Request, timeout: Duration) -> Result<(), String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(timeout)).build().into();
+    let mut call = agent.post(&request.url);
+    for (key, value) in &request.headers {
+        call = call.header(key, value);
+    }
+    call.send(&request.body).map_err(|e| format!("{}: {}", request.url, e))?;
+    return Ok(());
+}
+
+// webhooks of [[webhook]]. requests are sent in order by a thread, so a slow server doesn't hold up readings.
+// dropping it waits for the requests queued.
+pub struct Notifier {
+    hooks: Vec<Hook>,
+    queue: Option<SyncSender<Request>>,
+    sender: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    pub fn new(configs: &[WebhookConfig], low_battery_percent: u8, timeout: Duration) -> Result<Notifier, String> {
+        for config in configs {
+            check_config(config)?;
+        }
+        let hooks: Vec<Hook> = configs.iter().map(|config| Hook::new(config, low_battery_percent)).collect();
+        if hooks.is_empty() {
+            return Ok(Notifier { hooks: hooks, queue: None, sender: None });
+        }
+        let (queue, requests) = mpsc::sync_channel::<Request>(QUEUE_CAPACITY);
+        let sender = thread::spawn(move || {
+            for request in requests {
+                if let Err(msg) = send(&request, timeout) {
+                    log::warn!("Sending webhook failed: {}", msg);
+                }
+            }
+        });
+        return Ok(Notifier { hooks: hooks, queue: Some(queue), sender: Some(sender) });
+    }
+
+    fn queue(&self, request: Request) {
+        if let Some(queue) = &self.queue {
+            if let Err(TrySendError::Full(request)) = queue.try_send(request) {
+                log::warn!("Dropped webhook to {}. Too many are waiting", request.url);
+            }
+        }
+    }
+
+    // requests of each webhook for a new reading. `reading` queues them.
+    pub fn reading_requests(&mut self, device: &DeviceName, metric: Metric, value: f32, time: u64) -> Vec<Request> {
+        let mut requests = Vec::new();
+        for hook in &mut self.hooks {
+            for event in hook.reading_events(device, metric, value, time) {
+                requests.push(request(&hook.config, &event));
+            }
+        }
+        return requests;
+    }
+
+    pub fn reading(&mut self, device: &DeviceName, metric: Metric, value: f32, time: u64) {
+        for request in self.reading_requests(device, metric, value, time) {
+            self.queue(request);
+        }
+    }
+
+    pub fn sync_requests(&self, report: &SyncReport) -> Vec<Request> {
+        let mut requests = Vec::new();
+        for hook in self.hooks.iter().filter(|h| h.events.contains(&EventKind::Sync)) {
+            requests.push(Request {
+                url: hook.config.url.clone().unwrap_or_default(),
+                headers: headers(&hook.config),
+                body: serde_json::to_string(report).unwrap_or_default(),
+            });
+        }
+        for hook in self.hooks.iter().filter(|h| h.events.contains(&EventKind::SyncFailure)) {
+            for device in report.devices.iter().filter(|d| d.failed()) {
+                let label = device.name.as_deref().unwrap_or(&device.address);
+                let message = match (device.result.as_str(), &device.error) {
+                    ("not-seen", _) => format!("{}: not seen while syncing", label),
+                    (_, Some(error)) => format!("{}: sync failed: {}", label, error),
+                    (result, None) => format!("{}: sync {}", label, result),
+                };
+                let event = Event {
+                    event: EventKind::SyncFailure,
+                    time: report.time,
+                    address: device.address.clone(),
+                    name: device.name.clone(),
+                    metric: None,
+                    value: None,
+                    threshold: None,
+                    message: message,
+                };
+                requests.push(request(&hook.config, &event));
+            }
+        }
+        return requests;
+    }
+
+    pub fn sync(&self, report: &SyncReport) {
+        for request in self.sync_requests(report) {
+            self.queue(request);
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        // the sender ends when the queue is closed and empty.
+        self.queue.take();
+        if let Some(sender) = self.sender.take() {
+            sender.join().ok();
+        }
+    }
+}
+
+// `sync_webhook_url` of [general], as the [[webhook]] which does the same.
+pub fn sync_webhook(url: &str) -> WebhookConfig {
+    return WebhookConfig { url: Some(url.to_string()), events: Some(vec![EventKind::Sync]), ..Default::default() };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokyo() -> DeviceName {
+        return DeviceName { address: 0xAABBCCDDEEFF, address_text: "AA:BB:CC:DD:EE:FF".to_string(), name: Some("Tokyo".to_string()) };
+    }
+
+    fn notifier(toml: &str) -> Notifier {
+        let config: WebhookConfig = toml::from_str(toml).unwrap();
+        // no queue, so nothing is sent.
+        return Notifier { hooks: vec![Hook::new(&config, 20)], queue: None, sender: None };
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = SyncReport {
+            time: 1696891938,
+            success: false,
+            devices: vec![
+                DeviceReport {
+                    address: "11:22:33:44:55:66".to_string(),
+                    name: Some("Tokyo".to_string()),
+                    result: "synced".to_string(),
+                    error: None,
+                    seconds: Some(4),
+                    drift_seconds: Some(-62),
+                    battery_percent: Some(87),
+                },
+                DeviceReport {
+                    address: "AA:BB:CC:DD:EE:FF".to_string(),
+                    name: None,
+                    result: "not-seen".to_string(),
+                    error: None,
+                    seconds: None,
+                    drift_seconds: None,
+                    battery_percent: None,
+                },
+            ],
+        };
+        assert_eq!(serde_json::to_string(&report).unwrap(), concat!(
+            r#"{"time":1696891938,"success":false,"devices":["#,
+            r#"{"address":"11:22:33:44:55:66","name":"Tokyo","result":"synced","error":null,"seconds":4,"drift_seconds":-62,"battery_percent":87},"#,
+            r#"{"address":"AA:BB:CC:DD:EE:FF","name":null,"result":"not-seen","error":null,"seconds":null,"drift_seconds":null,"battery_percent":null}]}"#));
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut notifier = notifier("url = \"http://localhost:8080/hook\"\ntemperature_above = 28.0");
+        assert!(notifier.reading_requests(&tokyo(), Metric::Temperature, 27.5, 1696891900).is_empty());
+        let requests = notifier.reading_requests(&tokyo(), Metric::Temperature, 28.4, 1696891938);
+        assert_eq!(requests, vec![Request {
+            url: "http://localhost:8080/hook".to_string(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: concat!(r#"{"event":"threshold","time":1696891938,"address":"AA:BB:CC:DD:EE:FF","name":"Tokyo","metric":"temperature","#,
+                r#""value":28.4,"threshold":28.0,"message":"Tokyo: temperature 28.4 is above 28"}"#).to_string(),
+        }]);
+        // still above. sent again only after it's back within.
+        assert!(notifier.reading_requests(&tokyo(), Metric::Temperature, 28.6, 1696891948).is_empty());
+        assert!(notifier.reading_requests(&tokyo(), Metric::Temperature, 27.9, 1696891958).is_empty());
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Temperature, 28.1, 1696891968).len(), 1);
+
+        // battery_below is low_battery_percent by default.
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Battery, 15.0, 1696891968).len(), 1);
+    }
+
+    #[test]
+    fn test_readings() {
+        let mut notifier = notifier("url = \"https://ntfy.sh/xiaomi\"\nevents = [\"reading\"]\nreading_interval_seconds = 60");
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Temperature, 23.4, 1696891900).len(), 1);
+        assert!(notifier.reading_requests(&tokyo(), Metric::Temperature, 23.5, 1696891930).is_empty());
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Humidity, 68.0, 1696891930).len(), 1);
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Temperature, 23.5, 1696891960).len(), 1);
+        // threshold isn't one of its events, so a low battery is only a reading.
+        assert_eq!(notifier.reading_requests(&tokyo(), Metric::Battery, 5.0, 1696891900).len(), 1);
+    }
+
+    #[test]
+    fn test_template() {
+        let notifier = notifier("url = \"https://ntfy.sh/xiaomi\"\ntemplate = \"{name} failed: {message}\"\n[headers]\nContent-Type = \"text/plain\"\nTitle = \"Xiaomi\"");
+        let device = DeviceReport {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: Some("Living room \"north\"".to_string()),
+            result: "failed".to_string(),
+            error: Some("timed out".to_string()),
+            seconds: Some(30),
+            drift_seconds: None,
+            battery_percent: None,
+        };
+        let mut synced = device.clone();
+        synced.result = "synced".to_string();
+        let report = SyncReport { time: 1696891938, success: false, devices: vec![device, synced] };
+        let requests = notifier.sync_requests(&report);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers, vec![("Content-Type".to_string(), "text/plain".to_string()), ("Title".to_string(), "Xiaomi".to_string())]);
+        assert_eq!(requests[0].body, "Living room \"north\" failed: Living room \"north\": sync failed: timed out");
+
+        // escaped in JSON.
+        let event = Event {
+            event: EventKind::SyncFailure,
+            time: 1696891938,
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: Some("Living room \"north\"".to_string()),
+            metric: None,
+            value: None,
+            threshold: None,
+            message: "not seen".to_string(),
+        };
+        assert_eq!(render(r#"{"text":"{name} {event} {value}"}"#, &event, true), r#"{"text":"Living room \"north\" sync-failure "}"#);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut notifier = notifier("url = \"http://homeassistant.local:8123/api/webhook/xiaomi-sync\"\nevents = [\"sync\"]\ntemperature_above = 28.0");
+        assert!(notifier.reading_requests(&tokyo(), Metric::Temperature, 30.0, 1696891938).is_empty());
+        let report = SyncReport { time: 1696891938, success: true, devices: vec![] };
+        assert_eq!(notifier.sync_requests(&report), vec![Request {
+            url: "http://homeassistant.local:8123/api/webhook/xiaomi-sync".to_string(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: r#"{"time":1696891938,"success":true,"devices":[]}"#.to_string(),
+        }]);
+
+        let config = sync_webhook("http://homeassistant.local:8123/api/webhook/xiaomi-sync");
+        assert!(check_config(&config).is_ok());
+        assert_eq!(config.events(), vec![EventKind::Sync]);
+    }
+
+    #[test]
+    fn test_check_config() {
+        assert!(check_config(&toml::from_str("url = \"https://ntfy.sh/xiaomi\"").unwrap()).is_ok());
+        assert!(check_config(&toml::from_str("url = \"ntfy.sh/xiaomi\"").unwrap()).is_err());
+        assert!(check_config(&toml::from_str("url = \"https://ntfy.sh/xiaomi\"\nevents = []").unwrap()).is_err());
+        assert!(check_config(&WebhookConfig::default()).is_err());
+        assert!(toml::from_str::<WebhookConfig>("events = [\"alert\"]").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Jobs queued from any thread, run on a fixed number of worker threads. Syncing a device blocks for seconds,
+// so the advertisement handler queues addresses here instead of connecting itself. Workers run jobs at the
+// same time, each takes the next job as soon as it's done with its own.
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+pub struct WorkerPool<T> {
+    queue: JobQueue<T>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    // at least one worker is started.
+    pub fn new<F>(count: usize, run: F) -> WorkerPool<T>
+    where F: Fn(T) + Send + Sync + 'static {
+        let (tx, rx): (Sender<T>, Receiver<T>) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let run = Arc::new(run);
+        let mut workers: Vec<JoinHandle<()>> = Vec::new();
+        for _ in 0..count.max(1) {
+            let rx = rx.clone();
+            let run = run.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    // the lock is released before running the job, so other workers take jobs meanwhile.
+                    // recv fails when the queue is closed and empty.
+                    let next = rx.lock().unwrap().recv();
+                    match next {
+                        Ok(job) => run(job),
+                        Err(_) => break,
+                    }
+                }
+            }));
+        }
+        return WorkerPool { queue: JobQueue { tx: Arc::new(Mutex::new(Some(tx))) }, workers: workers };
+    }
+
+    // handed to threads queueing jobs, like the advertisement handler.
+    pub fn queue(&self) -> JobQueue<T> {
+        return self.queue.clone();
+    }
+
+    // jobs queued after this are dropped. workers finish the ones already queued, then exit.
+    pub fn close(&self) {
+        self.queue.tx.lock().unwrap().take();
+    }
+
+    // true once every worker exited, after `close`.
+    pub fn is_finished(&self) -> bool {
+        return self.workers.iter().all(|w| w.is_finished());
+    }
+
+    pub fn join(self) {
+        self.close();
+        for worker in self.workers {
+            worker.join().ok();
+        }
+    }
+}
+
+pub struct JobQueue<T> {
+    // None once the pool is closed.
+    tx: Arc<Mutex<Option<Sender<T>>>>,
+}
+
+impl<T> Clone for JobQueue<T> {
+    fn clone(&self) -> Self {
+        return JobQueue { tx: self.tx.clone() };
+    }
+}
+
+impl<T> JobQueue<T> {
+    // returns false when the pool is closed, and the job is dropped.
+    pub fn push(&self, job: T) -> bool {
+        return match self.tx.lock().unwrap().as_ref() {
+            Some(tx) => tx.send(job).is_ok(),
+            None => false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_jobs_overlap() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let (running_clone, most_running_clone, done_clone) = (running.clone(), most_running.clone(), done.clone());
+        let pool = WorkerPool::new(4, move |_job: u32| {
+            let now_running = running_clone.fetch_add(1, Ordering::SeqCst) + 1;
+            most_running_clone.fetch_max(now_running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+            running_clone.fetch_sub(1, Ordering::SeqCst);
+            done_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let start = Instant::now();
+        let queue = pool.queue();
+        for job in 0..4 {
+            assert!(queue.push(job));
+        }
+        pool.join();
+        // one after another, it would take 800 ms.
+        assert!(start.elapsed() < Duration::from_millis(600), "took {:?}", start.elapsed());
+        assert!(most_running.load(Ordering::SeqCst) > 1);
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_close() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let done_clone = done.clone();
+        let pool = WorkerPool::new(0, move |_job: u32| {
+            done_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        let queue = pool.queue();
+        assert!(queue.push(1));
+        pool.close();
+        assert!(!queue.push(2));
+        pool.join();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}